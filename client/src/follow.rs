@@ -0,0 +1,90 @@
+use std::fs::{self, File, Metadata};
+use std::io::{Read, Result as ioResult, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::sleep;
+
+use mproxy_common::{is_shutdown, SHUTDOWN_POLL_INTERVAL};
+
+/// Reads a file like `tail -F`.
+///
+/// At EOF, waits for more data to be appended instead of returning.
+/// If the file at `path` is truncated, reading restarts from the beginning.
+/// If the file is renamed or replaced (e.g. by log rotation), the remainder
+/// of the old file is read, then the new file at `path` is opened.
+/// Returns EOF once `shutdown` is set.
+pub struct FollowReader {
+    path: PathBuf,
+    file: File,
+    offset: u64,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl FollowReader {
+    pub fn new(path: PathBuf, file: File, shutdown: Arc<AtomicBool>) -> Self {
+        FollowReader {
+            path,
+            file,
+            offset: 0,
+            shutdown,
+        }
+    }
+
+    /// Reopen or rewind the file if it was rotated or truncated
+    fn check_rotation(&mut self) -> ioResult<()> {
+        let meta = match fs::metadata(&self.path) {
+            Ok(meta) => meta,
+            // moved away and not yet recreated
+            Err(_) => return Ok(()),
+        };
+        let current = self.file.metadata()?;
+        if !same_file(&meta, &current) {
+            if current.len() > self.offset {
+                // finish reading the old file first
+                return Ok(());
+            }
+            #[cfg(debug_assertions)]
+            println!("client: {} was rotated, reopening", self.path.display());
+            self.file = File::open(&self.path)?;
+            self.offset = 0;
+        } else if meta.len() < self.offset {
+            #[cfg(debug_assertions)]
+            println!("client: {} was truncated, rewinding", self.path.display());
+            self.file.seek(SeekFrom::Start(0))?;
+            self.offset = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Read for FollowReader {
+    fn read(&mut self, buf: &mut [u8]) -> ioResult<usize> {
+        loop {
+            let c = self.file.read(buf)?;
+            if c > 0 {
+                self.offset += c as u64;
+                return Ok(c);
+            }
+            if is_shutdown(&self.shutdown) {
+                return Ok(0);
+            }
+            sleep(SHUTDOWN_POLL_INTERVAL);
+            self.check_rotation()?;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    match (a.created(), b.created()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => true,
+    }
+}
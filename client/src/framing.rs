@@ -0,0 +1,146 @@
+use std::str::FromStr;
+
+use crate::BUFSIZE;
+
+/// Default maximum datagram payload size for [Framing::Packed].
+/// Fits within a 1500 byte ethernet frame over both IPv4 and IPv6
+pub const DEFAULT_MTU: usize = 1452;
+
+/// Determines how input bytes are split into datagrams
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Send each read from the input as-is. Lines may be split across datagrams
+    #[default]
+    Raw,
+    /// Send one newline-delimited line per datagram
+    Line,
+    /// Pack as many complete lines as will fit into each datagram, up to `mtu` bytes
+    Packed { mtu: usize },
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    /// Parse `raw`, `line`, or `packed`. Packed framing uses [DEFAULT_MTU]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Framing::Raw),
+            "line" => Ok(Framing::Line),
+            "packed" => Ok(Framing::Packed { mtu: DEFAULT_MTU }),
+            other => Err(format!(
+                "unknown framing '{}', expected one of raw, line, packed",
+                other
+            )),
+        }
+    }
+}
+
+/// Splits a byte stream into datagrams according to a [Framing] mode.
+///
+/// Incomplete lines are held back until the rest of the line arrives, so
+/// that line-based framing never splits a record across datagrams.
+/// Lines longer than the maximum datagram size are split.
+#[derive(Debug)]
+pub struct Framer {
+    framing: Framing,
+    pending: Vec<u8>,
+    packet: Vec<u8>,
+}
+
+impl Framer {
+    pub fn new(framing: Framing) -> Self {
+        Framer {
+            framing,
+            pending: vec![],
+            packet: vec![],
+        }
+    }
+
+    /// Largest datagram that will be emitted
+    fn max_datagram(&self) -> usize {
+        match self.framing {
+            Framing::Packed { mtu } => mtu.clamp(1, BUFSIZE),
+            _ => BUFSIZE,
+        }
+    }
+
+    /// Append `data` to the stream, and call `emit` for each complete datagram
+    pub fn push<E, F>(&mut self, data: &[u8], mut emit: F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        if self.framing == Framing::Raw {
+            return emit(data);
+        }
+        self.pending.extend_from_slice(data);
+
+        let mut start = 0;
+        while let Some(end) = self.pending[start..].iter().position(|b| *b == b'\n') {
+            let line = self.pending[start..start + end + 1].to_vec();
+            start += end + 1;
+            self.push_line(&line, &mut emit)?;
+        }
+        self.pending.drain(..start);
+
+        // a partial line that can never fit in a datagram is sent as-is
+        let max = self.max_datagram();
+        while self.pending.len() >= max {
+            let chunk: Vec<u8> = self.pending.drain(..max).collect();
+            self.flush_packet(&mut emit)?;
+            emit(&chunk)?;
+        }
+
+        // don't hold complete lines back waiting for more input
+        self.flush_packet(&mut emit)
+    }
+
+    /// Emit any remaining buffered bytes, e.g. a final line with no trailing newline
+    pub fn finish<E, F>(&mut self, mut emit: F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        if !self.pending.is_empty() {
+            let line = std::mem::take(&mut self.pending);
+            self.push_line(&line, &mut emit)?;
+        }
+        self.flush_packet(&mut emit)
+    }
+
+    fn push_line<E, F>(&mut self, line: &[u8], emit: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        // skip empty lines
+        if line.iter().all(|b| *b == b'\n' || *b == b'\r') {
+            return Ok(());
+        }
+        let max = self.max_datagram();
+        match self.framing {
+            Framing::Packed { .. } if line.len() <= max => {
+                if self.packet.len() + line.len() > max {
+                    self.flush_packet(emit)?;
+                }
+                self.packet.extend_from_slice(line);
+                Ok(())
+            }
+            _ => {
+                self.flush_packet(emit)?;
+                for chunk in line.chunks(max) {
+                    emit(chunk)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn flush_packet<E, F>(&mut self, emit: &mut F) -> Result<(), E>
+    where
+        F: FnMut(&[u8]) -> Result<(), E>,
+    {
+        if !self.packet.is_empty() {
+            emit(&self.packet)?;
+            self.packet.clear();
+        }
+        Ok(())
+    }
+}
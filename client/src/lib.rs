@@ -27,8 +27,11 @@
 //! // copy input to stdout
 //! let tee = true;
 //!
+//! // keep daily backups of the input for 7 days
+//! let backup_interval = Some(7);
+//!
 //! let client_thread = spawn(move || {
-//!     client_socket_stream(&path, server_addrs, tee).unwrap();
+//!     client_socket_stream(&path, server_addrs, tee, backup_interval).unwrap();
 //! });
 //!
 //! // run client until EOF
@@ -50,16 +53,58 @@
 //!   mproxy-client [FLAGS] [OPTIONS] ...
 //!
 //! OPTIONS:
-//!   --path        [FILE_DESCRIPTOR]   Filepath, descriptor, or handle. Use "-" for stdin
-//!   --server-addr [HOSTNAME:PORT]     Downstream UDP server address. May be repeated
+//!   --path            [FILE_DESCRIPTOR]   Filepath, descriptor, or handle. Use "-" for stdin
+//!   --server-addr     [HOSTNAME:PORT]     Downstream UDP server address. May be repeated
+//!   --framing         [raw|line|packed]   Split input into datagrams by read, by line, or
+//!                                         by packing whole lines up to --mtu. Default is raw
+//!   --mtu             [BYTES]             Maximum datagram size for packed framing. Default 1452
 //!
 //! FLAGS:
 //!   -h, --help    Prints help information
 //!   -t, --tee     Copy input to stdout
+//!   -f, --follow  Keep reading data appended to --path, reopening the file if it is rotated
+//!   --replay      Pace transmission using per-line timestamps, from an NMEA tag block
+//!                 'c:' field or a leading epoch/RFC3339 timestamp
+//!   --pcap        Read --path as a pcap or pcapng capture, and send each captured UDP payload
+//!                 with its original timing. Replay options set the pace and time window
+//!
+//! BACKUP OPTIONS:
+//!   --backup-interval  [DAYS]                Delete backups older than this many days. Enables backups
+//!   --backup-dir       [DIR]                 Backup directory. Enables backups. Default ./ais_backup
+//!   --backup-pattern   [PATTERN]             Backup filename with strftime-style %Y %m %d %H %M
+//!                                            placeholders. Default %Y-%m-%d.log
+//!   --backup-rotation  [never|hourly|daily]  Start a new backup file each hour or day. Default daily
+//!   --backup-max-size  [BYTES]               Start a new backup file after this many bytes
+//!   --backup-max-files [COUNT]               Keep at most this many rotated backup files
+//!   --backup-compress  [none|gzip|zstd]      Compress rotated backup files. Requires crate
+//!                                            feature gzip or zstd
+//!
+//! REPLAY OPTIONS:
+//!   --replay-speed [MULTIPLIER]  Playback speed multiplier. Default 1.0
+//!   --replay-start [TIMESTAMP]   Skip lines before this epoch or RFC3339 timestamp
+//!   --replay-end   [TIMESTAMP]   Stop at the first line after this epoch or RFC3339 timestamp
+//!
+//! NMEA OPTIONS:
+//!   --drop-corrupt       Drop lines which are not valid NMEA sentences, checked by *hh checksum
+//!   --quarantine [FILE]  Append lines which are not valid NMEA sentences to FILE, instead of
+//!                        sending them
+//!                        Use with --framing line or packed
+//!
+//! TAG BLOCK OPTIONS:
+//!   --tag-station [STATION]  Add an NMEA 4.10 tag block source station 's:STATION' to sentences
+//!                            without one. The same station is used for every --server-addr
+//!   --tag-time               Add a tag block send time 'c:EPOCH' to sentences without one
+//!   --strip-tags             Remove tag blocks before sending, e.g. for consumers which do not
+//!                            support them
+//!                            Use with --framing line or packed
 //!
 //! EXAMPLE:
 //!   mproxy-client --path /dev/random --server-addr '127.0.0.1:9920' --server-addr '[::1]:9921'
 //!   mproxy-client --path - --server-addr '224.0.0.1:9922' --server-addr '[ff02::1]:9923' --tee >> logfile.log
+//!   mproxy-client --path ais.nmea --server-addr 'localhost:9920' --framing line
+//!   mproxy-client --path /var/log/ais/receiver.log --server-addr 'localhost:9920' --follow --backup-interval 30
+//!   mproxy-client --path ais_backup/2024-01-01.log --server-addr 'localhost:9920' --replay --replay-speed 10
+//!   mproxy-client --path capture.pcapng --server-addr 'localhost:9920' --pcap
 //! ```
 //!
 //! ### See Also
@@ -69,216 +114,281 @@
 //! - [mproxy-reverse](https://docs.rs/mproxy-reverse/)
 //!
 
-use std::fs::OpenOptions;
-use std::io::{stdin, stdout, BufRead, BufReader, BufWriter, Read, Result as ioResult, Write};
-use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::fs::{File, OpenOptions};
+use std::io::{stdin, BufRead, BufReader};
+use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::fs::{self, create_dir_all};
-use std::time::{SystemTime, Duration};
-use std::path::Path;
-use time;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-const BUFSIZE: usize = 8096;
+mod follow;
+mod framing;
+mod replay;
+pub use follow::FollowReader;
+pub use framing::{Framer, Framing, DEFAULT_MTU};
+pub use replay::{line_timestamp, parse_timestamp, ReplayOptions};
+use replay::{Pace, Pacer};
 
-pub fn target_socket_interface(server_addr: &String) -> ioResult<(SocketAddr, UdpSocket)> {
-    let target_addr = server_addr
-        .to_socket_addrs()
-        .unwrap()
-        .next()
-        .expect("parsing socket address");
+use mproxy_common::{
+    is_shutdown, DatagramMeta, FileSource, NmeaFilter, Pipeline, PipelineSink, Received, Sink,
+    Source, UdpSink, WriterSink, UNSPECIFIED_ADDR,
+};
+pub use mproxy_common::{
+    send_to_target, target_socket_interface, Compression, MproxyError, PcapPacket, PcapReader,
+    RotatingFile, RotatingFileOptions, Rotation, ShutdownHandle, TagOptions, Validation,
+};
 
-    // Binds to a random UDP port for sending to downstream.
-    let unspec: SocketAddr = if target_addr.is_ipv4() {
-        SocketAddr::new(std::net::Ipv4Addr::UNSPECIFIED.into(), 0)
-    } else {
-        SocketAddr::new(std::net::Ipv6Addr::UNSPECIFIED.into(), 0)
-    };
+const BUFSIZE: usize = 8096;
 
-    let target_socket = UdpSocket::bind(unspec).expect("binding client socket");
-    //target_socket.connect(target_addr).unwrap_or_else(|e| panic!("{}", e));
+/// Options for [client_socket_stream_with]
+#[derive(Clone, Debug, Default)]
+pub struct ClientOptions {
+    /// Copy input to stdout
+    pub tee: bool,
+    /// Keep a copy of the input in rotating backup files
+    pub backup: Option<RotatingFileOptions>,
+    /// How input is split into datagrams
+    pub framing: Framing,
+    /// Keep reading data appended to `path` after EOF, like `tail -F`.
+    /// Truncated or rotated files are reopened. Ignored when reading stdin
+    pub follow: bool,
+    /// Pace transmission using per-line timestamps, instead of sending
+    /// input as fast as possible. Input is read line by line
+    pub replay: Option<ReplayOptions>,
+    /// Read input as a pcap or pcapng capture, and send each captured UDP
+    /// payload as a datagram with its original timing. The pace and replay
+    /// window are set by `replay`. `framing` is ignored
+    pub pcap: bool,
+    /// Drop or quarantine corrupt NMEA sentences instead of sending them.
+    /// Datagrams are validated line by line, so use line or packed framing.
+    /// Backups contain the unmodified input
+    pub validation: Validation,
+    /// Add a station identifier and send time to NMEA tag blocks, or strip
+    /// tag blocks before sending. Applied alike for all server addresses
+    pub tags: TagOptions,
+}
 
-    if target_addr.ip().is_multicast() {
-        match target_addr.ip() {
-            // join the ipv4 multicast group
-            IpAddr::V4(ip) => {
-                target_socket
-                    .join_multicast_v4(&ip, &std::net::Ipv4Addr::UNSPECIFIED)
-                    .unwrap_or_else(|e| panic!("{}", e));
+impl ClientOptions {
+    fn validate(&self) -> Result<(), MproxyError> {
+        if let Some(replay) = &self.replay {
+            if !(replay.speed.is_finite() && replay.speed > 0.0) {
+                return Err(MproxyError::Config(format!(
+                    "replay speed must be a positive number, got {}",
+                    replay.speed
+                )));
             }
+        }
+        Ok(())
+    }
+}
 
-            // for multicast ipv6, join the multicast group on an unspecified
-            // interface, then connect to an unspecified remote socket address
-            // with the target port
-            IpAddr::V6(ip) => {
-                #[cfg(target_os = "linux")]
-                let itf = 0;
-
-                #[cfg(target_os = "windows")]
-                let itf = 0;
-
-                #[cfg(target_os = "macos")]
-                let itf = default_net::get_default_interface()
-                    .expect("Getting default network interface")
-                    .index;
-
-                #[cfg(not(target_os = "windows"))]
-                target_socket
-                    .connect(SocketAddr::new(
-                        IpAddr::V6(Ipv6Addr::UNSPECIFIED),
-                        target_addr.port(),
-                    ))
-                    .unwrap_or_else(|e| panic!("{}", e));
+/// Daily backups in `./ais_backup`, deleting backups older than `days`
+pub fn backup_days(days: u64) -> RotatingFileOptions {
+    RotatingFileOptions {
+        max_age: Some(Duration::from_secs(days * 24 * 60 * 60)),
+        ..Default::default()
+    }
+}
 
-                #[cfg(target_os = "windows")]
-                target_socket
-                    .connect(target_addr)
-                    .unwrap_or_else(|e| panic!("{}", e));
+/// Read bytes from `path` info a buffer, and forward to downstream UDP server addresses.
+/// Optionally copy output to stdout.
+/// If `backup_interval` is set, daily backups of the input are kept in
+/// `./ais_backup` for that many days
+pub fn client_socket_stream(
+    path: &PathBuf,
+    server_addrs: Vec<String>,
+    tee: bool,
+    backup_interval: Option<u64>,
+) -> Result<(), MproxyError> {
+    let options = ClientOptions {
+        tee,
+        backup: backup_interval.map(backup_days),
+        ..Default::default()
+    };
+    client_socket_stream_with(path, server_addrs, &options)
+}
 
-                target_socket
-                    .join_multicast_v6(&ip, itf) // index 0 for unspecified interface
-                    .unwrap_or_else(|e| panic!("{}", e));
-            }
-        };
-    }
+/// Read bytes from `path`, and forward to downstream UDP server addresses.
+/// Input is split into datagrams according to `options.framing`.
+/// Runs until EOF, or forever if `options.follow` is set
+pub fn client_socket_stream_with(
+    path: &PathBuf,
+    server_addrs: Vec<String>,
+    options: &ClientOptions,
+) -> Result<(), MproxyError> {
+    options.validate()?;
+    let targets = client_targets(path, server_addrs)?;
+    let input = open_input(path)?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    stream_input(path, input, targets, options, shutdown)
+}
 
-    Ok((target_addr, target_socket))
+/// Spawn a thread running [client_socket_stream_with].
+/// Errors resolving `server_addrs` or opening `path` are returned immediately.
+/// In follow mode, the client runs until the returned handle is shut down.
+/// Shutdown is checked between reads, so a blocking read from stdin is only
+/// interrupted once it returns
+pub fn spawn_client(
+    path: PathBuf,
+    server_addrs: Vec<String>,
+    options: ClientOptions,
+) -> Result<ShutdownHandle, MproxyError> {
+    options.validate()?;
+    let targets = client_targets(&path, server_addrs)?;
+    let input = open_input(&path)?;
+    let name = format!("{}:client", path.display());
+    ShutdownHandle::spawn(name, move |shutdown| {
+        stream_input(&path, input, targets, &options, shutdown)
+    })
 }
 
-/// Read bytes from `path` info a buffer, and forward to downstream UDP server addresses.
-/// Optionally copy output to stdout
-pub fn client_socket_stream(path: &PathBuf, server_addrs: Vec<String>, tee: bool, backup_interval: Option<u64>) -> ioResult<()> {
+fn client_targets(path: &Path, server_addrs: Vec<String>) -> Result<Vec<UdpSink>, MproxyError> {
     let mut targets = vec![];
 
     for server_addr in server_addrs {
-        let (target_addr, target_socket) = target_socket_interface(&server_addr)?;
-
-        targets.push((target_addr, target_socket));
+        targets.push(UdpSink::connect(&server_addr)?);
         println!(
             "logging from {}: sending to {}",
-            &path.as_os_str().to_str().unwrap(),
+            path.display(),
             server_addr,
         );
     }
+    Ok(targets)
+}
 
+/// Open `path` for reading, or `None` if path is "-" (stdin)
+fn open_input(path: &PathBuf) -> Result<Option<File>, MproxyError> {
+    if path == &PathBuf::from_str("-").unwrap() {
+        return Ok(None);
+    }
+    OpenOptions::new()
+        .create(false)
+        .write(false)
+        .read(true)
+        .open(path)
+        .map(Some)
+        .map_err(|source| MproxyError::File {
+            path: path.clone(),
+            source,
+        })
+}
+
+fn stream_input(
+    path: &Path,
+    input: Option<File>,
+    targets: Vec<UdpSink>,
+    options: &ClientOptions,
+    shutdown: Arc<AtomicBool>,
+) -> Result<(), MproxyError> {
     // if path is "-" set read buffer to stdin
     // otherwise, create buffered reader from given file descriptor
-    let mut reader: Box<dyn BufRead> = if path == &PathBuf::from_str("-").unwrap() {
-        Box::new(BufReader::new(stdin()))
-    } else {
-        Box::new(BufReader::new(
-            OpenOptions::new()
-                .create(false)
-                .write(false)
-                .read(true)
-                .open(path)
-                .unwrap_or_else(|e| {
-                    panic!("opening {}, {}", path.as_os_str().to_str().unwrap(), e)
-                }),
-        ))
+    let mut reader: Box<dyn BufRead + Send> = match input {
+        None => Box::new(BufReader::new(stdin())),
+        Some(file) if options.follow => Box::new(BufReader::new(FollowReader::new(
+            path.to_path_buf(),
+            file,
+            shutdown.clone(),
+        ))),
+        Some(file) => Box::new(BufReader::new(file)),
     };
 
     let mut buf = vec![0u8; BUFSIZE];
-    let mut output_buffer = BufWriter::new(stdout());
+    let mut framer = Framer::new(options.framing);
 
-    while let Ok(c) = reader.read(&mut buf) {
-        if c == 0 {
-            #[cfg(debug_assertions)]
-            println!(
-                "\nclient: encountered EOF in {}, exiting...",
-                &path.display(),
-            );
-            break;
-        } else if c == 1 && String::from_utf8(buf[0..c].to_vec()).unwrap() == *"\n" {
-            // skip empty lines
-            continue;
-        }
+    // backups contain the unmodified input
+    let mut sinks: Vec<Box<dyn Sink>> = vec![];
+    if let Some(backup) = &options.backup {
+        sinks.push(Box::new(WriterSink::new(RotatingFile::new(
+            backup.clone(),
+        )?)));
+    }
+    let mut outputs: Vec<Box<dyn Sink>> = vec![];
+    for target in targets {
+        outputs.push(Box::new(target));
+    }
+    if options.tee {
+        outputs.push(Box::new(WriterSink::stdout()));
+    }
+    let mut pipeline = Pipeline::new().stage(NmeaFilter::new(&options.validation)?);
+    if !options.tags.is_passthrough() {
+        pipeline.push(Box::new(options.tags.clone()));
+    }
+    sinks.push(Box::new(PipelineSink::new(pipeline, outputs)));
 
-        // Backup data if needed
-        if backup_interval.is_some() {
-            backup_data(&buf[0..c], backup_interval)?;
+    let mut send = |msg: &[u8]| -> Result<(), MproxyError> {
+        let meta = DatagramMeta {
+            source: UNSPECIFIED_ADDR,
+            listen_addr: UNSPECIFIED_ADDR,
+            received: SystemTime::now(),
+        };
+        for sink in sinks.iter_mut() {
+            sink.send(msg, &meta)?;
+            sink.flush()?;
         }
+        Ok(())
+    };
 
-        for (target_addr, target_socket) in &targets {
-            if !(target_addr.is_ipv6() && target_addr.ip().is_multicast()) {
-                target_socket
-                    .send_to(&buf[0..c], target_addr)
-                    .unwrap_or_else(|e| panic!("sending to server socket: {}", e));
-            } else {
-                target_socket
-                    .send(&buf[0..c])
-                    .unwrap_or_else(|e| panic!("sending to server socket: {}", e));
+    let read_err = |source| MproxyError::File {
+        path: path.to_path_buf(),
+        source,
+    };
+
+    if options.pcap {
+        let mut pacer = Pacer::new(options.replay.clone().unwrap_or_default());
+        let mut capture = PcapReader::new(reader).map_err(read_err)?;
+        while let Some(packet) = capture.next_packet().map_err(read_err)? {
+            if is_shutdown(&shutdown) {
+                break;
+            }
+            match pacer.wait_until(Some(packet.timestamp), &shutdown) {
+                Pace::Send => send(&packet.payload)?,
+                Pace::Skip => continue,
+                Pace::Stop => break,
             }
         }
-        if tee {
-            let _o = output_buffer
-                .write(&buf[0..c])
-                .expect("writing to output buffer");
-            output_buffer.flush().unwrap();
-            #[cfg(debug_assertions)]
-            assert!(c == _o);
-        }
-
+        return Ok(());
     }
-    Ok(())
-}
 
-/// Backup the data to a file in ./ais_backup directory with current date as filename
-/// and clean up old backup files based on backup_interval
-fn backup_data(data: &[u8], backup_interval: Option<u64>) -> ioResult<()> {
-    let backup_dir = Path::new("./ais_backup");
-    
-    // Create backup directory if it doesn't exist
-    create_dir_all(backup_dir)?;
-    
-    // Get current date as YYYY-MM-DD format
-    let now = SystemTime::now();
-    let datetime: time::OffsetDateTime = now.into();
-    let current_date = format!("{:04}-{:02}-{:02}", 
-        datetime.year(), 
-        datetime.month() as u8, 
-        datetime.day()
-    );
-    
-    let backup_path = backup_dir.join(format!("{}.log", current_date));
-    
-    // Append data to backup file
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&backup_path)?;
-    file.write_all(data)?;
-    
-    // Clean up old backup files if backup_interval is specified
-    if let Some(days) = backup_interval {
-        if let Ok(entries) = fs::read_dir(backup_dir) {
-            for entry in entries.flatten() {
-                let filename = entry.file_name();
-                let filename_str = filename.to_string_lossy();
-                
-                // Only process .log files
-                if !filename_str.ends_with(".log") {
-                    continue;
-                }
-                
-                // Parse date from filename (format: YYYY-MM-DD.log)
-                let date_str = &filename_str[..10];
-                if let Ok(file_time) = time::Date::parse(
-                    date_str,
-                    &time::format_description::parse("[year]-[month]-[day]").unwrap()
-                ) {
-                    // Convert days to seconds
-                    let cutoff = now - Duration::from_secs(days * 24 * 60 * 60);
-                    let file_system_time: SystemTime = (file_time.with_hms(0, 0, 0).unwrap()).assume_utc().into();
-                    
-                    if file_system_time < cutoff {
-                        let _ = fs::remove_file(entry.path());
-                    }
-                }
+    if let Some(replay) = &options.replay {
+        let mut pacer = Pacer::new(replay.clone());
+        let mut line = vec![];
+        while !is_shutdown(&shutdown) {
+            line.clear();
+            if reader.read_until(b'\n', &mut line).map_err(read_err)? == 0 {
+                break;
+            }
+            match pacer.wait(&line, &shutdown) {
+                Pace::Send => framer.push(&line, &mut send)?,
+                Pace::Skip => continue,
+                Pace::Stop => break,
             }
         }
+        return framer.finish(&mut send);
     }
-    Ok(())
-}
 
+    let mut source = FileSource::from_reader(path, reader);
+    while !is_shutdown(&shutdown) {
+        let c = match source.recv(&mut buf)? {
+            Received::Data { len, .. } => len,
+            Received::Timeout => continue,
+            Received::Eof => {
+                #[cfg(debug_assertions)]
+                println!(
+                    "\nclient: encountered EOF in {}, exiting...",
+                    path.display(),
+                );
+                break;
+            }
+        };
+        if options.framing == Framing::Raw && c == 1 && buf[0] == b'\n' {
+            // skip empty lines. Line framing skips these itself, since a
+            // newline may terminate a partial line from the previous read
+            continue;
+        }
 
+        framer.push(&buf[0..c], &mut send)?;
+    }
+    framer.finish(&mut send)
+}
@@ -1,8 +1,12 @@
 use std::ffi::OsStr;
 use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 
-use mproxy_client::client_socket_stream;
+use mproxy_client::{
+    client_socket_stream_with, parse_timestamp, ClientOptions, Framing, ReplayOptions,
+    RotatingFileOptions, TagOptions, Validation,
+};
 
 use pico_args::Arguments;
 
@@ -17,15 +21,56 @@ USAGE:
 OPTIONS:
   --path            [FILE_DESCRIPTOR]   Filepath, descriptor, or handle. Use "-" for stdin
   --server-addr     [HOSTNAME:PORT]     Downstream UDP server address. May be repeated
-  --backup-interval [DAYS]              Backup interval in days. Default is no backup
+  --framing         [raw|line|packed]   Split input into datagrams by read, by line, or
+                                        by packing whole lines up to --mtu. Default is raw
+  --mtu             [BYTES]             Maximum datagram size for packed framing. Default 1452
 
 FLAGS:
   -h, --help    Prints help information
   -t, --tee     Copy input to stdout
+  -f, --follow  Keep reading data appended to --path, reopening the file if it is rotated
+  --replay      Pace transmission using per-line timestamps, from an NMEA tag block
+                'c:' field or a leading epoch/RFC3339 timestamp
+  --pcap        Read --path as a pcap or pcapng capture, and send each captured UDP payload
+                with its original timing. Replay options set the pace and time window
+
+BACKUP OPTIONS:
+  --backup-interval  [DAYS]                Delete backups older than this many days. Enables backups
+  --backup-dir       [DIR]                 Backup directory. Enables backups. Default ./ais_backup
+  --backup-pattern   [PATTERN]             Backup filename with strftime-style %Y %m %d %H %M
+                                           placeholders. Default %Y-%m-%d.log
+  --backup-rotation  [never|hourly|daily]  Start a new backup file each hour or day. Default daily
+  --backup-max-size  [BYTES]               Start a new backup file after this many bytes
+  --backup-max-files [COUNT]               Keep at most this many rotated backup files
+  --backup-compress  [none|gzip|zstd]      Compress rotated backup files. Requires crate
+                                           feature gzip or zstd
+
+REPLAY OPTIONS:
+  --replay-speed [MULTIPLIER]  Playback speed multiplier. Default 1.0
+  --replay-start [TIMESTAMP]   Skip lines before this epoch or RFC3339 timestamp
+  --replay-end   [TIMESTAMP]   Stop at the first line after this epoch or RFC3339 timestamp
+
+NMEA OPTIONS:
+  --drop-corrupt       Drop lines which are not valid NMEA sentences, checked by *hh checksum
+  --quarantine [FILE]  Append lines which are not valid NMEA sentences to FILE, instead of
+                       sending them
+                       Use with --framing line or packed
+
+TAG BLOCK OPTIONS:
+  --tag-station [STATION]  Add an NMEA 4.10 tag block source station 's:STATION' to sentences
+                           without one. The same station is used for every --server-addr
+  --tag-time               Add a tag block send time 'c:EPOCH' to sentences without one
+  --strip-tags             Remove tag blocks before sending, e.g. for consumers which do not
+                           support them
+                           Use with --framing line or packed
 
 EXAMPLE:
   mproxy-client --path /dev/random --server-addr '127.0.0.1:9920' --server-addr '[::1]:9921'
   mproxy-client --path - --server-addr '224.0.0.1:9922' --server-addr '[ff02::1]:9923' --tee >> logfile.log
+  mproxy-client --path ais.nmea --server-addr 'localhost:9920' --framing line
+  mproxy-client --path /var/log/ais/receiver.log --server-addr 'localhost:9920' --follow --backup-interval 30
+  mproxy-client --path ais_backup/2024-01-01.log --server-addr 'localhost:9920' --replay --replay-speed 10
+  mproxy-client --path capture.pcapng --server-addr 'localhost:9920' --pcap
 
 "#;
 
@@ -33,8 +78,7 @@ EXAMPLE:
 pub struct ClientArgs {
     path: PathBuf,
     server_addrs: Vec<String>,
-    tee: bool,
-    backup_interval: Option<u64>,
+    options: ClientOptions,
 }
 
 /// retrieve command line arguments as ClientArgs struct
@@ -44,25 +88,86 @@ fn parse_args() -> Result<ClientArgs, pico_args::Error> {
         print!("{}", HELP);
         exit(0);
     }
-    let tee = pargs.contains(["-t", "--tee"]);
-    let backup_interval: Option<u64> = pargs.opt_value_from_str("--backup-interval")?;
-
     fn parse_path(s: &OsStr) -> Result<PathBuf, &'static str> {
         Ok(s.into())
     }
 
+    let tee = pargs.contains(["-t", "--tee"]);
+    let follow = pargs.contains(["-f", "--follow"]);
+    let pcap = pargs.contains("--pcap");
+    let replay = if pargs.contains("--replay") || pcap {
+        fn parse_time(s: &str) -> Result<f64, &'static str> {
+            parse_timestamp(s).ok_or("expected epoch seconds or an RFC3339 timestamp")
+        }
+        Some(ReplayOptions {
+            speed: pargs.opt_value_from_str("--replay-speed")?.unwrap_or(1.0),
+            start: pargs.opt_value_from_fn("--replay-start", parse_time)?,
+            end: pargs.opt_value_from_fn("--replay-end", parse_time)?,
+        })
+    } else {
+        None
+    };
+    let backup_interval: Option<u64> = pargs.opt_value_from_str("--backup-interval")?;
+    let backup_dir: Option<PathBuf> = pargs.opt_value_from_os_str("--backup-dir", parse_path)?;
+    let backup = if backup_interval.is_some() || backup_dir.is_some() {
+        let default = RotatingFileOptions::default();
+        Some(RotatingFileOptions {
+            dir: backup_dir.unwrap_or(default.dir),
+            pattern: pargs
+                .opt_value_from_str("--backup-pattern")?
+                .unwrap_or(default.pattern),
+            rotation: pargs
+                .opt_value_from_str("--backup-rotation")?
+                .unwrap_or(default.rotation),
+            max_size: pargs.opt_value_from_str("--backup-max-size")?,
+            max_files: pargs.opt_value_from_str("--backup-max-files")?,
+            max_age: backup_interval.map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            compression: pargs
+                .opt_value_from_str("--backup-compress")?
+                .unwrap_or(default.compression),
+        })
+    } else {
+        None
+    };
+    let mut framing: Framing = pargs.opt_value_from_str("--framing")?.unwrap_or_default();
+    if let Some(mtu) = pargs.opt_value_from_str("--mtu")? {
+        match framing {
+            Framing::Packed { .. } => framing = Framing::Packed { mtu },
+            _ => println!("Warning: --mtu is only used with --framing packed"),
+        }
+    }
+
+    let drop_corrupt = pargs.contains("--drop-corrupt");
+    let validation = match pargs.opt_value_from_os_str("--quarantine", parse_path)? {
+        Some(path) => Validation::Quarantine(path),
+        None if drop_corrupt => Validation::Drop,
+        None => Validation::Off,
+    };
+    let tags = TagOptions {
+        station: pargs.opt_value_from_str("--tag-station")?,
+        timestamp: pargs.contains("--tag-time"),
+        strip: pargs.contains("--strip-tags"),
+    };
     let args = ClientArgs {
         path: pargs.value_from_os_str("--path", parse_path)?,
         server_addrs: pargs.values_from_str("--server-addr")?,
-        tee,
-        backup_interval,
+        options: ClientOptions {
+            tee,
+            backup,
+            framing,
+            follow,
+            replay,
+            pcap,
+            validation,
+            tags,
+        },
     };
     let remaining = pargs.finish();
     if !remaining.is_empty() {
         println!("Warning: unused arguments {:?}", remaining)
     }
 
-    if args.server_addrs.is_empty() && !args.tee {
+    if args.server_addrs.is_empty() && !args.options.tee {
         println!(
             "At least one server address (or the --tee flag) is required. See --help for more info"
         );
@@ -80,5 +185,8 @@ pub fn main() {
             exit(1);
         }
     };
-    let _ = client_socket_stream(&args.path, args.server_addrs, args.tee, args.backup_interval);
+    if let Err(e) = client_socket_stream_with(&args.path, args.server_addrs, &args.options) {
+        eprintln!("Error: {}.", e);
+        exit(1);
+    }
 }
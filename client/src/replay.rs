@@ -0,0 +1,134 @@
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+
+use mproxy_common::sleep_unless_shutdown;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Options for replaying archived logs at their original pace
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayOptions {
+    /// Playback speed multiplier, e.g. 2.0 replays twice as fast as recorded
+    pub speed: f64,
+    /// Skip lines timestamped before this time (epoch seconds)
+    pub start: Option<f64>,
+    /// Stop at the first line timestamped after this time (epoch seconds)
+    pub end: Option<f64>,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            speed: 1.0,
+            start: None,
+            end: None,
+        }
+    }
+}
+
+/// Parse a timestamp given as epoch seconds (e.g. `1700000000.25`) or as
+/// RFC3339 (e.g. `2023-11-14T22:13:20Z`). Returns epoch seconds
+pub fn parse_timestamp(s: &str) -> Option<f64> {
+    if let Ok(epoch) = s.parse::<f64>() {
+        return epoch.is_finite().then_some(epoch);
+    }
+    OffsetDateTime::parse(s, &Rfc3339)
+        .ok()
+        .map(|t| t.unix_timestamp_nanos() as f64 / 1e9)
+}
+
+/// Get the timestamp of a logged line, in epoch seconds.
+///
+/// The timestamp is read from the `c:` field of a leading NMEA 4.10 tag
+/// block (e.g. `\s:station,c:1700000000*hh\!AIVDM,...`), or from a leading
+/// epoch or RFC3339 timestamp followed by whitespace, `,` or `;`
+/// (e.g. `1700000000.25 !AIVDM,...`).
+/// Tag block times greater than 10^11 are interpreted as milliseconds.
+pub fn line_timestamp(line: &[u8]) -> Option<f64> {
+    let line = std::str::from_utf8(line).ok()?.trim_start();
+
+    if let Some(tagblock) = line.strip_prefix('\\') {
+        let end = tagblock.find('\\')?;
+        let fields = tagblock[..end].split('*').next()?;
+        let epoch: f64 = fields
+            .split(',')
+            .find_map(|f| f.strip_prefix("c:"))?
+            .parse()
+            .ok()?;
+        return Some(if epoch > 1e11 { epoch / 1000.0 } else { epoch });
+    }
+
+    let prefix = line
+        .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+        .next()?;
+    parse_timestamp(prefix)
+}
+
+/// Outcome of pacing a line
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Pace {
+    /// Send the line now
+    Send,
+    /// Line is before the replay window
+    Skip,
+    /// Line is after the replay window, or shutdown was requested
+    Stop,
+}
+
+/// Delays lines to reproduce the intervals between their timestamps.
+/// Lines without a timestamp are sent immediately after the previous line
+pub(crate) struct Pacer {
+    options: ReplayOptions,
+    /// First replayed timestamp, and when it was sent
+    origin: Option<(f64, Instant)>,
+    /// Most recent timestamp
+    last: Option<f64>,
+}
+
+impl Pacer {
+    pub(crate) fn new(options: ReplayOptions) -> Self {
+        Pacer {
+            options,
+            origin: None,
+            last: None,
+        }
+    }
+
+    /// Wait until `line` is due to be sent
+    pub(crate) fn wait(&mut self, line: &[u8], shutdown: &AtomicBool) -> Pace {
+        self.wait_until(line_timestamp(line), shutdown)
+    }
+
+    /// Wait until a message with timestamp `ts` is due to be sent
+    pub(crate) fn wait_until(&mut self, ts: Option<f64>, shutdown: &AtomicBool) -> Pace {
+        let ts = match ts {
+            Some(ts) => {
+                self.last = Some(ts);
+                ts
+            }
+            None => match self.last {
+                Some(ts) => ts,
+                None if self.options.start.is_some() => return Pace::Skip,
+                None => return Pace::Send,
+            },
+        };
+
+        if self.options.start.is_some_and(|start| ts < start) {
+            return Pace::Skip;
+        }
+        if self.options.end.is_some_and(|end| ts > end) {
+            return Pace::Stop;
+        }
+
+        let (t0, w0) = *self.origin.get_or_insert((ts, Instant::now()));
+        let offset = (ts - t0) / self.options.speed;
+        if offset > 0.0 {
+            let due = w0 + Duration::from_secs_f64(offset);
+            let wait = due.saturating_duration_since(Instant::now());
+            if sleep_unless_shutdown(shutdown, wait) {
+                return Pace::Stop;
+            }
+        }
+        Pace::Send
+    }
+}
@@ -1,15 +1,21 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 use testconfig::{truncate, TESTDATA, TESTINGDIR};
 
-use mproxy_client::client_socket_stream;
-use mproxy_server::listener;
+use mproxy_client::{
+    client_socket_stream, client_socket_stream_with, line_timestamp, spawn_client,
+    target_socket_interface, ClientOptions, Framing, MproxyError, ReplayOptions, TagOptions,
+};
+use mproxy_common::{PcapngWriter, Sentence};
+use mproxy_server::{listener, upstream_socket_interface};
 
 fn test_client(pathstr: &str, listen_addr: String, target_addr: String, tee: bool) {
-    let _l = listener(listen_addr, PathBuf::from_str(pathstr).unwrap(), false);
+    let _l = listener(listen_addr, PathBuf::from_str(pathstr).unwrap(), false).unwrap();
     let _c = client_socket_stream(&PathBuf::from(TESTDATA), vec![target_addr], tee, None);
     let bytesize = truncate(PathBuf::from_str(pathstr).unwrap());
     println!("log size: {}", bytesize);
@@ -18,7 +24,7 @@ fn test_client(pathstr: &str, listen_addr: String, target_addr: String, tee: boo
 
 #[test]
 fn test_client_socket_stream_unicast_ipv4() {
-    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv4_unicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv4_unicast.log"].join("");
     let listen_addr = "0.0.0.0:9910".to_string();
     let target_addr = "127.0.0.1:9910".to_string();
     test_client(pathstr, listen_addr, target_addr, false)
@@ -26,7 +32,7 @@ fn test_client_socket_stream_unicast_ipv4() {
 
 #[test]
 fn test_client_socket_stream_multicast_ipv4() {
-    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv4_multicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv4_multicast.log"].join("");
     let target_addr = "224.0.0.110:9911".to_string();
     let listen_addr = target_addr.clone();
     test_client(pathstr, listen_addr, target_addr, false)
@@ -34,7 +40,7 @@ fn test_client_socket_stream_multicast_ipv4() {
 
 #[test]
 fn test_client_socket_stream_unicast_ipv6() {
-    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv6_unicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv6_unicast.log"].join("");
     let listen_addr = "[::1]:9912".to_string();
     let target_addr = "[::1]:9912".to_string();
     test_client(pathstr, listen_addr, target_addr, false)
@@ -42,7 +48,7 @@ fn test_client_socket_stream_unicast_ipv6() {
 
 #[test]
 fn test_client_socket_stream_multicast_ipv6() {
-    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv6_multicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_client_ipv6_multicast.log"].join("");
     let listen_addr = "[ff02::0]:9913".to_string();
     let target_addr = "[ff02::1]:9913".to_string();
     test_client(pathstr, listen_addr, target_addr, false)
@@ -50,7 +56,7 @@ fn test_client_socket_stream_multicast_ipv6() {
 
 #[test]
 fn test_client_socket_tee() {
-    let pathstr = &[TESTINGDIR, "streamoutput_client_tee.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_client_tee.log"].join("");
     let target_addr = "127.0.0.1:9914".to_string();
     let listen_addr = "0.0.0.0:9914".to_string();
     test_client(pathstr, listen_addr, target_addr, true)
@@ -58,16 +64,16 @@ fn test_client_socket_tee() {
 
 #[test]
 fn test_client_multiple_servers() {
-    let pathstr_1 = &[TESTINGDIR, "streamoutput_client_ipv6_multiplex_1.log"].join(&"");
-    let pathstr_2 = &[TESTINGDIR, "streamoutput_client_ipv6_multiplex_2.log"].join(&"");
+    let pathstr_1 = &[TESTINGDIR, "streamoutput_client_ipv6_multiplex_1.log"].join("");
+    let pathstr_2 = &[TESTINGDIR, "streamoutput_client_ipv6_multiplex_2.log"].join("");
     let listen_addr_1 = "[::]:9915".to_string();
     let listen_addr_2 = "[::]:9916".to_string();
     let target_addr_1 = "[::1]:9915".to_string();
     let target_addr_2 = "[::1]:9916".to_string();
     //test_client(pathstr, listen_addr, target_addr, false)
 
-    let _l1 = listener(listen_addr_1, PathBuf::from_str(pathstr_1).unwrap(), false);
-    let _l2 = listener(listen_addr_2, PathBuf::from_str(pathstr_2).unwrap(), false);
+    let _l1 = listener(listen_addr_1, PathBuf::from_str(pathstr_1).unwrap(), false).unwrap();
+    let _l2 = listener(listen_addr_2, PathBuf::from_str(pathstr_2).unwrap(), false).unwrap();
     let _c = client_socket_stream(
         &PathBuf::from(TESTDATA),
         vec![target_addr_1, target_addr_2],
@@ -82,3 +88,306 @@ fn test_client_multiple_servers() {
     assert!(bytesize_2 > 0);
     assert!(bytesize_1 == bytesize_2);
 }
+
+#[test]
+fn test_client_unresolvable_server_addr() {
+    let target_addr = "not a socket address".to_string();
+    match target_socket_interface(&target_addr) {
+        Err(MproxyError::Resolve { addr, .. }) => assert_eq!(addr, target_addr),
+        other => panic!("expected resolve error, got {:?}", other),
+    }
+    let c = client_socket_stream(&PathBuf::from(TESTDATA), vec![target_addr], false, None);
+    assert!(matches!(c, Err(MproxyError::Resolve { .. })));
+}
+
+#[test]
+fn test_client_missing_input_file() {
+    let path = PathBuf::from(&[TESTINGDIR, "does_not_exist.log"].join(""));
+    let c = client_socket_stream(&path, vec!["127.0.0.1:9918".to_string()], false, None);
+    assert!(matches!(c, Err(MproxyError::File { .. })));
+}
+
+/// Stream TESTDATA to `listen_addr` with `framing`, and return the received datagrams
+fn framed_datagrams(listen_addr: &str, framing: Framing) -> Vec<Vec<u8>> {
+    let (_addr, listen_socket) = upstream_socket_interface(listen_addr.to_string()).unwrap();
+    listen_socket
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let options = ClientOptions {
+        framing,
+        ..Default::default()
+    };
+    client_socket_stream_with(
+        &PathBuf::from(TESTDATA),
+        vec![listen_addr.to_string()],
+        &options,
+    )
+    .unwrap();
+
+    let mut datagrams = vec![];
+    let mut buf = [0u8; 8096];
+    while let Ok((c, _remote)) = listen_socket.recv_from(&mut buf) {
+        datagrams.push(buf[0..c].to_vec());
+    }
+    datagrams
+}
+
+#[test]
+fn test_client_line_framing() {
+    let datagrams = framed_datagrams("127.0.0.1:9919", Framing::Line);
+    let expected: Vec<&[u8]> = include_bytes!("../../readme.md")
+        .split_inclusive(|b| *b == b'\n')
+        .filter(|l| l.iter().any(|b| *b != b'\n' && *b != b'\r'))
+        .collect();
+    assert_eq!(datagrams.len(), expected.len());
+    for (datagram, line) in datagrams.iter().zip(expected) {
+        assert_eq!(datagram.as_slice(), line);
+    }
+}
+
+#[test]
+fn test_client_packed_framing() {
+    let mtu = 512;
+    let datagrams = framed_datagrams("127.0.0.1:9920", Framing::Packed { mtu });
+    assert!(datagrams.len() > 1);
+    for datagram in &datagrams {
+        assert!(datagram.len() <= mtu);
+        assert!(datagram.ends_with(b"\n"));
+    }
+    let received: usize = datagrams.iter().map(|d| d.len()).sum();
+    let nonempty: usize = include_bytes!("../../readme.md")
+        .split_inclusive(|b| *b == b'\n')
+        .filter(|l| l.iter().any(|b| *b != b'\n' && *b != b'\r'))
+        .map(|l| l.len())
+        .sum();
+    assert_eq!(received, nonempty);
+}
+
+#[test]
+fn test_client_follow_append_truncate_rotate() {
+    let path = PathBuf::from(&[TESTINGDIR, "follow_input.log"].join(""));
+    let rotated = PathBuf::from(&[TESTINGDIR, "follow_input.log.1"].join(""));
+    let listen_addr = "127.0.0.1:9921".to_string();
+    let (_addr, listen_socket) = upstream_socket_interface(listen_addr.clone()).unwrap();
+    listen_socket
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let recv = || {
+        let mut buf = [0u8; 8096];
+        let (c, _remote) = listen_socket.recv_from(&mut buf).unwrap();
+        buf[0..c].to_vec()
+    };
+    let append = |p: &PathBuf, msg: &[u8]| {
+        let mut f = OpenOptions::new().append(true).open(p).unwrap();
+        f.write_all(msg).unwrap();
+    };
+
+    File::create(&path).unwrap().write_all(b"first\n").unwrap();
+    let options = ClientOptions {
+        framing: Framing::Line,
+        follow: true,
+        ..Default::default()
+    };
+    let client = spawn_client(path.clone(), vec![listen_addr], options).unwrap();
+    assert_eq!(recv(), b"first\n");
+
+    // appended data is sent after EOF
+    append(&path, b"second\n");
+    assert_eq!(recv(), b"second\n");
+
+    // truncated file is read again from the start
+    File::create(&path).unwrap().write_all(b"3\n").unwrap();
+    assert_eq!(recv(), b"3\n");
+
+    // rotated file is drained, then the new file is opened
+    append(&path, b"fourth\n");
+    std::fs::rename(&path, &rotated).unwrap();
+    File::create(&path).unwrap().write_all(b"fifth\n").unwrap();
+    assert_eq!(recv(), b"fourth\n");
+    assert_eq!(recv(), b"fifth\n");
+
+    client.shutdown().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&rotated).unwrap();
+}
+
+#[test]
+fn test_client_line_timestamp() {
+    assert_eq!(
+        line_timestamp(
+            b"\\s:rx1,c:1700000000*5A\\!AIVDM,1,1,,A,13u?etPv2;0n:dDPwUM1U1Cb069D,0*23\n"
+        ),
+        Some(1700000000.0)
+    );
+    assert_eq!(
+        line_timestamp(b"\\c:1700000000500*00\\!AIVDM,1,1,,A,x,0*00\n"),
+        Some(1700000000.5)
+    );
+    assert_eq!(
+        line_timestamp(b"1700000000.25 !AIVDM,1,1,,B,x,0*00\n"),
+        Some(1700000000.25)
+    );
+    assert_eq!(
+        line_timestamp(b"2023-11-14T22:13:20Z;!AIVDM,1,1,,B,x,0*00\n"),
+        Some(1700000000.0)
+    );
+    assert_eq!(line_timestamp(b"!AIVDM,1,1,,B,x,0*00\n"), None);
+}
+
+fn replay_datagrams(listen_addr: &str, replay: ReplayOptions) -> (Vec<Vec<u8>>, Duration) {
+    let path = PathBuf::from(&[TESTINGDIR, "replay_", &listen_addr[10..], ".log"].join(""));
+    File::create(&path)
+        .unwrap()
+        .write_all(
+            b"\\c:1000*00\\first\n\
+              continued\n\
+              1001.5 second\n\
+              1002 third\n",
+        )
+        .unwrap();
+    let options = ClientOptions {
+        framing: Framing::Line,
+        replay: Some(replay),
+        ..Default::default()
+    };
+    let (_addr, listen_socket) = upstream_socket_interface(listen_addr.to_string()).unwrap();
+    listen_socket
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let start = Instant::now();
+    client_socket_stream_with(&path, vec![listen_addr.to_string()], &options).unwrap();
+    let elapsed = start.elapsed();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut datagrams = vec![];
+    let mut buf = [0u8; 8096];
+    while let Ok((c, _remote)) = listen_socket.recv_from(&mut buf) {
+        datagrams.push(buf[0..c].to_vec());
+    }
+    (datagrams, elapsed)
+}
+
+#[test]
+fn test_client_replay_pacing() {
+    let replay = ReplayOptions {
+        speed: 10.0,
+        ..Default::default()
+    };
+    let (datagrams, elapsed) = replay_datagrams("127.0.0.1:9922", replay);
+    assert_eq!(datagrams.len(), 4);
+    // 2 seconds of recorded time at 10x speed
+    assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+}
+
+#[test]
+fn test_client_replay_window() {
+    let replay = ReplayOptions {
+        speed: 100.0,
+        start: Some(1001.0),
+        end: Some(1001.5),
+    };
+    let (datagrams, _elapsed) = replay_datagrams("127.0.0.1:9923", replay);
+    assert_eq!(datagrams, vec![b"1001.5 second\n".to_vec()]);
+}
+
+#[test]
+fn test_client_replay_invalid_speed() {
+    let replay = ReplayOptions {
+        speed: 0.0,
+        ..Default::default()
+    };
+    let options = ClientOptions {
+        replay: Some(replay),
+        ..Default::default()
+    };
+    let c = client_socket_stream_with(&PathBuf::from(TESTDATA), vec![], &options);
+    assert!(matches!(c, Err(MproxyError::Config(_))));
+}
+
+#[test]
+fn test_client_pcap_replay() {
+    let listen_addr = "127.0.0.1:9928";
+    let path = PathBuf::from(&[TESTINGDIR, "replay.pcapng"].join(""));
+    let mut writer = PcapngWriter::new(File::create(&path).unwrap()).unwrap();
+    let source = "10.0.0.1:50000".parse().unwrap();
+    let destination = "10.0.0.2:9920".parse().unwrap();
+    for (ms, payload) in [
+        (0, "first\n"),
+        (500, "second\n"),
+        (1000, "third,\nfourth\n"),
+    ] {
+        let t = UNIX_EPOCH + Duration::from_secs(1000) + Duration::from_millis(ms);
+        writer
+            .write_udp(t, source, destination, payload.as_bytes())
+            .unwrap();
+    }
+    writer.flush().unwrap();
+    drop(writer);
+
+    let options = ClientOptions {
+        pcap: true,
+        replay: Some(ReplayOptions {
+            speed: 2.0,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let (_addr, listen_socket) = upstream_socket_interface(listen_addr.to_string()).unwrap();
+    listen_socket
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let start = Instant::now();
+    client_socket_stream_with(&path, vec![listen_addr.to_string()], &options).unwrap();
+    let elapsed = start.elapsed();
+    std::fs::remove_file(&path).unwrap();
+
+    // each captured payload is sent as one datagram
+    let mut datagrams = vec![];
+    let mut buf = [0u8; 8096];
+    while let Ok((c, _remote)) = listen_socket.recv_from(&mut buf) {
+        datagrams.push(String::from_utf8(buf[0..c].to_vec()).unwrap());
+    }
+    assert_eq!(datagrams, vec!["first\n", "second\n", "third,\nfourth\n"]);
+    assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+}
+
+#[test]
+fn test_client_tag_station() {
+    let listen_addr = "127.0.0.1:9932";
+    let path = PathBuf::from(&[TESTINGDIR, "tag_station.nmea"].join(""));
+    let mut input = File::create(&path).unwrap();
+    writeln!(input, "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C").unwrap();
+    writeln!(
+        input,
+        "\\s:upstream*50\\!AIVDM,1,1,,A,H42O55i18tMET00000000000000,2*6D"
+    )
+    .unwrap();
+    drop(input);
+
+    let (_addr, listen_socket) = upstream_socket_interface(listen_addr.to_string()).unwrap();
+    listen_socket
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let options = ClientOptions {
+        framing: Framing::Line,
+        tags: TagOptions {
+            station: Some("client1".to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    client_socket_stream_with(&path, vec![listen_addr.to_string()], &options).unwrap();
+
+    let mut stations = vec![];
+    let mut buf = [0u8; 8096];
+    while let Ok((c, _remote)) = listen_socket.recv_from(&mut buf) {
+        let sentence = Sentence::parse(&buf[0..c]).unwrap();
+        let tags = sentence.tags().unwrap().unwrap();
+        assert_eq!(tags.time, None);
+        stations.push(tags.source.unwrap());
+    }
+    assert_eq!(stations, vec!["client1", "upstream"]);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,671 @@
+use std::error::Error;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::nmea::{sentences, AisFragment, Sentence};
+
+/// Errors decoding an AIS message payload
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AisError {
+    /// The payload contains a character outside the 6-bit armoring alphabet
+    InvalidCharacter(char),
+    /// The payload is shorter than required by its message type
+    TooShort { message_type: u8, bits: usize },
+    /// The sentence is one fragment of a multi-sentence message
+    Fragment,
+    /// The sentence is not a valid AIS sentence
+    Sentence(String),
+}
+
+impl fmt::Display for AisError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AisError::InvalidCharacter(c) => write!(f, "invalid payload character {:?}", c),
+            AisError::TooShort { message_type, bits } => write!(
+                f,
+                "payload of {} bits is too short for message type {}",
+                bits, message_type
+            ),
+            AisError::Fragment => write!(f, "sentence is part of a multi-sentence message"),
+            AisError::Sentence(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for AisError {}
+
+/// Class A position report, message types 1, 2, and 3
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PositionReport {
+    pub message_type: u8,
+    pub repeat: u8,
+    pub mmsi: u32,
+    /// Navigational status, e.g. 0 under way using engine, 1 at anchor
+    pub nav_status: u8,
+    /// Rate of turn, in degrees per minute. Positive to starboard
+    pub rot: Option<f64>,
+    /// Speed over ground, in knots
+    pub sog: Option<f64>,
+    pub position_accuracy: bool,
+    pub lon: Option<f64>,
+    pub lat: Option<f64>,
+    /// Course over ground, in degrees
+    pub cog: Option<f64>,
+    /// True heading, in degrees
+    pub heading: Option<u16>,
+    /// UTC second of the report
+    pub second: u8,
+    pub maneuver: u8,
+    pub raim: bool,
+}
+
+/// Base station report, message type 4
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BaseStationReport {
+    pub message_type: u8,
+    pub repeat: u8,
+    pub mmsi: u32,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub position_accuracy: bool,
+    pub lon: Option<f64>,
+    pub lat: Option<f64>,
+    /// Type of position fixing device
+    pub epfd: u8,
+    pub raim: bool,
+}
+
+/// Class A static and voyage related data, message type 5
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StaticVoyageData {
+    pub message_type: u8,
+    pub repeat: u8,
+    pub mmsi: u32,
+    pub ais_version: u8,
+    pub imo: Option<u32>,
+    pub callsign: String,
+    pub name: String,
+    pub ship_type: u8,
+    pub to_bow: u16,
+    pub to_stern: u16,
+    pub to_port: u8,
+    pub to_starboard: u8,
+    pub epfd: u8,
+    pub eta_month: u8,
+    pub eta_day: u8,
+    pub eta_hour: u8,
+    pub eta_minute: u8,
+    /// Draught, in meters
+    pub draught: f64,
+    pub destination: String,
+    pub dte: bool,
+}
+
+/// Class B position report, message type 18
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ClassBPositionReport {
+    pub message_type: u8,
+    pub repeat: u8,
+    pub mmsi: u32,
+    pub sog: Option<f64>,
+    pub position_accuracy: bool,
+    pub lon: Option<f64>,
+    pub lat: Option<f64>,
+    pub cog: Option<f64>,
+    pub heading: Option<u16>,
+    pub second: u8,
+    /// Carrier sense unit, rather than SOTDMA
+    pub cs_unit: bool,
+    pub raim: bool,
+}
+
+/// Extended class B position report, message type 19
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ClassBExtendedReport {
+    pub message_type: u8,
+    pub repeat: u8,
+    pub mmsi: u32,
+    pub sog: Option<f64>,
+    pub position_accuracy: bool,
+    pub lon: Option<f64>,
+    pub lat: Option<f64>,
+    pub cog: Option<f64>,
+    pub heading: Option<u16>,
+    pub second: u8,
+    pub name: String,
+    pub ship_type: u8,
+    pub to_bow: u16,
+    pub to_stern: u16,
+    pub to_port: u8,
+    pub to_starboard: u8,
+    pub epfd: u8,
+    pub raim: bool,
+    pub dte: bool,
+}
+
+/// Aid-to-navigation report, message type 21
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AidToNavigationReport {
+    pub message_type: u8,
+    pub repeat: u8,
+    pub mmsi: u32,
+    pub aid_type: u8,
+    pub name: String,
+    pub position_accuracy: bool,
+    pub lon: Option<f64>,
+    pub lat: Option<f64>,
+    pub to_bow: u16,
+    pub to_stern: u16,
+    pub to_port: u8,
+    pub to_starboard: u8,
+    pub epfd: u8,
+    pub second: u8,
+    pub off_position: bool,
+    pub raim: bool,
+    pub virtual_aid: bool,
+}
+
+/// Class B static data report, message type 24.
+/// Part A (0) contains the name, and part B (1) the remaining fields
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StaticDataReport {
+    pub message_type: u8,
+    pub repeat: u8,
+    pub mmsi: u32,
+    pub part: u8,
+    pub name: Option<String>,
+    pub ship_type: Option<u8>,
+    pub vendor_id: Option<String>,
+    pub callsign: Option<String>,
+    pub to_bow: Option<u16>,
+    pub to_stern: Option<u16>,
+    pub to_port: Option<u8>,
+    pub to_starboard: Option<u8>,
+}
+
+/// Long range position report, message type 27
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LongRangeReport {
+    pub message_type: u8,
+    pub repeat: u8,
+    pub mmsi: u32,
+    pub position_accuracy: bool,
+    pub raim: bool,
+    pub nav_status: u8,
+    pub lon: Option<f64>,
+    pub lat: Option<f64>,
+    /// Speed over ground, in whole knots
+    pub sog: Option<f64>,
+    /// Course over ground, in whole degrees
+    pub cog: Option<f64>,
+    /// Position is from the current GNSS fix
+    pub gnss: bool,
+}
+
+/// Header of a message type without a decoder
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OtherMessage {
+    pub message_type: u8,
+    pub repeat: u8,
+    pub mmsi: u32,
+}
+
+/// A decoded AIS message
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum AisMessage {
+    Position(PositionReport),
+    BaseStation(BaseStationReport),
+    StaticVoyage(StaticVoyageData),
+    ClassBPosition(ClassBPositionReport),
+    ClassBExtended(ClassBExtendedReport),
+    AidToNavigation(AidToNavigationReport),
+    StaticData(StaticDataReport),
+    LongRange(LongRangeReport),
+    Other(OtherMessage),
+}
+
+impl AisMessage {
+    pub fn message_type(&self) -> u8 {
+        match self {
+            AisMessage::Position(m) => m.message_type,
+            AisMessage::BaseStation(m) => m.message_type,
+            AisMessage::StaticVoyage(m) => m.message_type,
+            AisMessage::ClassBPosition(m) => m.message_type,
+            AisMessage::ClassBExtended(m) => m.message_type,
+            AisMessage::AidToNavigation(m) => m.message_type,
+            AisMessage::StaticData(m) => m.message_type,
+            AisMessage::LongRange(m) => m.message_type,
+            AisMessage::Other(m) => m.message_type,
+        }
+    }
+
+    pub fn mmsi(&self) -> u32 {
+        match self {
+            AisMessage::Position(m) => m.mmsi,
+            AisMessage::BaseStation(m) => m.mmsi,
+            AisMessage::StaticVoyage(m) => m.mmsi,
+            AisMessage::ClassBPosition(m) => m.mmsi,
+            AisMessage::ClassBExtended(m) => m.mmsi,
+            AisMessage::AidToNavigation(m) => m.mmsi,
+            AisMessage::StaticData(m) => m.mmsi,
+            AisMessage::LongRange(m) => m.mmsi,
+            AisMessage::Other(m) => m.mmsi,
+        }
+    }
+
+    /// Reported position as (longitude, latitude) in degrees, if the
+    /// message type has one and it is available
+    pub fn position(&self) -> Option<(f64, f64)> {
+        let (lon, lat) = match self {
+            AisMessage::Position(m) => (m.lon, m.lat),
+            AisMessage::BaseStation(m) => (m.lon, m.lat),
+            AisMessage::ClassBPosition(m) => (m.lon, m.lat),
+            AisMessage::ClassBExtended(m) => (m.lon, m.lat),
+            AisMessage::AidToNavigation(m) => (m.lon, m.lat),
+            AisMessage::LongRange(m) => (m.lon, m.lat),
+            _ => return None,
+        };
+        Some((lon?, lat?))
+    }
+}
+
+/// Decode a single-sentence AIS message
+pub fn decode_sentence(sentence: &Sentence) -> Result<AisMessage, AisError> {
+    let fragment = sentence
+        .ais()
+        .map_err(|e| AisError::Sentence(e.to_string()))?;
+    if fragment.fragment_count != 1 {
+        return Err(AisError::Fragment);
+    }
+    decode_payload(&fragment.payload, fragment.fill_bits)
+}
+
+/// Decode a multi-sentence AIS message from all of its fragments, in order
+pub fn decode_fragments(fragments: &[AisFragment]) -> Result<AisMessage, AisError> {
+    let first = fragments.first().ok_or(AisError::Fragment)?;
+    let complete = fragments.len() == first.fragment_count as usize
+        && fragments.iter().enumerate().all(|(i, f)| {
+            f.fragment_number as usize == i + 1
+                && f.fragment_count == first.fragment_count
+                && f.sequential_id == first.sequential_id
+        });
+    if !complete {
+        return Err(AisError::Fragment);
+    }
+    let payload: String = fragments.iter().map(|f| f.payload.as_str()).collect();
+    let fill_bits = fragments.last().map_or(0, |f| f.fill_bits);
+    decode_payload(&payload, fill_bits)
+}
+
+/// Consecutive lines of received data holding one complete AIS message, or
+/// a single line which is not
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AisLines<'a> {
+    /// Lines without their `\n` terminators
+    pub lines: Vec<&'a [u8]>,
+    /// Fragments of a complete AIS message, in order. Empty for lines which
+    /// are not AIS sentences, or fragments of an incomplete message
+    pub fragments: Vec<AisFragment>,
+}
+
+impl AisLines<'_> {
+    /// Returns true if the lines hold a complete AIS message
+    pub fn is_ais(&self) -> bool {
+        !self.fragments.is_empty()
+    }
+
+    pub fn decode(&self) -> Result<AisMessage, AisError> {
+        decode_fragments(&self.fragments)
+    }
+
+    /// Append the lines to `out`, each terminated by `\n`
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        for line in &self.lines {
+            out.extend_from_slice(line);
+            out.push(b'\n');
+        }
+    }
+}
+
+/// Split `data` into AIS messages and other lines.
+///
+/// The fragments of a multi-sentence message are grouped when they are
+/// consecutive lines, e.g. after reassembly. Fragments of incomplete
+/// messages are returned as separate lines
+pub fn ais_messages(data: &[u8]) -> Vec<AisLines<'_>> {
+    let mut messages = vec![];
+    let mut group: Vec<(&[u8], AisFragment)> = vec![];
+    for line in sentences(data) {
+        let fragment = match Sentence::parse(line).and_then(|s| s.ais()) {
+            Ok(fragment) => fragment,
+            Err(_) => {
+                flush(&mut group, &mut messages);
+                messages.push(AisLines {
+                    lines: vec![line],
+                    fragments: vec![],
+                });
+                continue;
+            }
+        };
+        let continues = group.last().is_some_and(|(_, last)| {
+            last.fragment_count == fragment.fragment_count
+                && last.sequential_id == fragment.sequential_id
+                && last.fragment_number + 1 == fragment.fragment_number
+        });
+        if !continues {
+            flush(&mut group, &mut messages);
+        }
+        let last = fragment.fragment_number == fragment.fragment_count;
+        group.push((line, fragment));
+        if !last {
+            continue;
+        }
+        if group[0].1.fragment_number != 1 {
+            flush(&mut group, &mut messages);
+            continue;
+        }
+        let (lines, fragments) = group.drain(..).unzip();
+        messages.push(AisLines { lines, fragments });
+    }
+    flush(&mut group, &mut messages);
+    messages
+}
+
+/// Move the fragments of an incomplete message to `messages` as separate lines
+fn flush<'a>(group: &mut Vec<(&'a [u8], AisFragment)>, messages: &mut Vec<AisLines<'a>>) {
+    messages.extend(group.drain(..).map(|(line, _)| AisLines {
+        lines: vec![line],
+        fragments: vec![],
+    }));
+}
+
+/// Decode the 6-bit armored `payload` of a complete AIS message, ignoring
+/// `fill_bits` padding bits at the end
+pub fn decode_payload(payload: &str, fill_bits: u8) -> Result<AisMessage, AisError> {
+    let bits = Bits::new(payload, fill_bits)?;
+    let message_type = bits.uint(0, 6)? as u8;
+    let repeat = bits.uint(6, 2)? as u8;
+    let mmsi = bits.uint(8, 30)? as u32;
+
+    let message = match message_type {
+        1..=3 => AisMessage::Position(PositionReport {
+            message_type,
+            repeat,
+            mmsi,
+            nav_status: bits.uint(38, 4)? as u8,
+            rot: rate_of_turn(bits.int(42, 8)?),
+            sog: tenths(bits.uint(50, 10)?, 1023),
+            position_accuracy: bits.flag(60)?,
+            lon: coordinate(bits.int(61, 28)?, 600_000.0, 180.0),
+            lat: coordinate(bits.int(89, 27)?, 600_000.0, 90.0),
+            cog: tenths(bits.uint(116, 12)?, 3600),
+            heading: heading(bits.uint(128, 9)?),
+            second: bits.uint(137, 6)? as u8,
+            maneuver: bits.uint(143, 2)? as u8,
+            raim: bits.flag(148)?,
+        }),
+        4 => AisMessage::BaseStation(BaseStationReport {
+            message_type,
+            repeat,
+            mmsi,
+            year: bits.uint(38, 14)? as u16,
+            month: bits.uint(52, 4)? as u8,
+            day: bits.uint(56, 5)? as u8,
+            hour: bits.uint(61, 5)? as u8,
+            minute: bits.uint(66, 6)? as u8,
+            second: bits.uint(72, 6)? as u8,
+            position_accuracy: bits.flag(78)?,
+            lon: coordinate(bits.int(79, 28)?, 600_000.0, 180.0),
+            lat: coordinate(bits.int(107, 27)?, 600_000.0, 90.0),
+            epfd: bits.uint(134, 4)? as u8,
+            raim: bits.flag(148)?,
+        }),
+        5 => AisMessage::StaticVoyage(StaticVoyageData {
+            message_type,
+            repeat,
+            mmsi,
+            ais_version: bits.uint(38, 2)? as u8,
+            imo: match bits.uint(40, 30)? {
+                0 => None,
+                imo => Some(imo as u32),
+            },
+            callsign: bits.text(70, 7)?,
+            name: bits.text(112, 20)?,
+            ship_type: bits.uint(232, 8)? as u8,
+            to_bow: bits.uint(240, 9)? as u16,
+            to_stern: bits.uint(249, 9)? as u16,
+            to_port: bits.uint(258, 6)? as u8,
+            to_starboard: bits.uint(264, 6)? as u8,
+            epfd: bits.uint(270, 4)? as u8,
+            eta_month: bits.uint(274, 4)? as u8,
+            eta_day: bits.uint(278, 5)? as u8,
+            eta_hour: bits.uint(283, 5)? as u8,
+            eta_minute: bits.uint(288, 6)? as u8,
+            draught: bits.uint(294, 8)? as f64 / 10.0,
+            destination: bits.text(302, 20)?,
+            // some transponders omit the final bits
+            dte: bits.flag(422).unwrap_or(true),
+        }),
+        18 => AisMessage::ClassBPosition(ClassBPositionReport {
+            message_type,
+            repeat,
+            mmsi,
+            sog: tenths(bits.uint(46, 10)?, 1023),
+            position_accuracy: bits.flag(56)?,
+            lon: coordinate(bits.int(57, 28)?, 600_000.0, 180.0),
+            lat: coordinate(bits.int(85, 27)?, 600_000.0, 90.0),
+            cog: tenths(bits.uint(112, 12)?, 3600),
+            heading: heading(bits.uint(124, 9)?),
+            second: bits.uint(133, 6)? as u8,
+            cs_unit: bits.flag(141)?,
+            raim: bits.flag(147)?,
+        }),
+        19 => AisMessage::ClassBExtended(ClassBExtendedReport {
+            message_type,
+            repeat,
+            mmsi,
+            sog: tenths(bits.uint(46, 10)?, 1023),
+            position_accuracy: bits.flag(56)?,
+            lon: coordinate(bits.int(57, 28)?, 600_000.0, 180.0),
+            lat: coordinate(bits.int(85, 27)?, 600_000.0, 90.0),
+            cog: tenths(bits.uint(112, 12)?, 3600),
+            heading: heading(bits.uint(124, 9)?),
+            second: bits.uint(133, 6)? as u8,
+            name: bits.text(143, 20)?,
+            ship_type: bits.uint(263, 8)? as u8,
+            to_bow: bits.uint(271, 9)? as u16,
+            to_stern: bits.uint(280, 9)? as u16,
+            to_port: bits.uint(289, 6)? as u8,
+            to_starboard: bits.uint(295, 6)? as u8,
+            epfd: bits.uint(301, 4)? as u8,
+            raim: bits.flag(305)?,
+            dte: bits.flag(306)?,
+        }),
+        21 => {
+            let mut name = bits.text(43, 20)?;
+            // name extension of up to 14 characters in the remaining bits
+            let extension = bits.len.saturating_sub(272) / 6;
+            if extension > 0 {
+                name.push_str(&bits.text(272, extension.min(14))?);
+            }
+            AisMessage::AidToNavigation(AidToNavigationReport {
+                message_type,
+                repeat,
+                mmsi,
+                aid_type: bits.uint(38, 5)? as u8,
+                name,
+                position_accuracy: bits.flag(163)?,
+                lon: coordinate(bits.int(164, 28)?, 600_000.0, 180.0),
+                lat: coordinate(bits.int(192, 27)?, 600_000.0, 90.0),
+                to_bow: bits.uint(219, 9)? as u16,
+                to_stern: bits.uint(228, 9)? as u16,
+                to_port: bits.uint(237, 6)? as u8,
+                to_starboard: bits.uint(243, 6)? as u8,
+                epfd: bits.uint(249, 4)? as u8,
+                second: bits.uint(253, 6)? as u8,
+                off_position: bits.flag(259)?,
+                raim: bits.flag(268)?,
+                virtual_aid: bits.flag(269)?,
+            })
+        }
+        24 => {
+            let part = bits.uint(38, 2)? as u8;
+            let mut report = StaticDataReport {
+                message_type,
+                repeat,
+                mmsi,
+                part,
+                name: None,
+                ship_type: None,
+                vendor_id: None,
+                callsign: None,
+                to_bow: None,
+                to_stern: None,
+                to_port: None,
+                to_starboard: None,
+            };
+            match part {
+                0 => report.name = Some(bits.text(40, 20)?),
+                1 => {
+                    report.ship_type = Some(bits.uint(40, 8)? as u8);
+                    report.vendor_id = Some(bits.text(48, 3)?);
+                    report.callsign = Some(bits.text(90, 7)?);
+                    report.to_bow = Some(bits.uint(132, 9)? as u16);
+                    report.to_stern = Some(bits.uint(141, 9)? as u16);
+                    report.to_port = Some(bits.uint(150, 6)? as u8);
+                    report.to_starboard = Some(bits.uint(156, 6)? as u8);
+                }
+                _ => {}
+            }
+            AisMessage::StaticData(report)
+        }
+        27 => AisMessage::LongRange(LongRangeReport {
+            message_type,
+            repeat,
+            mmsi,
+            position_accuracy: bits.flag(38)?,
+            raim: bits.flag(39)?,
+            nav_status: bits.uint(40, 4)? as u8,
+            lon: coordinate(bits.int(44, 18)?, 600.0, 180.0),
+            lat: coordinate(bits.int(62, 17)?, 600.0, 90.0),
+            sog: match bits.uint(79, 6)? {
+                63 => None,
+                sog => Some(sog as f64),
+            },
+            cog: match bits.uint(85, 9)? {
+                511 => None,
+                cog => Some(cog as f64),
+            },
+            gnss: !bits.flag(94)?,
+        }),
+        _ => AisMessage::Other(OtherMessage {
+            message_type,
+            repeat,
+            mmsi,
+        }),
+    };
+    Ok(message)
+}
+
+/// Unpacked 6-bit payload
+struct Bits {
+    values: Vec<u8>,
+    /// number of valid bits, excluding fill bits
+    len: usize,
+    message_type: u8,
+}
+
+impl Bits {
+    fn new(payload: &str, fill_bits: u8) -> Result<Self, AisError> {
+        let values = payload
+            .chars()
+            .map(|c| match c {
+                '0'..='W' => Ok(c as u8 - 48),
+                '`'..='w' => Ok(c as u8 - 56),
+                _ => Err(AisError::InvalidCharacter(c)),
+            })
+            .collect::<Result<Vec<u8>, _>>()?;
+        let len = (values.len() * 6).saturating_sub(fill_bits as usize);
+        let message_type = values.first().copied().unwrap_or_default();
+        Ok(Bits {
+            values,
+            len,
+            message_type,
+        })
+    }
+
+    /// Unsigned integer of `width` bits starting at bit `start`
+    fn uint(&self, start: usize, width: usize) -> Result<u64, AisError> {
+        if start + width > self.len {
+            return Err(AisError::TooShort {
+                message_type: self.message_type,
+                bits: self.len,
+            });
+        }
+        Ok((start..start + width).fold(0, |value, i| {
+            let bit = (self.values[i / 6] >> (5 - i % 6)) & 1;
+            (value << 1) | bit as u64
+        }))
+    }
+
+    /// Two's complement signed integer
+    fn int(&self, start: usize, width: usize) -> Result<i64, AisError> {
+        let value = self.uint(start, width)? as i64;
+        Ok(if value >> (width - 1) & 1 == 1 {
+            value - (1 << width)
+        } else {
+            value
+        })
+    }
+
+    fn flag(&self, bit: usize) -> Result<bool, AisError> {
+        Ok(self.uint(bit, 1)? == 1)
+    }
+
+    /// Text of `chars` 6-bit characters, with trailing `@` padding and
+    /// spaces removed
+    fn text(&self, start: usize, chars: usize) -> Result<String, AisError> {
+        let mut text = String::with_capacity(chars);
+        for i in 0..chars {
+            let c = self.uint(start + i * 6, 6)? as u8;
+            text.push(if c < 32 { (c + 64) as char } else { c as char });
+        }
+        let trimmed = match text.find('@') {
+            Some(end) => &text[..end],
+            None => &text,
+        };
+        Ok(trimmed.trim_end().to_string())
+    }
+}
+
+/// Latitude or longitude in degrees, or `None` if not available
+fn coordinate(raw: i64, scale: f64, limit: f64) -> Option<f64> {
+    let degrees = raw as f64 / scale;
+    (degrees.abs() <= limit).then_some(degrees)
+}
+
+/// Speed or course in tenths, or `None` if `raw` is the not available value
+fn tenths(raw: u64, not_available: u64) -> Option<f64> {
+    (raw != not_available).then(|| raw as f64 / 10.0)
+}
+
+fn heading(raw: u64) -> Option<u16> {
+    (raw != 511).then_some(raw as u16)
+}
+
+/// Rate of turn in degrees per minute, from the ROT_AIS indicator
+fn rate_of_turn(raw: i64) -> Option<f64> {
+    match raw {
+        -128 => None,
+        raw => {
+            let rot = (raw as f64 / 4.733).powi(2);
+            Some(if raw < 0 { -rot } else { rot })
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::ais::ais_messages;
+
+/// Options for [Deduplicator]
+#[derive(Clone, Debug)]
+pub struct DedupOptions {
+    /// Messages repeated within this long of the first copy are dropped
+    pub window: Duration,
+    /// Maximum number of recent messages remembered. The oldest message
+    /// is forgotten to make room for a new one
+    pub max_entries: usize,
+}
+
+impl Default for DedupOptions {
+    fn default() -> Self {
+        DedupOptions {
+            window: Duration::from_secs(2),
+            max_entries: 65536,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DedupState {
+    options: DedupOptions,
+    seen: HashMap<String, Instant>,
+    order: VecDeque<(Instant, String)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl DedupState {
+    /// Forget messages first seen before the window, and the oldest
+    /// messages in excess of `max_entries`
+    fn expire(&mut self, now: Instant, max_entries: usize) {
+        while let Some((first_seen, _)) = self.order.front() {
+            if now.saturating_duration_since(*first_seen) < self.options.window
+                && self.order.len() <= max_entries
+            {
+                break;
+            }
+            let (first_seen, key) = self.order.pop_front().unwrap();
+            // the entry may have been replaced by a later copy
+            if self.seen.get(&key) == Some(&first_seen) {
+                self.seen.remove(&key);
+            }
+        }
+    }
+
+    /// Returns true if `key` was seen within the window
+    fn check(&mut self, key: String, now: Instant) -> bool {
+        self.expire(now, self.options.max_entries);
+        match self.seen.get(&key) {
+            Some(first_seen)
+                if now.saturating_duration_since(*first_seen) < self.options.window =>
+            {
+                self.hits += 1;
+                true
+            }
+            _ => {
+                self.misses += 1;
+                self.expire(now, self.options.max_entries.saturating_sub(1));
+                self.seen.insert(key.clone(), now);
+                self.order.push_back((now, key));
+                false
+            }
+        }
+    }
+}
+
+/// Drops repeated copies of AIS messages, e.g. the same transmission heard
+/// by several receivers feeding one gateway.
+///
+/// Messages are compared by their armored payload, ignoring tag blocks,
+/// channel, and sequential message ID. Fragments of a multi-sentence
+/// message are compared together when they are consecutive lines of one
+/// datagram, e.g. after reassembly, and otherwise pass through unchanged,
+/// as do lines which are not AIS sentences.
+///
+/// Clones share the same state, so one deduplicator may be used by
+/// several listeners
+#[derive(Clone, Debug)]
+pub struct Deduplicator {
+    state: Arc<Mutex<DedupState>>,
+}
+
+impl Deduplicator {
+    pub fn new(options: &DedupOptions) -> Self {
+        Deduplicator {
+            state: Arc::new(Mutex::new(DedupState {
+                options: options.clone(),
+                seen: HashMap::new(),
+                order: VecDeque::new(),
+                hits: 0,
+                misses: 0,
+            })),
+        }
+    }
+
+    /// Returns the lines of `data` received at `now`, without messages
+    /// already seen within the window
+    pub fn filter(&self, data: &[u8], now: Instant) -> Vec<u8> {
+        let mut state = self.state.lock().expect("dedup state poisoned");
+        let mut out = Vec::with_capacity(data.len());
+        for message in ais_messages(data) {
+            if message.is_ais() {
+                let key = message
+                    .fragments
+                    .iter()
+                    .map(|f| format!("{}:{}", f.payload, f.fill_bits))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                if state.check(key, now) {
+                    continue;
+                }
+            }
+            message.write_to(&mut out);
+        }
+        out
+    }
+
+    /// Number of messages dropped as duplicates
+    pub fn hits(&self) -> u64 {
+        self.state.lock().expect("dedup state poisoned").hits
+    }
+
+    /// Number of messages passed through as first seen
+    pub fn misses(&self) -> u64 {
+        self.state.lock().expect("dedup state poisoned").misses
+    }
+
+    /// Number of recent messages currently remembered
+    pub fn len(&self) -> usize {
+        self.state.lock().expect("dedup state poisoned").seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::ais::{ais_messages, AisMessage};
+
+/// Limits the rate of position reports forwarded for each vessel, e.g. for
+/// downstream consumers which cannot keep up with class A reports sent
+/// every few seconds.
+///
+/// Position reports (message types 1, 2, 3, 18, 19, and 27) are dropped if
+/// another report from the same MMSI was passed within the interval. Other
+/// messages, such as static and voyage data, and lines which are not AIS
+/// messages pass through unchanged.
+///
+/// Clones share the same state, so one downsampler may be used by several
+/// listeners, e.g. each listener of a gateway forwarding to one consumer.
+/// Create a separate downsampler for each consumer to be limited
+#[derive(Clone, Debug)]
+pub struct Downsampler {
+    state: Arc<Mutex<DownsampleState>>,
+}
+
+#[derive(Debug)]
+struct DownsampleState {
+    interval: Duration,
+    last_passed: HashMap<u32, Instant>,
+    next_expiry: Option<Instant>,
+    dropped: u64,
+}
+
+impl DownsampleState {
+    /// Returns true if a position report from `mmsi` received at `now`
+    /// should be passed, and records it if so
+    fn check(&mut self, mmsi: u32, now: Instant) -> bool {
+        match self.last_passed.get(&mmsi) {
+            Some(last) if now.saturating_duration_since(*last) < self.interval => false,
+            _ => {
+                self.last_passed.insert(mmsi, now);
+                true
+            }
+        }
+    }
+
+    /// Forget vessels without a report passed within the interval, at most
+    /// once per interval
+    fn expire(&mut self, now: Instant) {
+        if self.next_expiry.is_some_and(|next| now < next) {
+            return;
+        }
+        let interval = self.interval;
+        self.last_passed
+            .retain(|_, last| now.saturating_duration_since(*last) < interval);
+        self.next_expiry = Some(now + interval);
+    }
+}
+
+impl Downsampler {
+    /// Pass at most one position report per MMSI per `interval`
+    pub fn new(interval: Duration) -> Self {
+        Downsampler {
+            state: Arc::new(Mutex::new(DownsampleState {
+                interval,
+                last_passed: HashMap::new(),
+                next_expiry: None,
+                dropped: 0,
+            })),
+        }
+    }
+
+    /// Returns the lines of `data` received at `now`, without position
+    /// reports from vessels which already had one passed within the interval
+    pub fn filter(&self, data: &[u8], now: Instant) -> Vec<u8> {
+        let mut state = self.state.lock().expect("downsample state poisoned");
+        state.expire(now);
+        let mut out = Vec::with_capacity(data.len());
+        for message in ais_messages(data) {
+            if let Ok(decoded) = message.decode() {
+                if is_position_report(&decoded) && !state.check(decoded.mmsi(), now) {
+                    state.dropped += 1;
+                    continue;
+                }
+            }
+            message.write_to(&mut out);
+        }
+        out
+    }
+
+    /// Number of position reports dropped
+    pub fn dropped(&self) -> u64 {
+        self.state
+            .lock()
+            .expect("downsample state poisoned")
+            .dropped
+    }
+
+    /// Number of vessels with a recent position report remembered
+    pub fn len(&self) -> usize {
+        self.state
+            .lock()
+            .expect("downsample state poisoned")
+            .last_passed
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn is_position_report(message: &AisMessage) -> bool {
+    matches!(
+        message,
+        AisMessage::Position(_)
+            | AisMessage::ClassBPosition(_)
+            | AisMessage::ClassBExtended(_)
+            | AisMessage::LongRange(_)
+    )
+}
@@ -0,0 +1,104 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+
+/// Errors returned by the mproxy client, server, and proxies.
+///
+/// Each variant records the address or path involved, so that embedding
+/// applications can report, retry, or recover from a failure instead of
+/// aborting the process.
+#[derive(Debug)]
+pub enum MproxyError {
+    /// Resolving a hostname or parsing a socket address failed
+    Resolve { addr: String, source: io::Error },
+    /// Binding a local socket failed
+    Bind { addr: String, source: io::Error },
+    /// Joining a multicast group failed
+    MulticastJoin { addr: String, source: io::Error },
+    /// Connecting to a remote TCP socket failed
+    Connect { addr: String, source: io::Error },
+    /// Sending to a socket failed
+    Send { addr: String, source: io::Error },
+    /// Receiving from a socket failed
+    Recv { addr: String, source: io::Error },
+    /// Establishing a TLS session failed
+    Tls { addr: String, reason: String },
+    /// A TCP upstream rejected the login or request sent after connecting,
+    /// e.g. with an HTTP error status
+    Handshake { addr: String, reason: String },
+    /// Opening, reading, or writing a file failed
+    File { path: PathBuf, source: io::Error },
+    /// Any other I/O failure, e.g. writing to stdout or spawning a thread
+    Io(io::Error),
+    /// An option or configuration value is invalid
+    Config(String),
+}
+
+impl fmt::Display for MproxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MproxyError::Resolve { addr, source } => {
+                write!(f, "resolving socket address {}: {}", addr, source)
+            }
+            MproxyError::Bind { addr, source } => write!(f, "binding {}: {}", addr, source),
+            MproxyError::MulticastJoin { addr, source } => {
+                write!(f, "joining multicast group {}: {}", addr, source)
+            }
+            MproxyError::Connect { addr, source } => {
+                write!(f, "connecting to {}: {}", addr, source)
+            }
+            MproxyError::Send { addr, source } => write!(f, "sending to {}: {}", addr, source),
+            MproxyError::Recv { addr, source } => {
+                write!(f, "receiving from {}: {}", addr, source)
+            }
+            MproxyError::Tls { addr, reason } => write!(f, "TLS with {}: {}", addr, reason),
+            MproxyError::Handshake { addr, reason } => {
+                write!(f, "handshake with {}: {}", addr, reason)
+            }
+            MproxyError::File { path, source } => write!(f, "{}: {}", path.display(), source),
+            MproxyError::Io(source) => write!(f, "{}", source),
+            MproxyError::Config(reason) => write!(f, "invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl Error for MproxyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MproxyError::Resolve { source, .. }
+            | MproxyError::Bind { source, .. }
+            | MproxyError::MulticastJoin { source, .. }
+            | MproxyError::Connect { source, .. }
+            | MproxyError::Send { source, .. }
+            | MproxyError::Recv { source, .. }
+            | MproxyError::File { source, .. }
+            | MproxyError::Io(source) => Some(source),
+            MproxyError::Tls { .. } | MproxyError::Handshake { .. } | MproxyError::Config(_) => {
+                None
+            }
+        }
+    }
+}
+
+impl From<io::Error> for MproxyError {
+    fn from(e: io::Error) -> Self {
+        MproxyError::Io(e)
+    }
+}
+
+/// Resolve `addr` (e.g. `"localhost:9920"` or `"[::1]:9921"`) to the first
+/// matching socket address
+pub fn resolve_socket_addr(addr: &str) -> Result<SocketAddr, MproxyError> {
+    match addr.to_socket_addrs() {
+        Ok(mut addrs) => addrs.next().ok_or_else(|| MproxyError::Resolve {
+            addr: addr.to_string(),
+            source: io::Error::new(io::ErrorKind::NotFound, "no addresses found"),
+        }),
+        Err(source) => Err(MproxyError::Resolve {
+            addr: addr.to_string(),
+            source,
+        }),
+    }
+}
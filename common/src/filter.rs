@@ -0,0 +1,298 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::read_to_string;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::ais::{ais_messages, AisMessage};
+use crate::MproxyError;
+
+/// Area containing the positions of messages passed by a [MessageFilter]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Geofence {
+    /// Longitude and latitude bounds, in degrees. Boxes crossing the
+    /// antimeridian have `min_lon` greater than `max_lon`
+    BoundingBox {
+        min_lon: f64,
+        min_lat: f64,
+        max_lon: f64,
+        max_lat: f64,
+    },
+    /// Polygon vertices as (longitude, latitude) pairs, in degrees
+    Polygon(Vec<(f64, f64)>),
+}
+
+impl Geofence {
+    /// Returns true if the position is inside the geofence
+    pub fn contains(&self, lon: f64, lat: f64) -> bool {
+        match self {
+            Geofence::BoundingBox {
+                min_lon,
+                min_lat,
+                max_lon,
+                max_lat,
+            } => {
+                let in_lon = if min_lon <= max_lon {
+                    (*min_lon..=*max_lon).contains(&lon)
+                } else {
+                    lon >= *min_lon || lon <= *max_lon
+                };
+                in_lon && (*min_lat..=*max_lat).contains(&lat)
+            }
+            Geofence::Polygon(vertices) => {
+                // even-odd rule
+                let mut inside = false;
+                let mut j = vertices.len().wrapping_sub(1);
+                for (i, &(xi, yi)) in vertices.iter().enumerate() {
+                    let (xj, yj) = vertices[j];
+                    if (yi > lat) != (yj > lat) && lon < (xj - xi) * (lat - yi) / (yj - yi) + xi {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+                inside
+            }
+        }
+    }
+}
+
+/// Vessels whose last position was inside a geofence
+#[derive(Debug, Default)]
+struct GeofenceState {
+    /// Time of the last position reported inside, by MMSI
+    inside: HashMap<u32, Instant>,
+    /// The same entries, oldest first
+    order: BTreeSet<(Instant, u32)>,
+}
+
+impl GeofenceState {
+    /// Record whether the position reported by `mmsi` at `now` is inside
+    fn record(&mut self, mmsi: u32, inside: bool, now: Instant) {
+        if let Some(last) = self.inside.remove(&mmsi) {
+            self.order.remove(&(last, mmsi));
+        }
+        if inside {
+            self.inside.insert(mmsi, now);
+            self.order.insert((now, mmsi));
+        }
+    }
+
+    /// Forget positions older than `max_age`, and the oldest positions in
+    /// excess of `max_entries`
+    fn expire(&mut self, now: Instant, max_age: Duration, max_entries: usize) {
+        while let Some(&(last, mmsi)) = self.order.first() {
+            if now.saturating_duration_since(last) < max_age && self.order.len() <= max_entries {
+                break;
+            }
+            self.order.pop_first();
+            self.inside.remove(&mmsi);
+        }
+    }
+}
+
+/// Selects AIS messages by message type, MMSI, and position.
+///
+/// Lines which are not complete AIS messages are dropped, so use
+/// reassembly upstream to pass multi-sentence messages. Messages without a
+/// position, such as static and voyage data, pass the geofence if the last
+/// position reported by the vessel was inside it, within
+/// [MessageFilter::max_position_age].
+///
+/// Clones share the positions recorded for the geofence, so one filter may
+/// be used by several listeners, e.g. each listener of a gateway
+#[derive(Clone, Debug)]
+pub struct MessageFilter {
+    /// Pass only these message types, if set
+    pub allow_types: Option<HashSet<u8>>,
+    /// Drop these message types
+    pub deny_types: HashSet<u8>,
+    /// Pass only these MMSIs, if set
+    pub allow_mmsi: Option<HashSet<u32>>,
+    /// Drop these MMSIs
+    pub deny_mmsi: HashSet<u32>,
+    /// Pass only messages from vessels inside this area, if set
+    pub geofence: Option<Geofence>,
+    /// Positions older than this are forgotten, so that messages without a
+    /// position are dropped for vessels which stopped reporting
+    pub max_position_age: Duration,
+    /// Maximum number of vessels remembered inside the geofence. The
+    /// oldest position is forgotten to make room for a new one
+    pub max_vessels: usize,
+    state: Arc<Mutex<GeofenceState>>,
+}
+
+impl Default for MessageFilter {
+    fn default() -> Self {
+        MessageFilter {
+            allow_types: None,
+            deny_types: HashSet::new(),
+            allow_mmsi: None,
+            deny_mmsi: HashSet::new(),
+            geofence: None,
+            max_position_age: Duration::from_secs(3600),
+            max_vessels: 65536,
+            state: Arc::default(),
+        }
+    }
+}
+
+impl MessageFilter {
+    /// Parse filter rules separated by `;`, e.g.
+    /// `types:1-3,18;mmsi-deny:blocked.txt;bbox:-10,35,30,60`.
+    ///
+    /// - `types:LIST`, `deny-types:LIST`: message types or ranges, comma separated
+    /// - `mmsi-allow:FILE`, `mmsi-deny:FILE`: MMSI lists, see [MessageFilter::load_mmsi]
+    /// - `bbox:MIN_LON,MIN_LAT,MAX_LON,MAX_LAT`: bounding box geofence
+    /// - `polygon:LON LAT,LON LAT,...`: polygon geofence, with at least 3 vertices
+    pub fn parse(rules: &str) -> Result<Self, MproxyError> {
+        let mut filter = MessageFilter::default();
+        let invalid = |rule: &str| MproxyError::Config(format!("invalid filter rule '{}'", rule));
+        for rule in rules.split(';').map(str::trim).filter(|r| !r.is_empty()) {
+            let (key, value) = rule.split_once(':').ok_or_else(|| invalid(rule))?;
+            match key {
+                "types" => {
+                    let types = parse_types(value).ok_or_else(|| invalid(rule))?;
+                    filter
+                        .allow_types
+                        .get_or_insert_with(HashSet::new)
+                        .extend(types);
+                }
+                "deny-types" => {
+                    let types = parse_types(value).ok_or_else(|| invalid(rule))?;
+                    filter.deny_types.extend(types);
+                }
+                "mmsi-allow" => {
+                    let mmsi = MessageFilter::load_mmsi(Path::new(value))?;
+                    filter
+                        .allow_mmsi
+                        .get_or_insert_with(HashSet::new)
+                        .extend(mmsi);
+                }
+                "mmsi-deny" => {
+                    let mmsi = MessageFilter::load_mmsi(Path::new(value))?;
+                    filter.deny_mmsi.extend(mmsi);
+                }
+                "bbox" => {
+                    let bounds = value
+                        .split(',')
+                        .map(|v| v.trim().parse::<f64>())
+                        .collect::<Result<Vec<f64>, _>>()
+                        .map_err(|_| invalid(rule))?;
+                    let [min_lon, min_lat, max_lon, max_lat] = bounds[..] else {
+                        return Err(invalid(rule));
+                    };
+                    filter.geofence = Some(Geofence::BoundingBox {
+                        min_lon,
+                        min_lat,
+                        max_lon,
+                        max_lat,
+                    });
+                }
+                "polygon" => {
+                    let vertices = value
+                        .split(',')
+                        .map(|vertex| {
+                            let (lon, lat) = vertex.trim().split_once(' ')?;
+                            Some((lon.trim().parse().ok()?, lat.trim().parse().ok()?))
+                        })
+                        .collect::<Option<Vec<(f64, f64)>>>()
+                        .filter(|vertices| vertices.len() >= 3)
+                        .ok_or_else(|| invalid(rule))?;
+                    filter.geofence = Some(Geofence::Polygon(vertices));
+                }
+                _ => return Err(invalid(rule)),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Read a list of MMSIs from `path`, separated by whitespace or commas.
+    /// Text following `#` on each line is ignored
+    pub fn load_mmsi(path: &Path) -> Result<HashSet<u32>, MproxyError> {
+        let text = read_to_string(path).map_err(|source| MproxyError::File {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        text.lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|mmsi| !mmsi.is_empty())
+            .map(|mmsi| {
+                mmsi.parse::<u32>().map_err(|_| {
+                    MproxyError::Config(format!("invalid MMSI '{}' in {}", mmsi, path.display()))
+                })
+            })
+            .collect()
+    }
+
+    /// Returns true if `message` passes the filter. Positions are recorded
+    /// for the geofence, including those of messages which do not pass
+    pub fn matches(&mut self, message: &AisMessage) -> bool {
+        self.matches_at(message, Instant::now())
+    }
+
+    /// Returns true if `message`, received at `now`, passes the filter
+    pub fn matches_at(&mut self, message: &AisMessage, now: Instant) -> bool {
+        let (message_type, mmsi) = (message.message_type(), message.mmsi());
+        let inside = match &self.geofence {
+            None => true,
+            Some(geofence) => {
+                let mut state = self.state.lock().expect("geofence state poisoned");
+                state.expire(now, self.max_position_age, self.max_vessels);
+                match message.position() {
+                    Some((lon, lat)) => {
+                        let inside = geofence.contains(lon, lat);
+                        state.record(mmsi, inside, now);
+                        state.expire(now, self.max_position_age, self.max_vessels);
+                        inside
+                    }
+                    None => state.inside.contains_key(&mmsi),
+                }
+            }
+        };
+        inside
+            && self
+                .allow_types
+                .as_ref()
+                .is_none_or(|types| types.contains(&message_type))
+            && !self.deny_types.contains(&message_type)
+            && self
+                .allow_mmsi
+                .as_ref()
+                .is_none_or(|allow| allow.contains(&mmsi))
+            && !self.deny_mmsi.contains(&mmsi)
+    }
+
+    /// Returns the lines of `data` holding AIS messages which pass the filter
+    pub fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let now = Instant::now();
+        let mut out = Vec::with_capacity(data.len());
+        for message in ais_messages(data) {
+            if message.decode().is_ok_and(|m| self.matches_at(&m, now)) {
+                message.write_to(&mut out);
+            }
+        }
+        out
+    }
+
+    /// Number of vessels currently remembered inside the geofence
+    pub fn vessels_inside(&self) -> usize {
+        self.state
+            .lock()
+            .expect("geofence state poisoned")
+            .inside
+            .len()
+    }
+}
+
+/// Parse a comma separated list of message types or ranges, e.g. `1-3,18`
+fn parse_types(list: &str) -> Option<Vec<u8>> {
+    let mut types = vec![];
+    for item in list.split(',').map(str::trim) {
+        match item.split_once('-') {
+            Some((start, end)) => types.extend(start.parse::<u8>().ok()?..=end.parse().ok()?),
+            None => types.push(item.parse().ok()?),
+        }
+    }
+    Some(types)
+}
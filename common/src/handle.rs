@@ -0,0 +1,130 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, Builder, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::MproxyError;
+
+/// Socket read timeout used by worker threads to periodically check whether
+/// shutdown has been requested
+pub const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Handle to a spawned listener or proxy thread.
+///
+/// Calling [ShutdownHandle::shutdown] (or dropping the handle) signals the
+/// thread to stop, then waits for it to flush its output and close its
+/// sockets.
+/// Use [ShutdownHandle::join] to wait for the thread without signalling it.
+#[derive(Debug)]
+pub struct ShutdownHandle {
+    name: String,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<(), MproxyError>>>,
+}
+
+impl ShutdownHandle {
+    /// Spawn a named thread running `f`.
+    /// `f` receives the shutdown flag, and should return once it is set.
+    pub fn spawn<F>(name: String, f: F) -> Result<ShutdownHandle, MproxyError>
+    where
+        F: FnOnce(Arc<AtomicBool>) -> Result<(), MproxyError> + Send + 'static,
+    {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let flag = shutdown.clone();
+        let thread = Builder::new().name(name.clone()).spawn(move || f(flag))?;
+        Ok(ShutdownHandle {
+            name,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    /// Thread name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns true if the thread has exited
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(|t| t.is_finished())
+    }
+
+    /// Signal the thread to stop, without waiting for it to exit
+    pub fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Signal the thread to stop, and wait for it to exit.
+    /// Returns the error that stopped the thread early, if any
+    pub fn shutdown(mut self) -> Result<(), MproxyError> {
+        self.stop();
+        self.wait()
+    }
+
+    /// Wait for the thread to exit.
+    /// Returns the error that stopped the thread, if any
+    pub fn join(mut self) -> Result<(), MproxyError> {
+        self.wait()
+    }
+
+    fn wait(&mut self) -> Result<(), MproxyError> {
+        match self.thread.take() {
+            Some(thread) => match thread.join() {
+                Ok(result) => result,
+                Err(panic) => std::panic::resume_unwind(panic),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ShutdownHandle {
+    fn drop(&mut self) {
+        self.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Returns true if `shutdown` has been set
+pub fn is_shutdown(shutdown: &AtomicBool) -> bool {
+    shutdown.load(Ordering::SeqCst)
+}
+
+/// Returns true if `e` was caused by a socket read timeout
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Returns true if `e` is a receive error which does not affect later
+/// datagrams, such as a connection refused or reset reported by ICMP on
+/// some platforms. Listeners log these and keep receiving
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::Interrupted
+    )
+}
+
+/// Sleep for `duration`, waking early if `shutdown` is set.
+/// Returns true if shutdown was requested
+pub fn sleep_unless_shutdown(shutdown: &AtomicBool, duration: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        if is_shutdown(shutdown) {
+            return true;
+        }
+        sleep(SHUTDOWN_POLL_INTERVAL.min(duration.saturating_sub(start.elapsed())));
+    }
+    is_shutdown(shutdown)
+}
@@ -0,0 +1,57 @@
+//! Multicast Network Dispatcher and Proxy
+//!
+//! # MPROXY: Common
+//! Shared types used by the mproxy client, server, and proxies.
+//!
+//! ### See Also
+//! - [mproxy-client](https://docs.rs/mproxy-client/)
+//! - [mproxy-server](https://docs.rs/mproxy-server/)
+//! - [mproxy-forward](https://docs.rs/mproxy-forward/)
+//! - [mproxy-reverse](https://docs.rs/mproxy-reverse/)
+//!
+
+mod ais;
+mod dedup;
+mod downsample;
+mod error;
+mod filter;
+mod handle;
+mod nmea;
+mod pcap;
+#[cfg(feature = "tls")]
+mod pem;
+mod reassemble;
+mod rotate;
+mod sink;
+mod socket;
+mod source;
+mod stage;
+mod tagblock;
+
+pub use ais::{
+    ais_messages, decode_fragments, decode_payload, decode_sentence, AidToNavigationReport,
+    AisError, AisLines, AisMessage, BaseStationReport, ClassBExtendedReport, ClassBPositionReport,
+    LongRangeReport, OtherMessage, PositionReport, StaticDataReport, StaticVoyageData,
+};
+pub use dedup::{DedupOptions, Deduplicator};
+pub use downsample::Downsampler;
+pub use error::{resolve_socket_addr, MproxyError};
+pub use filter::{Geofence, MessageFilter};
+pub use handle::{
+    is_shutdown, is_timeout, is_transient, sleep_unless_shutdown, ShutdownHandle,
+    SHUTDOWN_POLL_INTERVAL,
+};
+pub use nmea::{checksum, sentences, AisFragment, NmeaError, NmeaFilter, Sentence, Validation};
+pub use pcap::{PcapPacket, PcapReader, PcapSink, PcapngWriter};
+#[cfg(feature = "tls")]
+pub use pem::{load_certs, load_key};
+pub use reassemble::{Reassembler, ReassemblyOptions};
+pub use rotate::{format_pattern, Compression, RotatingFile, RotatingFileOptions, Rotation};
+pub use sink::{pump, PipelineSink, Sink, TcpFanoutSink, UdpSink, WriterSink};
+pub use socket::{send_to_target, target_socket_interface, upstream_socket_interface};
+pub use source::{
+    Acceptor, Connection, FileSource, Received, Source, StreamSource, TcpListenSource, UdpSource,
+    UNSPECIFIED_ADDR,
+};
+pub use stage::{DatagramMeta, Pipeline, Stage, StageClone};
+pub use tagblock::{TagBlock, TagOptions};
@@ -0,0 +1,288 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::tagblock::TagBlock;
+use crate::MproxyError;
+
+/// Errors found when parsing an NMEA 0183 sentence
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NmeaError {
+    /// The sentence does not start with `!` or `$`
+    MissingStart,
+    /// The sentence does not end with a `*hh` checksum
+    MissingChecksum,
+    /// The `*hh` checksum does not match the sentence contents
+    InvalidChecksum { expected: u8, computed: u8 },
+    /// A tag block or field is malformed
+    Malformed(&'static str),
+}
+
+impl fmt::Display for NmeaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NmeaError::MissingStart => write!(f, "sentence does not start with '!' or '$'"),
+            NmeaError::MissingChecksum => write!(f, "sentence has no checksum"),
+            NmeaError::InvalidChecksum { expected, computed } => write!(
+                f,
+                "checksum mismatch: expected {:02X}, computed {:02X}",
+                expected, computed
+            ),
+            NmeaError::Malformed(reason) => write!(f, "malformed sentence: {}", reason),
+        }
+    }
+}
+
+impl Error for NmeaError {}
+
+/// A checksum-validated NMEA 0183 sentence, e.g.
+/// `!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sentence {
+    /// Contents of a leading NMEA 4.10 tag block, without the enclosing
+    /// backslashes, e.g. `s:station,c:1700000000*5A`
+    pub tag_block: Option<String>,
+    /// `!` for encapsulated sentences such as AIS, or `$` otherwise
+    pub start: char,
+    /// Talker ID, e.g. `AI`, or `P` for proprietary sentences
+    pub talker: String,
+    /// Sentence type, e.g. `VDM`
+    pub sentence_type: String,
+    /// Comma separated fields following the address field
+    pub fields: Vec<String>,
+    pub checksum: u8,
+}
+
+/// Fields of an AIS `VDM` or `VDO` sentence
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AisFragment {
+    /// Number of sentences carrying the message
+    pub fragment_count: u8,
+    /// Position of this sentence in the message, starting from 1
+    pub fragment_number: u8,
+    /// Identifies the fragments of one multi-sentence message
+    pub sequential_id: Option<u8>,
+    /// Radio channel, `A` or `B`
+    pub channel: Option<char>,
+    /// 6-bit armored message payload
+    pub payload: String,
+    /// Number of padding bits at the end of the payload
+    pub fill_bits: u8,
+}
+
+impl Sentence {
+    /// Parse and validate a single sentence. Trailing whitespace and line
+    /// endings are ignored
+    pub fn parse(line: &[u8]) -> Result<Self, NmeaError> {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| NmeaError::Malformed("not valid UTF-8"))?
+            .trim();
+
+        let (tag_block, line) = match line.strip_prefix('\\') {
+            Some(rest) => {
+                let end = rest
+                    .find('\\')
+                    .ok_or(NmeaError::Malformed("unterminated tag block"))?;
+                (Some(rest[..end].to_string()), &rest[end + 1..])
+            }
+            None => (None, line),
+        };
+
+        let start = match line.chars().next() {
+            Some(c @ ('!' | '$')) => c,
+            _ => return Err(NmeaError::MissingStart),
+        };
+        let (body, expected) = line[1..]
+            .rsplit_once('*')
+            .ok_or(NmeaError::MissingChecksum)?;
+        let expected = match expected.len() {
+            2 => u8::from_str_radix(expected, 16).map_err(|_| NmeaError::MissingChecksum)?,
+            _ => return Err(NmeaError::MissingChecksum),
+        };
+        let computed = checksum(body.as_bytes());
+        if computed != expected {
+            return Err(NmeaError::InvalidChecksum { expected, computed });
+        }
+
+        let mut fields = body.split(',');
+        let address = fields.next().unwrap_or_default();
+        if address.is_empty() || !address.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(NmeaError::Malformed("invalid address field"));
+        }
+        let split = if address.starts_with('P') {
+            1
+        } else {
+            2.min(address.len())
+        };
+        Ok(Sentence {
+            tag_block,
+            start,
+            talker: address[..split].to_string(),
+            sentence_type: address[split..].to_string(),
+            fields: fields.map(str::to_string).collect(),
+            checksum: expected,
+        })
+    }
+
+    /// Parse the fields of the tag block, if present
+    pub fn tags(&self) -> Result<Option<TagBlock>, NmeaError> {
+        self.tag_block.as_deref().map(TagBlock::parse).transpose()
+    }
+
+    /// Returns true for AIS `VDM` (received) and `VDO` (own vessel) sentences
+    pub fn is_ais(&self) -> bool {
+        self.start == '!' && matches!(self.sentence_type.as_str(), "VDM" | "VDO")
+    }
+
+    /// Get the fragment and payload fields of an AIS sentence
+    pub fn ais(&self) -> Result<AisFragment, NmeaError> {
+        if !self.is_ais() {
+            return Err(NmeaError::Malformed("not an AIS sentence"));
+        }
+        if self.fields.len() < 6 {
+            return Err(NmeaError::Malformed("too few AIS fields"));
+        }
+        let number = |field: &str, reason| {
+            field
+                .parse::<u8>()
+                .map_err(|_| NmeaError::Malformed(reason))
+        };
+        let fragment_count = number(&self.fields[0], "invalid fragment count")?;
+        let fragment_number = number(&self.fields[1], "invalid fragment number")?;
+        if fragment_number == 0 || fragment_number > fragment_count {
+            return Err(NmeaError::Malformed("invalid fragment number"));
+        }
+        let sequential_id = match self.fields[2].as_str() {
+            "" => None,
+            id => Some(number(id, "invalid sequential message id")?),
+        };
+        let channel = self.fields[3].chars().next();
+        let fill_bits = number(&self.fields[5], "invalid fill bits")?;
+        if fill_bits > 5 {
+            return Err(NmeaError::Malformed("invalid fill bits"));
+        }
+        Ok(AisFragment {
+            fragment_count,
+            fragment_number,
+            sequential_id,
+            channel,
+            payload: self.fields[4].clone(),
+            fill_bits,
+        })
+    }
+}
+
+/// XOR checksum of the bytes between the start character and the `*`
+pub fn checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0, |sum, b| sum ^ b)
+}
+
+/// Split `data` into lines, skipping blank lines.
+/// Each line excludes its `\n` terminator, but may end with `\r`
+pub fn sentences(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split(|b| *b == b'\n')
+        .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+}
+
+/// Handling of corrupt NMEA sentences
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Validation {
+    /// Pass input through without validation
+    #[default]
+    Off,
+    /// Discard lines which are not valid NMEA sentences
+    Drop,
+    /// Append lines which are not valid NMEA sentences to a file, instead
+    /// of passing them downstream
+    Quarantine(PathBuf),
+}
+
+/// Removes corrupt sentences from received data, according to a
+/// [Validation] policy.
+///
+/// Data is validated line by line, so each datagram should contain whole
+/// sentences, e.g. as sent by a client using line or packed framing.
+///
+/// Clones append to the same quarantine file. Failing to write it does not
+/// stop the filter: the corrupt lines are discarded instead, and the first
+/// failure is reported on stderr
+#[derive(Clone, Debug)]
+pub struct NmeaFilter {
+    validation: Validation,
+    quarantine: Option<(PathBuf, Arc<File>)>,
+    corrupt: u64,
+    quarantine_failures: u64,
+}
+
+impl NmeaFilter {
+    /// Create a filter, opening the quarantine file for appending if needed
+    pub fn new(validation: &Validation) -> Result<Self, MproxyError> {
+        let quarantine = match validation {
+            Validation::Quarantine(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|source| MproxyError::File {
+                        path: path.clone(),
+                        source,
+                    })?;
+                Some((path.clone(), Arc::new(file)))
+            }
+            _ => None,
+        };
+        Ok(NmeaFilter {
+            validation: validation.clone(),
+            quarantine,
+            corrupt: 0,
+            quarantine_failures: 0,
+        })
+    }
+
+    /// Returns `data` with corrupt lines removed. Valid lines are unchanged
+    pub fn filter<'a>(&mut self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.validation == Validation::Off {
+            return Cow::Borrowed(data);
+        }
+        let mut valid = Vec::with_capacity(data.len());
+        let mut corrupt = vec![];
+        for line in sentences(data) {
+            let out = match Sentence::parse(line) {
+                Ok(_) => &mut valid,
+                Err(_) => {
+                    self.corrupt += 1;
+                    &mut corrupt
+                }
+            };
+            out.extend_from_slice(line);
+            out.push(b'\n');
+        }
+        if let (Some((path, file)), false) = (&self.quarantine, corrupt.is_empty()) {
+            if let Err(e) = file.as_ref().write_all(&corrupt) {
+                self.quarantine_failures += 1;
+                if self.quarantine_failures == 1 {
+                    eprintln!(
+                        "failed to quarantine corrupt sentences to {}: {}, discarding them",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+        Cow::Owned(valid)
+    }
+
+    /// Number of corrupt lines removed so far
+    pub fn corrupt(&self) -> u64 {
+        self.corrupt
+    }
+
+    /// Number of failed writes to the quarantine file so far
+    pub fn quarantine_failures(&self) -> u64 {
+        self.quarantine_failures
+    }
+}
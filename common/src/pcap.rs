@@ -0,0 +1,574 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::MproxyError;
+
+/// Raw IPv4 or IPv6 packets, without a link layer header
+const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_NULL: u16 = 0;
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_LINUX_SLL: u16 = 113;
+const LINKTYPE_IPV4: u16 = 228;
+const LINKTYPE_IPV6: u16 = 229;
+const LINKTYPE_LINUX_SLL2: u16 = 276;
+
+const PCAPNG_SHB: u32 = 0x0A0D_0D0A;
+const PCAPNG_IDB: u32 = 0x0000_0001;
+const PCAPNG_EPB: u32 = 0x0000_0006;
+const PCAPNG_BYTE_ORDER: u32 = 0x1A2B_3C4D;
+
+const PCAP_MICROS: u32 = 0xA1B2_C3D4;
+const PCAP_NANOS: u32 = 0xA1B2_3C4D;
+
+const IPPROTO_UDP: u8 = 17;
+
+/// Largest packet record or pcapng block read, well above the largest UDP
+/// datagram. Longer lengths are treated as corrupt rather than allocated
+const MAX_RECORD_LEN: usize = 256 * 1024;
+
+/// Writes UDP datagrams to a pcapng capture, readable by Wireshark or
+/// tcpdump.
+///
+/// Only the UDP payload is known to the receiver, so IP and UDP headers are
+/// synthesised from the source and destination addresses
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Write the pcapng section header and interface description to `writer`
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        // section header block, with unspecified section length
+        let mut shb = vec![];
+        shb.extend_from_slice(&PCAPNG_BYTE_ORDER.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut writer, PCAPNG_SHB, &shb)?;
+
+        // interface description block, with microsecond timestamps
+        let mut idb = vec![];
+        idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut writer, PCAPNG_IDB, &idb)?;
+
+        Ok(PcapngWriter { writer })
+    }
+
+    /// Record a UDP datagram from `source` to `destination`, received at `time`
+    pub fn write_udp(
+        &mut self,
+        time: SystemTime,
+        source: SocketAddr,
+        destination: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let packet = udp_packet(source, destination, payload)?;
+        let micros = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut epb = Vec::with_capacity(packet.len() + 24);
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(micros as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&packet);
+        write_block(&mut self.writer, PCAPNG_EPB, &epb)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A pcapng capture file which may be shared between threads.
+/// Each datagram is flushed to the file as it is written
+#[derive(Clone)]
+pub struct PcapSink {
+    path: PathBuf,
+    writer: Arc<Mutex<PcapngWriter<BufWriter<File>>>>,
+}
+
+impl PcapSink {
+    /// Create or truncate the capture file at `path`
+    pub fn create(path: &Path) -> Result<Self, MproxyError> {
+        let file_err = |source| MproxyError::File {
+            path: path.to_path_buf(),
+            source,
+        };
+        let file = File::create(path).map_err(file_err)?;
+        let mut writer = PcapngWriter::new(BufWriter::new(file)).map_err(file_err)?;
+        writer.flush().map_err(file_err)?;
+        Ok(PcapSink {
+            path: path.to_path_buf(),
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    /// Path of the capture file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record a UDP datagram, as [PcapngWriter::write_udp]
+    pub fn write_udp(
+        &self,
+        time: SystemTime,
+        source: SocketAddr,
+        destination: SocketAddr,
+        payload: &[u8],
+    ) -> Result<(), MproxyError> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer
+            .write_udp(time, source, destination, payload)
+            .and_then(|()| writer.flush())
+            .map_err(|source| MproxyError::File {
+                path: self.path.clone(),
+                source,
+            })
+    }
+}
+
+impl fmt::Debug for PcapSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PcapSink")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// A UDP datagram read from a capture
+#[derive(Clone, Debug, PartialEq)]
+pub struct PcapPacket {
+    /// Capture time, in epoch seconds
+    pub timestamp: f64,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// Reads UDP datagrams from a pcap or pcapng capture.
+///
+/// Ethernet, raw IP, loopback, and Linux cooked captures are supported.
+/// Packets other than unfragmented UDP over IPv4 or IPv6 are skipped
+pub struct PcapReader<R: Read> {
+    reader: R,
+    format: CaptureFormat,
+}
+
+enum CaptureFormat {
+    Pcap {
+        big_endian: bool,
+        /// timestamp fraction units per second
+        resolution: f64,
+        linktype: u16,
+    },
+    Pcapng {
+        big_endian: bool,
+        /// linktype and timestamp units per second of each interface
+        interfaces: Vec<(u16, f64)>,
+    },
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Read the capture file header from `reader`
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let format = if u32::from_le_bytes(magic) == PCAPNG_SHB {
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len)?;
+            let mut format = CaptureFormat::Pcapng {
+                big_endian: false,
+                interfaces: vec![],
+            };
+            read_section_header(&mut reader, &mut format, len)?;
+            format
+        } else {
+            let (big_endian, resolution) =
+                match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                    (PCAP_MICROS, _) => (false, 1e6),
+                    (PCAP_NANOS, _) => (false, 1e9),
+                    (_, PCAP_MICROS) => (true, 1e6),
+                    (_, PCAP_NANOS) => (true, 1e9),
+                    _ => return Err(invalid("not a pcap or pcapng capture")),
+                };
+            let mut header = [0u8; 20];
+            reader.read_exact(&mut header)?;
+            CaptureFormat::Pcap {
+                big_endian,
+                resolution,
+                linktype: read_u32(&header[16..20], big_endian) as u16,
+            }
+        };
+        Ok(PcapReader { reader, format })
+    }
+
+    /// Read the next UDP datagram, or `None` at the end of the capture
+    pub fn next_packet(&mut self) -> io::Result<Option<PcapPacket>> {
+        loop {
+            let frame = match &mut self.format {
+                CaptureFormat::Pcap {
+                    big_endian,
+                    resolution,
+                    linktype,
+                } => {
+                    let mut header = [0u8; 16];
+                    if !read_exact_or_eof(&mut self.reader, &mut header)? {
+                        return Ok(None);
+                    }
+                    let seconds = read_u32(&header[0..4], *big_endian) as f64;
+                    let fraction = read_u32(&header[4..8], *big_endian) as f64;
+                    let len = read_u32(&header[8..12], *big_endian) as usize;
+                    if len > MAX_RECORD_LEN {
+                        return Err(invalid("pcap packet record too long"));
+                    }
+                    let mut data = vec![0u8; len];
+                    self.reader.read_exact(&mut data)?;
+                    Some((seconds + fraction / *resolution, *linktype, data))
+                }
+                CaptureFormat::Pcapng { .. } => {
+                    let mut header = [0u8; 8];
+                    if !read_exact_or_eof(&mut self.reader, &mut header)? {
+                        return Ok(None);
+                    }
+                    self.read_block(header)?
+                }
+            };
+            if let Some((timestamp, linktype, data)) = frame {
+                if let Some((source, destination, payload)) = parse_udp(linktype, &data) {
+                    return Ok(Some(PcapPacket {
+                        timestamp,
+                        source,
+                        destination,
+                        payload: payload.to_vec(),
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Read a pcapng block, returning the packet it contains, if any
+    fn read_block(&mut self, header: [u8; 8]) -> io::Result<Option<(f64, u16, Vec<u8>)>> {
+        if u32::from_le_bytes(header[0..4].try_into().unwrap()) == PCAPNG_SHB {
+            read_section_header(
+                &mut self.reader,
+                &mut self.format,
+                header[4..8].try_into().unwrap(),
+            )?;
+            return Ok(None);
+        }
+        let CaptureFormat::Pcapng {
+            big_endian,
+            interfaces,
+        } = &mut self.format
+        else {
+            unreachable!()
+        };
+        let block_type = read_u32(&header[0..4], *big_endian);
+        let len = read_u32(&header[4..8], *big_endian) as usize;
+        if !(12..=MAX_RECORD_LEN).contains(&len) || !len.is_multiple_of(4) {
+            return Err(invalid("invalid pcapng block length"));
+        }
+        let mut body = vec![0u8; len - 8];
+        self.reader.read_exact(&mut body)?;
+        let body = &body[..len - 12];
+
+        match block_type {
+            PCAPNG_IDB if body.len() >= 8 => {
+                let linktype = read_u16(&body[0..2], *big_endian);
+                let resolution = interface_resolution(&body[8..], *big_endian);
+                interfaces.push((linktype, resolution));
+                Ok(None)
+            }
+            PCAPNG_EPB if body.len() >= 20 => {
+                let interface = read_u32(&body[0..4], *big_endian) as usize;
+                let (linktype, resolution) = *interfaces
+                    .get(interface)
+                    .ok_or_else(|| invalid("packet from undeclared pcapng interface"))?;
+                let ts = ((read_u32(&body[4..8], *big_endian) as u64) << 32)
+                    | read_u32(&body[8..12], *big_endian) as u64;
+                let captured = read_u32(&body[12..16], *big_endian) as usize;
+                let data = body
+                    .get(20..20 + captured)
+                    .ok_or_else(|| invalid("truncated pcapng packet"))?;
+                Ok(Some((ts as f64 / resolution, linktype, data.to_vec())))
+            }
+            // simple packet blocks have no timestamp, and other blocks
+            // contain no packets
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = io::Result<PcapPacket>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+/// Read the remainder of a pcapng section header block, after its type and
+/// length, and start a new section
+fn read_section_header<R: Read>(
+    reader: &mut R,
+    format: &mut CaptureFormat,
+    len: [u8; 4],
+) -> io::Result<()> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    let big_endian = match u32::from_le_bytes(magic) {
+        PCAPNG_BYTE_ORDER => false,
+        _ if u32::from_be_bytes(magic) == PCAPNG_BYTE_ORDER => true,
+        _ => return Err(invalid("invalid pcapng byte order magic")),
+    };
+    let len = read_u32(&len, big_endian) as usize;
+    if !(28..=MAX_RECORD_LEN).contains(&len) || !len.is_multiple_of(4) {
+        return Err(invalid("invalid pcapng section header length"));
+    }
+    let mut rest = vec![0u8; len - 12];
+    reader.read_exact(&mut rest)?;
+    *format = CaptureFormat::Pcapng {
+        big_endian,
+        interfaces: vec![],
+    };
+    Ok(())
+}
+
+/// Timestamp units per second, from the `if_tsresol` interface option
+fn interface_resolution(mut options: &[u8], big_endian: bool) -> f64 {
+    while options.len() >= 4 {
+        let code = read_u16(&options[0..2], big_endian);
+        let len = read_u16(&options[2..4], big_endian) as usize;
+        let value = match options.get(4..4 + len) {
+            Some(value) => value,
+            None => break,
+        };
+        match code {
+            0 => break,
+            9 if len == 1 => {
+                let exponent = (value[0] & 0x7f) as i32;
+                return if value[0] & 0x80 == 0 {
+                    10f64.powi(exponent)
+                } else {
+                    2f64.powi(exponent)
+                };
+            }
+            _ => {}
+        }
+        options = options
+            .get(4 + len.next_multiple_of(4)..)
+            .unwrap_or_default();
+    }
+    1e6
+}
+
+/// Extract the addresses and payload of a UDP datagram from a captured frame
+fn parse_udp(linktype: u16, frame: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let packet = match linktype {
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => frame,
+        LINKTYPE_NULL => frame.get(4..)?,
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            // skip VLAN tags
+            while matches!(frame.get(offset..offset + 2)?, [0x81, 0x00] | [0x88, 0xa8]) {
+                offset += 4;
+            }
+            frame.get(offset + 2..)?
+        }
+        LINKTYPE_LINUX_SLL => frame.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => frame.get(20..)?,
+        _ => return None,
+    };
+    let (source, destination, udp) = match packet.first()? >> 4 {
+        4 => {
+            let ihl = (packet[0] & 0x0f) as usize * 4;
+            let fragmented = u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]) & 0x3fff != 0;
+            if ihl < 20 || fragmented || *packet.get(9)? != IPPROTO_UDP {
+                return None;
+            }
+            let total = (u16::from_be_bytes([packet[2], packet[3]]) as usize).min(packet.len());
+            let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::from(source),
+                IpAddr::from(destination),
+                packet.get(ihl..total)?,
+            )
+        }
+        6 => {
+            if *packet.get(6)? != IPPROTO_UDP {
+                return None;
+            }
+            let total =
+                (40 + u16::from_be_bytes([packet[4], packet[5]]) as usize).min(packet.len());
+            let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::from(source),
+                IpAddr::from(destination),
+                packet.get(40..total)?,
+            )
+        }
+        _ => return None,
+    };
+    let source_port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+    let destination_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+    let len = (u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]) as usize).min(udp.len());
+    Some((
+        SocketAddr::new(source, source_port),
+        SocketAddr::new(destination, destination_port),
+        udp.get(8..len)?,
+    ))
+}
+
+/// Synthesise an IPv4 or IPv6 packet containing a UDP datagram.
+/// If the address families differ, IPv4 addresses are mapped to IPv6
+fn udp_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let udp_len = payload.len() + 8;
+    let (src_ip, dst_ip) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+        (s, d) => (IpAddr::V6(to_ipv6(s)), IpAddr::V6(to_ipv6(d))),
+    };
+    let header_len = if src_ip.is_ipv4() { 20 } else { 40 };
+    if udp_len + header_len > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "datagram too large to capture",
+        ));
+    }
+
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&destination.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(header_len + udp_len);
+    let mut pseudo_header = vec![];
+    match (src_ip, dst_ip) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            // identification, don't fragment, TTL 64, UDP
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0]);
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+            let checksum = internet_checksum(&[&packet]);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            pseudo_header.extend_from_slice(&s.octets());
+            pseudo_header.extend_from_slice(&d.octets());
+            pseudo_header.extend_from_slice(&[0, IPPROTO_UDP]);
+            pseudo_header.extend_from_slice(&(udp_len as u16).to_be_bytes());
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            // UDP, hop limit 64
+            packet.extend_from_slice(&[IPPROTO_UDP, 64]);
+            packet.extend_from_slice(&s.octets());
+            packet.extend_from_slice(&d.octets());
+
+            pseudo_header.extend_from_slice(&s.octets());
+            pseudo_header.extend_from_slice(&d.octets());
+            pseudo_header.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+        }
+        _ => unreachable!(),
+    }
+    let checksum = match internet_checksum(&[&pseudo_header, &udp]) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&udp);
+    Ok(packet)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) if ip == Ipv4Addr::UNSPECIFIED => Ipv6Addr::UNSPECIFIED,
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// RFC 1071 ones' complement checksum over the concatenation of `parts`.
+/// Each part except the last must have an even length
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for pair in part.chunks(2) {
+            let word = match pair {
+                [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+                [hi] => u16::from_be_bytes([*hi, 0]),
+                _ => unreachable!(),
+            };
+            sum += word as u32;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Write a pcapng block with the given `body`, padded to 32 bits
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let padding = body.len().next_multiple_of(4) - body.len();
+    let len = (12 + body.len() + padding) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&[0u8; 3][..padding])?;
+    writer.write_all(&len.to_le_bytes())
+}
+
+/// Fill `buf`, returning false if the reader is already at EOF
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(c) => filled += c,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn read_u16(bytes: &[u8], big_endian: bool) -> u16 {
+    let bytes: [u8; 2] = bytes.try_into().unwrap();
+    if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> u32 {
+    let bytes: [u8; 4] = bytes.try_into().unwrap();
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::nmea::{sentences, Sentence};
+
+/// Options for [Reassembler]
+#[derive(Clone, Debug)]
+pub struct ReassemblyOptions {
+    /// Discard incomplete messages after waiting this long for the
+    /// remaining fragments
+    pub timeout: Duration,
+    /// Maximum number of incomplete messages held at once. The oldest
+    /// incomplete message is discarded to make room for a new one
+    pub max_pending: usize,
+    /// Number of fragments discarded without completing a message.
+    /// Clones of the options share the same counter
+    pub orphaned: Arc<AtomicU64>,
+}
+
+impl Default for ReassemblyOptions {
+    fn default() -> Self {
+        ReassemblyOptions {
+            timeout: Duration::from_secs(2),
+            max_pending: 1024,
+            orphaned: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+/// Identifies the fragments of one multi-sentence message
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct GroupKey {
+    source: SocketAddr,
+    sequential_id: Option<u8>,
+    channel: Option<char>,
+    fragment_count: u8,
+}
+
+#[derive(Clone, Debug)]
+struct Group {
+    started: Instant,
+    lines: Vec<Option<Vec<u8>>>,
+}
+
+impl Group {
+    fn received(&self) -> u64 {
+        self.lines.iter().filter(|line| line.is_some()).count() as u64
+    }
+}
+
+/// Buffers the fragments of multi-sentence AIS messages, such as type 5
+/// static and voyage data, until all fragments have been received.
+///
+/// Complete messages are emitted as consecutive lines in a single output,
+/// so they are not split across datagrams or interleaved with other
+/// traffic. Single-sentence messages and other lines pass through
+/// unchanged
+#[derive(Clone, Debug)]
+pub struct Reassembler {
+    options: ReassemblyOptions,
+    pending: HashMap<GroupKey, Group>,
+}
+
+impl Reassembler {
+    pub fn new(options: &ReassemblyOptions) -> Self {
+        Reassembler {
+            options: options.clone(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Add the lines of a datagram received from `source` at `now`.
+    /// Returns the lines which are ready to be passed downstream
+    pub fn push(&mut self, source: SocketAddr, data: &[u8], now: Instant) -> Vec<u8> {
+        self.expire(now);
+        let mut out = Vec::with_capacity(data.len());
+        for line in sentences(data) {
+            let fragment = match Sentence::parse(line).and_then(|s| s.ais()) {
+                Ok(fragment) if fragment.fragment_count > 1 => fragment,
+                _ => {
+                    out.extend_from_slice(line);
+                    out.push(b'\n');
+                    continue;
+                }
+            };
+            let key = GroupKey {
+                source,
+                sequential_id: fragment.sequential_id,
+                channel: fragment.channel,
+                fragment_count: fragment.fragment_count,
+            };
+            let index = fragment.fragment_number as usize - 1;
+
+            // a repeated fragment number starts a new message
+            if self
+                .pending
+                .get(&key)
+                .is_some_and(|group| group.lines[index].is_some())
+            {
+                let group = self.pending.remove(&key).unwrap();
+                self.orphan(group.received());
+            }
+            if !self.pending.contains_key(&key) && self.pending.len() >= self.options.max_pending {
+                self.evict_oldest();
+            }
+            let group = self.pending.entry(key.clone()).or_insert_with(|| Group {
+                started: now,
+                lines: vec![None; fragment.fragment_count as usize],
+            });
+            group.lines[index] = Some(line.to_vec());
+
+            if group.lines.iter().all(Option::is_some) {
+                let group = self.pending.remove(&key).unwrap();
+                for line in group.lines.into_iter().flatten() {
+                    out.extend_from_slice(&line);
+                    out.push(b'\n');
+                }
+            }
+        }
+        out
+    }
+
+    /// Discard incomplete messages older than the timeout
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.options.timeout;
+        let mut expired = 0;
+        self.pending.retain(|_, group| {
+            let keep = now.saturating_duration_since(group.started) < timeout;
+            if !keep {
+                expired += group.received();
+            }
+            keep
+        });
+        self.orphan(expired);
+    }
+
+    /// Number of incomplete messages currently held
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Number of fragments discarded without completing a message
+    pub fn orphaned(&self) -> u64 {
+        self.options.orphaned.load(Ordering::Relaxed)
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .pending
+            .iter()
+            .min_by_key(|(_, group)| group.started)
+            .map(|(key, _)| key.clone());
+        if let Some(group) = oldest.and_then(|key| self.pending.remove(&key)) {
+            self.orphan(group.received());
+        }
+    }
+
+    fn orphan(&self, fragments: u64) {
+        if fragments > 0 {
+            self.options
+                .orphaned
+                .fetch_add(fragments, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,485 @@
+use std::fs::{self, create_dir_all, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use time::OffsetDateTime;
+
+use crate::MproxyError;
+
+/// When a [RotatingFile] starts writing to a new file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    /// Only rotate when the file exceeds `max_size`
+    Never,
+    /// Start a new file every hour (UTC)
+    Hourly,
+    /// Start a new file every day (UTC)
+    #[default]
+    Daily,
+}
+
+impl Rotation {
+    /// Index of the rotation period containing `t`
+    fn period(&self, t: OffsetDateTime) -> i64 {
+        match self {
+            Rotation::Never => 0,
+            Rotation::Hourly => t.unix_timestamp().div_euclid(3600),
+            Rotation::Daily => t.unix_timestamp().div_euclid(86400),
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            other => Err(format!(
+                "unknown rotation '{}', expected one of never, hourly, daily",
+                other
+            )),
+        }
+    }
+}
+
+/// Compression applied to files once they are rotated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Requires feature `gzip`
+    Gzip,
+    /// Requires feature `zstd`
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gz"),
+            Compression::Zstd => Some("zst"),
+        }
+    }
+
+    fn validate(&self) -> Result<(), MproxyError> {
+        match self {
+            #[cfg(not(feature = "gzip"))]
+            Compression::Gzip => Err(MproxyError::Config(
+                "gzip compression requires feature 'gzip'".to_string(),
+            )),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(MproxyError::Config(
+                "zstd compression requires feature 'zstd'".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            other => Err(format!(
+                "unknown compression '{}', expected one of none, gzip, zstd",
+                other
+            )),
+        }
+    }
+}
+
+/// Options for [RotatingFile]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RotatingFileOptions {
+    /// Directory containing the output files
+    pub dir: PathBuf,
+    /// Output filename. May contain the UTC date/time placeholders
+    /// `%Y` `%m` `%d` `%H` `%M` `%S` `%j` `%s` and `%%`, as used by strftime
+    pub pattern: String,
+    /// Start a new file every hour or day
+    pub rotation: Rotation,
+    /// Start a new file once the current file exceeds this many bytes.
+    /// The full file is renamed with a numbered suffix, e.g. `2024-01-01.log.1`
+    pub max_size: Option<u64>,
+    /// Delete the oldest rotated files, keeping at most this many
+    pub max_files: Option<usize>,
+    /// Delete rotated files last modified longer ago than this
+    pub max_age: Option<Duration>,
+    /// Compress files once they are rotated
+    pub compression: Compression,
+}
+
+impl Default for RotatingFileOptions {
+    /// Daily files in `./ais_backup`, named `YYYY-MM-DD.log`
+    fn default() -> Self {
+        RotatingFileOptions {
+            dir: PathBuf::from("./ais_backup"),
+            pattern: "%Y-%m-%d.log".to_string(),
+            rotation: Rotation::Daily,
+            max_size: None,
+            max_files: None,
+            max_age: None,
+            compression: Compression::None,
+        }
+    }
+}
+
+/// File writer with time and size based rotation, retention, and compression.
+///
+/// The current file is kept open between writes, and is appended to if it
+/// already exists.
+/// Rotated files are compressed and old files are deleted on a background
+/// thread, so that writes are not blocked.
+#[derive(Debug)]
+pub struct RotatingFile {
+    options: RotatingFileOptions,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    period: i64,
+    written: u64,
+    housekeeping: Option<JoinHandle<()>>,
+}
+
+impl RotatingFile {
+    /// Create `options.dir` if needed, and open the current output file
+    pub fn new(options: RotatingFileOptions) -> Result<Self, MproxyError> {
+        options.compression.validate()?;
+        if options.pattern.is_empty() {
+            return Err(MproxyError::Config(
+                "output filename pattern is empty".to_string(),
+            ));
+        }
+        create_dir_all(&options.dir).map_err(|source| MproxyError::File {
+            path: options.dir.clone(),
+            source,
+        })?;
+        let now = OffsetDateTime::now_utc();
+        let path = options.dir.join(format_pattern(&options.pattern, now));
+        let (writer, written) = open_append(&path)?;
+        let mut file = RotatingFile {
+            period: options.rotation.period(now),
+            options,
+            path,
+            writer,
+            written,
+            housekeeping: None,
+        };
+        file.housekeeping(None);
+        Ok(file)
+    }
+
+    /// Path of the file currently being written
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Close the current file and start a new one, as if the rotation period
+    /// had elapsed
+    pub fn rotate(&mut self) -> Result<(), MproxyError> {
+        let now = OffsetDateTime::now_utc();
+        let path = self
+            .options
+            .dir
+            .join(format_pattern(&self.options.pattern, now));
+        self.rotate_to(path, now)
+    }
+
+    /// Close and reopen the current file, e.g. after it was moved by an
+    /// external tool such as logrotate
+    pub fn reopen(&mut self) -> Result<(), MproxyError> {
+        self.flush_current()?;
+        let (writer, written) = open_append(&self.path)?;
+        self.writer = writer;
+        self.written = written;
+        Ok(())
+    }
+
+    fn rotate_to(&mut self, path: PathBuf, now: OffsetDateTime) -> Result<(), MproxyError> {
+        self.flush_current()?;
+        let closed = if path == self.path {
+            // same filename, so move the full file out of the way
+            let numbered = numbered_path(&self.path, self.options.compression);
+            fs::rename(&self.path, &numbered).map_err(|source| MproxyError::File {
+                path: self.path.clone(),
+                source,
+            })?;
+            numbered
+        } else {
+            self.path.clone()
+        };
+        let (writer, written) = open_append(&path)?;
+        self.writer = writer;
+        self.written = written;
+        self.path = path;
+        self.period = self.options.rotation.period(now);
+        self.housekeeping(Some(closed));
+        Ok(())
+    }
+
+    fn flush_current(&mut self) -> Result<(), MproxyError> {
+        self.writer.flush().map_err(|source| MproxyError::File {
+            path: self.path.clone(),
+            source,
+        })
+    }
+
+    /// Compress `closed`, then enforce retention limits, on a background thread
+    fn housekeeping(&mut self, closed: Option<PathBuf>) {
+        if let Some(previous) = self.housekeeping.take() {
+            let _ = previous.join();
+        }
+        let options = self.options.clone();
+        let current = self.path.clone();
+        self.housekeeping = Builder::new()
+            .name(format!("{}:housekeeping", current.display()))
+            .spawn(move || {
+                if let Some(closed) = closed {
+                    if let Err(e) = compress(&closed, options.compression) {
+                        eprintln!("compressing {}: {}", closed.display(), e);
+                    }
+                }
+                enforce_retention(&options, &current);
+            })
+            .ok();
+    }
+
+    fn check_rotation(&mut self) -> Result<(), MproxyError> {
+        let size_exceeded = self.options.max_size.is_some_and(|max| self.written >= max);
+        let now = OffsetDateTime::now_utc();
+        if self.options.rotation.period(now) != self.period {
+            let path = self
+                .options
+                .dir
+                .join(format_pattern(&self.options.pattern, now));
+            self.rotate_to(path, now)
+        } else if size_exceeded {
+            self.rotate_to(self.path.clone(), now)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_rotation().map_err(io::Error::other)?;
+        let c = self.writer.write(buf)?;
+        self.written += c as u64;
+        Ok(c)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for RotatingFile {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+        if let Some(housekeeping) = self.housekeeping.take() {
+            let _ = housekeeping.join();
+        }
+    }
+}
+
+fn open_append(path: &Path) -> Result<(BufWriter<File>, u64), MproxyError> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|source| MproxyError::File {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    let written = file.metadata().map(|m| m.len()).unwrap_or(0);
+    Ok((BufWriter::new(file), written))
+}
+
+/// Append `suffix` to the filename of `path`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// First unused `path.N`, also checking for compressed copies
+fn numbered_path(path: &Path, compression: Compression) -> PathBuf {
+    (1..)
+        .map(|n| with_suffix(path, &format!(".{}", n)))
+        .find(|p| {
+            !p.exists()
+                && compression
+                    .extension()
+                    .is_none_or(|ext| !with_suffix(p, &format!(".{}", ext)).exists())
+        })
+        .unwrap()
+}
+
+/// Compress `path`, replacing it with `path.gz` or `path.zst`
+fn compress(path: &Path, compression: Compression) -> io::Result<()> {
+    let ext = match compression.extension() {
+        Some(ext) => ext,
+        None => return Ok(()),
+    };
+    let mut target = with_suffix(path, &format!(".{}", ext));
+    if target.exists() {
+        target = with_suffix(&numbered_path(path, compression), &format!(".{}", ext));
+    }
+    encode(File::open(path)?, File::create(&target)?, compression)?;
+    fs::remove_file(path)
+}
+
+fn encode(input: File, output: File, compression: Compression) -> io::Result<()> {
+    match compression {
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+            io::copy(&mut &input, &mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(output, 0)?;
+            io::copy(&mut &input, &mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        }
+        other => {
+            drop((input, output));
+            Err(io::Error::other(format!(
+                "{:?} compression is not enabled",
+                other
+            )))
+        }
+    }
+}
+
+/// Delete files matching `options.pattern` that exceed the retention limits.
+/// The file currently being written is never deleted
+fn enforce_retention(options: &RotatingFileOptions, current: &Path) {
+    if options.max_files.is_none() && options.max_age.is_none() {
+        return;
+    }
+    let entries = match fs::read_dir(&options.dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let mut files: Vec<(SystemTime, PathBuf)> = entries
+        .flatten()
+        .filter(|entry| {
+            matches_pattern(&options.pattern, &entry.file_name().to_string_lossy())
+                && entry.path() != current
+        })
+        .filter_map(|entry| {
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            Some((modified, entry.path()))
+        })
+        .collect();
+
+    // newest first
+    files.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+
+    let now = SystemTime::now();
+    for (i, (modified, path)) in files.iter().enumerate() {
+        let too_many = options.max_files.is_some_and(|max| i >= max);
+        let too_old = options
+            .max_age
+            .is_some_and(|age| now.duration_since(*modified).unwrap_or_default() > age);
+        if too_many || too_old {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Format strftime-style date/time placeholders in `pattern`
+pub fn format_pattern(pattern: &str, t: OffsetDateTime) -> String {
+    let mut out = String::with_capacity(pattern.len() + 8);
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&format!("{:04}", t.year())),
+            Some('m') => out.push_str(&format!("{:02}", t.month() as u8)),
+            Some('d') => out.push_str(&format!("{:02}", t.day())),
+            Some('H') => out.push_str(&format!("{:02}", t.hour())),
+            Some('M') => out.push_str(&format!("{:02}", t.minute())),
+            Some('S') => out.push_str(&format!("{:02}", t.second())),
+            Some('j') => out.push_str(&format!("{:03}", t.ordinal())),
+            Some('s') => out.push_str(&t.unix_timestamp().to_string()),
+            Some('%') => out.push('%'),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+/// Returns true if `name` could have been produced by [format_pattern],
+/// optionally followed by a rotation or compression suffix such as `.1.gz`
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let name = name.as_bytes();
+    let mut pos = 0;
+    let mut chars = pattern.chars();
+    let digits = |pos: usize, n: usize| {
+        name.len() >= pos + n && name[pos..pos + n].iter().all(u8::is_ascii_digit)
+    };
+    while let Some(c) = chars.next() {
+        let width = match (c, chars.clone().next()) {
+            ('%', Some('Y')) => Some(4),
+            ('%', Some('j')) => Some(3),
+            ('%', Some('m' | 'd' | 'H' | 'M' | 'S')) => Some(2),
+            ('%', Some('s')) => {
+                let n = name[pos..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_digit())
+                    .count();
+                Some(n.max(1))
+            }
+            ('%', Some('%')) => {
+                chars.next();
+                if name.get(pos) != Some(&b'%') {
+                    return false;
+                }
+                pos += 1;
+                continue;
+            }
+            _ => None,
+        };
+        match width {
+            Some(n) => {
+                chars.next();
+                if !digits(pos, n) {
+                    return false;
+                }
+                pos += n;
+            }
+            None => {
+                let mut utf8 = [0u8; 4];
+                let literal = c.encode_utf8(&mut utf8).as_bytes();
+                if !name[pos..].starts_with(literal) {
+                    return false;
+                }
+                pos += literal.len();
+            }
+        }
+    }
+    pos == name.len() || name[pos] == b'.'
+}
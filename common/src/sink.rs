@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::io::{stdout, BufWriter, Stdout, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::Builder;
+
+use crate::handle::{is_shutdown, is_timeout, SHUTDOWN_POLL_INTERVAL};
+use crate::pcap::PcapSink;
+use crate::socket::{send_to_target, target_socket_interface};
+use crate::source::{accept_connection, bind_tcp_listener, Acceptor, Connection, Received, Source};
+use crate::stage::{DatagramMeta, Pipeline};
+use crate::MproxyError;
+
+/// Read buffer size used by [pump]
+const BUFSIZE: usize = 8096;
+
+/// Output written by [pump], e.g. a UDP socket, a file, or stdout
+pub trait Sink: Send {
+    /// Write one datagram, received as described by `meta`
+    fn send(&mut self, data: &[u8], meta: &DatagramMeta) -> Result<(), MproxyError>;
+
+    /// Flush buffered output. Called by [pump] after each datagram and each
+    /// read timeout, so sinks may also do periodic work here
+    fn flush(&mut self) -> Result<(), MproxyError> {
+        Ok(())
+    }
+}
+
+impl Sink for Box<dyn Sink> {
+    fn send(&mut self, data: &[u8], meta: &DatagramMeta) -> Result<(), MproxyError> {
+        self.as_mut().send(data, meta)
+    }
+
+    fn flush(&mut self) -> Result<(), MproxyError> {
+        self.as_mut().flush()
+    }
+}
+
+/// Copy input from `source` to each of `sinks` in order, until the input
+/// ends, a source or sink returns an error, or `shutdown` is set.
+/// Sinks are flushed after each datagram and before returning
+pub fn pump(
+    source: &mut dyn Source,
+    sinks: &mut [Box<dyn Sink>],
+    shutdown: &AtomicBool,
+) -> Result<(), MproxyError> {
+    let mut buf = vec![0u8; BUFSIZE];
+    let result = (|| {
+        while !is_shutdown(shutdown) {
+            match source.recv(&mut buf)? {
+                Received::Data { len, meta } => {
+                    for sink in sinks.iter_mut() {
+                        sink.send(&buf[0..len], &meta)?;
+                    }
+                }
+                Received::Timeout => {}
+                Received::Eof => break,
+            }
+            for sink in sinks.iter_mut() {
+                sink.flush()?;
+            }
+        }
+        Ok(())
+    })();
+    for sink in sinks.iter_mut() {
+        sink.flush()?;
+    }
+    result
+}
+
+/// Sends datagrams to a UDP socket address. Multicast groups are joined
+#[derive(Debug)]
+pub struct UdpSink {
+    addr: SocketAddr,
+    socket: UdpSocket,
+}
+
+impl UdpSink {
+    /// Resolve `addr` and bind a socket for sending to it, as
+    /// [target_socket_interface]
+    pub fn connect(addr: &str) -> Result<Self, MproxyError> {
+        let (addr, socket) = target_socket_interface(addr)?;
+        Ok(UdpSink { addr, socket })
+    }
+
+    /// Resolved target address
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Sink for UdpSink {
+    fn send(&mut self, data: &[u8], _meta: &DatagramMeta) -> Result<(), MproxyError> {
+        send_to_target(data, &self.addr, &self.socket)?;
+        Ok(())
+    }
+}
+
+/// Writes datagrams to a file, stdout, or any other writer
+pub struct WriterSink<W: Write + Send> {
+    writer: W,
+}
+
+impl<W: Write + Send> WriterSink<W> {
+    pub fn new(writer: W) -> Self {
+        WriterSink { writer }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+}
+
+impl WriterSink<BufWriter<Stdout>> {
+    /// Copy datagrams to stdout
+    pub fn stdout() -> Self {
+        WriterSink::new(BufWriter::new(stdout()))
+    }
+}
+
+impl<W: Write + Send> Sink for WriterSink<W> {
+    fn send(&mut self, data: &[u8], _meta: &DatagramMeta) -> Result<(), MproxyError> {
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), MproxyError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Sink for PcapSink {
+    fn send(&mut self, data: &[u8], meta: &DatagramMeta) -> Result<(), MproxyError> {
+        self.write_udp(meta.received, meta.source, meta.listen_addr, data)
+    }
+}
+
+/// Passes datagrams through a [Pipeline], then sends its output to each of
+/// `sinks`. Use it to apply stages to some outputs of [pump] but not others
+pub struct PipelineSink {
+    pipeline: Pipeline,
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl PipelineSink {
+    pub fn new(pipeline: Pipeline, sinks: Vec<Box<dyn Sink>>) -> Self {
+        PipelineSink { pipeline, sinks }
+    }
+}
+
+impl Sink for PipelineSink {
+    fn send(&mut self, data: &[u8], meta: &DatagramMeta) -> Result<(), MproxyError> {
+        for datagram in self.pipeline.run(data, meta)? {
+            for sink in self.sinks.iter_mut() {
+                sink.send(&datagram, meta)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), MproxyError> {
+        for sink in self.sinks.iter_mut() {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends datagrams to every client connected to a TCP listener.
+///
+/// Connections are accepted when the sink is written or flushed. Clients
+/// may have their own pipeline, chosen by client IP address, e.g. to pass
+/// each partner only the messages they are entitled to. Clients which
+/// disconnect or stop reading for longer than the write timeout are dropped
+pub struct TcpFanoutSink {
+    listener: TcpListener,
+    pipelines: HashMap<IpAddr, Pipeline>,
+    acceptor: Option<Arc<dyn Acceptor>>,
+    accepted: (Sender<Accepted>, Receiver<Accepted>),
+    clients: Vec<(Box<dyn Connection>, SocketAddr)>,
+}
+
+/// Connection set up by an [Acceptor] on its own thread
+type Accepted = (Box<dyn Connection>, SocketAddr);
+
+impl TcpFanoutSink {
+    /// Listen for TCP connections on `addr`
+    pub fn bind(addr: &str) -> Result<Self, MproxyError> {
+        Ok(TcpFanoutSink {
+            listener: bind_tcp_listener(addr)?,
+            pipelines: HashMap::new(),
+            acceptor: None,
+            accepted: channel(),
+            clients: vec![],
+        })
+    }
+
+    /// Set up new connections with `acceptor`, e.g. to terminate TLS.
+    /// Clients are sent datagrams once set up, without blocking others
+    pub fn with_acceptor(mut self, acceptor: Arc<dyn Acceptor>) -> Self {
+        self.acceptor = Some(acceptor);
+        self
+    }
+
+    /// Pass datagrams sent to clients connecting from each IP address
+    /// through its pipeline. Clients connecting from the same address share
+    /// the output of one pipeline, which runs once per datagram
+    pub fn with_pipelines(mut self, pipelines: HashMap<IpAddr, Pipeline>) -> Self {
+        self.pipelines = pipelines;
+        self
+    }
+
+    /// Number of connected clients
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    fn accept(&mut self) -> Result<(), MproxyError> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer_addr)) => {
+                    #[cfg(debug_assertions)]
+                    println!("new client {:?}", stream);
+                    stream.set_nonblocking(false)?;
+                    match &self.acceptor {
+                        Some(acceptor) => self.spawn_acceptor(acceptor.clone(), stream, peer_addr),
+                        None => {
+                            stream.set_write_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+                            self.add_client(Box::new(stream), peer_addr)
+                        }
+                    }
+                }
+                Err(e) if is_timeout(&e) => break,
+                Err(e) => {
+                    eprintln!("dropping client: {}", e);
+                    break;
+                }
+            }
+        }
+        while let Ok((connection, peer_addr)) = self.accepted.1.try_recv() {
+            self.add_client(connection, peer_addr);
+        }
+        Ok(())
+    }
+
+    /// Set up a connection on its own thread, so that slow clients do not
+    /// delay others
+    fn spawn_acceptor(
+        &self,
+        acceptor: Arc<dyn Acceptor>,
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+    ) {
+        let accepted = self.accepted.0.clone();
+        let spawned = Builder::new()
+            .name(format!("{}:accept", peer_addr))
+            .spawn(move || {
+                let connection = accept_connection(stream, Some(acceptor.as_ref())).and_then(
+                    |(connection, socket)| {
+                        socket.set_write_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+                        Ok(connection)
+                    },
+                );
+                match connection {
+                    // the sink may have been dropped in the meantime
+                    Ok(connection) => {
+                        let _ = accepted.send((connection, peer_addr));
+                    }
+                    Err(e) => eprintln!("dropping client {}: {}", peer_addr, e),
+                }
+            });
+        if let Err(e) = spawned {
+            eprintln!("dropping client {}: {}", peer_addr, e);
+        }
+    }
+
+    fn add_client(&mut self, connection: Box<dyn Connection>, peer_addr: SocketAddr) {
+        self.clients.push((connection, peer_addr));
+    }
+}
+
+impl Sink for TcpFanoutSink {
+    fn send(&mut self, data: &[u8], meta: &DatagramMeta) -> Result<(), MproxyError> {
+        self.accept()?;
+        let pipelines = &mut self.pipelines;
+        let mut outputs: HashMap<IpAddr, Option<Vec<Vec<u8>>>> = HashMap::new();
+        self.clients.retain_mut(|(stream, peer_addr)| {
+            // IPv4 clients of an IPv6 listener connect from mapped addresses
+            let ip = peer_addr.ip().to_canonical();
+            let datagrams: Vec<&[u8]> = match pipelines.get_mut(&ip) {
+                None => vec![data],
+                Some(pipeline) => {
+                    let output = outputs.entry(ip).or_insert_with(|| {
+                        pipeline
+                            .run(data, meta)
+                            .map_err(|e| eprintln!("dropping clients from {}: {}", ip, e))
+                            .ok()
+                    });
+                    match output {
+                        Some(datagrams) => datagrams.iter().map(Vec::as_slice).collect(),
+                        None => return false,
+                    }
+                }
+            };
+            for datagram in datagrams {
+                if let Err(_e) = stream.write_all(datagram) {
+                    #[cfg(debug_assertions)]
+                    eprintln!("reverse_proxy: closing {} {}", peer_addr, _e);
+                    return false;
+                }
+            }
+            true
+        });
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), MproxyError> {
+        self.accept()
+    }
+}
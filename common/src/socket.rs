@@ -0,0 +1,157 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use crate::error::{resolve_socket_addr, MproxyError};
+
+/// Resolve `listen_addr` and bind a UDP socket listening on it.
+/// Multicast groups are joined on any available interface.
+pub fn upstream_socket_interface(
+    listen_addr: String,
+) -> Result<(SocketAddr, UdpSocket), MproxyError> {
+    let addr = resolve_socket_addr(&listen_addr)?;
+    let bind = |bind_addr: SocketAddr| {
+        UdpSocket::bind(bind_addr).map_err(|source| MproxyError::Bind {
+            addr: bind_addr.to_string(),
+            source,
+        })
+    };
+    let join_err = |source| MproxyError::MulticastJoin {
+        addr: addr.to_string(),
+        source,
+    };
+    let listen_socket;
+    match (addr.ip().is_multicast(), addr.ip()) {
+        (false, std::net::IpAddr::V4(_)) => {
+            listen_socket = bind(addr)?;
+        }
+        (false, std::net::IpAddr::V6(_)) => {
+            listen_socket = bind(addr)?;
+        }
+        (true, std::net::IpAddr::V4(ip)) => {
+            #[cfg(not(target_os = "windows"))]
+            {
+                listen_socket = bind(addr)?;
+                listen_socket
+                    .join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)
+                    .map_err(join_err)?;
+            }
+            #[cfg(target_os = "windows")]
+            {
+                listen_socket = bind(SocketAddr::new(
+                    IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    addr.port(),
+                ))?;
+
+                listen_socket
+                    .join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)
+                    .map_err(join_err)?;
+            }
+        }
+        (true, std::net::IpAddr::V6(ip)) => {
+            listen_socket = bind(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                addr.port(),
+            ))?;
+
+            // specify "any available interface" with index 0
+            #[cfg(not(target_os = "macos"))]
+            let itf = 0; // unspecified
+            #[cfg(target_os = "macos")]
+            let itf = 12; // en0
+
+            listen_socket
+                .join_multicast_v6(&ip, itf)
+                .map_err(join_err)?;
+
+            #[cfg(target_os = "windows")]
+            listen_socket.connect(&addr).map_err(join_err)?;
+        }
+    };
+    Ok((addr, listen_socket))
+}
+
+/// Resolve `server_addr` and bind a UDP socket for sending to it.
+/// Multicast groups are joined on the default interface.
+pub fn target_socket_interface(server_addr: &str) -> Result<(SocketAddr, UdpSocket), MproxyError> {
+    let target_addr = resolve_socket_addr(server_addr)?;
+
+    // Binds to a random UDP port for sending to downstream.
+    let unspec: SocketAddr = if target_addr.is_ipv4() {
+        SocketAddr::new(std::net::Ipv4Addr::UNSPECIFIED.into(), 0)
+    } else {
+        SocketAddr::new(std::net::Ipv6Addr::UNSPECIFIED.into(), 0)
+    };
+
+    let target_socket = UdpSocket::bind(unspec).map_err(|source| MproxyError::Bind {
+        addr: unspec.to_string(),
+        source,
+    })?;
+    //target_socket.connect(target_addr).unwrap_or_else(|e| panic!("{}", e));
+
+    let join_err = |source| MproxyError::MulticastJoin {
+        addr: target_addr.to_string(),
+        source,
+    };
+
+    if target_addr.ip().is_multicast() {
+        match target_addr.ip() {
+            // join the ipv4 multicast group
+            IpAddr::V4(ip) => {
+                target_socket
+                    .join_multicast_v4(&ip, &std::net::Ipv4Addr::UNSPECIFIED)
+                    .map_err(join_err)?;
+            }
+
+            // for multicast ipv6, join the multicast group on an unspecified
+            // interface, then connect to an unspecified remote socket address
+            // with the target port
+            IpAddr::V6(ip) => {
+                #[cfg(target_os = "linux")]
+                let itf = 0;
+
+                #[cfg(target_os = "windows")]
+                let itf = 0;
+
+                #[cfg(target_os = "macos")]
+                let itf = default_net::get_default_interface()
+                    .map_err(|e| join_err(std::io::Error::new(std::io::ErrorKind::NotFound, e)))?
+                    .index;
+
+                #[cfg(not(target_os = "windows"))]
+                target_socket
+                    .connect(SocketAddr::new(
+                        IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                        target_addr.port(),
+                    ))
+                    .map_err(join_err)?;
+
+                #[cfg(target_os = "windows")]
+                target_socket.connect(target_addr).map_err(join_err)?;
+
+                target_socket
+                    .join_multicast_v6(&ip, itf) // index 0 for unspecified interface
+                    .map_err(join_err)?;
+            }
+        };
+    }
+
+    Ok((target_addr, target_socket))
+}
+
+/// Send `msg` to `target_addr` via `target_socket`, as returned by
+/// [target_socket_interface].
+/// IPv6 multicast sockets are connected, so `send()` is used instead of `send_to()`
+pub fn send_to_target(
+    msg: &[u8],
+    target_addr: &SocketAddr,
+    target_socket: &UdpSocket,
+) -> Result<usize, MproxyError> {
+    let sent = if !(target_addr.is_ipv6() && target_addr.ip().is_multicast()) {
+        target_socket.send_to(msg, target_addr)
+    } else {
+        target_socket.send(msg)
+    };
+    sent.map_err(|source| MproxyError::Send {
+        addr: target_addr.to_string(),
+        source,
+    })
+}
@@ -0,0 +1,403 @@
+use std::fs::File;
+use std::io::{stdin, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread::{sleep, Builder, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::handle::{is_shutdown, is_timeout, is_transient, SHUTDOWN_POLL_INTERVAL};
+use crate::socket::upstream_socket_interface;
+use crate::stage::DatagramMeta;
+use crate::MproxyError;
+
+/// Read buffer size of sources which read on their own threads
+const BUFSIZE: usize = 8096;
+
+/// Chunks held for [TcpListenSource::recv] before client threads block
+const CHANNEL_BOUND: usize = 1024;
+
+/// Time allowed for an [Acceptor] to set up a connection, e.g. a TLS
+/// handshake
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Byte stream of an accepted TCP client, e.g. a TLS session
+pub trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// Sets up connections accepted by [TcpListenSource] and [TcpFanoutSink](crate::TcpFanoutSink),
+/// e.g. to terminate TLS. Runs on a separate thread for each client, with
+/// read and write timeouts on the socket
+pub trait Acceptor: Send + Sync {
+    /// Wrap a newly accepted TCP stream, or reject the client
+    fn accept(&self, stream: TcpStream) -> Result<Box<dyn Connection>, MproxyError>;
+}
+
+/// Address reported by sources which do not read from a socket, such as
+/// files and stdin
+pub const UNSPECIFIED_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// Outcome of [Source::recv]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Received {
+    /// `len` bytes were read into the buffer
+    Data { len: usize, meta: DatagramMeta },
+    /// Nothing was read before the source's read timeout, or a transient
+    /// receive error was logged and skipped. Sources time out periodically
+    /// so that [pump](crate::pump) can check for shutdown
+    Timeout,
+    /// The input has ended
+    Eof,
+}
+
+/// Input read by [pump](crate::pump), e.g. a UDP socket, a TCP connection,
+/// or a file
+pub trait Source: Send {
+    /// Read the next datagram or chunk of input into `buf`
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Received, MproxyError>;
+}
+
+/// Datagrams received by a UDP socket. Multicast addresses are joined
+#[derive(Debug)]
+pub struct UdpSource {
+    addr: SocketAddr,
+    socket: UdpSocket,
+}
+
+impl UdpSource {
+    /// Bind a UDP socket listening on `listen_addr`, as
+    /// [upstream_socket_interface]
+    pub fn bind(listen_addr: String) -> Result<Self, MproxyError> {
+        let (addr, socket) = upstream_socket_interface(listen_addr)?;
+        socket.set_broadcast(true)?;
+        socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+        Ok(UdpSource { addr, socket })
+    }
+
+    /// Resolved listening address
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Source for UdpSource {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Received, MproxyError> {
+        match self.socket.recv_from(buf) {
+            Ok((len, source)) => Ok(Received::Data {
+                len,
+                meta: DatagramMeta {
+                    source,
+                    listen_addr: self.addr,
+                    received: SystemTime::now(),
+                },
+            }),
+            Err(e) if is_timeout(&e) => Ok(Received::Timeout),
+            Err(e) if is_transient(&e) => {
+                eprintln!("{}: skipping receive error: {}", self.addr, e);
+                Ok(Received::Timeout)
+            }
+            Err(source) => Err(MproxyError::Recv {
+                addr: self.addr.to_string(),
+                source,
+            }),
+        }
+    }
+}
+
+/// Chunks of a byte stream, such as a TCP or TLS connection. Set a read
+/// timeout on sockets so that [Received::Timeout] is returned while idle
+pub struct StreamSource<R: Read + Send> {
+    reader: R,
+    name: String,
+    source: SocketAddr,
+    local: SocketAddr,
+}
+
+impl<R: Read + Send> StreamSource<R> {
+    /// Read from `reader`, reporting errors with `name`, e.g. the remote
+    /// address. Chunks are reported as received from [UNSPECIFIED_ADDR]
+    pub fn new(reader: R, name: String) -> Self {
+        StreamSource {
+            reader,
+            name,
+            source: UNSPECIFIED_ADDR,
+            local: UNSPECIFIED_ADDR,
+        }
+    }
+
+    /// Report chunks as received from `source` by local address `local`
+    pub fn with_addrs(mut self, source: SocketAddr, local: SocketAddr) -> Self {
+        self.source = source;
+        self.local = local;
+        self
+    }
+}
+
+impl StreamSource<TcpStream> {
+    /// Read from a connected TCP socket, with a read timeout
+    pub fn tcp(stream: TcpStream) -> Result<Self, MproxyError> {
+        stream.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+        let (source, local) = (stream.peer_addr()?, stream.local_addr()?);
+        Ok(StreamSource::new(stream, source.to_string()).with_addrs(source, local))
+    }
+}
+
+impl<R: Read + Send> Source for StreamSource<R> {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Received, MproxyError> {
+        match self.reader.read(buf) {
+            Ok(0) => Ok(Received::Eof),
+            Ok(len) => Ok(Received::Data {
+                len,
+                meta: DatagramMeta {
+                    source: self.source,
+                    listen_addr: self.local,
+                    received: SystemTime::now(),
+                },
+            }),
+            Err(e) if is_timeout(&e) => Ok(Received::Timeout),
+            Err(source) => Err(MproxyError::Recv {
+                addr: self.name.clone(),
+                source,
+            }),
+        }
+    }
+}
+
+/// Chunks read from a file, or from stdin if the path is "-"
+pub struct FileSource {
+    path: PathBuf,
+    reader: Box<dyn Read + Send>,
+}
+
+impl FileSource {
+    /// Open `path` for reading. Use "-" for stdin
+    pub fn open(path: &Path) -> Result<Self, MproxyError> {
+        if path == Path::new("-") {
+            return Ok(FileSource::from_reader(path, stdin()));
+        }
+        let file = File::open(path).map_err(|source| MproxyError::File {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Ok(FileSource::from_reader(path, file))
+    }
+
+    /// Read from `reader`, reporting errors with `path`, e.g. for a file
+    /// opened with a wrapper such as a decompressor
+    pub fn from_reader(path: &Path, reader: impl Read + Send + 'static) -> Self {
+        FileSource {
+            path: path.to_path_buf(),
+            reader: Box::new(reader),
+        }
+    }
+}
+
+impl Source for FileSource {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Received, MproxyError> {
+        match self.reader.read(buf) {
+            Ok(0) => Ok(Received::Eof),
+            Ok(len) => Ok(Received::Data {
+                len,
+                meta: DatagramMeta {
+                    source: UNSPECIFIED_ADDR,
+                    listen_addr: UNSPECIFIED_ADDR,
+                    received: SystemTime::now(),
+                },
+            }),
+            Err(e) if is_timeout(&e) => Ok(Received::Timeout),
+            Err(source) => Err(MproxyError::File {
+                path: self.path.clone(),
+                source,
+            }),
+        }
+    }
+}
+
+/// Bytes received from any client connected to a TCP listener.
+///
+/// Connections are accepted and read on background threads, which exit
+/// when the source is dropped. Reads from each client are received
+/// separately, so chunks from different clients are not interleaved
+pub struct TcpListenSource {
+    addr: SocketAddr,
+    chunks: Option<Receiver<(Vec<u8>, DatagramMeta)>>,
+    shutdown: Arc<AtomicBool>,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+impl TcpListenSource {
+    /// Listen for TCP connections on `addr`
+    pub fn bind(addr: &str) -> Result<Self, MproxyError> {
+        Self::listen(addr, None)
+    }
+
+    /// Listen for TCP connections on `addr`, set up by `acceptor` before
+    /// reading
+    pub fn bind_with(addr: &str, acceptor: Arc<dyn Acceptor>) -> Result<Self, MproxyError> {
+        Self::listen(addr, Some(acceptor))
+    }
+
+    fn listen(addr: &str, acceptor: Option<Arc<dyn Acceptor>>) -> Result<Self, MproxyError> {
+        let listener = bind_tcp_listener(addr)?;
+        let local = listener.local_addr()?;
+        let (sender, chunks) = sync_channel(CHANNEL_BOUND);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let flag = shutdown.clone();
+        let accept_thread = Builder::new()
+            .name(format!("{}:accept", local))
+            .spawn(move || {
+                accept_clients(listener, flag, move |stream, shutdown| {
+                    read_client(stream, acceptor.as_deref(), local, &sender, &shutdown)
+                })
+            })?;
+        Ok(TcpListenSource {
+            addr: local,
+            chunks: Some(chunks),
+            shutdown,
+            accept_thread: Some(accept_thread),
+        })
+    }
+
+    /// Local listening address
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Source for TcpListenSource {
+    /// Chunks longer than `buf` are truncated
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Received, MproxyError> {
+        let chunks = self.chunks.as_ref().expect("receiver is dropped on drop");
+        match chunks.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+            Ok((chunk, meta)) => {
+                let len = chunk.len().min(buf.len());
+                buf[..len].copy_from_slice(&chunk[..len]);
+                Ok(Received::Data { len, meta })
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(Received::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Ok(Received::Eof),
+        }
+    }
+}
+
+impl Drop for TcpListenSource {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // unblock client threads waiting for room in the channel
+        self.chunks.take();
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Forward reads from a connected client to `sender` until it disconnects
+fn read_client(
+    stream: TcpStream,
+    acceptor: Option<&dyn Acceptor>,
+    local: SocketAddr,
+    sender: &SyncSender<(Vec<u8>, DatagramMeta)>,
+    shutdown: &AtomicBool,
+) {
+    let accepted = accept_connection(stream, acceptor).and_then(|(stream, socket)| {
+        socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+        Ok((stream, socket.peer_addr()?))
+    });
+    let (mut stream, peer) = match accepted {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            eprintln!("dropping client: {}", e);
+            return;
+        }
+    };
+    let mut buf = [0u8; BUFSIZE];
+    while !is_shutdown(shutdown) {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(c) => {
+                let meta = DatagramMeta {
+                    source: peer,
+                    listen_addr: local,
+                    received: SystemTime::now(),
+                };
+                if sender.send((buf[0..c].to_vec(), meta)).is_err() {
+                    break;
+                }
+            }
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => {
+                eprintln!("err: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Set up an accepted client with `acceptor`, if any. Returns the
+/// connection, and a handle to its socket for setting timeouts
+pub(crate) fn accept_connection(
+    stream: TcpStream,
+    acceptor: Option<&dyn Acceptor>,
+) -> Result<(Box<dyn Connection>, TcpStream), MproxyError> {
+    let socket = stream.try_clone()?;
+    let connection: Box<dyn Connection> = match acceptor {
+        Some(acceptor) => {
+            socket.set_read_timeout(Some(ACCEPT_TIMEOUT))?;
+            socket.set_write_timeout(Some(ACCEPT_TIMEOUT))?;
+            acceptor.accept(stream)?
+        }
+        None => Box::new(stream),
+    };
+    Ok((connection, socket))
+}
+
+/// Bind a non-blocking TCP listener on `addr`
+pub(crate) fn bind_tcp_listener(addr: &str) -> Result<TcpListener, MproxyError> {
+    let listener = TcpListener::bind(addr).map_err(|source| MproxyError::Bind {
+        addr: addr.to_string(),
+        source,
+    })?;
+    // poll for new connections so that the accept loop can be shut down
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Accept incoming connections on `listener` until `shutdown` is set,
+/// spawning `handle_client` on a new thread for each connection.
+/// Waits for client threads to exit before returning
+fn accept_clients<F>(listener: TcpListener, shutdown: Arc<AtomicBool>, handle_client: F)
+where
+    F: Fn(TcpStream, Arc<AtomicBool>) + Clone + Send + 'static,
+{
+    let mut clients: Vec<JoinHandle<()>> = vec![];
+    while !is_shutdown(&shutdown) {
+        match listener.accept() {
+            Ok((stream, peer_addr)) => {
+                #[cfg(debug_assertions)]
+                println!("new client {:?}", stream);
+                if let Err(e) = stream.set_nonblocking(false) {
+                    eprintln!("dropping client: {}", e);
+                    continue;
+                }
+                let handle_client = handle_client.clone();
+                let shutdown = shutdown.clone();
+                let spawned = Builder::new()
+                    .name(format!("{}:client", peer_addr))
+                    .spawn(move || handle_client(stream, shutdown));
+                match spawned {
+                    Ok(client) => clients.push(client),
+                    Err(e) => eprintln!("dropping client: {}", e),
+                }
+            }
+            Err(e) if is_timeout(&e) => sleep(SHUTDOWN_POLL_INTERVAL),
+            Err(e) => eprintln!("dropping client: {}", e),
+        }
+        clients.retain(|c| !c.is_finished());
+    }
+    for client in clients {
+        let _ = client.join();
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Instant, SystemTime};
+
+use crate::dedup::Deduplicator;
+use crate::downsample::Downsampler;
+use crate::filter::MessageFilter;
+use crate::nmea::NmeaFilter;
+use crate::reassemble::Reassembler;
+use crate::tagblock::TagOptions;
+use crate::MproxyError;
+
+/// Where and when a datagram passed to a [Stage] was received
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DatagramMeta {
+    /// Address of the upstream sender
+    pub source: SocketAddr,
+    /// Local address of the listener which received the datagram
+    pub listen_addr: SocketAddr,
+    /// Time the datagram was received
+    pub received: SystemTime,
+}
+
+/// One step of a forwarding [Pipeline], e.g. a filter or a format conversion.
+///
+/// Stages are cloned for each listener or downstream target they are
+/// configured for, so implement [Clone] such that clones keep separate
+/// state, or share it with [std::sync::Arc] where that is intended
+pub trait Stage: StageClone + Send {
+    /// Transform a datagram, returning zero or more datagrams to pass to the
+    /// next stage. Empty datagrams are dropped by the pipeline
+    fn process(&mut self, data: &[u8], meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError>;
+}
+
+/// Clones boxed stages. Implemented for every [Stage] which is [Clone]
+pub trait StageClone {
+    fn clone_box(&self) -> Box<dyn Stage>;
+}
+
+impl<T: Stage + Clone + 'static> StageClone for T {
+    fn clone_box(&self) -> Box<dyn Stage> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Stage> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// A chain of stages, each passed the output of the previous one
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("stages", &self.stages.len())
+            .finish()
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    /// Append `stage` to the end of the pipeline
+    pub fn stage(mut self, stage: impl Stage + 'static) -> Self {
+        self.push(Box::new(stage));
+        self
+    }
+
+    /// Append a boxed `stage` to the end of the pipeline
+    pub fn push(&mut self, stage: Box<dyn Stage>) {
+        self.stages.push(stage);
+    }
+
+    /// Append the stages of `other` to the end of the pipeline
+    pub fn extend(&mut self, other: &Pipeline) {
+        self.stages.extend(other.stages.iter().cloned());
+    }
+
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Pass a datagram through each stage in order, returning the
+    /// non-empty datagrams output by the last stage
+    pub fn run(&mut self, data: &[u8], meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+        if data.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut datagrams = vec![data.to_vec()];
+        for stage in self.stages.iter_mut() {
+            let mut output = vec![];
+            for datagram in &datagrams {
+                output.extend(stage.process(datagram, meta)?);
+            }
+            datagrams = output;
+            datagrams.retain(|datagram| !datagram.is_empty());
+            if datagrams.is_empty() {
+                break;
+            }
+        }
+        Ok(datagrams)
+    }
+}
+
+impl Stage for Pipeline {
+    fn process(&mut self, data: &[u8], meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+        self.run(data, meta)
+    }
+}
+
+/// Wrap the output of a built-in stage, dropping it if empty
+fn single(data: Vec<u8>) -> Vec<Vec<u8>> {
+    if data.is_empty() {
+        vec![]
+    } else {
+        vec![data]
+    }
+}
+
+impl Stage for NmeaFilter {
+    fn process(&mut self, data: &[u8], _meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+        Ok(single(self.filter(data).into_owned()))
+    }
+}
+
+impl Stage for TagOptions {
+    fn process(&mut self, data: &[u8], meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+        Ok(single(self.apply(data, meta.received).into_owned()))
+    }
+}
+
+impl Stage for Reassembler {
+    fn process(&mut self, data: &[u8], meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+        Ok(single(self.push(meta.source, data, Instant::now())))
+    }
+}
+
+impl Stage for Deduplicator {
+    fn process(&mut self, data: &[u8], _meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+        Ok(single(self.filter(data, Instant::now())))
+    }
+}
+
+impl Stage for MessageFilter {
+    fn process(&mut self, data: &[u8], _meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+        Ok(single(self.filter(data)))
+    }
+}
+
+impl Stage for Downsampler {
+    fn process(&mut self, data: &[u8], _meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+        Ok(single(self.filter(data, Instant::now())))
+    }
+}
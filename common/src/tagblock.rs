@@ -0,0 +1,173 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::time::SystemTime;
+
+use crate::nmea::{checksum, sentences, NmeaError};
+
+/// NMEA 4.10 tag block, e.g. `\s:station,c:1700000000*5A\` preceding a
+/// sentence
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TagBlock {
+    /// `s:` source station identifier
+    pub source: Option<String>,
+    /// `c:` UNIX time in seconds. Some sources use milliseconds
+    pub time: Option<u64>,
+    /// `d:` destination identifier
+    pub destination: Option<String>,
+    /// `n:` line count
+    pub line: Option<u64>,
+    /// `r:` relative time
+    pub relative_time: Option<u64>,
+    /// `g:` sentence grouping, e.g. `1-2-42`
+    pub group: Option<String>,
+    /// `t:` text string
+    pub text: Option<String>,
+    /// Fields with other codes, in order
+    pub other: Vec<(String, String)>,
+}
+
+impl TagBlock {
+    /// Parse the contents of a tag block, without the enclosing
+    /// backslashes. The `*hh` checksum is validated if present
+    pub fn parse(contents: &str) -> Result<Self, NmeaError> {
+        let fields = match contents.rsplit_once('*') {
+            Some((fields, expected)) => {
+                let expected = u8::from_str_radix(expected, 16)
+                    .map_err(|_| NmeaError::Malformed("invalid tag block checksum"))?;
+                let computed = checksum(fields.as_bytes());
+                if computed != expected {
+                    return Err(NmeaError::InvalidChecksum { expected, computed });
+                }
+                fields
+            }
+            None => contents,
+        };
+
+        let mut tags = TagBlock::default();
+        for field in fields.split(',').filter(|f| !f.is_empty()) {
+            let (code, value) = field
+                .split_once(':')
+                .ok_or(NmeaError::Malformed("invalid tag block field"))?;
+            let number = |value: &str| {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| NmeaError::Malformed("invalid tag block number"))
+            };
+            match code {
+                "s" => tags.source = Some(value.to_string()),
+                "c" => tags.time = Some(number(value)?),
+                "d" => tags.destination = Some(value.to_string()),
+                "n" => tags.line = Some(number(value)?),
+                "r" => tags.relative_time = Some(number(value)?),
+                "g" => tags.group = Some(value.to_string()),
+                "t" => tags.text = Some(value.to_string()),
+                _ => tags.other.push((code.to_string(), value.to_string())),
+            }
+        }
+        Ok(tags)
+    }
+
+    /// Split a line into its tag block and the remaining sentence.
+    /// Lines without a tag block are returned unchanged
+    pub fn split(line: &[u8]) -> Result<(Option<TagBlock>, &[u8]), NmeaError> {
+        let Some(rest) = line.strip_prefix(b"\\") else {
+            return Ok((None, line));
+        };
+        let end = rest
+            .iter()
+            .position(|b| *b == b'\\')
+            .ok_or(NmeaError::Malformed("unterminated tag block"))?;
+        let contents = std::str::from_utf8(&rest[..end])
+            .map_err(|_| NmeaError::Malformed("not valid UTF-8"))?;
+        Ok((Some(TagBlock::parse(contents)?), &rest[end + 1..]))
+    }
+}
+
+/// Formats the tag block with its checksum and enclosing backslashes
+impl fmt::Display for TagBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let numbers = |code, value: Option<u64>| value.map(|v| (code, v.to_string()));
+        let text = |code, value: &Option<String>| value.clone().map(|v| (code, v));
+        let fields = [
+            text("s", &self.source),
+            numbers("c", self.time),
+            text("d", &self.destination),
+            numbers("n", self.line),
+            numbers("r", self.relative_time),
+            text("g", &self.group),
+            text("t", &self.text),
+        ];
+        let fields: Vec<String> = fields
+            .into_iter()
+            .flatten()
+            .map(|(code, value)| format!("{}:{}", code, value))
+            .chain(
+                self.other
+                    .iter()
+                    .map(|(code, value)| format!("{}:{}", code, value)),
+            )
+            .collect();
+        let fields = fields.join(",");
+        write!(f, "\\{}*{:02X}\\", fields, checksum(fields.as_bytes()))
+    }
+}
+
+/// Tag block handling for sentences passed downstream
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TagOptions {
+    /// Add an `s:` source station identifier to sentences without one
+    pub station: Option<String>,
+    /// Add a `c:` receive time to sentences without one
+    pub timestamp: bool,
+    /// Remove tag blocks, e.g. for consumers which do not support them.
+    /// Takes precedence over `station` and `timestamp`
+    pub strip: bool,
+}
+
+impl TagOptions {
+    /// Returns true if lines are passed through unchanged
+    pub fn is_passthrough(&self) -> bool {
+        !self.strip && self.station.is_none() && !self.timestamp
+    }
+
+    /// Add or remove the tag blocks of each line of `data`, received at
+    /// `received`. Existing station identifiers and times are kept, so
+    /// provenance is preserved across several hops. Lines with a malformed
+    /// tag block are passed through unchanged
+    pub fn apply<'a>(&self, data: &'a [u8], received: SystemTime) -> Cow<'a, [u8]> {
+        if self.is_passthrough() {
+            return Cow::Borrowed(data);
+        }
+        let time = received
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut out = Vec::with_capacity(data.len() + 32);
+        for line in sentences(data) {
+            let (tags, sentence) = match TagBlock::split(line) {
+                Ok(split) => split,
+                Err(_) => (None, line),
+            };
+            if line.starts_with(b"\\") && tags.is_none() {
+                // malformed tag block
+                out.extend_from_slice(line);
+            } else if self.strip {
+                out.extend_from_slice(sentence);
+            } else {
+                let mut tags = tags.unwrap_or_default();
+                if tags.source.is_none() {
+                    tags.source = self.station.clone();
+                }
+                if tags.time.is_none() && self.timestamp {
+                    tags.time = Some(time);
+                }
+                if tags != TagBlock::default() {
+                    out.extend_from_slice(tags.to_string().as_bytes());
+                }
+                out.extend_from_slice(sentence);
+            }
+            out.push(b'\n');
+        }
+        Cow::Owned(out)
+    }
+}
@@ -0,0 +1,188 @@
+use mproxy_common::{
+    decode_payload, decode_sentence, AisError, AisMessage, PositionReport, Sentence,
+};
+
+fn decode(line: &str) -> AisMessage {
+    decode_sentence(&Sentence::parse(line.as_bytes()).unwrap()).unwrap()
+}
+
+#[test]
+fn test_ais_position_report() {
+    let message = decode("!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C");
+    assert_eq!((message.message_type(), message.mmsi()), (1, 366053209));
+    let AisMessage::Position(report) = message else {
+        panic!("expected position report, got {:?}", message);
+    };
+    assert_eq!(
+        report,
+        PositionReport {
+            message_type: 1,
+            repeat: 0,
+            mmsi: 366053209,
+            nav_status: 3,
+            rot: Some(0.0),
+            sog: Some(0.0),
+            position_accuracy: false,
+            lon: report.lon,
+            lat: report.lat,
+            cog: Some(219.3),
+            heading: Some(1),
+            second: 59,
+            maneuver: 0,
+            raim: false,
+        }
+    );
+    assert!((report.lon.unwrap() + 122.341618).abs() < 1e-6);
+    assert!((report.lat.unwrap() - 37.802118).abs() < 1e-6);
+}
+
+#[test]
+fn test_ais_static_voyage() {
+    let first = Sentence::parse(
+        b"!AIVDM,2,1,3,B,55P5TL01VIaAL@7WKO@mBplU@<PDhh000000001S;AJ::4A80?4i@E53,0*3E",
+    )
+    .unwrap();
+    let second = Sentence::parse(b"!AIVDM,2,2,3,B,1@0000000000000,2*55").unwrap();
+    assert_eq!(decode_sentence(&first), Err(AisError::Fragment));
+
+    let (first, second) = (first.ais().unwrap(), second.ais().unwrap());
+    let payload = first.payload + &second.payload;
+    let AisMessage::StaticVoyage(data) = decode_payload(&payload, second.fill_bits).unwrap() else {
+        panic!("expected static and voyage data");
+    };
+    assert_eq!(data.mmsi, 369190000);
+    assert_eq!(data.imo, Some(6710932));
+    assert_eq!(data.callsign, "WDA9674");
+    assert_eq!(data.name, "MT.MITCHELL");
+    assert_eq!(data.ship_type, 99);
+    assert_eq!(
+        (data.to_bow, data.to_stern, data.to_port, data.to_starboard),
+        (90, 90, 10, 10)
+    );
+    assert_eq!(
+        (data.eta_month, data.eta_day, data.eta_hour, data.eta_minute),
+        (1, 2, 8, 0)
+    );
+    assert_eq!(data.draught, 6.0);
+    assert_eq!(data.destination, "SEATTLE");
+    assert!(!data.dte);
+}
+
+#[test]
+fn test_ais_base_station() {
+    let AisMessage::BaseStation(report) = decode("!AIVDM,1,1,,A,403OviQvQHeeNruAe0GVWd702000,0*05")
+    else {
+        panic!("expected base station report");
+    };
+    assert_eq!(report.mmsi, 3669702);
+    assert_eq!((report.year, report.month, report.day), (2024, 5, 17));
+    assert_eq!((report.hour, report.minute, report.second), (13, 45, 30));
+    assert_eq!((report.lon, report.lat), (Some(-70.5), Some(41.25)));
+    assert_eq!(report.epfd, 7);
+}
+
+#[test]
+fn test_ais_class_b() {
+    let AisMessage::ClassBPosition(report) =
+        decode("!AIVDM,1,1,,A,B52MJh00Nmks:05J4L1howV44000,0*1D")
+    else {
+        panic!("expected class B position report");
+    };
+    assert_eq!(report.mmsi, 338123456);
+    assert_eq!((report.sog, report.cog), (Some(12.3), Some(180.5)));
+    assert_eq!((report.lon, report.lat), (Some(-122.4), Some(37.8)));
+    assert_eq!(report.heading, None);
+    assert!(report.cs_unit);
+
+    let AisMessage::ClassBExtended(report) =
+        decode("!AIVDM,1,1,,A,C52ulL@3wp<12hK;0W3Q0eN0V:304T::l:0000000000BP`2Q1RP,0*31")
+    else {
+        panic!("expected extended class B position report");
+    };
+    assert_eq!(report.mmsi, 338654321);
+    assert_eq!((report.sog, report.cog), (None, None));
+    assert_eq!((report.lon, report.lat), (Some(10.5), Some(-33.75)));
+    assert_eq!(report.heading, Some(90));
+    assert_eq!(report.name, "SEA BREEZE");
+    assert_eq!(report.ship_type, 37);
+    assert_eq!(
+        (
+            report.to_bow,
+            report.to_stern,
+            report.to_port,
+            report.to_starboard
+        ),
+        (10, 5, 2, 3)
+    );
+
+    let AisMessage::StaticData(part_a) = decode("!AIVDM,1,1,,A,H42O55i18tMET00000000000000,2*6D")
+    else {
+        panic!("expected static data report");
+    };
+    assert_eq!((part_a.mmsi, part_a.part), (271041815, 0));
+    assert_eq!(part_a.name.as_deref(), Some("PROGUY"));
+    assert_eq!(part_a.callsign, None);
+
+    let AisMessage::StaticData(part_b) = decode("!AIVDM,1,1,,A,H42O55lti4h0000D3nink01P8230,0*27")
+    else {
+        panic!("expected static data report");
+    };
+    assert_eq!(part_b.part, 1);
+    assert_eq!(part_b.name, None);
+    assert_eq!(part_b.ship_type, Some(60));
+    assert_eq!(part_b.vendor_id.as_deref(), Some("1D0"));
+    assert_eq!(part_b.callsign.as_deref(), Some("TC6163"));
+    assert_eq!(part_b.to_starboard, Some(3));
+}
+
+#[test]
+fn test_ais_aid_to_navigation() {
+    let AisMessage::AidToNavigation(report) =
+        decode("!AIVDM,1,1,,A,E>kb9O0aS@7PUV0W2@10dh194R3sWu`h:l8p000003vP11@,4*67")
+    else {
+        panic!("expected aid-to-navigation report");
+    };
+    assert_eq!(report.mmsi, 993692028);
+    assert_eq!(report.aid_type, 1);
+    // name extension is appended
+    assert_eq!(report.name, "SF OAKLAND BAY BRIDGE");
+    assert_eq!((report.lon, report.lat), (Some(-122.35), Some(37.8)));
+    assert!(report.virtual_aid);
+    assert!(!report.off_position);
+}
+
+#[test]
+fn test_ais_long_range() {
+    let AisMessage::LongRange(report) = decode("!AIVDM,1,1,,A,K35E2bAE190FdLbL,0*02") else {
+        panic!("expected long range report");
+    };
+    assert_eq!(report.mmsi, 206914217);
+    assert_eq!(report.nav_status, 5);
+    assert_eq!((report.lon, report.lat), (Some(137.02), Some(4.84)));
+    assert_eq!((report.sog, report.cog), (Some(57.0), Some(167.0)));
+    assert!(report.gnss);
+}
+
+#[test]
+fn test_ais_errors() {
+    assert_eq!(
+        decode_payload("15M67F", 0),
+        Err(AisError::TooShort {
+            message_type: 1,
+            bits: 36
+        })
+    );
+    assert_eq!(
+        decode_payload("15M67F!", 0),
+        Err(AisError::InvalidCharacter('!'))
+    );
+    let other = Sentence::parse(b"$GPGLL,4916.45,N,12311.12,W,225444,A*31").unwrap();
+    assert!(matches!(
+        decode_sentence(&other),
+        Err(AisError::Sentence(_))
+    ));
+
+    // message types without a decoder still have a header
+    let message = decode_payload("85M67FC0", 0).unwrap();
+    assert_eq!((message.message_type(), message.mmsi()), (8, 366053209));
+}
@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+
+use mproxy_common::{DedupOptions, Deduplicator};
+
+const SINGLE: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+const TAGGED: &str = "\\s:station2*01\\!AIVDM,1,1,,A,15M67FC000G?ufbE`FepT@3n00Sa,0*5F\n";
+const FIRST: &str =
+    "!AIVDM,2,1,3,B,55P5TL01VIaAL@7WKO@mBplU@<PDhh000000001S;AJ::4A80?4i@E53,0*3E\n";
+const SECOND: &str = "!AIVDM,2,2,3,B,1@0000000000000,2*55\n";
+
+#[test]
+fn test_dedup_window() {
+    let dedup = Deduplicator::new(&DedupOptions::default());
+    let now = Instant::now();
+
+    assert_eq!(dedup.filter(SINGLE.as_bytes(), now), SINGLE.as_bytes());
+    // tag block and channel are ignored
+    assert!(dedup.filter(TAGGED.as_bytes(), now).is_empty());
+    assert_eq!((dedup.hits(), dedup.misses()), (1, 1));
+
+    // lines which are not AIS sentences pass through
+    let other = "$GPGLL,4916.45,N,12311.12,W,225444,A*31\nnot a sentence\n";
+    assert_eq!(dedup.filter(other.as_bytes(), now), other.as_bytes());
+    assert_eq!(dedup.filter(other.as_bytes(), now), other.as_bytes());
+
+    // copies after the window are passed through
+    let later = now + Duration::from_secs(3);
+    assert_eq!(dedup.filter(SINGLE.as_bytes(), later), SINGLE.as_bytes());
+    assert_eq!((dedup.hits(), dedup.misses()), (1, 2));
+}
+
+#[test]
+fn test_dedup_fragments() {
+    let dedup = Deduplicator::new(&DedupOptions::default());
+    let now = Instant::now();
+    let message = format!("{}{}", FIRST, SECOND);
+
+    // consecutive fragments are compared together
+    assert_eq!(dedup.filter(message.as_bytes(), now), message.as_bytes());
+    assert!(dedup.filter(message.as_bytes(), now).is_empty());
+
+    // unpaired fragments pass through
+    assert_eq!(dedup.filter(SECOND.as_bytes(), now), SECOND.as_bytes());
+    assert_eq!(dedup.filter(FIRST.as_bytes(), now), FIRST.as_bytes());
+    assert_eq!((dedup.hits(), dedup.misses()), (1, 1));
+}
+
+#[test]
+fn test_dedup_bounded() {
+    let options = DedupOptions {
+        max_entries: 1,
+        ..Default::default()
+    };
+    let dedup = Deduplicator::new(&options);
+    let now = Instant::now();
+    let message = format!("{}{}", FIRST, SECOND);
+
+    dedup.filter(SINGLE.as_bytes(), now);
+    dedup.filter(message.as_bytes(), now);
+    assert_eq!(dedup.len(), 1);
+
+    // the oldest message was forgotten, so is not a duplicate
+    assert_eq!(dedup.filter(SINGLE.as_bytes(), now), SINGLE.as_bytes());
+
+    // clones share state
+    let clone = dedup.clone();
+    assert!(clone.filter(SINGLE.as_bytes(), now).is_empty());
+    assert_eq!((dedup.hits(), dedup.misses()), (1, 3));
+}
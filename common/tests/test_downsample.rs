@@ -0,0 +1,62 @@
+use std::time::{Duration, Instant};
+
+use mproxy_common::Downsampler;
+
+const POSITION: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+const CLASS_B: &str = "!AIVDM,1,1,,A,B52MJh00Nmks:05J4L1howV44000,0*1D\n";
+const FIRST: &str =
+    "!AIVDM,2,1,3,B,55P5TL01VIaAL@7WKO@mBplU@<PDhh000000001S;AJ::4A80?4i@E53,0*3E\n";
+const SECOND: &str = "!AIVDM,2,2,3,B,1@0000000000000,2*55\n";
+
+#[test]
+fn test_downsample_position_reports() {
+    let downsampler = Downsampler::new(Duration::from_secs(10));
+    let now = Instant::now();
+    let data = [POSITION, CLASS_B].concat();
+
+    // one report per vessel within the interval
+    let out = downsampler.filter([POSITION, POSITION, CLASS_B].concat().as_bytes(), now);
+    assert_eq!(out, data.as_bytes());
+    let later = now + Duration::from_secs(5);
+    assert!(downsampler.filter(data.as_bytes(), later).is_empty());
+    assert_eq!((downsampler.dropped(), downsampler.len()), (3, 2));
+
+    // reports are passed again after the interval
+    let later = now + Duration::from_secs(10);
+    assert_eq!(downsampler.filter(data.as_bytes(), later), data.as_bytes());
+    assert_eq!(downsampler.dropped(), 3);
+}
+
+#[test]
+fn test_downsample_passes_other_messages() {
+    let downsampler = Downsampler::new(Duration::from_secs(10));
+    let now = Instant::now();
+    let data = [FIRST, SECOND, "not a sentence\n", SECOND].concat();
+
+    for _ in 0..3 {
+        assert_eq!(downsampler.filter(data.as_bytes(), now), data.as_bytes());
+    }
+    assert_eq!(downsampler.dropped(), 0);
+    assert!(downsampler.is_empty());
+
+    // vessels are forgotten after the interval
+    downsampler.filter(POSITION.as_bytes(), now);
+    assert_eq!(downsampler.len(), 1);
+    downsampler.filter(b"", now + Duration::from_secs(20));
+    assert!(downsampler.is_empty());
+}
+
+#[test]
+fn test_downsample_clones_share_state() {
+    let downsampler = Downsampler::new(Duration::from_secs(10));
+    let other_listener = downsampler.clone();
+    let now = Instant::now();
+
+    // a report passed by one clone is counted against the others
+    assert_eq!(
+        downsampler.filter(POSITION.as_bytes(), now),
+        POSITION.as_bytes()
+    );
+    assert!(other_listener.filter(POSITION.as_bytes(), now).is_empty());
+    assert_eq!((downsampler.dropped(), downsampler.len()), (1, 1));
+}
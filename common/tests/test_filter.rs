@@ -0,0 +1,176 @@
+use std::fs::{remove_file, write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use mproxy_common::{AisMessage, Geofence, MessageFilter, PositionReport};
+
+use testconfig::TESTINGDIR;
+
+const POSITION: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+const CLASS_B: &str = "!AIVDM,1,1,,A,B52MJh00Nmks:05J4L1howV44000,0*1D\n";
+const EXTENDED: &str = "!AIVDM,1,1,,A,C52ulL@3wp<12hK;0W3Q0eN0V:304T::l:0000000000BP`2Q1RP,0*31\n";
+const FIRST: &str =
+    "!AIVDM,2,1,3,B,55P5TL01VIaAL@7WKO@mBplU@<PDhh000000001S;AJ::4A80?4i@E53,0*3E\n";
+const SECOND: &str = "!AIVDM,2,2,3,B,1@0000000000000,2*55\n";
+
+fn filtered(filter: &mut MessageFilter, lines: &[&str]) -> String {
+    String::from_utf8(filter.filter(lines.concat().as_bytes())).unwrap()
+}
+
+#[test]
+fn test_filter_message_types() {
+    let mut filter = MessageFilter::parse("types:1-3,18").unwrap();
+    let out = filtered(&mut filter, &[POSITION, CLASS_B, EXTENDED, FIRST, SECOND]);
+    assert_eq!(out, [POSITION, CLASS_B].concat());
+
+    // fragments are passed together, and other lines are dropped
+    let mut filter = MessageFilter::parse("deny-types:1-3;deny-types:18").unwrap();
+    let out = filtered(&mut filter, &[POSITION, "not a sentence\n", FIRST, SECOND]);
+    assert_eq!(out, [FIRST, SECOND].concat());
+
+    // incomplete messages are dropped
+    assert!(filtered(&mut filter, &[FIRST, CLASS_B]).is_empty());
+}
+
+#[test]
+fn test_filter_mmsi_lists() {
+    let path = PathBuf::from(TESTINGDIR).join("mmsi.txt");
+    write(
+        &path,
+        "# partner vessels\n366053209, 338123456\n369190000 # tug\n",
+    )
+    .unwrap();
+    let rules = format!("mmsi-allow:{}", path.display());
+    let mut filter = MessageFilter::parse(&rules).unwrap();
+    assert_eq!(filter.allow_mmsi.as_ref().unwrap().len(), 3);
+    let out = filtered(&mut filter, &[POSITION, EXTENDED, FIRST, SECOND]);
+    assert_eq!(out, [POSITION, FIRST, SECOND].concat());
+
+    let rules = format!("mmsi-deny:{}", path.display());
+    let mut filter = MessageFilter::parse(&rules).unwrap();
+    assert_eq!(filtered(&mut filter, &[POSITION, EXTENDED]), EXTENDED);
+
+    write(&path, "366053209 notanmmsi\n").unwrap();
+    assert!(MessageFilter::parse(&rules).is_err());
+    remove_file(&path).unwrap();
+    assert!(MessageFilter::parse(&rules).is_err());
+}
+
+#[test]
+fn test_filter_geofence() {
+    // San Francisco Bay
+    let mut filter = MessageFilter::parse("bbox:-123,37,-122,38").unwrap();
+    let out = filtered(&mut filter, &[POSITION, CLASS_B, EXTENDED]);
+    assert_eq!(out, [POSITION, CLASS_B].concat());
+
+    // static data passes once the vessel has reported a position inside
+    assert!(filtered(&mut filter, &[FIRST, SECOND]).is_empty());
+    let report = PositionReport {
+        message_type: 1,
+        repeat: 0,
+        mmsi: 369190000,
+        nav_status: 0,
+        rot: None,
+        sog: None,
+        position_accuracy: false,
+        lon: Some(-122.5),
+        lat: Some(37.5),
+        cog: None,
+        heading: None,
+        second: 0,
+        maneuver: 0,
+        raim: false,
+    };
+    assert!(filter.matches(&AisMessage::Position(report.clone())));
+    assert_eq!(
+        filtered(&mut filter, &[FIRST, SECOND]),
+        [FIRST, SECOND].concat()
+    );
+
+    // and is dropped again after it leaves
+    let outside = PositionReport {
+        lon: Some(0.0),
+        ..report
+    };
+    assert!(!filter.matches(&AisMessage::Position(outside)));
+    assert!(filtered(&mut filter, &[FIRST, SECOND]).is_empty());
+}
+
+#[test]
+fn test_geofence_contains() {
+    let triangle = Geofence::Polygon(vec![(0.0, 0.0), (10.0, 0.0), (0.0, 10.0)]);
+    assert!(triangle.contains(2.0, 2.0));
+    assert!(!triangle.contains(6.0, 6.0));
+    assert!(!triangle.contains(-1.0, 1.0));
+
+    // boxes may cross the antimeridian
+    let pacific = Geofence::BoundingBox {
+        min_lon: 170.0,
+        min_lat: -10.0,
+        max_lon: -170.0,
+        max_lat: 10.0,
+    };
+    assert!(pacific.contains(179.5, 0.0));
+    assert!(pacific.contains(-175.0, 5.0));
+    assert!(!pacific.contains(0.0, 0.0));
+    assert!(!pacific.contains(179.5, 20.0));
+
+    let filter = MessageFilter::parse("polygon:0 0,10 0,0 10").unwrap();
+    assert_eq!(filter.geofence, Some(triangle));
+    for rules in ["polygon:0 0,10 0", "bbox:1,2,3", "types:1-x", "speed:10"] {
+        assert!(MessageFilter::parse(rules).is_err(), "{}", rules);
+    }
+}
+
+#[test]
+fn test_filter_geofence_shared_and_bounded() {
+    let report = PositionReport {
+        message_type: 1,
+        repeat: 0,
+        mmsi: 369190000,
+        nav_status: 0,
+        rot: None,
+        sog: None,
+        position_accuracy: false,
+        lon: Some(-122.5),
+        lat: Some(37.5),
+        cog: None,
+        heading: None,
+        second: 0,
+        maneuver: 0,
+        raim: false,
+    };
+    let static_data = |filter: &mut MessageFilter| filtered(filter, &[FIRST, SECOND]);
+
+    // a position heard by one listener's clone passes static data on another
+    let mut first = MessageFilter::parse("bbox:-123,37,-122,38").unwrap();
+    let mut second = first.clone();
+    assert!(first.matches(&AisMessage::Position(report.clone())));
+    assert_eq!(static_data(&mut second), [FIRST, SECOND].concat());
+    assert_eq!(second.vessels_inside(), 1);
+
+    // positions are forgotten once older than the maximum age
+    let start = Instant::now();
+    let mut filter = MessageFilter::parse("bbox:-123,37,-122,38").unwrap();
+    filter.max_position_age = Duration::from_secs(60);
+    assert!(filter.matches_at(&AisMessage::Position(report.clone()), start));
+    assert_eq!(filter.vessels_inside(), 1);
+    let other = PositionReport {
+        mmsi: 338123456,
+        ..report.clone()
+    };
+    assert!(filter.matches_at(
+        &AisMessage::Position(other.clone()),
+        start + Duration::from_secs(61)
+    ));
+    assert_eq!(filter.vessels_inside(), 1);
+    assert!(static_data(&mut filter).is_empty());
+
+    // and the oldest position is forgotten to make room for a new one
+    let mut filter = MessageFilter::parse("bbox:-123,37,-122,38").unwrap();
+    filter.max_vessels = 1;
+    assert!(filter.matches_at(&AisMessage::Position(report), start));
+    assert!(filter.matches_at(&AisMessage::Position(other), start + Duration::from_secs(1)));
+    assert_eq!(filter.vessels_inside(), 1);
+    assert!(static_data(&mut filter).is_empty());
+}
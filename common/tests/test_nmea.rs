@@ -0,0 +1,124 @@
+use std::fs::{read_to_string, remove_file};
+use std::path::PathBuf;
+
+use mproxy_common::{sentences, NmeaError, NmeaFilter, Sentence, Validation};
+
+use testconfig::TESTINGDIR;
+
+const SINGLE: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C";
+const FIRST: &str = "!AIVDM,2,1,3,B,55P5TL01VIaAL@7WKO@mBplU@<PDhh000000001S;AJ::4A80?4i@E53,0*3E";
+const SECOND: &str = "!AIVDM,2,2,3,B,1@0000000000000,2*55";
+
+#[test]
+fn test_nmea_parse_ais() {
+    let sentence = Sentence::parse(format!("{}\r\n", SINGLE).as_bytes()).unwrap();
+    assert_eq!(sentence.start, '!');
+    assert_eq!(sentence.talker, "AI");
+    assert_eq!(sentence.sentence_type, "VDM");
+    assert_eq!(sentence.checksum, 0x5C);
+    assert_eq!(sentence.tag_block, None);
+    let ais = sentence.ais().unwrap();
+    assert_eq!((ais.fragment_count, ais.fragment_number), (1, 1));
+    assert_eq!(ais.sequential_id, None);
+    assert_eq!(ais.channel, Some('B'));
+    assert_eq!(ais.payload, "15M67FC000G?ufbE`FepT@3n00Sa");
+    assert_eq!(ais.fill_bits, 0);
+
+    let first = Sentence::parse(FIRST.as_bytes()).unwrap().ais().unwrap();
+    let second = Sentence::parse(SECOND.as_bytes()).unwrap().ais().unwrap();
+    assert_eq!((first.fragment_count, first.fragment_number), (2, 1));
+    assert_eq!((second.fragment_count, second.fragment_number), (2, 2));
+    assert_eq!(first.sequential_id, Some(3));
+    assert_eq!(second.sequential_id, Some(3));
+    assert_eq!(second.fill_bits, 2);
+}
+
+#[test]
+fn test_nmea_parse_other() {
+    let tagged = format!("\\s:station1,c:1700000000*71\\{}", SINGLE);
+    let sentence = Sentence::parse(tagged.as_bytes()).unwrap();
+    assert_eq!(
+        sentence.tag_block.as_deref(),
+        Some("s:station1,c:1700000000*71")
+    );
+    assert!(sentence.is_ais());
+
+    let gga = Sentence::parse(b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47")
+        .unwrap();
+    assert_eq!(
+        (gga.talker.as_str(), gga.sentence_type.as_str()),
+        ("GP", "GGA")
+    );
+    assert_eq!(gga.fields.len(), 14);
+    assert!(!gga.is_ais());
+    assert!(gga.ais().is_err());
+
+    let proprietary = Sentence::parse(b"$PGRME,15.0,M,45.0,M,25.0,M*1C").unwrap();
+    assert_eq!(proprietary.talker, "P");
+    assert_eq!(proprietary.sentence_type, "GRME");
+}
+
+#[test]
+fn test_nmea_parse_errors() {
+    let corrupt = SINGLE.replace("FepT", "FepU");
+    assert!(matches!(
+        Sentence::parse(corrupt.as_bytes()),
+        Err(NmeaError::InvalidChecksum { expected: 0x5C, .. })
+    ));
+    assert_eq!(
+        Sentence::parse(&SINGLE.as_bytes()[..SINGLE.len() - 3]),
+        Err(NmeaError::MissingChecksum)
+    );
+    assert_eq!(
+        Sentence::parse(&SINGLE.as_bytes()[1..]),
+        Err(NmeaError::MissingStart)
+    );
+    assert_eq!(
+        Sentence::parse(b"\\s:station1*00!AIVDM"),
+        Err(NmeaError::Malformed("unterminated tag block"))
+    );
+}
+
+#[test]
+fn test_nmea_filter() {
+    let input = format!(
+        "{}\r\n{}\ngarbage\n\n{}\n!AIVDM,1,1,,A,truncat\n",
+        FIRST, SECOND, SINGLE
+    );
+    assert_eq!(sentences(input.as_bytes()).count(), 5);
+
+    let mut off = NmeaFilter::new(&Validation::Off).unwrap();
+    assert_eq!(off.filter(input.as_bytes()), input.as_bytes());
+
+    let expected = format!("{}\r\n{}\n{}\n", FIRST, SECOND, SINGLE);
+    let mut drop = NmeaFilter::new(&Validation::Drop).unwrap();
+    assert_eq!(drop.filter(input.as_bytes()), expected.as_bytes());
+    assert_eq!(drop.corrupt(), 2);
+
+    let path = PathBuf::from(TESTINGDIR).join("quarantine.log");
+    let _ = remove_file(&path);
+    let mut quarantine = NmeaFilter::new(&Validation::Quarantine(path.clone())).unwrap();
+    assert_eq!(quarantine.filter(input.as_bytes()), expected.as_bytes());
+    assert_eq!(
+        read_to_string(&path).unwrap(),
+        "garbage\n!AIVDM,1,1,,A,truncat\n"
+    );
+    remove_file(&path).unwrap();
+}
+
+/// Valid lines pass even if the quarantine file cannot be written
+#[cfg(target_os = "linux")]
+#[test]
+fn test_nmea_filter_quarantine_failure() {
+    let input = format!("{}\ngarbage\n", SINGLE);
+    let mut quarantine =
+        NmeaFilter::new(&Validation::Quarantine(PathBuf::from("/dev/full"))).unwrap();
+    for _ in 0..2 {
+        assert_eq!(
+            quarantine.filter(input.as_bytes()),
+            format!("{}\n", SINGLE).as_bytes()
+        );
+    }
+    assert_eq!(quarantine.corrupt(), 2);
+    assert_eq!(quarantine.quarantine_failures(), 2);
+}
@@ -0,0 +1,140 @@
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use mproxy_common::{PcapReader, PcapngWriter};
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn test_pcapng_roundtrip() {
+    let t0 = SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_250_000);
+    let t1 = t0 + Duration::from_millis(1500);
+    let datagrams = [
+        (
+            t0,
+            addr("127.0.0.1:50000"),
+            addr("0.0.0.0:9920"),
+            &b"!AIVDM,1\n"[..],
+        ),
+        (
+            t1,
+            addr("[::1]:50001"),
+            addr("[::]:9921"),
+            &b"\xff\x00odd"[..],
+        ),
+        // mixed address families are mapped to IPv6
+        (t1, addr("10.0.0.1:50002"), addr("[ff02::1]:9922"), &b""[..]),
+    ];
+
+    let mut capture = vec![];
+    let mut writer = PcapngWriter::new(&mut capture).unwrap();
+    for (t, src, dst, payload) in datagrams {
+        writer.write_udp(t, src, dst, payload).unwrap();
+    }
+    writer.flush().unwrap();
+
+    let packets: Vec<_> = PcapReader::new(&capture[..])
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(packets.len(), 3);
+    assert_eq!(packets[0].timestamp, 1_700_000_000.25);
+    assert_eq!(packets[0].source, addr("127.0.0.1:50000"));
+    assert_eq!(packets[0].destination, addr("0.0.0.0:9920"));
+    assert_eq!(packets[0].payload, b"!AIVDM,1\n");
+    assert_eq!(packets[1].timestamp, 1_700_000_001.75);
+    assert_eq!(packets[1].source, addr("[::1]:50001"));
+    assert_eq!(packets[1].payload, b"\xff\x00odd");
+    assert_eq!(packets[2].source, addr("[::ffff:10.0.0.1]:50002"));
+    assert_eq!(packets[2].destination, addr("[ff02::1]:9922"));
+    assert!(packets[2].payload.is_empty());
+}
+
+/// Classic big-endian pcap with nanosecond timestamps and an Ethernet link
+/// layer, as written by tcpdump
+#[test]
+fn test_pcap_ethernet() {
+    let mut capture = vec![];
+    capture.extend_from_slice(&0xA1B2_3C4Du32.to_be_bytes());
+    capture.extend_from_slice(&[0, 2, 0, 4]);
+    capture.extend_from_slice(&[0; 8]);
+    capture.extend_from_slice(&65535u32.to_be_bytes());
+    capture.extend_from_slice(&1u32.to_be_bytes());
+
+    let mut frame = vec![0u8; 12];
+    frame.extend_from_slice(&[0x08, 0x00]);
+    let payload = b"hello";
+    let ip_len = 20 + 8 + payload.len() as u16;
+    frame.extend_from_slice(&[0x45, 0]);
+    frame.extend_from_slice(&ip_len.to_be_bytes());
+    frame.extend_from_slice(&[0, 0, 0, 0, 64, 17, 0, 0, 192, 168, 0, 1, 192, 168, 0, 2]);
+    frame.extend_from_slice(&1234u16.to_be_bytes());
+    frame.extend_from_slice(&9920u16.to_be_bytes());
+    frame.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(payload);
+    // ethernet padding
+    frame.extend_from_slice(&[0; 4]);
+
+    for (seconds, nanos) in [(1_700_000_000u32, 500_000_000u32), (1_700_000_002, 0)] {
+        capture.extend_from_slice(&seconds.to_be_bytes());
+        capture.extend_from_slice(&nanos.to_be_bytes());
+        capture.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        capture.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        capture.extend_from_slice(&frame);
+    }
+
+    let packets: Vec<_> = PcapReader::new(&capture[..])
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(packets.len(), 2);
+    assert_eq!(packets[0].timestamp, 1_700_000_000.5);
+    assert_eq!(packets[1].timestamp, 1_700_000_002.0);
+    assert_eq!(packets[0].source, addr("192.168.0.1:1234"));
+    assert_eq!(packets[0].destination, addr("192.168.0.2:9920"));
+    assert_eq!(packets[0].payload, payload);
+}
+
+#[test]
+fn test_pcap_invalid() {
+    assert!(PcapReader::new(&b"not a capture"[..]).is_err());
+}
+
+/// Corrupt length fields are rejected before anything is allocated
+#[test]
+fn test_pcap_oversized_length() {
+    let mut capture = vec![];
+    capture.extend_from_slice(&0xA1B2_C3D4u32.to_le_bytes());
+    capture.extend_from_slice(&[2, 0, 4, 0]);
+    capture.extend_from_slice(&[0; 8]);
+    capture.extend_from_slice(&65535u32.to_le_bytes());
+    capture.extend_from_slice(&101u32.to_le_bytes());
+    capture.extend_from_slice(&[0; 8]);
+    capture.extend_from_slice(&u32::MAX.to_le_bytes());
+    capture.extend_from_slice(&u32::MAX.to_le_bytes());
+    let e = PcapReader::new(&capture[..])
+        .unwrap()
+        .next_packet()
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+    // a pcapng section header followed by a block claiming 4 GiB
+    let mut capture = vec![];
+    capture.extend_from_slice(&0x0A0D_0D0Au32.to_le_bytes());
+    capture.extend_from_slice(&28u32.to_le_bytes());
+    capture.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+    capture.extend_from_slice(&[1, 0, 0, 0]);
+    capture.extend_from_slice(&u64::MAX.to_le_bytes());
+    capture.extend_from_slice(&28u32.to_le_bytes());
+    capture.extend_from_slice(&6u32.to_le_bytes());
+    capture.extend_from_slice(&0xFFFF_FFFCu32.to_le_bytes());
+    let e = PcapReader::new(&capture[..])
+        .unwrap()
+        .next_packet()
+        .unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+}
@@ -0,0 +1,35 @@
+#![cfg(feature = "tls")]
+
+use std::path::PathBuf;
+
+use mproxy_common::{load_certs, load_key, MproxyError};
+
+use testconfig::TESTINGDIR;
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from([TESTINGDIR, "tls/", name].join(""))
+}
+
+#[test]
+fn test_pem_key_errors() {
+    assert!(load_key(&fixture("client.key")).is_ok());
+    assert_eq!(load_certs(&fixture("ca.pem")).unwrap().len(), 1);
+
+    let e = load_key(&fixture("encrypted.key")).unwrap_err();
+    assert!(matches!(e, MproxyError::Config(_)), "{:?}", e);
+    assert!(e.to_string().contains("encrypted private keys"), "{}", e);
+
+    // P-521 is parsed, but rustls cannot sign with it
+    let e = load_key(&fixture("unsupported.key")).unwrap_err();
+    assert!(matches!(e, MproxyError::Config(_)), "{:?}", e);
+    assert!(
+        e.to_string().contains("unsupported private key type"),
+        "{}",
+        e
+    );
+
+    let e = load_key(&fixture("ca.pem")).unwrap_err();
+    assert!(e.to_string().contains("no PEM private key found"), "{}", e);
+    let e = load_certs(&fixture("client.key")).unwrap_err();
+    assert!(e.to_string().contains("no PEM certificates found"), "{}", e);
+}
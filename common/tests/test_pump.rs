@@ -0,0 +1,142 @@
+use std::io::{self, BufRead, BufReader, Cursor, ErrorKind, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+use mproxy_common::{
+    is_transient, pump, DatagramMeta, FileSource, MessageFilter, MproxyError, Pipeline,
+    PipelineSink, Received, ShutdownHandle, Sink, Source, TcpFanoutSink, TcpListenSource, UdpSink,
+    UdpSource, WriterSink,
+};
+
+const POSITION: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+const CLASS_B: &str = "!AIVDM,1,1,,A,B52MJh00Nmks:05J4L1howV44000,0*1D\n";
+
+/// Collects the output of a sink shared with a pump thread
+#[derive(Clone, Default)]
+struct Collected(Arc<Mutex<Vec<u8>>>);
+
+impl Sink for Collected {
+    fn send(&mut self, data: &[u8], _meta: &DatagramMeta) -> Result<(), MproxyError> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pump_file_to_writer() {
+    let input = format!("{}{}", POSITION, CLASS_B);
+    let mut source = FileSource::from_reader(Path::new("input"), Cursor::new(input.clone()));
+    let output = Collected::default();
+    let mut sinks: Vec<Box<dyn Sink>> = vec![
+        Box::new(output.clone()),
+        Box::new(WriterSink::new(Vec::new())),
+    ];
+
+    // returns at end of input
+    pump(&mut source, &mut sinks, &AtomicBool::new(false)).unwrap();
+    assert_eq!(*output.0.lock().unwrap(), input.as_bytes());
+    assert_eq!(
+        source.recv(&mut [0u8; 16]).unwrap(),
+        Received::Eof,
+        "source is consumed"
+    );
+}
+
+#[test]
+fn test_pump_pipeline_sink() {
+    let input = format!("{}{}", POSITION, CLASS_B);
+    let mut source = FileSource::from_reader(Path::new("input"), Cursor::new(input.clone()));
+    let raw = Collected::default();
+    let filtered = Collected::default();
+    let pipeline = Pipeline::new().stage(MessageFilter::parse("types:18").unwrap());
+    let mut sinks: Vec<Box<dyn Sink>> = vec![
+        Box::new(raw.clone()),
+        Box::new(PipelineSink::new(
+            pipeline,
+            vec![Box::new(filtered.clone())],
+        )),
+    ];
+
+    pump(&mut source, &mut sinks, &AtomicBool::new(false)).unwrap();
+
+    // stages apply to the outputs of the pipeline sink only
+    assert_eq!(*raw.0.lock().unwrap(), input.as_bytes());
+    assert_eq!(*filtered.0.lock().unwrap(), CLASS_B.as_bytes());
+}
+
+#[test]
+fn test_pump_udp_to_udp() {
+    let listen_addr = "127.0.0.1:9940";
+    let target_addr = "127.0.0.1:9941";
+
+    let target = UdpSocket::bind(target_addr).unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let mut source = UdpSource::bind(listen_addr.to_string()).unwrap();
+    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(UdpSink::connect(target_addr).unwrap())];
+    let handle = ShutdownHandle::spawn("test_pump_udp_to_udp".to_string(), move |shutdown| {
+        pump(&mut source, &mut sinks, &shutdown)
+    })
+    .unwrap();
+
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender.send_to(POSITION.as_bytes(), listen_addr).unwrap();
+
+    let mut buf = [0u8; 1024];
+    let (c, _) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..c], POSITION.as_bytes());
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn test_pump_tcp_listen_to_tcp_fanout() {
+    let listen_addr = "127.0.0.1:9942";
+    let fanout_addr = "127.0.0.1:9943";
+
+    let mut source = TcpListenSource::bind(listen_addr).unwrap();
+    let fanout = TcpFanoutSink::bind(fanout_addr).unwrap().with_pipelines(
+        [(
+            "127.0.0.1".parse().unwrap(),
+            Pipeline::new().stage(MessageFilter::parse("types:1").unwrap()),
+        )]
+        .into(),
+    );
+    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(fanout)];
+    let handle = ShutdownHandle::spawn("test_pump_tcp".to_string(), move |shutdown| {
+        pump(&mut source, &mut sinks, &shutdown)
+    })
+    .unwrap();
+
+    let client = TcpStream::connect(fanout_addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    // clients are accepted when the sink is flushed after a read timeout
+    sleep(Duration::from_millis(250));
+
+    let mut upstream = TcpStream::connect(listen_addr).unwrap();
+    upstream.write_all(CLASS_B.as_bytes()).unwrap();
+    sleep(Duration::from_millis(50));
+    upstream.write_all(POSITION.as_bytes()).unwrap();
+
+    // the client connecting from a filtered address receives matching messages only
+    let mut line = String::new();
+    BufReader::new(client).read_line(&mut line).unwrap();
+    assert_eq!(line, POSITION);
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn test_transient_receive_errors() {
+    // ICMP errors reported on UDP sockets do not stop listeners
+    for kind in [ErrorKind::ConnectionRefused, ErrorKind::ConnectionReset] {
+        assert!(is_transient(&io::Error::from(kind)));
+    }
+    assert!(!is_transient(&io::Error::from(ErrorKind::PermissionDenied)));
+    assert!(!is_transient(&io::Error::from(ErrorKind::InvalidInput)));
+}
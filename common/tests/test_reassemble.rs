@@ -0,0 +1,77 @@
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use mproxy_common::{Reassembler, ReassemblyOptions};
+
+const SINGLE: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+const FIRST: &str =
+    "!AIVDM,2,1,3,B,55P5TL01VIaAL@7WKO@mBplU@<PDhh000000001S;AJ::4A80?4i@E53,0*3E\n";
+const SECOND: &str = "!AIVDM,2,2,3,B,1@0000000000000,2*55\n";
+
+fn source(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[test]
+fn test_reassemble_across_datagrams() {
+    let mut reassembler = Reassembler::new(&ReassemblyOptions::default());
+    let now = Instant::now();
+
+    // first fragment is held, other traffic passes through
+    let out = reassembler.push(source(1), format!("{}{}", FIRST, SINGLE).as_bytes(), now);
+    assert_eq!(out, SINGLE.as_bytes());
+    assert_eq!(reassembler.pending(), 1);
+
+    // fragments from another source are not combined
+    assert!(reassembler
+        .push(source(2), SECOND.as_bytes(), now)
+        .is_empty());
+    assert_eq!(reassembler.pending(), 2);
+
+    // the complete message is emitted in order
+    let out = reassembler.push(source(1), SECOND.as_bytes(), now);
+    assert_eq!(out, format!("{}{}", FIRST, SECOND).as_bytes());
+    assert_eq!(reassembler.pending(), 1);
+    assert_eq!(reassembler.orphaned(), 0);
+
+    // fragments received out of order within one datagram
+    let out = reassembler.push(source(3), format!("{}{}", SECOND, FIRST).as_bytes(), now);
+    assert_eq!(out, format!("{}{}", FIRST, SECOND).as_bytes());
+}
+
+#[test]
+fn test_reassemble_orphans() {
+    let options = ReassemblyOptions {
+        timeout: Duration::from_secs(1),
+        max_pending: 2,
+        ..Default::default()
+    };
+    let mut reassembler = Reassembler::new(&options);
+    let now = Instant::now();
+
+    // a repeated fragment replaces the incomplete message
+    reassembler.push(source(1), FIRST.as_bytes(), now);
+    reassembler.push(source(1), FIRST.as_bytes(), now);
+    assert_eq!(reassembler.orphaned(), 1);
+
+    // the oldest incomplete message is evicted when full
+    reassembler.push(source(2), FIRST.as_bytes(), now + Duration::from_millis(10));
+    reassembler.push(source(3), FIRST.as_bytes(), now + Duration::from_millis(20));
+    assert_eq!(reassembler.pending(), 2);
+    assert_eq!(reassembler.orphaned(), 2);
+    let out = reassembler.push(
+        source(1),
+        SECOND.as_bytes(),
+        now + Duration::from_millis(30),
+    );
+    assert!(out.is_empty());
+
+    // incomplete messages expire after the timeout
+    reassembler.expire(now + Duration::from_secs(2));
+    assert_eq!(reassembler.pending(), 0);
+    assert_eq!(reassembler.orphaned(), 5);
+
+    // clones of the options share the counter
+    assert_eq!(options.orphaned.load(Ordering::Relaxed), 5);
+}
@@ -0,0 +1,131 @@
+use std::fs::{read_dir, read_to_string, remove_dir_all};
+use std::io::Write;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::Duration;
+
+use mproxy_common::{
+    format_pattern, Compression, MproxyError, RotatingFile, RotatingFileOptions, Rotation,
+};
+use time::OffsetDateTime;
+
+use testconfig::TESTINGDIR;
+
+fn testing_dir(name: &str) -> PathBuf {
+    let dir = PathBuf::from(TESTINGDIR).join(name);
+    let _ = remove_dir_all(&dir);
+    dir
+}
+
+fn filenames(dir: &PathBuf) -> Vec<String> {
+    let mut names: Vec<String> = read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_format_pattern() {
+    let t = OffsetDateTime::from_unix_timestamp(1700000000).unwrap();
+    assert_eq!(format_pattern("%Y-%m-%d.log", t), "2023-11-14.log");
+    assert_eq!(
+        format_pattern("ais_%Y%m%dT%H%M%S.nm4", t),
+        "ais_20231114T221320.nm4"
+    );
+    assert_eq!(format_pattern("%j_%s_100%%", t), "318_1700000000_100%");
+}
+
+#[test]
+fn test_rotate_size() {
+    let dir = testing_dir("rotate_size");
+    let mut file = RotatingFile::new(RotatingFileOptions {
+        dir: dir.clone(),
+        pattern: "backup.log".to_string(),
+        rotation: Rotation::Never,
+        max_size: Some(10),
+        ..Default::default()
+    })
+    .unwrap();
+    for line in ["0123456789\n", "abcdefghij\n", "tail\n"] {
+        file.write_all(line.as_bytes()).unwrap();
+    }
+    file.flush().unwrap();
+    drop(file);
+
+    assert_eq!(
+        filenames(&dir),
+        vec!["backup.log", "backup.log.1", "backup.log.2"]
+    );
+    assert_eq!(
+        read_to_string(dir.join("backup.log.1")).unwrap(),
+        "0123456789\n"
+    );
+    assert_eq!(read_to_string(dir.join("backup.log")).unwrap(), "tail\n");
+    remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rotate_max_files() {
+    let dir = testing_dir("rotate_max_files");
+    let mut file = RotatingFile::new(RotatingFileOptions {
+        dir: dir.clone(),
+        pattern: "backup.log".to_string(),
+        rotation: Rotation::Never,
+        max_files: Some(2),
+        ..Default::default()
+    })
+    .unwrap();
+    for i in 0..4 {
+        writeln!(file, "file {}", i).unwrap();
+        // distinct modification times
+        sleep(Duration::from_millis(20));
+        file.rotate().unwrap();
+    }
+    drop(file);
+
+    // the current file plus the two most recently rotated files
+    let mut kept: Vec<String> = filenames(&dir)
+        .iter()
+        .filter(|name| name.as_str() != "backup.log")
+        .map(|name| read_to_string(dir.join(name)).unwrap())
+        .collect();
+    kept.sort();
+    assert_eq!(kept, vec!["file 2\n", "file 3\n"]);
+    assert_eq!(read_to_string(dir.join("backup.log")).unwrap(), "");
+    remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_rotate_invalid_options() {
+    let dir = testing_dir("rotate_invalid");
+    let empty = RotatingFile::new(RotatingFileOptions {
+        dir: dir.clone(),
+        pattern: String::new(),
+        ..Default::default()
+    });
+    assert!(matches!(empty, Err(MproxyError::Config(_))));
+    assert!("weekly".parse::<Rotation>().is_err());
+    assert!("lz4".parse::<Compression>().is_err());
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn test_rotate_gzip() {
+    let dir = testing_dir("rotate_gzip");
+    let mut file = RotatingFile::new(RotatingFileOptions {
+        dir: dir.clone(),
+        pattern: "backup.log".to_string(),
+        rotation: Rotation::Never,
+        compression: Compression::Gzip,
+        ..Default::default()
+    })
+    .unwrap();
+    writeln!(file, "compressed").unwrap();
+    file.rotate().unwrap();
+    drop(file);
+
+    assert_eq!(filenames(&dir), vec!["backup.log", "backup.log.1.gz"]);
+    remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,85 @@
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use mproxy_common::{DatagramMeta, MessageFilter, MproxyError, Pipeline, Stage, TagOptions};
+
+const POSITION: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+const CLASS_B: &str = "!AIVDM,1,1,,A,B52MJh00Nmks:05J4L1howV44000,0*1D\n";
+
+/// Split a datagram into one datagram per line
+#[derive(Clone)]
+struct SplitLines;
+
+impl Stage for SplitLines {
+    fn process(&mut self, data: &[u8], _meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+        Ok(data
+            .split_inclusive(|b| *b == b'\n')
+            .map(|line| line.to_vec())
+            .collect())
+    }
+}
+
+/// Count datagrams passed through
+#[derive(Clone, Default)]
+struct Counter(usize);
+
+impl Stage for Counter {
+    fn process(&mut self, data: &[u8], _meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+        self.0 += 1;
+        Ok(vec![data.to_vec()])
+    }
+}
+
+fn meta() -> DatagramMeta {
+    let addr: SocketAddr = "127.0.0.1:9920".parse().unwrap();
+    DatagramMeta {
+        source: addr,
+        listen_addr: addr,
+        received: SystemTime::UNIX_EPOCH,
+    }
+}
+
+#[test]
+fn test_pipeline_stages() {
+    let data = [POSITION, CLASS_B].concat();
+
+    // an empty pipeline passes datagrams through
+    let mut pipeline = Pipeline::new();
+    assert_eq!(
+        pipeline.run(data.as_bytes(), &meta()).unwrap(),
+        vec![data.as_bytes()]
+    );
+    assert!(pipeline.run(b"", &meta()).unwrap().is_empty());
+
+    // each output of a stage is passed to the next
+    let mut pipeline = Pipeline::new()
+        .stage(SplitLines)
+        .stage(MessageFilter::parse("types:18").unwrap())
+        .stage(TagOptions {
+            station: Some("rx1".to_string()),
+            ..Default::default()
+        });
+    assert_eq!(pipeline.len(), 3);
+    let out = pipeline.run(data.as_bytes(), &meta()).unwrap();
+    assert_eq!(out.len(), 1);
+    assert!(out[0].starts_with(b"\\s:rx1*"));
+    assert!(out[0].ends_with(CLASS_B.as_bytes()));
+}
+
+#[test]
+fn test_pipeline_clone() {
+    let data = [POSITION, CLASS_B].concat();
+    let mut first = Pipeline::new().stage(Counter::default()).stage(SplitLines);
+    let mut second = first.clone();
+    first.extend(&Pipeline::new().stage(SplitLines));
+    assert_eq!((first.len(), second.len()), (3, 2));
+
+    assert_eq!(first.run(data.as_bytes(), &meta()).unwrap().len(), 2);
+    assert_eq!(second.run(data.as_bytes(), &meta()).unwrap().len(), 2);
+
+    // pipelines nest, and filtered datagrams are dropped
+    let mut nested = Pipeline::new()
+        .stage(second)
+        .stage(MessageFilter::parse("deny-types:1-3,18").unwrap());
+    assert!(nested.run(data.as_bytes(), &meta()).unwrap().is_empty());
+}
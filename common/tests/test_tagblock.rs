@@ -0,0 +1,75 @@
+use std::time::{Duration, SystemTime};
+
+use mproxy_common::{NmeaError, Sentence, TagBlock, TagOptions};
+
+const SENTENCE: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C";
+
+#[test]
+fn test_tagblock_parse() {
+    let tags = TagBlock::parse("s:station1,c:1700000000,g:1-2-42,x:other*0F").unwrap();
+    assert_eq!(tags.source.as_deref(), Some("station1"));
+    assert_eq!(tags.time, Some(1700000000));
+    assert_eq!(tags.group.as_deref(), Some("1-2-42"));
+    assert_eq!(tags.other, vec![("x".to_string(), "other".to_string())]);
+    assert_eq!(
+        tags.to_string(),
+        "\\s:station1,c:1700000000,g:1-2-42,x:other*0F\\"
+    );
+
+    // the checksum is optional, but validated if present
+    assert_eq!(
+        TagBlock::parse("c:1700000000").unwrap().time,
+        Some(1700000000)
+    );
+    assert!(matches!(
+        TagBlock::parse("s:station1*00"),
+        Err(NmeaError::InvalidChecksum { .. })
+    ));
+    assert!(TagBlock::parse("c:yesterday").is_err());
+
+    let line = format!("\\s:station1*02\\{}", SENTENCE);
+    let sentence = Sentence::parse(line.as_bytes()).unwrap();
+    let tags = sentence.tags().unwrap().unwrap();
+    assert_eq!(tags.source.as_deref(), Some("station1"));
+    let (split, rest) = TagBlock::split(line.as_bytes()).unwrap();
+    assert_eq!(split, Some(tags));
+    assert_eq!(rest, SENTENCE.as_bytes());
+}
+
+#[test]
+fn test_tagblock_apply() {
+    let received = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+    let tagged = format!("\\s:upstream*50\\{}\n", SENTENCE);
+    let input = format!("{}\n{}", SENTENCE, tagged);
+
+    let passthrough = TagOptions::default();
+    assert_eq!(
+        passthrough.apply(input.as_bytes(), received),
+        input.as_bytes()
+    );
+
+    // existing station identifiers are kept
+    let inject = TagOptions {
+        station: Some("gateway".to_string()),
+        timestamp: true,
+        ..Default::default()
+    };
+    let output = String::from_utf8(inject.apply(input.as_bytes(), received).to_vec()).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 2);
+    for (line, station) in lines.iter().zip(["gateway", "upstream"]) {
+        let sentence = Sentence::parse(line.as_bytes()).unwrap();
+        let tags = sentence.tags().unwrap().unwrap();
+        assert_eq!(tags.source.as_deref(), Some(station));
+        assert_eq!(tags.time, Some(1700000000));
+    }
+
+    let strip = TagOptions {
+        strip: true,
+        ..inject
+    };
+    assert_eq!(
+        strip.apply(input.as_bytes(), received),
+        format!("{}\n{}\n", SENTENCE, SENTENCE).as_bytes()
+    );
+}
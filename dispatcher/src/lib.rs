@@ -0,0 +1,141 @@
+//! Multicast Network Dispatcher and Proxy
+//!
+//! # MPROXY: Dispatcher
+//! Run the clients, servers, and proxies described by a TOML topology file.
+//!
+//! ## Quick Start
+//! In `Cargo.toml`
+//! ```toml
+//! [dependencies]
+//! mproxy-dispatcher = "0.1"
+//! ```
+//!
+//! Example `src/main.rs`
+//! ```rust,no_run
+//! use mproxy_dispatcher::Topology;
+//!
+//! let topology = Topology::parse(r#"
+//!     [inputs.receivers]
+//!     udp = "0.0.0.0:9920"
+//!     outputs = ["logfile"]
+//!
+//!     [outputs.logfile]
+//!     file = "dispatcher_demo.log"
+//! "#).unwrap();
+//!
+//! // start the threads described by the topology, and wait for them
+//! for thread in topology.spawn().unwrap() {
+//!     thread.join().unwrap();
+//! }
+//! ```
+//!
+//! ## Command Line Interface
+//! Install with Cargo
+//! ```bash
+//! cargo install mproxy-dispatcher
+//! ```
+//!
+//! ```text
+//! MPROXY: Dispatcher
+//!
+//! Run the clients, servers, and proxies described by a TOML topology file.
+//!
+//! USAGE:
+//!   mproxy [FLAGS] --config [FILE]
+//!
+//! OPTIONS:
+//!   --config [FILE]  Topology file of named inputs, outputs, and pipelines
+//!
+//! FLAGS:
+//!   -h, --help   Prints help information
+//!   --check      Validate the topology file and print the threads it describes, then exit
+//!
+//! TOPOLOGY:
+//!   [inputs.NAME]     One of the keys below, plus 'outputs = ["NAME", ...]'
+//!     file = PATH                 Stream a file, or stdin if "-", to udp outputs as mproxy-client.
+//!                                 Options: tee, framing, mtu, follow, replay, replay_speed,
+//!                                 replay_start, replay_end, pcap (true to read a capture), backup
+//!     udp = ADDR                  Listen for UDP or multicast datagrams
+//!                                 To udp outputs, forward as mproxy-forward. Options: tee,
+//!                                 pcap (FILE), reassemble, reassembly_timeout,
+//!                                 reassembly_max_pending, dedup, dedup_window, dedup_max_entries
+//!                                 Several listen addresses, as [ADDR, ...], share these options
+//!                                 To one file output, log as mproxy-server. Options: tee,
+//!                                 pcap (FILE), backup, reassemble, reassembly_timeout,
+//!                                 reassembly_max_pending
+//!                                 To one tcp_listen output, serve TCP clients as mproxy-reverse.
+//!                                 ADDR must be a multicast address
+//!     tcp_connect = ADDR          Connect to a TCP upstream and forward to one udp output
+//!                                 Options: preamble (TEXT), preamble_file, http_get (PATH),
+//!                                 http_headers = { NAME = "VALUE" }, no_preamble, as the
+//!                                 mproxy-forward --preamble options
+//!                                 Also idle_timeout (MILLIS), keepalive, keepalive_idle (SECS),
+//!                                 keepalive_interval (SECS), keepalive_retries, as the
+//!                                 mproxy-forward liveness options
+//!     tcp_listen = ADDR           Accept TCP connections and forward to one udp output
+//!     file and udp inputs also accept drop_corrupt and quarantine (FILE). Inputs writing to udp
+//!     outputs also accept tag_station, tag_time, and strip_tags
+//!
+//!   [inputs.NAME.backup]  Rotating backups of the input. Keys: dir, pattern, rotation, max_size,
+//!                         max_files, interval (DAYS), compress
+//!
+//!   [inputs.NAME.tls]     TLS settings of a tcp_connect input, with crate feature tls. Keys:
+//!                         ca_file, client_cert, client_key, server_name, pins, insecure,
+//!                         as the mproxy-forward --tls-* options
+//!
+//!   [inputs.NAME.retry]   Reconnection of a tcp_connect input, as the mproxy-forward retry
+//!                         options. Keys: initial (MILLIS), max (MILLIS), jitter, max_retries
+//!
+//!   [outputs.NAME]    One of the keys below
+//!     udp = ADDR                  UDP or multicast target. Options: pipeline (NAME)
+//!     file = PATH                 Log file. Options: format, timestamp, rotate, max_size,
+//!                                 max_files, max_age (DAYS), compress
+//!     tcp_listen = ADDR           Serve TCP clients. Options: clients = { "IP" = "PIPELINE" }
+//!
+//!   [pipelines.NAME]  Applied to a udp output, or to tcp_listen clients by IP address
+//!     filter = RULES              AIS message filter rules, as mproxy-forward --filter
+//!     downsample = MILLIS         At most one position report per vessel per MILLIS
+//!
+//!   All inputs of a topology run at once. An input writes to outputs of one kind; to combine
+//!   kinds, forward to a multicast udp output and add an input listening on it for each kind
+//!
+//! EXAMPLE:
+//!   mproxy --config topology.toml
+//!
+//!   # topology.toml
+//!   [inputs.receivers]
+//!   udp = "0.0.0.0:9920"
+//!   dedup = true
+//!   outputs = ["relay", "partner"]
+//!
+//!   [inputs.archive]
+//!   udp = "224.0.0.1:9922"
+//!   outputs = ["logfile"]
+//!
+//!   [outputs.relay]
+//!   udp = "224.0.0.1:9922"
+//!
+//!   [outputs.partner]
+//!   udp = "partner.example.com:9921"
+//!   pipeline = "class_a"
+//!
+//!   [outputs.logfile]
+//!   file = "ais_%Y-%m-%d.log"
+//!   rotate = "daily"
+//!   max_age = 90
+//!
+//!   [pipelines.class_a]
+//!   filter = "types:1-3"
+//!   downsample = 30000
+//! ```
+//!
+//! ### See Also
+//! - [mproxy-client](https://docs.rs/mproxy-client/)
+//! - [mproxy-server](https://docs.rs/mproxy-server/)
+//! - [mproxy-forward](https://docs.rs/mproxy-forward/)
+//! - [mproxy-reverse](https://docs.rs/mproxy-reverse/)
+//!
+
+mod topology;
+
+pub use topology::{Task, Topology};
@@ -0,0 +1,1151 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::read_to_string;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use mproxy_client::{parse_timestamp, spawn_client, ClientOptions, Framing, ReplayOptions};
+use mproxy_common::{
+    resolve_socket_addr, Compression, DedupOptions, Deduplicator, Downsampler, MessageFilter,
+    MproxyError, PcapSink, ReassemblyOptions, RotatingFileOptions, Rotation, ShutdownHandle,
+    TagOptions, Validation,
+};
+use mproxy_forward::{
+    parse_fingerprint, proxy_gateway_with, proxy_tcp_udp_with, BackoffOptions, ForwardOptions,
+    HttpRequest, KeepaliveOptions, Preamble, TlsOptions, UpstreamOptions,
+};
+use mproxy_reverse::{reverse_proxy_tcp_udp, reverse_proxy_udp_tcp_with, ReverseOptions};
+use mproxy_server::{listener_with, OutputFormat, ServerOptions, TimestampFormat};
+use serde::de::{self, DeserializeOwned};
+use serde::{Deserialize, Deserializer};
+use toml::{Table, Value};
+
+/// A thread described by a topology file, equivalent to one started by
+/// the `mproxy-client`, `mproxy-server`, `mproxy-forward`, or
+/// `mproxy-reverse` binaries
+#[derive(Clone, Debug)]
+pub enum Task {
+    /// Stream a file to UDP targets, as [spawn_client]
+    Client {
+        input: String,
+        path: PathBuf,
+        targets: Vec<String>,
+        options: ClientOptions,
+    },
+    /// Log a UDP listener to a file, as [listener_with]
+    Server {
+        input: String,
+        listen_addr: String,
+        path: PathBuf,
+        pcap: Option<PathBuf>,
+        options: ServerOptions,
+    },
+    /// Forward UDP listeners to UDP targets, as [proxy_gateway_with]
+    Forward {
+        input: String,
+        listen_addrs: Vec<String>,
+        targets: Vec<String>,
+        pcap: Option<PathBuf>,
+        options: ForwardOptions,
+    },
+    /// Forward a TCP or TLS upstream to a UDP target, as
+    /// [proxy_tcp_udp_with]
+    TcpConnect {
+        input: String,
+        upstream: String,
+        target: String,
+        options: UpstreamOptions,
+    },
+    /// Serve a multicast UDP listener to TCP clients, as
+    /// [reverse_proxy_udp_tcp_with]
+    TcpServe {
+        input: String,
+        multicast_addr: String,
+        tcp_listen_addr: String,
+        options: ReverseOptions,
+    },
+    /// Forward bytes from TCP clients to a UDP target, as
+    /// [reverse_proxy_tcp_udp]
+    TcpListen {
+        input: String,
+        listen_addr: String,
+        target: String,
+    },
+}
+
+impl Task {
+    /// Name of the input in the topology file
+    pub fn input(&self) -> &str {
+        match self {
+            Task::Client { input, .. }
+            | Task::Server { input, .. }
+            | Task::Forward { input, .. }
+            | Task::TcpConnect { input, .. }
+            | Task::TcpServe { input, .. }
+            | Task::TcpListen { input, .. } => input,
+        }
+    }
+
+    /// Start the thread, or a thread for each listen address of a
+    /// forwarder. Pcap captures are created here rather than when the
+    /// topology is parsed
+    pub fn spawn(&self) -> Result<Vec<ShutdownHandle>, MproxyError> {
+        let thread = match self {
+            Task::Client {
+                path,
+                targets,
+                options,
+                ..
+            } => spawn_client(path.clone(), targets.clone(), options.clone()),
+            Task::Server {
+                listen_addr,
+                path,
+                pcap,
+                options,
+                ..
+            } => {
+                let mut options = options.clone();
+                if let Some(pcap) = pcap {
+                    options.pcap = Some(PcapSink::create(pcap)?);
+                }
+                listener_with(listen_addr.clone(), path.clone(), &options)
+            }
+            Task::Forward {
+                listen_addrs,
+                targets,
+                pcap,
+                options,
+                ..
+            } => {
+                let mut options = options.clone();
+                if let Some(pcap) = pcap {
+                    options.pcap = Some(PcapSink::create(pcap)?);
+                }
+                return proxy_gateway_with(targets, listen_addrs, &options);
+            }
+            Task::TcpConnect {
+                upstream,
+                target,
+                options,
+                ..
+            } => proxy_tcp_udp_with(upstream.clone(), target.clone(), options).map(Into::into),
+            Task::TcpServe {
+                multicast_addr,
+                tcp_listen_addr,
+                options,
+                ..
+            } => {
+                reverse_proxy_udp_tcp_with(multicast_addr.clone(), tcp_listen_addr.clone(), options)
+            }
+            Task::TcpListen {
+                listen_addr,
+                target,
+                ..
+            } => reverse_proxy_tcp_udp(listen_addr.clone(), target.clone()),
+        }?;
+        Ok(vec![thread])
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Task::Client {
+                input,
+                path,
+                targets,
+                ..
+            } => write!(
+                f,
+                "{}: stream {} to UDP {}",
+                input,
+                path.display(),
+                targets.join(", ")
+            ),
+            Task::Server {
+                input,
+                listen_addr,
+                path,
+                ..
+            } => write!(
+                f,
+                "{}: log UDP {} to {}",
+                input,
+                listen_addr,
+                path.display()
+            ),
+            Task::Forward {
+                input,
+                listen_addrs,
+                targets,
+                ..
+            } => write!(
+                f,
+                "{}: forward UDP {} to UDP {}",
+                input,
+                listen_addrs.join(", "),
+                targets.join(", ")
+            ),
+            Task::TcpConnect {
+                input,
+                upstream,
+                target,
+                ..
+            } => write!(f, "{}: forward TCP {} to UDP {}", input, upstream, target),
+            Task::TcpServe {
+                input,
+                multicast_addr,
+                tcp_listen_addr,
+                ..
+            } => write!(
+                f,
+                "{}: serve UDP {} to TCP clients of {}",
+                input, multicast_addr, tcp_listen_addr
+            ),
+            Task::TcpListen {
+                input,
+                listen_addr,
+                target,
+            } => write!(
+                f,
+                "{}: forward TCP clients of {} to UDP {}",
+                input, listen_addr, target
+            ),
+        }
+    }
+}
+
+/// Threads described by a topology file of named inputs, outputs, and
+/// pipelines.
+///
+/// The whole file is validated when parsed, so that mistakes are reported
+/// before any socket is bound or file is opened
+#[derive(Clone, Debug, Default)]
+pub struct Topology {
+    pub tasks: Vec<Task>,
+}
+
+impl Topology {
+    /// Read and validate a topology file
+    pub fn load(path: &Path) -> Result<Self, MproxyError> {
+        let text = read_to_string(path).map_err(|source| MproxyError::File {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Topology::parse(&text).map_err(|e| match e {
+            MproxyError::Config(reason) => {
+                MproxyError::Config(format!("{}: {}", path.display(), reason))
+            }
+            e => e,
+        })
+    }
+
+    /// Parse and validate a topology from TOML text
+    pub fn parse(text: &str) -> Result<Self, MproxyError> {
+        let root: Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| MproxyError::Config(e.to_string()))?;
+        build(root).map_err(MproxyError::Config)
+    }
+
+    /// Start every thread of the topology. If any fails to start, threads
+    /// already started are shut down
+    pub fn spawn(&self) -> Result<Vec<ShutdownHandle>, MproxyError> {
+        let mut threads = vec![];
+        for task in &self.tasks {
+            match task.spawn() {
+                Ok(started) => threads.extend(started),
+                Err(e) => {
+                    for thread in threads {
+                        let _ = thread.shutdown();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(threads)
+    }
+}
+
+/// A table whose keys are removed as they are read, so that unknown keys
+/// can be reported
+struct Section {
+    name: String,
+    table: Table,
+    /// Describes what the keys are read for, e.g. in errors for keys which
+    /// are valid elsewhere
+    role: Option<String>,
+}
+
+impl Section {
+    fn new(name: String, table: Table) -> Self {
+        Section {
+            name,
+            table,
+            role: None,
+        }
+    }
+
+    fn key(&self, key: &str) -> String {
+        if self.name.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.name, key)
+        }
+    }
+
+    fn mismatch(&self, key: &str, expected: &str, value: &Value) -> String {
+        format!(
+            "{}: expected {}, found {} {}",
+            self.key(key),
+            expected,
+            value.type_str(),
+            value
+        )
+    }
+
+    fn has(&self, key: &str) -> bool {
+        self.table.contains_key(key)
+    }
+
+    fn string(&mut self, key: &str) -> Result<Option<String>, String> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(v) => Err(self.mismatch(key, "a string", &v)),
+        }
+    }
+
+    fn path(&mut self, key: &str) -> Result<Option<PathBuf>, String> {
+        Ok(self.string(key)?.map(PathBuf::from))
+    }
+
+    fn flag(&mut self, key: &str) -> Result<bool, String> {
+        match self.table.remove(key) {
+            None => Ok(false),
+            Some(Value::Boolean(b)) => Ok(b),
+            Some(v) => Err(self.mismatch(key, "true or false", &v)),
+        }
+    }
+
+    fn count<T: TryFrom<i64>>(&mut self, key: &str) -> Result<Option<T>, String> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Integer(i)) if i >= 0 => T::try_from(i)
+                .map(Some)
+                .map_err(|_| format!("{}: {} is out of range", self.key(key), i)),
+            Some(v) => Err(self.mismatch(key, "a non-negative integer", &v)),
+        }
+    }
+
+    fn float(&mut self, key: &str) -> Result<Option<f64>, String> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Float(x)) => Ok(Some(x)),
+            Some(Value::Integer(i)) => Ok(Some(i as f64)),
+            Some(v) => Err(self.mismatch(key, "a number", &v)),
+        }
+    }
+
+    /// A string parsed with [FromStr], e.g. an enum option
+    fn parsed<T: FromStr<Err = String>>(&mut self, key: &str) -> Result<Option<T>, String> {
+        match self.string(key)? {
+            None => Ok(None),
+            Some(s) => s
+                .parse()
+                .map(Some)
+                .map_err(|e| format!("{}: {}", self.key(key), e)),
+        }
+    }
+
+    /// A string, or an array of strings
+    fn strings(&mut self, key: &str) -> Result<Vec<String>, String> {
+        match self.table.remove(key) {
+            None => Ok(vec![]),
+            Some(Value::String(s)) => Ok(vec![s]),
+            Some(Value::Array(values)) => values
+                .into_iter()
+                .map(|v| match v {
+                    Value::String(s) => Ok(s),
+                    v => Err(self.mismatch(key, "an array of strings", &v)),
+                })
+                .collect(),
+            Some(v) => Err(self.mismatch(key, "an array of strings", &v)),
+        }
+    }
+
+    fn section(&mut self, key: &str) -> Result<Option<Section>, String> {
+        match self.table.remove(key) {
+            None => Ok(None),
+            Some(Value::Table(table)) => Ok(Some(Section::new(self.key(key), table))),
+            Some(v) => Err(self.mismatch(key, "a table", &v)),
+        }
+    }
+
+    /// Named tables, e.g. each `[inputs.NAME]`
+    fn sections(&mut self, key: &str) -> Result<Vec<(String, Section)>, String> {
+        let Some(section) = self.section(key)? else {
+            return Ok(vec![]);
+        };
+        let name = section.name;
+        section
+            .table
+            .into_iter()
+            .map(|(entry, value)| match value {
+                Value::Table(table) => Ok((
+                    entry.clone(),
+                    Section::new(format!("{}.{}", name, entry), table),
+                )),
+                v => Err(format!(
+                    "{}.{}: expected a table, found {} {}",
+                    name,
+                    entry,
+                    v.type_str(),
+                    v
+                )),
+            })
+            .collect()
+    }
+
+    /// A table read with serde, e.g. one with a fixed set of keys
+    fn table<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>, String> {
+        match self.section(key)? {
+            None => Ok(None),
+            Some(section) => section.deserialize().map(Some),
+        }
+    }
+
+    /// Read the whole section with serde
+    fn deserialize<T: DeserializeOwned>(self) -> Result<T, String> {
+        Value::Table(self.table)
+            .try_into()
+            .map_err(|e: toml::de::Error| format!("{}: {}", self.name, e.message()))
+    }
+
+    /// Exactly one of `kinds`, returning the kind and its value
+    fn kind(&mut self, kinds: &[&'static str]) -> Result<(&'static str, String), String> {
+        let kind = self.present(kinds)?;
+        let value = self.string(kind)?.expect("key is present");
+        Ok((kind, value))
+    }
+
+    /// Exactly one of `kinds`, returning the kind and its values, given as
+    /// a string or an array of strings
+    fn kinds(&mut self, kinds: &[&'static str]) -> Result<(&'static str, Vec<String>), String> {
+        let kind = self.present(kinds)?;
+        let values = self.strings(kind)?;
+        if values.is_empty() {
+            return Err(format!("{}: expected at least one value", self.key(kind)));
+        }
+        Ok((kind, values))
+    }
+
+    /// Which one of `kinds` is set, if exactly one is
+    fn present(&self, kinds: &[&'static str]) -> Result<&'static str, String> {
+        let present: Vec<&'static str> = kinds.iter().copied().filter(|k| self.has(k)).collect();
+        match present.as_slice() {
+            [kind] => Ok(kind),
+            _ => Err(format!(
+                "{}: expected exactly one of {}",
+                self.name,
+                kinds.join(", ")
+            )),
+        }
+    }
+
+    /// Report any keys which were not read
+    fn finish(self) -> Result<(), String> {
+        match self.table.keys().next() {
+            None => Ok(()),
+            Some(key) => match &self.role {
+                Some(role) => Err(format!(
+                    "{}: unknown key, or not supported for {}",
+                    self.key(key),
+                    role
+                )),
+                None => Err(format!("{}: unknown key", self.key(key))),
+            },
+        }
+    }
+}
+
+/// Filter and rate limit applied to an output
+#[derive(Clone, Default)]
+struct PipelineConfig {
+    filter: Option<MessageFilter>,
+    /// Interval of a [Downsampler] created for each consumer, as clones
+    /// share their state
+    downsample: Option<Duration>,
+}
+
+enum Output {
+    Udp {
+        addr: String,
+        pipeline: Option<String>,
+    },
+    File {
+        path: PathBuf,
+        format: OutputFormat,
+        timestamp: TimestampFormat,
+        rotate: Option<RotatingFileOptions>,
+    },
+    TcpListen {
+        addr: String,
+        clients: Vec<(IpAddr, String)>,
+    },
+}
+
+impl Output {
+    fn kind(&self) -> &'static str {
+        match self {
+            Output::Udp { .. } => "udp",
+            Output::File { .. } => "file",
+            Output::TcpListen { .. } => "tcp_listen",
+        }
+    }
+}
+
+fn build(root: Table) -> Result<Topology, String> {
+    let mut root = Section::new(String::new(), root);
+    let pipeline_sections = root.sections("pipelines")?;
+    let output_sections = root.sections("outputs")?;
+    let input_sections = root.sections("inputs")?;
+    root.finish()?;
+
+    let mut pipelines = HashMap::new();
+    for (name, section) in pipeline_sections {
+        pipelines.insert(name, parse_pipeline(section)?);
+    }
+    let mut outputs = BTreeMap::new();
+    for (name, section) in output_sections {
+        outputs.insert(name, parse_output(section, &pipelines)?);
+    }
+    if input_sections.is_empty() {
+        return Err("no inputs are defined".to_string());
+    }
+
+    let mut tasks = vec![];
+    let mut used: HashMap<String, String> = HashMap::new();
+    for (name, section) in input_sections {
+        let (task, output_names) = parse_input(&name, section, &outputs, &pipelines)?;
+        for output in output_names {
+            if let Some(other) = used.insert(output.clone(), name.clone()) {
+                if !matches!(outputs[&output], Output::Udp { .. }) {
+                    return Err(format!(
+                        "outputs.{}: used by more than one input ({} and {})",
+                        output, other, name
+                    ));
+                }
+            }
+        }
+        tasks.push(task);
+    }
+
+    for name in outputs.keys() {
+        if !used.contains_key(name) {
+            return Err(format!("outputs.{}: not used by any input", name));
+        }
+    }
+    let used_pipelines: HashSet<&String> = outputs
+        .values()
+        .flat_map(|output| match output {
+            Output::Udp { pipeline, .. } => pipeline.iter().collect::<Vec<_>>(),
+            Output::TcpListen { clients, .. } => clients.iter().map(|(_, p)| p).collect(),
+            Output::File { .. } => vec![],
+        })
+        .collect();
+    for name in pipelines.keys() {
+        if !used_pipelines.contains(name) {
+            return Err(format!("pipelines.{}: not used by any output", name));
+        }
+    }
+    Ok(Topology { tasks })
+}
+
+/// `[pipelines.NAME]` table
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PipelineTable {
+    filter: Option<String>,
+    downsample: Option<u64>,
+}
+
+fn parse_pipeline(section: Section) -> Result<PipelineConfig, String> {
+    let key = section.key("filter");
+    let table: PipelineTable = section.deserialize()?;
+    let filter = match table.filter {
+        Some(rules) => Some(MessageFilter::parse(&rules).map_err(|e| format!("{}: {}", key, e))?),
+        None => None,
+    };
+    let downsample = table.downsample.map(Duration::from_millis);
+    Ok(PipelineConfig { filter, downsample })
+}
+
+fn check_pipeline(
+    section: &Section,
+    key: &str,
+    name: &str,
+    pipelines: &HashMap<String, PipelineConfig>,
+) -> Result<(), String> {
+    if pipelines.contains_key(name) {
+        Ok(())
+    } else {
+        Err(format!("{}: unknown pipeline '{}'", section.key(key), name))
+    }
+}
+
+fn parse_output(
+    mut section: Section,
+    pipelines: &HashMap<String, PipelineConfig>,
+) -> Result<Output, String> {
+    let output = match section.kind(&["udp", "file", "tcp_listen"])? {
+        ("udp", addr) => {
+            section.role = Some("udp outputs".to_string());
+            let pipeline = section.string("pipeline")?;
+            if let Some(name) = &pipeline {
+                check_pipeline(&section, "pipeline", name, pipelines)?;
+            }
+            Output::Udp { addr, pipeline }
+        }
+        ("file", path) => {
+            section.role = Some("file outputs".to_string());
+            let rotation: Option<Rotation> = section.parsed("rotate")?;
+            let max_size = section.count("max_size")?;
+            let max_files = section.count("max_files")?;
+            let max_age: Option<u64> = section.count("max_age")?;
+            let compression = section.parsed("compress")?;
+            let rotate = if rotation.is_some()
+                || max_size.is_some()
+                || max_files.is_some()
+                || max_age.is_some()
+                || compression.is_some()
+            {
+                Some(RotatingFileOptions {
+                    rotation: rotation.unwrap_or(Rotation::Never),
+                    max_size,
+                    max_files,
+                    max_age: max_age.map(days_duration),
+                    compression: compression.unwrap_or_default(),
+                    ..Default::default()
+                })
+            } else {
+                None
+            };
+            Output::File {
+                path: PathBuf::from(path),
+                format: section.parsed("format")?.unwrap_or_default(),
+                timestamp: section.parsed("timestamp")?.unwrap_or_default(),
+                rotate,
+            }
+        }
+        (_, addr) => {
+            section.role = Some("tcp_listen outputs".to_string());
+            let mut clients = vec![];
+            if let Some(mut table) = section.section("clients")? {
+                let entries: Vec<String> = table.table.keys().cloned().collect();
+                for ip in entries {
+                    let pipeline = table.string(&ip)?.expect("key is present");
+                    let addr = ip
+                        .parse::<IpAddr>()
+                        .map_err(|e| format!("{}: invalid client IP: {}", table.key(&ip), e))?;
+                    check_pipeline(&table, &ip, &pipeline, pipelines)?;
+                    clients.push((addr, pipeline));
+                }
+            }
+            Output::TcpListen { addr, clients }
+        }
+    };
+    section.finish()?;
+    Ok(output)
+}
+
+fn parse_input(
+    name: &str,
+    mut section: Section,
+    outputs: &BTreeMap<String, Output>,
+    pipelines: &HashMap<String, PipelineConfig>,
+) -> Result<(Task, Vec<String>), String> {
+    let (kind, sources) = section.kinds(&["file", "udp", "tcp_connect", "tcp_listen"])?;
+    let output_names = section.strings("outputs")?;
+    let mut targets: Vec<&Output> = vec![];
+    for output in &output_names {
+        match outputs.get(output) {
+            Some(o) => targets.push(o),
+            None => {
+                return Err(format!(
+                    "{}: unknown output '{}'",
+                    section.key("outputs"),
+                    output
+                ))
+            }
+        }
+    }
+    let output_kind = match targets.first() {
+        Some(first) => {
+            if let Some(other) = targets.iter().find(|o| o.kind() != first.kind()) {
+                return Err(format!(
+                    "{}: outputs must all be the same kind, found {} and {}. \
+                     Forward to a multicast udp output, and add an input for each kind",
+                    section.key("outputs"),
+                    first.kind(),
+                    other.kind()
+                ));
+            }
+            first.kind()
+        }
+        None => "none",
+    };
+    section.role = Some(format!("{} inputs with {} outputs", kind, output_kind));
+    let input = name.to_string();
+
+    // several listeners share the options of one forwarder, as mproxy-forward
+    // with repeated --udp-listen-addr
+    let gateway = kind == "udp" && matches!(output_kind, "udp" | "none");
+    if sources.len() > 1 && !gateway {
+        return Err(format!(
+            "{}: several addresses are only supported for udp inputs with udp outputs",
+            section.key(kind)
+        ));
+    }
+    let source = sources[0].clone();
+
+    // udp targets, rejecting pipelines where the thread cannot apply them
+    let udp_targets = |section: &Section, pipelines_supported: bool| -> Result<_, String> {
+        let mut addrs = vec![];
+        for (output, target) in output_names.iter().zip(&targets) {
+            if let Output::Udp { addr, pipeline } = target {
+                if pipeline.is_some() && !pipelines_supported {
+                    return Err(format!(
+                        "outputs.{}.pipeline: not supported for outputs of {} inputs",
+                        output, kind
+                    ));
+                }
+                addrs.push(addr.clone());
+            }
+        }
+        // invalid tee values are reported when the option is read
+        let tee = !matches!(section.table.get("tee"), None | Some(Value::Boolean(false)));
+        if addrs.is_empty() && !tee {
+            return Err(format!(
+                "{}: at least one output (or tee = true) is required",
+                section.key("outputs")
+            ));
+        }
+        Ok(addrs)
+    };
+    let single = |section: &Section| -> Result<(), String> {
+        match targets.len() {
+            1 => Ok(()),
+            _ => Err(format!(
+                "{}: {} inputs with {} outputs require exactly one output",
+                section.key("outputs"),
+                kind,
+                output_kind
+            )),
+        }
+    };
+
+    let task = match (kind, output_kind) {
+        ("file", "udp" | "none") => {
+            let targets = udp_targets(&section, false)?;
+            let tee = section.flag("tee")?;
+            let pcap = section.flag("pcap")?;
+            let mut framing: Framing = section.parsed("framing")?.unwrap_or_default();
+            if let Some(mtu) = section.count("mtu")? {
+                match framing {
+                    Framing::Packed { .. } => framing = Framing::Packed { mtu },
+                    _ => {
+                        return Err(format!(
+                            "{}: only used with framing = \"packed\"",
+                            section.key("mtu")
+                        ))
+                    }
+                }
+            }
+            let replay = section.flag("replay")?;
+            let speed = section.float("replay_speed")?;
+            let start = timestamp(&mut section, "replay_start")?;
+            let end = timestamp(&mut section, "replay_end")?;
+            let replay = if replay || pcap {
+                Some(ReplayOptions {
+                    speed: speed.unwrap_or(1.0),
+                    start,
+                    end,
+                })
+            } else {
+                None
+            };
+            let options = ClientOptions {
+                tee,
+                backup: backup(&mut section)?,
+                framing,
+                follow: section.flag("follow")?,
+                replay,
+                pcap,
+                validation: validation(&mut section)?,
+                tags: tags(&mut section)?,
+            };
+            Task::Client {
+                input,
+                path: PathBuf::from(source),
+                targets,
+                options,
+            }
+        }
+        ("udp", "udp" | "none") => {
+            let addrs = udp_targets(&section, true)?;
+            let mut filters = HashMap::new();
+            let mut downsample = HashMap::new();
+            for target in &targets {
+                if let Output::Udp {
+                    addr,
+                    pipeline: Some(pipeline),
+                } = target
+                {
+                    let pipeline = &pipelines[pipeline];
+                    if let Some(filter) = &pipeline.filter {
+                        filters.insert(addr.clone(), filter.clone());
+                    }
+                    if let Some(interval) = pipeline.downsample {
+                        downsample.insert(addr.clone(), Downsampler::new(interval));
+                    }
+                }
+            }
+            let options = ForwardOptions {
+                tee: section.flag("tee")?,
+                validation: validation(&mut section)?,
+                reassembly: reassembly(&mut section)?,
+                dedup: dedup(&mut section)?.as_ref().map(Deduplicator::new),
+                tags: tags(&mut section)?,
+                filters,
+                downsample,
+                ..Default::default()
+            };
+            Task::Forward {
+                input,
+                listen_addrs: sources,
+                targets: addrs,
+                pcap: section.path("pcap")?,
+                options,
+            }
+        }
+        ("udp", "file") => {
+            single(&section)?;
+            let Output::File {
+                path,
+                format,
+                timestamp,
+                rotate,
+            } = targets[0]
+            else {
+                unreachable!("output kind is file")
+            };
+            let options = ServerOptions {
+                tee: section.flag("tee")?,
+                backup: backup(&mut section)?,
+                rotate: rotate.clone(),
+                format: *format,
+                timestamp: *timestamp,
+                validation: validation(&mut section)?,
+                reassembly: reassembly(&mut section)?,
+                ..Default::default()
+            };
+            Task::Server {
+                input,
+                listen_addr: source,
+                path: path.clone(),
+                pcap: section.path("pcap")?,
+                options,
+            }
+        }
+        ("udp", "tcp_listen") => {
+            single(&section)?;
+            let Output::TcpListen { addr, clients } = targets[0] else {
+                unreachable!("output kind is tcp_listen")
+            };
+            // as with mproxy-reverse, TCP clients are served from a multicast group
+            let resolved = resolve_socket_addr(&source)
+                .map_err(|e| format!("{}: {}", section.key(kind), e))?;
+            if !resolved.ip().is_multicast() {
+                return Err(format!(
+                    "{}: inputs with tcp_listen outputs must listen on a multicast address",
+                    section.key(kind)
+                ));
+            }
+            let mut options = ReverseOptions::default();
+            for (ip, pipeline) in clients {
+                let pipeline = &pipelines[pipeline];
+                if let Some(filter) = &pipeline.filter {
+                    options.filters.insert(*ip, filter.clone());
+                }
+                if let Some(interval) = pipeline.downsample {
+                    options.downsample.insert(*ip, Downsampler::new(interval));
+                }
+            }
+            Task::TcpServe {
+                input,
+                multicast_addr: source,
+                tcp_listen_addr: addr.clone(),
+                options,
+            }
+        }
+        ("tcp_connect", "udp") => {
+            single(&section)?;
+            let target = udp_targets(&section, false)?.remove(0);
+            Task::TcpConnect {
+                input,
+                upstream: source,
+                target,
+                options: upstream(&mut section)?,
+            }
+        }
+        ("tcp_listen", "udp") => {
+            single(&section)?;
+            let target = udp_targets(&section, false)?.remove(0);
+            Task::TcpListen {
+                input,
+                listen_addr: source,
+                target,
+            }
+        }
+        _ => {
+            return Err(format!(
+                "{}: {} inputs cannot write to {} outputs",
+                section.key("outputs"),
+                kind,
+                output_kind
+            ))
+        }
+    };
+    section.finish()?;
+    Ok((task, output_names))
+}
+
+fn days_duration(days: u64) -> Duration {
+    Duration::from_secs(days * 24 * 60 * 60)
+}
+
+/// Epoch seconds, or an RFC3339 timestamp string
+fn timestamp(section: &mut Section, key: &str) -> Result<Option<f64>, String> {
+    match section.table.get(key) {
+        Some(Value::String(_)) => {
+            let s = section.string(key)?.expect("key is present");
+            parse_timestamp(&s).map(Some).ok_or_else(|| {
+                format!(
+                    "{}: expected epoch seconds or an RFC3339 timestamp, found {:?}",
+                    section.key(key),
+                    s
+                )
+            })
+        }
+        _ => section.float(key),
+    }
+}
+
+fn validation(section: &mut Section) -> Result<Validation, String> {
+    let drop_corrupt = section.flag("drop_corrupt")?;
+    Ok(match section.path("quarantine")? {
+        Some(path) => Validation::Quarantine(path),
+        None if drop_corrupt => Validation::Drop,
+        None => Validation::Off,
+    })
+}
+
+fn tags(section: &mut Section) -> Result<TagOptions, String> {
+    Ok(TagOptions {
+        station: section.string("tag_station")?,
+        timestamp: section.flag("tag_time")?,
+        strip: section.flag("strip_tags")?,
+    })
+}
+
+fn reassembly(section: &mut Section) -> Result<Option<ReassemblyOptions>, String> {
+    let reassemble = section.flag("reassemble")?;
+    let timeout: Option<u64> = section.count("reassembly_timeout")?;
+    let max_pending = section.count("reassembly_max_pending")?;
+    if !(reassemble || timeout.is_some() || max_pending.is_some()) {
+        return Ok(None);
+    }
+    let default = ReassemblyOptions::default();
+    Ok(Some(ReassemblyOptions {
+        timeout: timeout
+            .map(Duration::from_millis)
+            .unwrap_or(default.timeout),
+        max_pending: max_pending.unwrap_or(default.max_pending),
+        ..default
+    }))
+}
+
+fn dedup(section: &mut Section) -> Result<Option<DedupOptions>, String> {
+    let dedup = section.flag("dedup")?;
+    let window: Option<u64> = section.count("dedup_window")?;
+    let max_entries = section.count("dedup_max_entries")?;
+    if !(dedup || window.is_some() || max_entries.is_some()) {
+        return Ok(None);
+    }
+    let default = DedupOptions::default();
+    Ok(Some(DedupOptions {
+        window: window.map(Duration::from_millis).unwrap_or(default.window),
+        max_entries: max_entries.unwrap_or(default.max_entries),
+    }))
+}
+
+/// `[inputs.NAME.backup]` table
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BackupTable {
+    dir: Option<PathBuf>,
+    pattern: Option<String>,
+    #[serde(default, deserialize_with = "from_str")]
+    rotation: Option<Rotation>,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+    /// Days
+    interval: Option<u64>,
+    #[serde(default, deserialize_with = "from_str")]
+    compress: Option<Compression>,
+}
+
+fn backup(section: &mut Section) -> Result<Option<RotatingFileOptions>, String> {
+    let Some(backup) = section.table::<BackupTable>("backup")? else {
+        return Ok(None);
+    };
+    let default = RotatingFileOptions::default();
+    Ok(Some(RotatingFileOptions {
+        dir: backup.dir.unwrap_or(default.dir),
+        pattern: backup.pattern.unwrap_or(default.pattern),
+        rotation: backup.rotation.unwrap_or(default.rotation),
+        max_size: backup.max_size,
+        max_files: backup.max_files,
+        max_age: backup.interval.map(days_duration),
+        compression: backup.compress.unwrap_or(default.compression),
+    }))
+}
+
+/// `[inputs.NAME.tls]` table
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsTable {
+    ca_file: Option<PathBuf>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    server_name: Option<String>,
+    #[serde(default)]
+    pins: Vec<String>,
+    #[serde(default)]
+    insecure: bool,
+}
+
+/// Options of a tcp_connect input
+fn upstream(section: &mut Section) -> Result<UpstreamOptions, String> {
+    let mut options = UpstreamOptions::default();
+    if let Some(tls) = section.table::<TlsTable>("tls")? {
+        let pins = tls
+            .pins
+            .iter()
+            .map(|pin| parse_fingerprint(pin))
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("{}: {}", section.key("tls.pins"), e))?;
+        options.tls = TlsOptions {
+            ca_file: tls.ca_file,
+            client_cert: tls.client_cert,
+            client_key: tls.client_key,
+            server_name: tls.server_name,
+            pins,
+            insecure: tls.insecure,
+        };
+    }
+    options.preamble = preamble(section)?;
+    if let Some(retry) = section.table::<RetryTable>("retry")? {
+        let default = BackoffOptions::default();
+        options.backoff = BackoffOptions {
+            initial: retry
+                .initial
+                .map(Duration::from_millis)
+                .unwrap_or(default.initial),
+            max: retry.max.map(Duration::from_millis).unwrap_or(default.max),
+            jitter: retry.jitter.unwrap_or(default.jitter),
+            max_retries: retry.max_retries,
+            ..default
+        };
+    }
+    let idle_timeout: Option<u64> = section.count("idle_timeout")?;
+    options.idle_timeout = idle_timeout.map(Duration::from_millis);
+    options.keepalive = keepalive(section)?;
+    Ok(options)
+}
+
+fn keepalive(section: &mut Section) -> Result<Option<KeepaliveOptions>, String> {
+    let keepalive = section.flag("keepalive")?;
+    let idle: Option<u64> = section.count("keepalive_idle")?;
+    let interval: Option<u64> = section.count("keepalive_interval")?;
+    let retries = section.count("keepalive_retries")?;
+    if !(keepalive || idle.is_some() || interval.is_some() || retries.is_some()) {
+        return Ok(None);
+    }
+    let default = KeepaliveOptions::default();
+    Ok(Some(KeepaliveOptions {
+        idle: idle.map(Duration::from_secs).unwrap_or(default.idle),
+        interval: interval
+            .map(Duration::from_secs)
+            .unwrap_or(default.interval),
+        retries: retries.unwrap_or(default.retries),
+    }))
+}
+
+/// `[inputs.NAME.retry]` table
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetryTable {
+    /// Milliseconds
+    initial: Option<u64>,
+    /// Milliseconds
+    max: Option<u64>,
+    jitter: Option<f64>,
+    max_retries: Option<u32>,
+}
+
+/// At most one of the preamble keys of a tcp_connect input, as the
+/// mproxy-forward --preamble options
+fn preamble(section: &mut Section) -> Result<Preamble, String> {
+    let text = section.string("preamble")?;
+    let file = section.path("preamble_file")?;
+    let path = section.string("http_get")?;
+    let headers = section.table::<BTreeMap<String, String>>("http_headers")?;
+    let none = section.flag("no_preamble")?;
+    if headers.is_some() && path.is_none() {
+        return Err(format!(
+            "{}: requires http_get",
+            section.key("http_headers")
+        ));
+    }
+    Ok(match (text, file, path, none) {
+        (None, None, None, false) => Preamble::default(),
+        (None, None, None, true) => Preamble::None,
+        (Some(text), None, None, false) => Preamble::Text(format!("{}\r\n", text)),
+        (None, Some(file), None, false) => Preamble::from_file(&file)
+            .map_err(|e| format!("{}: {}", section.key("preamble_file"), e))?,
+        (None, None, Some(path), false) => Preamble::Http(HttpRequest {
+            path,
+            headers: headers.unwrap_or_default().into_iter().collect(),
+        }),
+        _ => {
+            return Err(format!(
+                "{}: expected at most one of preamble, preamble_file, http_get, no_preamble",
+                section.name
+            ))
+        }
+    })
+}
+
+/// Deserialize a string option parsed with [FromStr], e.g. an enum option
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(de::Error::custom)
+}
@@ -0,0 +1,420 @@
+use std::net::UdpSocket;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use mproxy_dispatcher::{Task, Topology};
+use mproxy_forward::{HttpRequest, Preamble};
+
+const CLASS_B: &str = "!AIVDM,1,1,,A,B52MJh00Nmks:05J4L1howV44000,0*1D\n";
+const POSITION: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+
+/// Error message of an invalid topology
+fn error(text: &str) -> String {
+    Topology::parse(text).unwrap_err().to_string()
+}
+
+#[test]
+fn test_topology_tasks() {
+    let topology = Topology::parse(
+        r#"
+        # receivers forward to a partner and an archive relay
+        [inputs.receivers]
+        udp = "127.0.0.1:9950"
+        dedup = true
+        tag_station = "rx1"
+        outputs = ["partner", "relay"]
+
+        [inputs.archive]
+        udp = "224.0.0.1:9951"
+        tee = false
+        outputs = ["logfile"]
+
+        [inputs.replay]
+        file = "ais.log"
+        framing = "packed"
+        mtu = 1000
+        outputs = ["relay"]
+        backup = { dir = "backups", interval = 7 }
+
+        [outputs.partner]
+        udp = "127.0.0.1:9952"
+        pipeline = "class_a"
+
+        [outputs.relay]
+        udp = '224.0.0.1:9951'
+
+        [outputs.logfile]
+        file = "ais.log"
+        format = "prefix"
+        rotate = "daily"
+
+        [pipelines.class_a]
+        filter = """
+        types:1-3;
+        deny-types:2"""
+        downsample = 30_000
+        "#,
+    )
+    .unwrap();
+
+    // inputs are started in name order
+    let names: Vec<&str> = topology.tasks.iter().map(|t| t.input()).collect();
+    assert_eq!(names, ["archive", "receivers", "replay"]);
+
+    match &topology.tasks[0] {
+        Task::Server { path, options, .. } => {
+            assert_eq!(path, &PathBuf::from("ais.log"));
+            assert!(options.rotate.is_some());
+        }
+        task => panic!("expected a server, found {}", task),
+    }
+    match &topology.tasks[1] {
+        Task::Forward {
+            targets, options, ..
+        } => {
+            assert_eq!(targets, &["127.0.0.1:9952", "224.0.0.1:9951"]);
+            assert!(options.dedup.is_some());
+            assert_eq!(options.tags.station.as_deref(), Some("rx1"));
+            assert!(options.filters.contains_key("127.0.0.1:9952"));
+            assert!(options.downsample.contains_key("127.0.0.1:9952"));
+            assert!(!options.filters.contains_key("224.0.0.1:9951"));
+        }
+        task => panic!("expected a forwarder, found {}", task),
+    }
+    match &topology.tasks[2] {
+        Task::Client { options, .. } => {
+            let backup = options.backup.as_ref().unwrap();
+            assert_eq!(backup.dir, PathBuf::from("backups"));
+            assert_eq!(backup.max_age, Some(Duration::from_secs(7 * 24 * 60 * 60)));
+        }
+        task => panic!("expected a client, found {}", task),
+    }
+}
+
+#[test]
+fn test_topology_syntax_errors() {
+    let e = error("[inputs.a]\nudp = \"127.0.0.1:9950\"\noutputs = [\"b\"\n");
+    assert!(e.contains("line 3"), "{}", e);
+
+    let e = error("[inputs.a]\nudp = \"127.0.0.1:9950\"\nudp = \"127.0.0.1:9951\"\n");
+    assert!(e.contains("duplicate key `udp`"), "{}", e);
+
+    // valid TOML which does not describe a topology
+    let e = error("[[inputs]]\nudp = \"127.0.0.1:9950\"\n");
+    assert!(e.contains("inputs: expected a table, found array"), "{}", e);
+
+    let e = error("[inputs.a]\nudp = \"127.0.0.1:9950\"\ntee = 1979-05-27\n");
+    assert!(e.contains("inputs.a.tee: expected true or false"), "{}", e);
+
+    // including serde tables
+    let e =
+        error("[inputs.a]\nfile = \"-\"\ntee = true\n[inputs.a.backup]\nrotation = \"weekly\"\n");
+    assert!(
+        e.contains("inputs.a.backup: unknown rotation 'weekly'"),
+        "{}",
+        e
+    );
+}
+
+#[test]
+fn test_topology_validation_errors() {
+    let outputs = r#"
+        [outputs.udp]
+        udp = "127.0.0.1:9952"
+        [outputs.log]
+        file = "ais.log"
+        "#;
+
+    assert!(error("").contains("no inputs are defined"));
+
+    let e = error(
+        r#"[inputs.a]
+        udp = "127.0.0.1:9950"
+        outputs = ["missing"]"#,
+    );
+    assert!(
+        e.contains("inputs.a.outputs: unknown output 'missing'"),
+        "{}",
+        e
+    );
+
+    let e = error(&format!(
+        "[inputs.a]\nudp = \"127.0.0.1:9950\"\noutputs = [\"udp\", \"log\"]\n{}",
+        outputs
+    ));
+    assert!(e.contains("outputs must all be the same kind"), "{}", e);
+
+    let e = error(&format!(
+        "[inputs.a]\nudp = \"127.0.0.1:9950\"\noutputs = [\"udp\"]\n{}",
+        outputs
+    ));
+    assert!(e.contains("outputs.log: not used by any input"), "{}", e);
+
+    let e = error(
+        r#"[inputs.a]
+        udp = "127.0.0.1:9950"
+        tee = "yes""#,
+    );
+    assert!(e.contains("inputs.a.tee: expected true or false"), "{}", e);
+
+    let e = error(&format!(
+        "[inputs.a]\nudp = [\"127.0.0.1:9950\", \"127.0.0.1:9951\"]\noutputs = [\"log\"]\n{}",
+        outputs
+    ));
+    assert!(
+        e.contains("inputs.a.udp: several addresses are only supported"),
+        "{}",
+        e
+    );
+
+    // options of other roles are rejected
+    let e = error(
+        r#"[inputs.a]
+        udp = "127.0.0.1:9950"
+        framing = "line"
+        tee = true"#,
+    );
+    assert!(e.contains("inputs.a.framing: unknown key"), "{}", e);
+
+    let e = error(
+        r#"[inputs.a]
+        udp = "127.0.0.1:9950"
+        outputs = ["clients"]
+        [outputs.clients]
+        tcp_listen = "127.0.0.1:9953""#,
+    );
+    assert!(e.contains("must listen on a multicast address"), "{}", e);
+
+    let e = error(
+        r#"[inputs.a]
+        udp = "127.0.0.1:9950"
+        outputs = ["b"]
+        [outputs.b]
+        udp = "127.0.0.1:9952"
+        pipeline = "p"
+        [pipelines.p]
+        filter = "types:x""#,
+    );
+    assert!(e.contains("pipelines.p.filter"), "{}", e);
+}
+
+#[test]
+fn test_topology_spawn() {
+    let target = UdpSocket::bind("127.0.0.1:9955").unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let topology = Topology::parse(
+        r#"
+        [inputs.receivers]
+        udp = "127.0.0.1:9954"
+        outputs = ["consumer"]
+
+        [outputs.consumer]
+        udp = "127.0.0.1:9955"
+        pipeline = "class_b"
+
+        [pipelines.class_b]
+        filter = "types:18"
+        "#,
+    )
+    .unwrap();
+    let threads = topology.spawn().unwrap();
+
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for msg in [POSITION, CLASS_B] {
+        sender.send_to(msg.as_bytes(), "127.0.0.1:9954").unwrap();
+    }
+
+    let mut buf = [0u8; 1024];
+    let (c, _) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..c], CLASS_B.as_bytes());
+    for thread in threads {
+        thread.shutdown().unwrap();
+    }
+}
+
+#[test]
+fn test_topology_upstream_options() {
+    let fingerprint = "D0:C8".repeat(16);
+    let topology = Topology::parse(&format!(
+        r#"
+        [inputs.feed]
+        tcp_connect = "ais.example.com:443"
+        http_get = "/stream"
+        http_headers = {{ Authorization = "Bearer ${{AIS_TOKEN}}" }}
+        idle_timeout = 30_000
+        keepalive_retries = 3
+        outputs = ["relay"]
+
+        [inputs.feed.retry]
+        initial = 500
+        max_retries = 10
+
+        [inputs.feed.tls]
+        ca_file = "ca.pem"
+        server_name = "feed.example.com"
+        pins = ["{}"]
+
+        [outputs.relay]
+        udp = "127.0.0.1:9952"
+        "#,
+        fingerprint
+    ))
+    .unwrap();
+    match &topology.tasks[0] {
+        Task::TcpConnect { options, .. } => {
+            assert_eq!(options.tls.ca_file, Some(PathBuf::from("ca.pem")));
+            assert_eq!(options.tls.server_name.as_deref(), Some("feed.example.com"));
+            assert_eq!(options.tls.pins.len(), 1);
+            assert_eq!(options.tls.pins[0][..2], [0xD0, 0xC8]);
+            assert!(!options.tls.insecure);
+            assert_eq!(options.backoff.initial, Duration::from_millis(500));
+            assert_eq!(options.backoff.max, Duration::from_secs(60));
+            assert_eq!(options.backoff.max_retries, Some(10));
+            assert_eq!(options.idle_timeout, Some(Duration::from_secs(30)));
+            let keepalive = options.keepalive.as_ref().unwrap();
+            assert_eq!(keepalive.retries, 3);
+            assert_eq!(keepalive.idle, Duration::from_secs(60));
+            assert_eq!(
+                options.preamble,
+                Preamble::Http(HttpRequest {
+                    path: "/stream".to_string(),
+                    headers: vec![(
+                        "Authorization".to_string(),
+                        "Bearer ${AIS_TOKEN}".to_string()
+                    )],
+                })
+            );
+        }
+        task => panic!("expected a TCP connection, found {}", task),
+    }
+
+    let input = "[inputs.a]\ntcp_connect = \"localhost:9950\"\noutputs = [\"b\"]\n\
+                 [outputs.b]\nudp = \"127.0.0.1:9952\"\n";
+    let e = error(&format!("{}[inputs.a.tls]\npins = [\"D0:C8\"]\n", input));
+    assert!(e.contains("inputs.a.tls.pins"), "{}", e);
+    assert!(e.contains("invalid SHA-256 fingerprint"), "{}", e);
+    let e = error(&format!("{}[inputs.a.tls]\nkey = \"client.key\"\n", input));
+    assert!(e.contains("inputs.a.tls: unknown field `key`"), "{}", e);
+    let e = error(&format!("{}[inputs.a.retry]\nmax_retries = -1\n", input));
+    assert!(e.contains("inputs.a.retry: invalid value"), "{}", e);
+    let e = error(&input.replace("outputs =", "keepalive = 1\noutputs ="));
+    assert!(
+        e.contains("inputs.a.keepalive: expected true or false"),
+        "{}",
+        e
+    );
+
+    let topology =
+        Topology::parse(&input.replace("outputs =", "no_preamble = true\noutputs =")).unwrap();
+    match &topology.tasks[0] {
+        Task::TcpConnect { options, .. } => {
+            assert_eq!(options.preamble, Preamble::None);
+            assert_eq!(options.idle_timeout, None);
+            assert!(options.keepalive.is_none());
+        }
+        task => panic!("expected a TCP connection, found {}", task),
+    }
+    let e = error(&input.replace(
+        "outputs =",
+        "preamble = \"LOGIN\"\nhttp_get = \"/\"\noutputs =",
+    ));
+    assert!(
+        e.contains("inputs.a: expected at most one of preamble"),
+        "{}",
+        e
+    );
+    let e = error(&input.replace("outputs =", "http_headers = { A = \"b\" }\noutputs ="));
+    assert!(
+        e.contains("inputs.a.http_headers: requires http_get"),
+        "{}",
+        e
+    );
+}
+
+#[test]
+fn test_topology_shared_downsample_pipeline() {
+    let targets: Vec<UdpSocket> = ["127.0.0.1:9957", "127.0.0.1:9958"]
+        .iter()
+        .map(|addr| {
+            let target = UdpSocket::bind(addr).unwrap();
+            target
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            target
+        })
+        .collect();
+    let topology = Topology::parse(
+        r#"
+        [inputs.receivers]
+        udp = "127.0.0.1:9956"
+        outputs = ["first", "second"]
+
+        [outputs.first]
+        udp = "127.0.0.1:9957"
+        pipeline = "slow"
+
+        [outputs.second]
+        udp = "127.0.0.1:9958"
+        pipeline = "slow"
+
+        [pipelines.slow]
+        downsample = 60_000
+        "#,
+    )
+    .unwrap();
+    let threads = topology.spawn().unwrap();
+
+    // each output is limited separately, so both receive the first report
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for _ in 0..2 {
+        sender
+            .send_to(POSITION.as_bytes(), "127.0.0.1:9956")
+            .unwrap();
+    }
+    let mut buf = [0u8; 1024];
+    for target in &targets {
+        let (c, _) = target.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..c], POSITION.as_bytes());
+        assert!(target.recv_from(&mut buf).is_err());
+    }
+    for thread in threads {
+        thread.shutdown().unwrap();
+    }
+}
+
+#[test]
+fn test_topology_gateway_shared_dedup() {
+    let target = UdpSocket::bind("127.0.0.1:9935").unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let topology = Topology::parse(
+        r#"
+        [inputs.receivers]
+        udp = ["127.0.0.1:9933", "127.0.0.1:9934"]
+        outputs = ["partner"]
+        dedup = true
+
+        [outputs.partner]
+        udp = "127.0.0.1:9935"
+        "#,
+    )
+    .unwrap();
+    let threads = topology.spawn().unwrap();
+    assert_eq!(threads.len(), 2);
+
+    // the same sentence heard on both listeners is forwarded once
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    for addr in ["127.0.0.1:9933", "127.0.0.1:9934"] {
+        sender.send_to(POSITION.as_bytes(), addr).unwrap();
+    }
+    let mut buf = [0u8; 1024];
+    let (c, _) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..c], POSITION.as_bytes());
+    assert!(target.recv_from(&mut buf).is_err());
+    for thread in threads {
+        thread.shutdown().unwrap();
+    }
+}
@@ -14,28 +14,111 @@
 //!
 //! Example `src/main.rs`
 //! ```rust,no_run
-//! use std::thread::JoinHandle;
-//!
-//! use mproxy_forward::{forward_udp, proxy_tcp_udp};
+//! use mproxy_forward::{forward_udp, proxy_tcp_udp, ShutdownHandle};
 //!
 //! let udp_listen_addr: String ="[ff02::1]:9920".into();
 //! let udp_downstream_addrs = vec!["[::1]:9921".into(), "localhost:9922".into()];
 //! let tcp_connect_addr: String = "localhost:9925".into();
 //! let tee = true;  // copy input to stdout
 //!
-//! let mut threads: Vec<JoinHandle<()>> = vec![];
+//! let mut threads: Vec<ShutdownHandle> = vec![];
 //!
 //! // spawn UDP socket listener and forward to downstream addresses
-//! threads.push(forward_udp(udp_listen_addr.clone(), &udp_downstream_addrs, tee));
+//! threads.push(forward_udp(udp_listen_addr.clone(), &udp_downstream_addrs, tee).unwrap());
 //!
 //! // connect to TCP upstream, and forward to UDP socket listener
-//! threads.push(proxy_tcp_udp(tcp_connect_addr, udp_listen_addr));
+//! threads.push(proxy_tcp_udp(tcp_connect_addr, udp_listen_addr).unwrap());
 //!
+//! // run until the threads exit. Call thread.shutdown() to stop a thread
 //! for thread in threads {
 //!     thread.join().unwrap();
 //! }
 //! ```
 //!
+//! ## Pipeline Stages
+//! Datagrams may be transformed before forwarding by implementing [Stage],
+//! for every listener with [ForwardOptions::stages] or for individual
+//! downstream addresses with [ForwardOptions::target_stages]
+//! ```rust,no_run
+//! use mproxy_forward::{
+//!     forward_udp_with, DatagramMeta, ForwardOptions, MessageFilter, MproxyError, Pipeline, Stage,
+//! };
+//!
+//! /// Convert each datagram to uppercase
+//! #[derive(Clone)]
+//! struct Uppercase;
+//!
+//! impl Stage for Uppercase {
+//!     fn process(&mut self, data: &[u8], _meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+//!         Ok(vec![data.to_ascii_uppercase()])
+//!     }
+//! }
+//!
+//! let partner = "localhost:9922".to_string();
+//! let options = ForwardOptions {
+//!     stages: Pipeline::new().stage(Uppercase),
+//!     target_stages: [(
+//!         partner.clone(),
+//!         Pipeline::new().stage(MessageFilter::parse("types:1-3").unwrap()),
+//!     )]
+//!     .into(),
+//!     ..Default::default()
+//! };
+//! let downstream_addrs = vec!["[::1]:9921".into(), partner];
+//! let thread = forward_udp_with("0.0.0.0:9920".into(), &downstream_addrs, &options).unwrap();
+//! thread.join().unwrap();
+//! ```
+//!
+//! ## Upstream Options
+//! Connections to TCP upstreams may send a login line or an HTTP request
+//! before forwarding, and are retried with exponential backoff, also when
+//! the upstream stops sending. With
+//! feature `tls`, they may also trust a private CA, authenticate with a
+//! client certificate, and pin the server certificate
+//! ```rust,no_run
+//! use std::time::Duration;
+//!
+//! use mproxy_forward::{
+//!     parse_fingerprint, proxy_tcp_udp_with, BackoffOptions, HttpRequest, KeepaliveOptions,
+//!     Preamble, TlsOptions, UpstreamOptions, UpstreamState,
+//! };
+//!
+//! let options = UpstreamOptions {
+//!     tls: TlsOptions {
+//!         ca_file: Some("ca.pem".into()),
+//!         client_cert: Some("client.pem".into()),
+//!         client_key: Some("client.key".into()),
+//!         server_name: Some("ais.example.com".into()),
+//!         pins: vec![parse_fingerprint(
+//!             "D0:C8:07:65:FE:99:8B:67:47:2B:6C:18:9D:4D:AC:4E:56:54:37:F4:33:0E:94:CE:0F:13:08:1E:5E:28:C9:4A",
+//!         )
+//!         .unwrap()],
+//!         insecure: false,
+//!     },
+//!     // credentials are read from environment variable AIS_TOKEN
+//!     preamble: Preamble::Http(HttpRequest {
+//!         path: "/stream".into(),
+//!         headers: vec![("Authorization".into(), "Bearer ${AIS_TOKEN}".into())],
+//!     }),
+//!     // reconnect after 1, 2, 4, ... seconds, up to 5 minutes
+//!     backoff: BackoffOptions {
+//!         max: Duration::from_secs(300),
+//!         ..Default::default()
+//!     },
+//!     // reconnect if the feed stops sending for 30 seconds
+//!     idle_timeout: Some(Duration::from_secs(30)),
+//!     keepalive: Some(KeepaliveOptions::default()),
+//! };
+//! let thread = proxy_tcp_udp_with("10.0.0.5:9925".into(), "[::1]:9921".into(), &options).unwrap();
+//!
+//! // e.g. alarm if the upstream is down
+//! let status = thread.status();
+//! if status.state != UpstreamState::Connected {
+//!     eprintln!("upstream is {}: {:?}", status.state, status.last_error);
+//! }
+//! thread.join().unwrap();
+//! ```
+//!
 //! ## Command Line Interface
 //! Install with cargo
 //! ```bash
@@ -54,11 +137,96 @@
 //!   --udp-listen-addr     [HOSTNAME:PORT]     UDP listening socket address. May be repeated
 //!   --udp-downstream-addr [HOSTNAME:PORT]     UDP downstream socket address. May be repeated
 //!   --tcp-connect-addr    [HOSTNAME:PORT]     Connect to TCP host, forwarding stream. May be repeated
+//!   --pcap                [FILE]              Also record datagrams received by the UDP listeners in a
+//!                                             pcapng capture, e.g. for inspection with Wireshark
 //!
 //! FLAGS:
 //!   -h, --help    Prints help information
 //!   -t, --tee     Copy input to stdout
 //!
+//! NMEA OPTIONS:
+//!   --drop-corrupt                     Drop lines which are not valid NMEA sentences, checked by
+//!                                      *hh checksum
+//!   --quarantine             [FILE]    Append lines which are not valid NMEA sentences to FILE,
+//!                                      instead of forwarding them
+//!   --reassemble                       Hold the fragments of multi-sentence AIS messages (e.g. type
+//!                                      5) from each source until complete, then pass them on together
+//!   --reassembly-timeout     [MILLIS]  Discard incomplete messages after this long. Default 2000
+//!   --reassembly-max-pending [COUNT]   Hold at most this many incomplete messages. Default 1024
+//!   --dedup                            Drop repeated copies of AIS messages received by any UDP
+//!                                      listener, e.g. the same transmission heard by several receivers
+//!   --dedup-window           [MILLIS]  Drop copies received within this long of the first. Default 2000
+//!   --dedup-max-entries      [COUNT]   Remember at most this many recent messages. Default 65536
+//!
+//! TAG BLOCK OPTIONS:
+//!   --tag-station [STATION]  Add an NMEA 4.10 tag block source station 's:STATION' to sentences
+//!                            without one. May be repeated, once for each --udp-listen-addr in order
+//!   --tag-time               Add a tag block receive time 'c:EPOCH' to sentences without one
+//!   --strip-tags             Remove tag blocks before forwarding, e.g. for consumers which do not
+//!                            support them
+//!
+//! FILTER OPTIONS:
+//!   --filter [ADDR=RULES]  Forward only AIS messages matching RULES to downstream address ADDR.
+//!                          May be repeated. Lines which are not AIS messages are not forwarded
+//!                          to ADDR. RULES are separated by ';'
+//!     types:LIST                            Message types or ranges, e.g. types:1-3,18
+//!     deny-types:LIST                       Drop message types or ranges
+//!     mmsi-allow:FILE                       MMSIs separated by whitespace or commas
+//!     mmsi-deny:FILE                        Drop MMSIs listed in FILE
+//!     bbox:MIN_LON,MIN_LAT,MAX_LON,MAX_LAT  Geofence by bounding box
+//!     polygon:LON LAT,LON LAT,...           Geofence by polygon
+//!   Static messages pass the geofence if the vessel's last position was inside it,
+//!   within the past hour
+//!
+//! DOWNSAMPLING OPTIONS:
+//!   --downsample [ADDR=MILLIS]  Forward at most one position report per vessel per MILLIS to
+//!                               downstream address ADDR, after any --filter. May be repeated.
+//!                               Static and voyage data and other messages are always forwarded
+//!
+//! PREAMBLE OPTIONS:
+//!   Sent to --tcp-connect-addr upstreams after connecting. By default nothing is sent, or with
+//!   crate feature tls an HTTP GET request for /, as in earlier versions.
+//!   ${NAME} in a preamble or header value is replaced by environment variable NAME
+//!   --preamble      [TEXT]          Send TEXT followed by CRLF, e.g. a login line
+//!   --preamble-file [FILE]          Send the contents of FILE
+//!   --http-get      [PATH]          Send an HTTP GET request for PATH, and forward the response body
+//!                                   if the response status is 2xx
+//!   --http-header   [NAME: VALUE]   Add a header to the --http-get request. May be repeated
+//!   --no-preamble                   Send nothing, e.g. to a raw NMEA feed over TLS
+//!
+//! RETRY OPTIONS:
+//!   Reconnecting to --tcp-connect-addr upstreams after a failure or disconnect
+//!   Failures are counted as consecutive until data is received from a connection
+//!   --retry-initial [MILLIS]    Delay after the first failure. Default 1000
+//!   --retry-max     [MILLIS]    Longest delay, doubling after each consecutive failure. Default 60000
+//!   --retry-jitter  [FRACTION]  Reduce each delay by a random fraction of up to FRACTION, from 0 to 1.
+//!                               Default 0.2
+//!   --max-retries   [COUNT]     Give up after COUNT consecutive failures. Default is to retry forever
+//!
+//! LIVENESS OPTIONS:
+//!   Detecting --tcp-connect-addr upstreams which stay connected but stop sending
+//!   --idle-timeout       [MILLIS]  Reconnect if no data is received for MILLIS. Default is to wait
+//!                                  forever
+//!   --keepalive                    Enable TCP keepalive probes. Linux and macOS only
+//!   --keepalive-idle     [SECS]    Idle time before the first probe. Default 60
+//!   --keepalive-interval [SECS]    Time between unanswered probes. Default 10
+//!   --keepalive-retries  [COUNT]   Drop the connection after COUNT unanswered probes. Default 5
+//!
+//! TLS OPTIONS:
+//!   Apply to --tcp-connect-addr. Require crate feature tls
+//!   --tls-ca-file     [FILE]         PEM bundle of CA certificates to trust instead of the built-in
+//!                                    web PKI roots
+//!   --tls-cert        [FILE]         PEM client certificate chain, for mutual TLS
+//!   --tls-key         [FILE]         PEM private key of --tls-cert, in PKCS#8, PKCS#1 or SEC1
+//!                                    format. Encrypted keys are not supported
+//!   --tls-server-name [NAME]         Name to send in SNI and verify the server certificate against.
+//!                                    Default is the host of --tcp-connect-addr, which must be a
+//!                                    DNS name unless this is set
+//!   --tls-pin-sha256  [FINGERPRINT]  Accept only a server certificate with this SHA-256 fingerprint,
+//!                                    in hex with optional colons. May be repeated
+//!   --tls-insecure                   Skip server certificate verification, except for pins.
+//!                                    For lab use only
+//!
 //! EXAMPLE:
 //!   mproxy-forward --udp-listen-addr '0.0.0.0:9920' \
 //!     --udp-downstream-addr '[::1]:9921' \
@@ -74,64 +242,182 @@
 //! - [mproxy-reverse](https://docs.rs/mproxy-reverse/)
 //!
 
-use std::io::{stdout, BufWriter, Read, Write};
-use std::net::{SocketAddr, TcpStream, UdpSocket};
-use std::thread::{spawn, Builder, JoinHandle};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+mod preamble;
+use preamble::Handshake;
+pub use preamble::{HttpRequest, Preamble};
+mod tls;
+#[cfg(feature = "tls")]
+use tls::TlsConnector;
+mod upstream;
+pub use tls::{parse_fingerprint, TlsOptions};
+#[cfg(feature = "tls")]
+pub use tls::{tls_connection, tls_connection_with};
+pub use upstream::{
+    BackoffOptions, FailureKind, KeepaliveOptions, UpstreamFailure, UpstreamHandle, UpstreamState,
+    UpstreamStatus,
+};
+use upstream::{IdleSource, StatusMonitor, StatusSource};
+
+use mproxy_common::{
+    is_shutdown, resolve_socket_addr, sleep_unless_shutdown, NmeaFilter, Reassembler,
+    SHUTDOWN_POLL_INTERVAL,
+};
+#[cfg(feature = "tls")]
+pub use mproxy_common::{load_certs, load_key};
+pub use mproxy_common::{
+    pump, DatagramMeta, DedupOptions, Deduplicator, Downsampler, FileSource, Geofence,
+    MessageFilter, MproxyError, PcapSink, Pipeline, PipelineSink, ReassemblyOptions, Received,
+    ShutdownHandle, Sink, Source, Stage, StreamSource, TagOptions, TcpFanoutSink, TcpListenSource,
+    UdpSink, UdpSource, Validation, WriterSink,
+};
+#[cfg(feature = "tls")]
+use rustls::StreamOwned;
+
+/// Time allowed for a TCP upstream to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time allowed for a TCP upstream to complete a TLS handshake, or to
+/// respond to the preamble
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-use mproxy_client::target_socket_interface;
-use mproxy_server::upstream_socket_interface;
+/// Options for [proxy_tcp_udp_with]
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamOptions {
+    /// Verification and authentication of TLS connections
+    pub tls: TlsOptions,
+    /// Data sent after connecting, e.g. a login line or an HTTP request
+    pub preamble: Preamble,
+    /// Delays between attempts to reconnect
+    pub backoff: BackoffOptions,
+    /// Reconnect if no data is received for this long, e.g. from a feed
+    /// which stays connected but stops sending. Never if `None`
+    pub idle_timeout: Option<Duration>,
+    /// Enable TCP keepalive probes on upstream connections
+    pub keepalive: Option<KeepaliveOptions>,
+}
+
+/// Options for [forward_udp_with] and [proxy_gateway_with]
+#[derive(Clone, Debug, Default)]
+pub struct ForwardOptions {
+    /// Copy input to stdout
+    pub tee: bool,
+    /// Also record received datagrams in a pcapng capture
+    pub pcap: Option<PcapSink>,
+    /// Drop or quarantine corrupt NMEA sentences instead of forwarding them
+    pub validation: Validation,
+    /// Hold the fragments of multi-sentence AIS messages from each source
+    /// address until complete, then forward them in a single datagram
+    pub reassembly: Option<ReassemblyOptions>,
+    /// Drop repeated copies of AIS messages. The deduplicator is shared by
+    /// all listeners using these options, e.g. each listener of a
+    /// [proxy_gateway_with] gateway
+    pub dedup: Option<Deduplicator>,
+    /// Add a station identifier and receive time to NMEA tag blocks, or
+    /// strip tag blocks before forwarding
+    pub tags: TagOptions,
+    /// Filters for individual downstream addresses, keyed by the address
+    /// as given in `downstream_addrs`. Other addresses receive all input.
+    /// Geofence positions are shared by all listeners using these options
+    pub filters: HashMap<String, MessageFilter>,
+    /// Rate limits on position reports for individual downstream addresses,
+    /// applied after filters. Each address is limited independently, across
+    /// all listeners using these options, e.g. each listener of a
+    /// [proxy_gateway_with] gateway
+    pub downsample: HashMap<String, Downsampler>,
+    /// Additional stages applied to each received datagram after the
+    /// built-in options above, before it is sent to any downstream address.
+    /// Each listener runs its own clone of the pipeline
+    pub stages: Pipeline,
+    /// Additional stages for individual downstream addresses, applied after
+    /// the address' filter and rate limit
+    pub target_stages: HashMap<String, Pipeline>,
+}
 
-const BUFSIZE: usize = 8096;
+impl ForwardOptions {
+    /// Stages applied to each datagram received by a listener
+    fn listener_pipeline(&self) -> Result<Pipeline, MproxyError> {
+        let mut pipeline = Pipeline::new().stage(NmeaFilter::new(&self.validation)?);
+        if !self.tags.is_passthrough() {
+            pipeline.push(Box::new(self.tags.clone()));
+        }
+        if let Some(reassembly) = &self.reassembly {
+            pipeline.push(Box::new(Reassembler::new(reassembly)));
+        }
+        if let Some(dedup) = &self.dedup {
+            pipeline.push(Box::new(dedup.clone()));
+        }
+        pipeline.extend(&self.stages);
+        Ok(pipeline)
+    }
+
+    /// Stages applied to datagrams sent to downstream address `target`
+    fn target_pipeline(&self, target: &str) -> Pipeline {
+        let mut pipeline = Pipeline::new();
+        if let Some(filter) = self.filters.get(target) {
+            pipeline.push(Box::new(filter.clone()));
+        }
+        if let Some(downsampler) = self.downsample.get(target) {
+            pipeline.push(Box::new(downsampler.clone()));
+        }
+        if let Some(stages) = self.target_stages.get(target) {
+            pipeline.extend(stages);
+        }
+        pipeline
+    }
+}
 
 /// Forward UDP upstream `listen_addr` to downstream UDP socket addresses.
 /// `listen_addr` may be a multicast address.
-pub fn forward_udp(listen_addr: String, downstream_addrs: &[String], tee: bool) -> JoinHandle<()> {
-    let (_addr, listen_socket) =
-        upstream_socket_interface(listen_addr).expect("binding server socket listener");
-    let mut output_buffer = BufWriter::new(stdout());
-    let targets: Vec<(SocketAddr, UdpSocket)> = downstream_addrs
-        .iter()
-        .map(|t| target_socket_interface(t).expect("binding client socket sender"))
-        .collect();
-    let mut buf = [0u8; BUFSIZE]; // receive buffer
-    Builder::new()
-        .name(format!("{:#?}", listen_socket))
-        .spawn(move || {
-            //listen_socket.read_timeout().unwrap();
-            listen_socket.set_broadcast(true).unwrap();
-            loop {
-                match listen_socket.recv_from(&mut buf[0..]) {
-                    Ok((c, _remote_addr)) => {
-                        for (target_addr, target_socket) in &targets {
-                            if !(target_addr.is_ipv6() && target_addr.ip().is_multicast()) {
-                                target_socket
-                                    .send_to(&buf[0..c], target_addr)
-                                    .unwrap_or_else(|e| panic!("sending to server socket: {}", e));
-                            } else {
-                                target_socket
-                                    .send(&buf[0..c])
-                                    .unwrap_or_else(|e| panic!("sending to server socket: {}", e));
-                            }
-                        }
-                        if tee {
-                            let _o = output_buffer
-                                .write(&buf[0..c])
-                                .expect("writing to output buffer");
-                            #[cfg(debug_assertions)]
-                            assert!(c == _o);
-                        }
-                    }
-                    Err(err) => {
-                        //output_buffer.flush().unwrap();
-                        eprintln!("forward_udp: got an error: {}", err);
-                        #[cfg(debug_assertions)]
-                        panic!("forward_udp: got an error: {}", err);
-                    }
-                }
-                output_buffer.flush().unwrap();
-            }
-        })
-        .unwrap()
+pub fn forward_udp(
+    listen_addr: String,
+    downstream_addrs: &[String],
+    tee: bool,
+) -> Result<ShutdownHandle, MproxyError> {
+    let options = ForwardOptions {
+        tee,
+        ..Default::default()
+    };
+    forward_udp_with(listen_addr, downstream_addrs, &options)
+}
+
+/// Forward UDP upstream `listen_addr` to downstream UDP socket addresses,
+/// as [forward_udp] with additional options
+pub fn forward_udp_with(
+    listen_addr: String,
+    downstream_addrs: &[String],
+    options: &ForwardOptions,
+) -> Result<ShutdownHandle, MproxyError> {
+    let mut outputs: Vec<Box<dyn Sink>> = vec![];
+    for target in downstream_addrs {
+        let sink = UdpSink::connect(target)?;
+        outputs.push(Box::new(PipelineSink::new(
+            options.target_pipeline(target),
+            vec![Box::new(sink)],
+        )));
+    }
+    if options.tee {
+        outputs.push(Box::new(WriterSink::stdout()));
+    }
+
+    // captures contain the unmodified input
+    let mut sinks: Vec<Box<dyn Sink>> = vec![];
+    if let Some(pcap) = &options.pcap {
+        sinks.push(Box::new(pcap.clone()));
+    }
+    sinks.push(Box::new(PipelineSink::new(
+        options.listener_pipeline()?,
+        outputs,
+    )));
+
+    let mut source = UdpSource::bind(listen_addr)?;
+    ShutdownHandle::spawn(format!("{}:forward_udp", source.addr()), move |shutdown| {
+        pump(&mut source, &mut sinks, &shutdown)
+    })
 }
 
 /// Wrapper for forward_udp listening on multiple upstream addresses
@@ -139,24 +425,66 @@ pub fn proxy_gateway(
     downstream_addrs: &[String],
     listen_addrs: &[String],
     tee: bool,
-) -> Vec<JoinHandle<()>> {
-    let mut threads: Vec<JoinHandle<()>> = vec![];
+) -> Result<Vec<ShutdownHandle>, MproxyError> {
+    let options = ForwardOptions {
+        tee,
+        ..Default::default()
+    };
+    proxy_gateway_with(downstream_addrs, listen_addrs, &options)
+}
+
+/// Wrapper for [forward_udp_with] listening on multiple upstream addresses
+pub fn proxy_gateway_with(
+    downstream_addrs: &[String],
+    listen_addrs: &[String],
+    options: &ForwardOptions,
+) -> Result<Vec<ShutdownHandle>, MproxyError> {
+    let mut threads: Vec<ShutdownHandle> = vec![];
     for listen_addr in listen_addrs {
         #[cfg(debug_assertions)]
         println!(
             "proxy: forwarding {:?} -> {:?}",
             listen_addr, downstream_addrs
         );
-        threads.push(forward_udp(listen_addr.to_string(), downstream_addrs, tee));
+        threads.push(forward_udp_with(
+            listen_addr.to_string(),
+            downstream_addrs,
+            options,
+        )?);
     }
-    threads
+    Ok(threads)
 }
 
 /// Connect to TCP upstream server, and forward received bytes to a
 /// downstream UDP socket socket address.
 /// TLS can be enabled with feature `tls` (provided by crate `rustls`).
-pub fn proxy_tcp_udp(upstream_tcp: String, downstream_udp: String) -> JoinHandle<()> {
-    let mut buf = [0u8; BUFSIZE];
+///
+/// Connection failures are retried with the default [BackoffOptions]
+/// until the returned handle is shut down.
+pub fn proxy_tcp_udp(
+    upstream_tcp: String,
+    downstream_udp: String,
+) -> Result<ShutdownHandle, MproxyError> {
+    proxy_tcp_udp_with(upstream_tcp, downstream_udp, &UpstreamOptions::default()).map(Into::into)
+}
+
+/// Connect to TCP upstream server, and forward received bytes to a
+/// downstream UDP socket address, as [proxy_tcp_udp].
+/// Certificates, keys, and preamble variables are loaded before the thread
+/// is spawned, so that errors are returned immediately rather than retried.
+///
+/// Failures are retried according to [UpstreamOptions::backoff], including
+/// stalls detected by [UpstreamOptions::idle_timeout]. The returned handle
+/// reports the state of the connection
+pub fn proxy_tcp_udp_with(
+    upstream_tcp: String,
+    downstream_udp: String,
+    options: &UpstreamOptions,
+) -> Result<UpstreamHandle, MproxyError> {
+    options.backoff.validate()?;
+    let connector = Connector::new(&upstream_tcp, options)?;
+    let backoff = options.backoff.clone();
+    let monitor = StatusMonitor::new();
 
     #[cfg(debug_assertions)]
     println!(
@@ -164,125 +492,137 @@ pub fn proxy_tcp_udp(upstream_tcp: String, downstream_udp: String) -> JoinHandle
         upstream_tcp, downstream_udp
     );
 
-    spawn(move || loop {
-        let target = target_socket_interface(&downstream_udp);
-
-        let (target_addr, target_socket) = if let Ok((target_addr, target_socket)) = target {
-            (target_addr, target_socket)
-        } else {
-            println!("Retrying...");
-            std::thread::sleep(std::time::Duration::from_secs(5));
-            continue;
-        };
-
-        #[cfg(feature = "tls")]
-        let (mut conn, mut stream) =
-            if let Ok((conn, stream)) = tls_connection(upstream_tcp.clone()) {
-                (conn, stream)
-            } else {
-                println!("Retrying...");
-                std::thread::sleep(std::time::Duration::from_secs(5));
-                continue;
-            };
-        #[cfg(feature = "tls")]
-        let mut stream = TlsStream::new(&mut conn, &mut stream);
-        #[cfg(not(feature = "tls"))]
-        let stream = TcpStream::connect(upstream_tcp.clone());
-        #[cfg(not(feature = "tls"))]
-        let mut stream = if let Ok(s) = stream {
-            s
-        } else {
-            println!("Retrying...");
-            std::thread::sleep(std::time::Duration::from_secs(5));
-            continue;
-        };
-
-        loop {
-            match stream.read(&mut buf[0..]) {
-                Ok(c) => {
-                    if c == 0 {
-                        eprintln!("encountered EOF, disconnecting TCP proxy thread...");
-                        break;
-                    }
-                    if !(target_addr.is_ipv6() && target_addr.ip().is_multicast()) {
-                        target_socket
-                            .send_to(&buf[0..c], target_addr)
-                            .expect("sending to UDP socket");
-                    } else {
-                        target_socket
-                            .send(&buf[0..c])
-                            .expect("sending to UDP socket");
+    let name = format!("{}:proxy_tcp_udp", upstream_tcp);
+    let status = monitor.clone();
+    let handle = ShutdownHandle::spawn(name, move |shutdown| {
+        let result = loop {
+            status.connecting();
+            let connected = UdpSink::connect(&downstream_udp)
+                .and_then(|sink| Ok((sink, connector.connect(&upstream_tcp)?)));
+            let e = match connected {
+                Ok((sink, source)) => {
+                    status.connected();
+                    let mut source = StatusSource::new(source, status.clone());
+                    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(sink)];
+                    match pump(&mut source, &mut sinks, &shutdown) {
+                        Ok(()) => MproxyError::Recv {
+                            addr: upstream_tcp.clone(),
+                            source: io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "connection closed by upstream",
+                            ),
+                        },
+                        Err(e) => e,
                     }
                 }
-                Err(e) => {
-                    eprintln!("err: {}", e);
-                    break;
-                }
+                Err(e) => e,
+            };
+            if is_shutdown(&shutdown) {
+                break Ok(());
+            }
+            let failures = status.failed(&e);
+            if backoff.max_retries.is_some_and(|max| failures > max) {
+                eprintln!("{}, giving up after {} attempts", e, failures);
+                break Err(e);
             }
+            let delay = backoff.delay(failures);
+            eprintln!("{}, retrying in {:.1}s", e, delay.as_secs_f64());
+            status.backoff(delay);
+            if sleep_unless_shutdown(&shutdown, delay) {
+                break Ok(());
+            }
+        };
+        status.stopped();
+        result
+    })?;
+    Ok(UpstreamHandle::new(handle, monitor))
+}
+
+/// Resolve `addr` and open a TCP connection to it
+pub(crate) fn connect_tcp(addr: &str) -> Result<TcpStream, MproxyError> {
+    let socket_addr = resolve_socket_addr(addr)?;
+    TcpStream::connect_timeout(&socket_addr, CONNECT_TIMEOUT).map_err(|source| {
+        MproxyError::Connect {
+            addr: addr.to_string(),
+            source,
         }
-        println!("Retrying...");
-        std::thread::sleep(std::time::Duration::from_secs(5))
     })
 }
 
-#[cfg(feature = "tls")]
-use rustls::client::{ClientConfig, ClientConnection, ServerName};
-#[cfg(feature = "tls")]
-use rustls::Stream as TlsStream;
-#[cfg(feature = "tls")]
-use std::sync::Arc;
-#[cfg(feature = "tls")]
-use webpki_roots::TLS_SERVER_ROOTS;
+/// Opens connections to the upstream of [proxy_tcp_udp_with], and sends
+/// the preamble
+#[derive(Clone)]
+struct Connector {
+    #[cfg(feature = "tls")]
+    tls: TlsConnector,
+    handshake: Handshake,
+    idle_timeout: Option<Duration>,
+    keepalive: Option<KeepaliveOptions>,
+}
 
-#[cfg(feature = "tls")]
-pub fn tls_connection(
-    tls_connect_addr: String,
-) -> Result<(ClientConnection, TcpStream), Box<dyn std::error::Error>> {
-    let mut root_store = rustls::RootCertStore::empty();
-    root_store.add_server_trust_anchors(TLS_SERVER_ROOTS.0.iter().map(|ta| {
-        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-            ta.subject,
-            ta.spki,
-            ta.name_constraints,
+impl Connector {
+    fn new(upstream_tcp: &str, options: &UpstreamOptions) -> Result<Self, MproxyError> {
+        #[cfg(not(feature = "tls"))]
+        if options.tls != TlsOptions::default() {
+            return Err(MproxyError::Config(
+                "TLS options require crate feature tls".to_string(),
+            ));
+        }
+        if options.idle_timeout == Some(Duration::ZERO) {
+            return Err(MproxyError::Config(
+                "idle timeout must be greater than zero".to_string(),
+            ));
+        }
+        if let Some(keepalive) = &options.keepalive {
+            keepalive.validate()?;
+        }
+        Ok(Connector {
+            #[cfg(feature = "tls")]
+            tls: TlsConnector::new(upstream_tcp, &options.tls)?,
+            handshake: Handshake::new(&options.preamble, upstream_tcp)?,
+            idle_timeout: options.idle_timeout,
+            keepalive: options.keepalive.clone(),
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn connect(&self, upstream_tcp: &str) -> Result<Box<dyn Source>, MproxyError> {
+        let sock = connect_tcp(upstream_tcp)?;
+        self.start(sock.try_clone()?, sock, upstream_tcp)
+    }
+
+    #[cfg(feature = "tls")]
+    fn connect(&self, upstream_tcp: &str) -> Result<Box<dyn Source>, MproxyError> {
+        let (conn, sock) = self.tls.connect(upstream_tcp)?;
+        self.start(
+            sock.try_clone()?,
+            StreamOwned::new(conn, sock),
+            upstream_tcp,
         )
-    }));
-    let config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
-    let rc_config: Arc<ClientConfig> = Arc::new(config);
-    let dns_name: String = tls_connect_addr.split(':').next().unwrap().to_string();
-    let server_name = ServerName::try_from(dns_name.as_str());
-    let server_name = if let Ok(name) = server_name {
-        name
-    } else {
-        return Err(format!("Resolving DNS for {}", dns_name).into());
-    };
-    let conn = rustls::ClientConnection::new(rc_config, server_name);
-    let mut conn = if let Ok(c) = conn {
-        c
-    } else {
-        return Err("Performing handshake".into());
-    };
-    let sock = TcpStream::connect(tls_connect_addr.clone());
-    let sock = if let Ok(s) = sock {
-        s
-    } else {
-        return Err(format!("Connecting to {}", tls_connect_addr).into());
-    };
-    sock.set_nodelay(true).unwrap();
+    }
 
-    // request tls
-    let request = format!(
-        "GET / HTTP/1.1\r\n\
-         Host: {}\r\n\
-         Connection: close\r\n\
-         Accept-Encoding: identity\r\n\
-         \r\n",
-        tls_connect_addr
-    );
-    if let Some(mut early_data) = conn.early_data() {
-        early_data.write_all(request.as_bytes()).unwrap();
+    /// Send the preamble on `stream`, connected by socket `sock`
+    fn start<S: Read + Write + Send + 'static>(
+        &self,
+        sock: TcpStream,
+        stream: S,
+        upstream_tcp: &str,
+    ) -> Result<Box<dyn Source>, MproxyError> {
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.apply(&sock)?;
+        }
+        sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let reader = self.handshake.start(stream, upstream_tcp)?;
+        sock.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+        let (source, local) = (sock.peer_addr()?, sock.local_addr()?);
+        let source = StreamSource::new(reader, upstream_tcp.to_string()).with_addrs(source, local);
+        Ok(match self.idle_timeout {
+            Some(timeout) => Box::new(IdleSource::new(
+                Box::new(source),
+                upstream_tcp.to_string(),
+                timeout,
+            )),
+            None => Box::new(source),
+        })
     }
-    Ok((conn, sock))
 }
@@ -1,6 +1,13 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 
-use mproxy_forward::{proxy_gateway, proxy_tcp_udp};
+use mproxy_forward::{
+    forward_udp_with, parse_fingerprint, proxy_tcp_udp_with, BackoffOptions, DedupOptions,
+    Deduplicator, Downsampler, ForwardOptions, HttpRequest, KeepaliveOptions, MessageFilter,
+    PcapSink, Preamble, ReassemblyOptions, TagOptions, TlsOptions, UpstreamOptions, Validation,
+};
 
 use pico_args::Arguments;
 
@@ -16,11 +23,96 @@ OPTIONS:
   --udp-listen-addr     [HOSTNAME:PORT]     UDP listening socket address. May be repeated
   --udp-downstream-addr [HOSTNAME:PORT]     UDP downstream socket address. May be repeated
   --tcp-connect-addr    [HOSTNAME:PORT]     Connect to TCP host, forwarding stream. May be repeated
+  --pcap                [FILE]              Also record datagrams received by the UDP listeners in a
+                                            pcapng capture, e.g. for inspection with Wireshark
 
 FLAGS:
   -h, --help    Prints help information
   -t, --tee     Copy input to stdout
 
+NMEA OPTIONS:
+  --drop-corrupt                     Drop lines which are not valid NMEA sentences, checked by
+                                     *hh checksum
+  --quarantine             [FILE]    Append lines which are not valid NMEA sentences to FILE,
+                                     instead of forwarding them
+  --reassemble                       Hold the fragments of multi-sentence AIS messages (e.g. type
+                                     5) from each source until complete, then pass them on together
+  --reassembly-timeout     [MILLIS]  Discard incomplete messages after this long. Default 2000
+  --reassembly-max-pending [COUNT]   Hold at most this many incomplete messages. Default 1024
+  --dedup                            Drop repeated copies of AIS messages received by any UDP
+                                     listener, e.g. the same transmission heard by several receivers
+  --dedup-window           [MILLIS]  Drop copies received within this long of the first. Default 2000
+  --dedup-max-entries      [COUNT]   Remember at most this many recent messages. Default 65536
+
+TAG BLOCK OPTIONS:
+  --tag-station [STATION]  Add an NMEA 4.10 tag block source station 's:STATION' to sentences
+                           without one. May be repeated, once for each --udp-listen-addr in order
+  --tag-time               Add a tag block receive time 'c:EPOCH' to sentences without one
+  --strip-tags             Remove tag blocks before forwarding, e.g. for consumers which do not
+                           support them
+
+FILTER OPTIONS:
+  --filter [ADDR=RULES]  Forward only AIS messages matching RULES to downstream address ADDR.
+                         May be repeated. Lines which are not AIS messages are not forwarded
+                         to ADDR. RULES are separated by ';'
+    types:LIST                            Message types or ranges, e.g. types:1-3,18
+    deny-types:LIST                       Drop message types or ranges
+    mmsi-allow:FILE                       MMSIs separated by whitespace or commas
+    mmsi-deny:FILE                        Drop MMSIs listed in FILE
+    bbox:MIN_LON,MIN_LAT,MAX_LON,MAX_LAT  Geofence by bounding box
+    polygon:LON LAT,LON LAT,...           Geofence by polygon
+  Static messages pass the geofence if the vessel's last position was inside it,
+  within the past hour
+
+DOWNSAMPLING OPTIONS:
+  --downsample [ADDR=MILLIS]  Forward at most one position report per vessel per MILLIS to
+                              downstream address ADDR, after any --filter. May be repeated.
+                              Static and voyage data and other messages are always forwarded
+
+PREAMBLE OPTIONS:
+  Sent to --tcp-connect-addr upstreams after connecting. By default nothing is sent, or with
+  crate feature tls an HTTP GET request for /, as in earlier versions.
+  ${NAME} in a preamble or header value is replaced by environment variable NAME
+  --preamble      [TEXT]          Send TEXT followed by CRLF, e.g. a login line
+  --preamble-file [FILE]          Send the contents of FILE
+  --http-get      [PATH]          Send an HTTP GET request for PATH, and forward the response body
+                                  if the response status is 2xx
+  --http-header   [NAME: VALUE]   Add a header to the --http-get request. May be repeated
+  --no-preamble                   Send nothing, e.g. to a raw NMEA feed over TLS
+
+RETRY OPTIONS:
+  Reconnecting to --tcp-connect-addr upstreams after a failure or disconnect
+  Failures are counted as consecutive until data is received from a connection
+  --retry-initial [MILLIS]    Delay after the first failure. Default 1000
+  --retry-max     [MILLIS]    Longest delay, doubling after each consecutive failure. Default 60000
+  --retry-jitter  [FRACTION]  Reduce each delay by a random fraction of up to FRACTION, from 0 to 1.
+                              Default 0.2
+  --max-retries   [COUNT]     Give up after COUNT consecutive failures. Default is to retry forever
+
+LIVENESS OPTIONS:
+  Detecting --tcp-connect-addr upstreams which stay connected but stop sending
+  --idle-timeout       [MILLIS]  Reconnect if no data is received for MILLIS. Default is to wait
+                                 forever
+  --keepalive                    Enable TCP keepalive probes. Linux and macOS only
+  --keepalive-idle     [SECS]    Idle time before the first probe. Default 60
+  --keepalive-interval [SECS]    Time between unanswered probes. Default 10
+  --keepalive-retries  [COUNT]   Drop the connection after COUNT unanswered probes. Default 5
+
+TLS OPTIONS:
+  Apply to --tcp-connect-addr. Require crate feature tls
+  --tls-ca-file     [FILE]         PEM bundle of CA certificates to trust instead of the built-in
+                                   web PKI roots
+  --tls-cert        [FILE]         PEM client certificate chain, for mutual TLS
+  --tls-key         [FILE]         PEM private key of --tls-cert, in PKCS#8, PKCS#1 or SEC1
+                                   format. Encrypted keys are not supported
+  --tls-server-name [NAME]         Name to send in SNI and verify the server certificate against.
+                                   Default is the host of --tcp-connect-addr, which must be a
+                                   DNS name unless this is set
+  --tls-pin-sha256  [FINGERPRINT]  Accept only a server certificate with this SHA-256 fingerprint,
+                                   in hex with optional colons. May be repeated
+  --tls-insecure                   Skip server certificate verification, except for pins.
+                                   For lab use only
+
 EXAMPLE:
   mproxy-forward --udp-listen-addr '0.0.0.0:9920' \
     --udp-downstream-addr '[::1]:9921' \
@@ -34,9 +126,121 @@ pub struct GatewayArgs {
     udp_listen_addrs: Vec<String>,
     udp_downstream_addrs: Vec<String>,
     tcp_connect_addrs: Vec<String>,
+    pcap: Option<PathBuf>,
+    validation: Validation,
+    reassembly: Option<ReassemblyOptions>,
+    dedup: Option<DedupOptions>,
+    tag_stations: Vec<String>,
+    tags: TagOptions,
+    filters: HashMap<String, MessageFilter>,
+    downsample: HashMap<String, Downsampler>,
+    upstream: UpstreamOptions,
     tee: bool,
 }
 
+/// Parse a downstream address and its filter rules, e.g. `localhost:9921=types:1-3`
+fn parse_filter(arg: &str) -> Result<(String, MessageFilter), String> {
+    let (addr, rules) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected ADDR=RULES, got '{}'", arg))?;
+    let filter = MessageFilter::parse(rules).map_err(|e| e.to_string())?;
+    Ok((addr.to_string(), filter))
+}
+
+/// Parse a downstream address and its downsampling interval, e.g. `localhost:9921=30000`
+fn parse_downsample(arg: &str) -> Result<(String, Downsampler), String> {
+    let (addr, millis) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected ADDR=MILLIS, got '{}'", arg))?;
+    let millis = millis
+        .parse::<u64>()
+        .map_err(|e| format!("invalid interval '{}': {}", millis, e))?;
+    let downsampler = Downsampler::new(Duration::from_millis(millis));
+    Ok((addr.to_string(), downsampler))
+}
+
+/// Parse an HTTP header, e.g. `Authorization: Bearer ${AIS_TOKEN}`
+fn parse_header(arg: &str) -> Result<(String, String), String> {
+    let (name, value) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME: VALUE, got '{}'", arg))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+/// Parse at most one of --preamble, --preamble-file, --http-get, and
+/// --no-preamble
+fn parse_preamble(pargs: &mut Arguments) -> Result<Preamble, pico_args::Error> {
+    let text: Option<String> = pargs.opt_value_from_str("--preamble")?;
+    let file: Option<PathBuf> = pargs.opt_value_from_str("--preamble-file")?;
+    let path: Option<String> = pargs.opt_value_from_str("--http-get")?;
+    let headers = pargs.values_from_fn("--http-header", parse_header)?;
+    let none = pargs.contains("--no-preamble");
+    let preamble = match (text, file, path, none) {
+        (None, None, None, false) => Preamble::default(),
+        (None, None, None, true) => Preamble::None,
+        (Some(text), None, None, false) => Preamble::Text(format!("{}\r\n", text)),
+        (None, Some(file), None, false) => {
+            Preamble::from_file(&file).map_err(|e| pico_args::Error::ArgumentParsingFailed {
+                cause: e.to_string(),
+            })?
+        }
+        (None, None, Some(path), false) => Preamble::Http(HttpRequest {
+            path,
+            headers: vec![],
+        }),
+        _ => {
+            return Err(pico_args::Error::ArgumentParsingFailed {
+                cause: "--preamble, --preamble-file, --http-get, and --no-preamble are exclusive"
+                    .to_string(),
+            })
+        }
+    };
+    match preamble {
+        Preamble::Http(http) => Ok(Preamble::Http(HttpRequest { headers, ..http })),
+        _ if !headers.is_empty() => Err(pico_args::Error::ArgumentParsingFailed {
+            cause: "--http-header requires --http-get".to_string(),
+        }),
+        preamble => Ok(preamble),
+    }
+}
+
+/// Parse the --retry-* options, keeping defaults for those not given
+fn parse_backoff(pargs: &mut Arguments) -> Result<BackoffOptions, pico_args::Error> {
+    let default = BackoffOptions::default();
+    let initial: Option<u64> = pargs.opt_value_from_str("--retry-initial")?;
+    let max: Option<u64> = pargs.opt_value_from_str("--retry-max")?;
+    Ok(BackoffOptions {
+        initial: initial
+            .map(Duration::from_millis)
+            .unwrap_or(default.initial),
+        max: max.map(Duration::from_millis).unwrap_or(default.max),
+        jitter: pargs
+            .opt_value_from_str("--retry-jitter")?
+            .unwrap_or(default.jitter),
+        max_retries: pargs.opt_value_from_str("--max-retries")?,
+        ..default
+    })
+}
+
+/// Parse the --keepalive options. Keepalive is enabled if any are given
+fn parse_keepalive(pargs: &mut Arguments) -> Result<Option<KeepaliveOptions>, pico_args::Error> {
+    let enabled = pargs.contains("--keepalive");
+    let idle: Option<u64> = pargs.opt_value_from_str("--keepalive-idle")?;
+    let interval: Option<u64> = pargs.opt_value_from_str("--keepalive-interval")?;
+    let retries: Option<u32> = pargs.opt_value_from_str("--keepalive-retries")?;
+    if !enabled && idle.is_none() && interval.is_none() && retries.is_none() {
+        return Ok(None);
+    }
+    let default = KeepaliveOptions::default();
+    Ok(Some(KeepaliveOptions {
+        idle: idle.map(Duration::from_secs).unwrap_or(default.idle),
+        interval: interval
+            .map(Duration::from_secs)
+            .unwrap_or(default.interval),
+        retries: retries.unwrap_or(default.retries),
+    }))
+}
+
 fn parse_args() -> Result<GatewayArgs, pico_args::Error> {
     let mut pargs = Arguments::from_env();
     if pargs.contains(["-h", "--help"]) || pargs.clone().finish().is_empty() {
@@ -44,10 +248,81 @@ fn parse_args() -> Result<GatewayArgs, pico_args::Error> {
         exit(0);
     }
 
+    let drop_corrupt = pargs.contains("--drop-corrupt");
+    let validation = match pargs.opt_value_from_str("--quarantine")? {
+        Some(path) => Validation::Quarantine(path),
+        None if drop_corrupt => Validation::Drop,
+        None => Validation::Off,
+    };
+    let reassemble = pargs.contains("--reassemble");
+    let reassembly_timeout: Option<u64> = pargs.opt_value_from_str("--reassembly-timeout")?;
+    let reassembly_max_pending: Option<usize> =
+        pargs.opt_value_from_str("--reassembly-max-pending")?;
+    let reassembly =
+        if reassemble || reassembly_timeout.is_some() || reassembly_max_pending.is_some() {
+            let default = ReassemblyOptions::default();
+            Some(ReassemblyOptions {
+                timeout: reassembly_timeout
+                    .map(Duration::from_millis)
+                    .unwrap_or(default.timeout),
+                max_pending: reassembly_max_pending.unwrap_or(default.max_pending),
+                ..default
+            })
+        } else {
+            None
+        };
+    let dedup = pargs.contains("--dedup");
+    let dedup_window: Option<u64> = pargs.opt_value_from_str("--dedup-window")?;
+    let dedup_max_entries: Option<usize> = pargs.opt_value_from_str("--dedup-max-entries")?;
+    let dedup = if dedup || dedup_window.is_some() || dedup_max_entries.is_some() {
+        let default = DedupOptions::default();
+        Some(DedupOptions {
+            window: dedup_window
+                .map(Duration::from_millis)
+                .unwrap_or(default.window),
+            max_entries: dedup_max_entries.unwrap_or(default.max_entries),
+        })
+    } else {
+        None
+    };
     let args = GatewayArgs {
         udp_listen_addrs: pargs.values_from_str("--udp-listen-addr")?,
         udp_downstream_addrs: pargs.values_from_str("--udp-downstream-addr")?,
         tcp_connect_addrs: pargs.values_from_str("--tcp-connect-addr")?,
+        pcap: pargs.opt_value_from_str("--pcap")?,
+        validation,
+        reassembly,
+        dedup,
+        tag_stations: pargs.values_from_str("--tag-station")?,
+        tags: TagOptions {
+            timestamp: pargs.contains("--tag-time"),
+            strip: pargs.contains("--strip-tags"),
+            ..Default::default()
+        },
+        filters: pargs
+            .values_from_fn("--filter", parse_filter)?
+            .into_iter()
+            .collect(),
+        downsample: pargs
+            .values_from_fn("--downsample", parse_downsample)?
+            .into_iter()
+            .collect(),
+        upstream: UpstreamOptions {
+            tls: TlsOptions {
+                ca_file: pargs.opt_value_from_str("--tls-ca-file")?,
+                client_cert: pargs.opt_value_from_str("--tls-cert")?,
+                client_key: pargs.opt_value_from_str("--tls-key")?,
+                server_name: pargs.opt_value_from_str("--tls-server-name")?,
+                pins: pargs.values_from_fn("--tls-pin-sha256", parse_fingerprint)?,
+                insecure: pargs.contains("--tls-insecure"),
+            },
+            preamble: parse_preamble(&mut pargs)?,
+            backoff: parse_backoff(&mut pargs)?,
+            idle_timeout: pargs
+                .opt_value_from_str("--idle-timeout")?
+                .map(Duration::from_millis),
+            keepalive: parse_keepalive(&mut pargs)?,
+        },
         tee: pargs.contains(["-t", "--tee"]),
     };
 
@@ -70,19 +345,51 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut threads = vec![];
 
     if args.udp_listen_addrs.is_empty() {
-        panic!("Atleast one UDP listen address is required");
+        eprintln!("Error: atleast one UDP listen address is required.");
+        exit(1);
     }
 
     for upstream in args.tcp_connect_addrs {
-        threads.push(proxy_tcp_udp(upstream, args.udp_listen_addrs[0].clone()));
+        threads.push(
+            proxy_tcp_udp_with(upstream, args.udp_listen_addrs[0].clone(), &args.upstream)?.into(),
+        );
     }
 
-    for thread in proxy_gateway(&args.udp_downstream_addrs, &args.udp_listen_addrs, args.tee) {
-        threads.push(thread);
+    let options = ForwardOptions {
+        tee: args.tee,
+        pcap: match &args.pcap {
+            Some(path) => Some(PcapSink::create(path)?),
+            None => None,
+        },
+        validation: args.validation,
+        reassembly: args.reassembly,
+        dedup: args.dedup.as_ref().map(Deduplicator::new),
+        tags: args.tags,
+        filters: args.filters,
+        downsample: args.downsample,
+        ..Default::default()
+    };
+    if args.tag_stations.len() > 1 && args.tag_stations.len() != args.udp_listen_addrs.len() {
+        eprintln!("Error: --tag-station must be given once, or once for each --udp-listen-addr.");
+        exit(1);
+    }
+    for (i, listen_addr) in args.udp_listen_addrs.iter().enumerate() {
+        // each listener may tag sentences with its own station identifier
+        let mut options = options.clone();
+        options.tags.station = args
+            .tag_stations
+            .get(i)
+            .or(args.tag_stations.first())
+            .cloned();
+        threads.push(forward_udp_with(
+            listen_addr.clone(),
+            &args.udp_downstream_addrs,
+            &options,
+        )?);
     }
 
     for thread in threads {
-        thread.join().expect("joining proxy thread");
+        thread.join()?;
     }
     Ok(())
 }
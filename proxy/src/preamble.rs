@@ -0,0 +1,207 @@
+use std::env;
+use std::fs::read_to_string;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+use mproxy_common::MproxyError;
+
+/// Longest HTTP response head accepted before streaming the body
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+/// Data sent to a TCP upstream after connecting, before forwarding
+/// begins. See [UpstreamOptions](crate::UpstreamOptions).
+///
+/// The default is nothing, or with feature `tls` an HTTP GET request for
+/// `/`, as TLS upstreams were always sent by earlier versions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Preamble {
+    /// Send nothing, e.g. for raw NMEA feeds
+    None,
+    /// Send this text, e.g. a login line. `${NAME}` is replaced by the
+    /// value of environment variable `NAME`, so that credentials need not
+    /// be stored in files or command lines
+    Text(String),
+    /// Send an HTTP GET request, and forward the response body if the
+    /// response status is 2xx
+    Http(HttpRequest),
+}
+
+/// HTTP request sent by [Preamble::Http]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HttpRequest {
+    /// Request target, e.g. `/stream?format=nmea`
+    pub path: String,
+    /// Additional headers, e.g. `("Authorization", "Bearer ${AIS_TOKEN}")`.
+    /// Values are expanded as [Preamble::Text]
+    pub headers: Vec<(String, String)>,
+}
+
+impl Default for Preamble {
+    #[cfg(not(feature = "tls"))]
+    fn default() -> Self {
+        Preamble::None
+    }
+
+    #[cfg(feature = "tls")]
+    fn default() -> Self {
+        Preamble::Http(HttpRequest {
+            path: "/".to_string(),
+            headers: vec![],
+        })
+    }
+}
+
+impl Preamble {
+    /// Text preamble read from `path`, sent as is after expanding
+    /// environment variables
+    pub fn from_file(path: &Path) -> Result<Self, MproxyError> {
+        let text = read_to_string(path).map_err(|source| MproxyError::File {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Ok(Preamble::Text(text))
+    }
+}
+
+/// Replace each `${NAME}` in `template` with environment variable `NAME`
+fn expand_env(template: &str) -> Result<String, MproxyError> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| {
+            MproxyError::Config(format!("unterminated '${{' in preamble '{}'", template))
+        })?;
+        let name = &rest[start + 2..start + end];
+        let value = env::var(name)
+            .map_err(|e| MproxyError::Config(format!("preamble variable {}: {}", name, e)))?;
+        expanded.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// A [Preamble] with environment variables expanded, ready to send on
+/// each connection
+#[derive(Clone, Debug)]
+pub(crate) struct Handshake {
+    request: Vec<u8>,
+    http: bool,
+}
+
+impl Handshake {
+    /// Expand `preamble` for connections to `addr`
+    pub(crate) fn new(preamble: &Preamble, addr: &str) -> Result<Self, MproxyError> {
+        match preamble {
+            Preamble::None => Ok(Handshake {
+                request: vec![],
+                http: false,
+            }),
+            Preamble::Text(text) => Ok(Handshake {
+                request: expand_env(text)?.into_bytes(),
+                http: false,
+            }),
+            Preamble::Http(http) => {
+                if !http.path.starts_with('/') {
+                    return Err(MproxyError::Config(format!(
+                        "HTTP request path '{}' must start with '/'",
+                        http.path
+                    )));
+                }
+                // HTTP/1.0, so that the response body is not chunked
+                let mut request = format!(
+                    "GET {} HTTP/1.0\r\n\
+                     Host: {}\r\n\
+                     User-Agent: mproxy-forward/{}\r\n\
+                     Accept-Encoding: identity\r\n",
+                    http.path,
+                    addr,
+                    env!("CARGO_PKG_VERSION")
+                );
+                for (name, value) in &http.headers {
+                    request.push_str(&format!("{}: {}\r\n", name, expand_env(value)?));
+                }
+                request.push_str("\r\n");
+                Ok(Handshake {
+                    request: request.into_bytes(),
+                    http: true,
+                })
+            }
+        }
+    }
+
+    /// Send the preamble on `stream`, and check the response to an HTTP
+    /// request. Returns a reader of the data to forward
+    pub(crate) fn start<S: Read + Write + Send + 'static>(
+        &self,
+        mut stream: S,
+        addr: &str,
+    ) -> Result<Box<dyn Read + Send>, MproxyError> {
+        let handshake_err = |reason: String| MproxyError::Handshake {
+            addr: addr.to_string(),
+            reason,
+        };
+        if !self.request.is_empty() {
+            stream
+                .write_all(&self.request)
+                .and_then(|_| stream.flush())
+                .map_err(|e| handshake_err(format!("sending preamble: {}", e)))?;
+        }
+        if !self.http {
+            return Ok(Box::new(stream));
+        }
+
+        // the body may follow the response head in the same read
+        let mut reader = BufReader::new(stream);
+        let mut head_len = 0;
+        let mut status = None;
+        loop {
+            // read at most one byte past the limit, so an endless line fails
+            // the length check below instead of filling memory
+            let mut line = vec![];
+            let len = (&mut reader)
+                .take((MAX_RESPONSE_HEAD - head_len + 1) as u64)
+                .read_until(b'\n', &mut line)
+                .map_err(|e| handshake_err(format!("reading HTTP response: {}", e)))?;
+            head_len += len;
+            if len == 0 {
+                return Err(handshake_err(
+                    "connection closed in HTTP response".to_string(),
+                ));
+            }
+            if head_len > MAX_RESPONSE_HEAD {
+                return Err(handshake_err("HTTP response head is too long".to_string()));
+            }
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            match status {
+                None => status = Some(line.to_string()),
+                Some(_) => {
+                    let (name, value) = line.split_once(':').unwrap_or((line, ""));
+                    if name.eq_ignore_ascii_case("transfer-encoding")
+                        && !value.trim().eq_ignore_ascii_case("identity")
+                    {
+                        return Err(handshake_err(format!(
+                            "unsupported HTTP transfer encoding '{}'",
+                            value.trim()
+                        )));
+                    }
+                }
+            }
+        }
+        let status = status.unwrap_or_default();
+        match status.split_whitespace().collect::<Vec<_>>()[..] {
+            [version, code, ..] if version.starts_with("HTTP/") && code.starts_with('2') => {
+                Ok(Box::new(reader))
+            }
+            _ => Err(handshake_err(format!(
+                "unexpected HTTP response '{}'",
+                status
+            ))),
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use std::path::PathBuf;
+
+use mproxy_common::MproxyError;
+
+#[cfg(feature = "tls")]
+use crate::{connect_tcp, HANDSHAKE_TIMEOUT};
+#[cfg(feature = "tls")]
+use mproxy_common::{load_certs, load_key};
+
+#[cfg(feature = "tls")]
+use std::net::{IpAddr, SocketAddr, TcpStream};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use std::time::SystemTime;
+
+#[cfg(feature = "tls")]
+use rustls::client::{
+    ClientConfig, ClientConnection, ServerCertVerified, ServerCertVerifier, ServerName,
+    WebPkiVerifier,
+};
+#[cfg(feature = "tls")]
+use rustls::{Certificate, RootCertStore};
+#[cfg(feature = "tls")]
+use webpki_roots::TLS_SERVER_ROOTS;
+
+/// TLS settings for connections made by
+/// [proxy_tcp_udp_with](crate::proxy_tcp_udp_with). Requires crate feature
+/// `tls`. The default trusts the built-in web PKI roots, without a client
+/// certificate
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsOptions {
+    /// PEM bundle of CA certificates to trust instead of the built-in roots
+    pub ca_file: Option<PathBuf>,
+    /// PEM certificate chain presented to the server, for mutual TLS
+    pub client_cert: Option<PathBuf>,
+    /// PEM private key of `client_cert`, in PKCS#8, PKCS#1 or SEC1 format.
+    /// Encrypted keys are not supported
+    pub client_key: Option<PathBuf>,
+    /// Name to send in SNI and verify the server certificate against,
+    /// instead of the host part of the upstream address
+    pub server_name: Option<String>,
+    /// Accept only server certificates with one of these SHA-256
+    /// fingerprints, in addition to the usual verification
+    pub pins: Vec<[u8; 32]>,
+    /// Skip server certificate verification, except for `pins`.
+    /// For lab use only
+    pub insecure: bool,
+}
+
+#[cfg(feature = "tls")]
+impl TlsOptions {
+    fn validate(&self) -> Result<(), MproxyError> {
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            return Err(MproxyError::Config(
+                "a TLS client certificate and key must be given together".to_string(),
+            ));
+        }
+        if self.insecure && self.ca_file.is_some() {
+            return Err(MproxyError::Config(
+                "a TLS CA bundle has no effect in insecure mode".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Parse a SHA-256 certificate fingerprint of 64 hex digits, optionally
+/// separated by colons as printed by `openssl x509 -fingerprint -sha256`
+pub fn parse_fingerprint(s: &str) -> Result<[u8; 32], MproxyError> {
+    let invalid = || {
+        MproxyError::Config(format!(
+            "invalid SHA-256 fingerprint '{}', expected 64 hex digits",
+            s
+        ))
+    };
+    let hex: Vec<u8> = s.bytes().filter(|b| *b != b':').collect();
+    if hex.len() != 64 {
+        return Err(invalid());
+    }
+    let mut fingerprint = [0u8; 32];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        fingerprint[i] = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(fingerprint)
+}
+
+/// Client configuration and server name for connecting to one upstream
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+pub(crate) struct TlsConnector {
+    config: Arc<ClientConfig>,
+    server_name: ServerName,
+}
+
+#[cfg(feature = "tls")]
+impl TlsConnector {
+    /// Load certificates and keys for connecting to `addr`
+    pub(crate) fn new(addr: &str, options: &TlsOptions) -> Result<Self, MproxyError> {
+        options.validate()?;
+        let tls_err = |reason: String| MproxyError::Tls {
+            addr: addr.to_string(),
+            reason,
+        };
+
+        let mut roots = RootCertStore::empty();
+        match &options.ca_file {
+            Some(path) => {
+                for cert in load_certs(path)? {
+                    roots.add(&cert).map_err(|e| {
+                        tls_err(format!(
+                            "invalid CA certificate in {}: {}",
+                            path.display(),
+                            e
+                        ))
+                    })?;
+                }
+            }
+            None => roots.add_server_trust_anchors(TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            })),
+        }
+        let verifier = PinnedVerifier {
+            webpki: (!options.insecure).then(|| WebPkiVerifier::new(roots, None)),
+            pins: options.pins.clone(),
+        };
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier));
+        let config = match (&options.client_cert, &options.client_key) {
+            (Some(cert), Some(key)) => builder
+                .with_single_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(|e| tls_err(format!("invalid client certificate or key: {}", e)))?,
+            _ => builder.with_no_client_auth(),
+        };
+
+        let name = match &options.server_name {
+            Some(name) => name.clone(),
+            None => host(addr).to_string(),
+        };
+        let server_name = match name.parse::<IpAddr>() {
+            // certificates are verified against DNS names only
+            Ok(ip) if !options.insecure => {
+                return Err(tls_err(format!(
+                    "cannot verify a certificate for IP address {}, set a server name",
+                    ip
+                )))
+            }
+            Ok(ip) => ServerName::IpAddress(ip),
+            Err(_) => ServerName::try_from(name.as_str())
+                .map_err(|e| tls_err(format!("invalid server name {}: {}", name, e)))?,
+        };
+        Ok(TlsConnector {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+
+    /// Open a TCP connection to `addr` and complete a TLS handshake
+    pub(crate) fn connect(&self, addr: &str) -> Result<(ClientConnection, TcpStream), MproxyError> {
+        let tls_err = |reason: String| MproxyError::Tls {
+            addr: addr.to_string(),
+            reason,
+        };
+        let mut conn = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(|e| tls_err(format!("performing handshake: {}", e)))?;
+        let mut sock = connect_tcp(addr)?;
+        sock.set_nodelay(true)?;
+        sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)
+                .map_err(|e| tls_err(format!("performing handshake: {}", e)))?;
+        }
+        Ok((conn, sock))
+    }
+}
+
+/// Open a TLS connection to `tls_connect_addr`, trusting the built-in web
+/// PKI roots. Nothing is sent after the handshake; see [Preamble](crate::Preamble)
+#[cfg(feature = "tls")]
+pub fn tls_connection(
+    tls_connect_addr: String,
+) -> Result<(ClientConnection, TcpStream), MproxyError> {
+    tls_connection_with(tls_connect_addr, &TlsOptions::default())
+}
+
+/// Open a TLS connection to `tls_connect_addr` with the given `options`
+#[cfg(feature = "tls")]
+pub fn tls_connection_with(
+    tls_connect_addr: String,
+    options: &TlsOptions,
+) -> Result<(ClientConnection, TcpStream), MproxyError> {
+    TlsConnector::new(&tls_connect_addr, options)?.connect(&tls_connect_addr)
+}
+
+/// Host part of `addr`, e.g. `example.com` of `example.com:443`, or `::1`
+/// of `[::1]:443`
+#[cfg(feature = "tls")]
+fn host(addr: &str) -> &str {
+    if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
+        if socket_addr.is_ipv6() {
+            return addr[1..].split(']').next().unwrap_or(addr);
+        }
+    }
+    match addr.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => addr,
+    }
+}
+
+/// Server certificate verification by the web PKI, if enabled, and by
+/// fingerprint, if any pins are set
+#[cfg(feature = "tls")]
+struct PinnedVerifier {
+    webpki: Option<WebPkiVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+#[cfg(feature = "tls")]
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if !self.pins.is_empty() {
+            let digest = ring::digest::digest(&ring::digest::SHA256, &end_entity.0);
+            if !self.pins.iter().any(|pin| pin[..] == *digest.as_ref()) {
+                return Err(rustls::Error::General(
+                    "server certificate does not match any pinned fingerprint".to_string(),
+                ));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use mproxy_common::{MproxyError, Received, ShutdownHandle, Source};
+
+/// Delays between attempts to reconnect to a TCP upstream.
+///
+/// The delay starts at `initial` and is multiplied by `multiplier` after
+/// each consecutive failure, up to `max`. Each delay is then reduced by a
+/// random fraction of up to `jitter`, so that many proxies of the same
+/// upstream do not reconnect at once. The delay is reset once data is
+/// received on a connection, so that an upstream which accepts connections
+/// and closes them at once is backed off as if it refused them
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackoffOptions {
+    /// Delay after the first failure
+    pub initial: Duration,
+    /// Longest delay
+    pub max: Duration,
+    /// Factor applied to the delay after each failure, at least 1
+    pub multiplier: f64,
+    /// Largest fraction of each delay removed at random, from 0 to 1
+    pub jitter: f64,
+    /// Give up after this many consecutive failures, returning the last
+    /// error from the thread. Retries forever if `None`
+    pub max_retries: Option<u32>,
+}
+
+impl Default for BackoffOptions {
+    fn default() -> Self {
+        BackoffOptions {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_retries: None,
+        }
+    }
+}
+
+impl BackoffOptions {
+    pub(crate) fn validate(&self) -> Result<(), MproxyError> {
+        if self.multiplier.is_nan() || self.multiplier < 1.0 {
+            return Err(MproxyError::Config(format!(
+                "backoff multiplier {} must be at least 1",
+                self.multiplier
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(MproxyError::Config(format!(
+                "backoff jitter {} must be between 0 and 1",
+                self.jitter
+            )));
+        }
+        if self.initial > self.max {
+            return Err(MproxyError::Config(format!(
+                "initial backoff {:?} exceeds the maximum {:?}",
+                self.initial, self.max
+            )));
+        }
+        Ok(())
+    }
+
+    /// Delay before reconnecting after `failures` consecutive failures,
+    /// counting from 1
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let secs = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay = Duration::try_from_secs_f64(secs)
+            .unwrap_or(self.max)
+            .min(self.max);
+        delay.mul_f64(1.0 - self.jitter * random_fraction())
+    }
+}
+
+/// TCP keepalive probes sent on idle upstream connections, so that a peer
+/// which disappears without closing the connection is detected by the
+/// operating system. Supported on Linux and macOS
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeepaliveOptions {
+    /// Idle time before the first probe, in whole seconds
+    pub idle: Duration,
+    /// Time between unanswered probes, in whole seconds
+    pub interval: Duration,
+    /// Unanswered probes before the connection is dropped
+    pub retries: u32,
+}
+
+impl Default for KeepaliveOptions {
+    fn default() -> Self {
+        KeepaliveOptions {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            retries: 5,
+        }
+    }
+}
+
+impl KeepaliveOptions {
+    pub(crate) fn validate(&self) -> Result<(), MproxyError> {
+        if cfg!(not(any(target_os = "linux", target_os = "macos"))) {
+            return Err(MproxyError::Config(
+                "TCP keepalive options are not supported on this platform".to_string(),
+            ));
+        }
+        for (name, secs) in [("idle", self.idle), ("interval", self.interval)] {
+            if secs.as_secs() == 0 || secs.as_secs() > i32::MAX as u64 {
+                return Err(MproxyError::Config(format!(
+                    "TCP keepalive {} {:?} must be at least one second",
+                    name, secs
+                )));
+            }
+        }
+        if self.retries == 0 || self.retries > i32::MAX as u32 {
+            return Err(MproxyError::Config(format!(
+                "TCP keepalive retries {} must be at least 1",
+                self.retries
+            )));
+        }
+        Ok(())
+    }
+
+    /// Enable keepalive probes on `sock`
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub(crate) fn apply(&self, sock: &TcpStream) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        #[cfg(target_os = "linux")]
+        const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPIDLE;
+        #[cfg(target_os = "macos")]
+        const TCP_KEEPIDLE: libc::c_int = libc::TCP_KEEPALIVE;
+
+        let fd = sock.as_raw_fd();
+        for (level, name, value) in [
+            (libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1),
+            (libc::IPPROTO_TCP, TCP_KEEPIDLE, self.idle.as_secs()),
+            (
+                libc::IPPROTO_TCP,
+                libc::TCP_KEEPINTVL,
+                self.interval.as_secs(),
+            ),
+            (libc::IPPROTO_TCP, libc::TCP_KEEPCNT, self.retries.into()),
+        ] {
+            // checked by validate
+            let value = value as libc::c_int;
+            // SAFETY: fd is an open socket owned by sock, and value outlives the call
+            let ret = unsafe {
+                libc::setsockopt(
+                    fd,
+                    level,
+                    name,
+                    &value as *const libc::c_int as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub(crate) fn apply(&self, _sock: &TcpStream) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// A [Source] which fails if no data is received for `timeout`, so that an
+/// upstream which stays connected but stops sending is reconnected
+pub(crate) struct IdleSource {
+    source: Box<dyn Source>,
+    name: String,
+    timeout: Duration,
+    last_data: Instant,
+}
+
+impl IdleSource {
+    pub(crate) fn new(source: Box<dyn Source>, name: String, timeout: Duration) -> Self {
+        IdleSource {
+            source,
+            name,
+            timeout,
+            last_data: Instant::now(),
+        }
+    }
+}
+
+impl Source for IdleSource {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Received, MproxyError> {
+        let received = self.source.recv(buf)?;
+        match received {
+            Received::Data { .. } => self.last_data = Instant::now(),
+            Received::Timeout if self.last_data.elapsed() >= self.timeout => {
+                return Err(MproxyError::Recv {
+                    addr: self.name.clone(),
+                    source: io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "no data received for {:.1}s",
+                            self.last_data.elapsed().as_secs_f64()
+                        ),
+                    ),
+                });
+            }
+            _ => {}
+        }
+        Ok(received)
+    }
+}
+
+/// A [Source] which reports the first data received on a connection to a
+/// [StatusMonitor]
+pub(crate) struct StatusSource {
+    source: Box<dyn Source>,
+    status: StatusMonitor,
+    received: bool,
+}
+
+impl StatusSource {
+    pub(crate) fn new(source: Box<dyn Source>, status: StatusMonitor) -> Self {
+        StatusSource {
+            source,
+            status,
+            received: false,
+        }
+    }
+}
+
+impl Source for StatusSource {
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Received, MproxyError> {
+        let received = self.source.recv(buf)?;
+        if !self.received && matches!(received, Received::Data { .. }) {
+            self.received = true;
+            self.status.received();
+        }
+        Ok(received)
+    }
+}
+
+/// Uniformly distributed number in `[0, 1)`, from the randomly keyed
+/// hasher of the standard library
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Connection state of a TCP upstream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamState {
+    /// Resolving, connecting, or sending the preamble
+    Connecting,
+    /// Forwarding received data
+    Connected,
+    /// Waiting to reconnect after a failure
+    Backoff,
+    /// The thread has exited, after shutdown or too many failures
+    Stopped,
+}
+
+impl fmt::Display for UpstreamState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            UpstreamState::Connecting => "connecting",
+            UpstreamState::Connected => "connected",
+            UpstreamState::Backoff => "backoff",
+            UpstreamState::Stopped => "stopped",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+/// Cause of a failed or lost connection to a TCP upstream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The upstream hostname could not be resolved
+    Resolve,
+    /// The TCP connection was refused or timed out
+    Connect,
+    /// TLS verification or negotiation failed
+    Tls,
+    /// The upstream rejected the preamble
+    Handshake,
+    /// Reading from an established connection failed
+    Read,
+    /// The upstream closed the connection
+    Eof,
+    /// The upstream sent no data for longer than
+    /// [UpstreamOptions::idle_timeout](crate::UpstreamOptions::idle_timeout)
+    Stall,
+    /// The downstream UDP socket could not be bound or written
+    Downstream,
+    /// Any other error
+    Other,
+}
+
+impl FailureKind {
+    /// Classify an error returned while connecting or forwarding
+    pub fn of(e: &MproxyError) -> Self {
+        match e {
+            MproxyError::Resolve { .. } => FailureKind::Resolve,
+            MproxyError::Connect { .. } => FailureKind::Connect,
+            MproxyError::Tls { .. } => FailureKind::Tls,
+            MproxyError::Handshake { .. } => FailureKind::Handshake,
+            MproxyError::Recv { source, .. } if source.kind() == io::ErrorKind::UnexpectedEof => {
+                FailureKind::Eof
+            }
+            // read timeouts are reported as Received::Timeout, so a TimedOut
+            // error comes from the idle timeout
+            MproxyError::Recv { source, .. } if source.kind() == io::ErrorKind::TimedOut => {
+                FailureKind::Stall
+            }
+            MproxyError::Recv { .. } => FailureKind::Read,
+            MproxyError::Bind { .. }
+            | MproxyError::MulticastJoin { .. }
+            | MproxyError::Send { .. } => FailureKind::Downstream,
+            _ => FailureKind::Other,
+        }
+    }
+}
+
+/// The most recent failure of a TCP upstream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamFailure {
+    pub kind: FailureKind,
+    /// Error message
+    pub message: String,
+    pub time: SystemTime,
+}
+
+/// Connection state of a TCP upstream, from [UpstreamHandle::status]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamStatus {
+    pub state: UpstreamState,
+    /// When `state` was entered
+    pub since: SystemTime,
+    /// When the last successful connection was established
+    pub connected_at: Option<SystemTime>,
+    /// When the next attempt to connect is due, in state
+    /// [UpstreamState::Backoff]
+    pub retry_at: Option<SystemTime>,
+    /// Consecutive failures since data was last received
+    pub failures: u32,
+    /// Connections dropped because the upstream stopped sending, since the
+    /// thread was spawned
+    pub stalls: u64,
+    pub last_error: Option<UpstreamFailure>,
+}
+
+/// Shared [UpstreamStatus], updated by the proxy thread
+#[derive(Clone, Debug)]
+pub(crate) struct StatusMonitor(Arc<Mutex<UpstreamStatus>>);
+
+impl StatusMonitor {
+    pub(crate) fn new() -> Self {
+        StatusMonitor(Arc::new(Mutex::new(UpstreamStatus {
+            state: UpstreamState::Connecting,
+            since: SystemTime::now(),
+            connected_at: None,
+            retry_at: None,
+            failures: 0,
+            stalls: 0,
+            last_error: None,
+        })))
+    }
+
+    fn update(&self, f: impl FnOnce(&mut UpstreamStatus)) {
+        // a panic while holding the lock leaves the status usable
+        let mut status = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut status)
+    }
+
+    fn enter(status: &mut UpstreamStatus, state: UpstreamState) {
+        status.state = state;
+        status.since = SystemTime::now();
+        status.retry_at = None;
+    }
+
+    pub(crate) fn connecting(&self) {
+        self.update(|s| Self::enter(s, UpstreamState::Connecting));
+    }
+
+    pub(crate) fn connected(&self) {
+        self.update(|s| {
+            Self::enter(s, UpstreamState::Connected);
+            s.connected_at = Some(s.since);
+        });
+    }
+
+    /// Reset the consecutive failures once a connection has delivered data
+    fn received(&self) {
+        self.update(|s| s.failures = 0);
+    }
+
+    /// Record a failure. Returns the number of consecutive failures
+    pub(crate) fn failed(&self, e: &MproxyError) -> u32 {
+        let mut failures = 0;
+        let kind = FailureKind::of(e);
+        self.update(|s| {
+            s.failures += 1;
+            failures = s.failures;
+            if kind == FailureKind::Stall {
+                s.stalls += 1;
+            }
+            s.last_error = Some(UpstreamFailure {
+                kind,
+                message: e.to_string(),
+                time: SystemTime::now(),
+            });
+        });
+        failures
+    }
+
+    pub(crate) fn backoff(&self, delay: Duration) {
+        self.update(|s| {
+            Self::enter(s, UpstreamState::Backoff);
+            s.retry_at = Some(s.since + delay);
+        });
+    }
+
+    pub(crate) fn stopped(&self) {
+        self.update(|s| Self::enter(s, UpstreamState::Stopped));
+    }
+
+    fn get(&self) -> UpstreamStatus {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// Handle to a thread spawned by [proxy_tcp_udp_with](crate::proxy_tcp_udp_with),
+/// reporting the state of its upstream connection
+#[derive(Debug)]
+pub struct UpstreamHandle {
+    handle: ShutdownHandle,
+    monitor: StatusMonitor,
+}
+
+impl UpstreamHandle {
+    pub(crate) fn new(handle: ShutdownHandle, monitor: StatusMonitor) -> Self {
+        UpstreamHandle { handle, monitor }
+    }
+
+    /// Current connection state, e.g. for alarming on a dead upstream
+    pub fn status(&self) -> UpstreamStatus {
+        self.monitor.get()
+    }
+
+    /// Thread name
+    pub fn name(&self) -> &str {
+        self.handle.name()
+    }
+
+    /// Returns true if the thread has exited
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Signal the thread to stop, without waiting for it to exit
+    pub fn stop(&self) {
+        self.handle.stop()
+    }
+
+    /// Signal the thread to stop, and wait for it to exit, as
+    /// [ShutdownHandle::shutdown]
+    pub fn shutdown(self) -> Result<(), MproxyError> {
+        self.handle.shutdown()
+    }
+
+    /// Wait for the thread to exit, as [ShutdownHandle::join]
+    pub fn join(self) -> Result<(), MproxyError> {
+        self.handle.join()
+    }
+}
+
+impl From<UpstreamHandle> for ShutdownHandle {
+    fn from(handle: UpstreamHandle) -> Self {
+        handle.handle
+    }
+}
@@ -0,0 +1,193 @@
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use mproxy_forward::{
+    proxy_tcp_udp_with, BackoffOptions, FailureKind, MproxyError, UpstreamHandle, UpstreamOptions,
+    UpstreamState, UpstreamStatus,
+};
+
+/// Backoff of a few milliseconds, without jitter
+fn fast_backoff(max_retries: Option<u32>) -> UpstreamOptions {
+    UpstreamOptions {
+        backoff: BackoffOptions {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(20),
+            jitter: 0.0,
+            max_retries,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// Poll the status of `thread` until `f` holds, or panic after 5 seconds
+fn wait_for(thread: &UpstreamHandle, f: impl Fn(&UpstreamStatus) -> bool) -> UpstreamStatus {
+    let start = Instant::now();
+    loop {
+        let status = thread.status();
+        if f(&status) {
+            return status;
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "{:?}", status);
+        sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_backoff_delay() {
+    let backoff = BackoffOptions {
+        initial: Duration::from_secs(1),
+        max: Duration::from_secs(10),
+        jitter: 0.0,
+        ..Default::default()
+    };
+    let delays: Vec<u64> = (1..=6).map(|n| backoff.delay(n).as_secs()).collect();
+    assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+    assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(10));
+
+    let backoff = BackoffOptions {
+        jitter: 0.5,
+        ..backoff
+    };
+    for _ in 0..100 {
+        let delay = backoff.delay(3);
+        assert!(delay > Duration::from_secs(2) && delay <= Duration::from_secs(4));
+    }
+}
+
+#[test]
+fn test_backoff_max_retries() {
+    // nothing listens on the upstream port
+    let thread = proxy_tcp_udp_with(
+        "localhost:9986".to_string(),
+        "127.0.0.1:9987".to_string(),
+        &fast_backoff(Some(2)),
+    )
+    .unwrap();
+    let status = wait_for(&thread, |s| s.state == UpstreamState::Stopped);
+    assert_eq!(status.failures, 3);
+    assert_eq!(status.connected_at, None);
+    assert_eq!(status.last_error.unwrap().kind, FailureKind::Connect);
+    assert!(matches!(thread.join(), Err(MproxyError::Connect { .. })));
+}
+
+#[test]
+fn test_backoff_resolve_error() {
+    let thread = proxy_tcp_udp_with(
+        "upstream.invalid:9986".to_string(),
+        "127.0.0.1:9987".to_string(),
+        &fast_backoff(Some(0)),
+    )
+    .unwrap();
+    assert!(matches!(thread.join(), Err(MproxyError::Resolve { .. })));
+}
+
+#[test]
+fn test_backoff_options_errors() {
+    for backoff in [
+        BackoffOptions {
+            multiplier: 0.5,
+            ..Default::default()
+        },
+        BackoffOptions {
+            jitter: 1.5,
+            ..Default::default()
+        },
+        BackoffOptions {
+            initial: Duration::from_secs(120),
+            ..Default::default()
+        },
+    ] {
+        let options = UpstreamOptions {
+            backoff,
+            ..Default::default()
+        };
+        let e = proxy_tcp_udp_with(
+            "localhost:9986".to_string(),
+            "127.0.0.1:9987".to_string(),
+            &options,
+        )
+        .unwrap_err();
+        assert!(matches!(e, MproxyError::Config(_)), "{}", e);
+    }
+}
+
+// with feature tls, upstream connections are TLS; see test_tls.rs
+#[cfg(not(feature = "tls"))]
+#[test]
+fn test_upstream_state() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:9988").unwrap();
+    let thread = proxy_tcp_udp_with(
+        "127.0.0.1:9988".to_string(),
+        "127.0.0.1:9989".to_string(),
+        &UpstreamOptions {
+            backoff: BackoffOptions {
+                initial: Duration::from_secs(60),
+                max: Duration::from_secs(60),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let (client, _) = listener.accept().unwrap();
+    let status = wait_for(&thread, |s| s.state == UpstreamState::Connected);
+    assert!(status.connected_at.is_some());
+    assert_eq!(status.failures, 0);
+
+    // the upstream closes the connection
+    drop(client);
+    let status = wait_for(&thread, |s| s.state == UpstreamState::Backoff);
+    assert_eq!(status.failures, 1);
+    assert_eq!(status.last_error.unwrap().kind, FailureKind::Eof);
+    assert!(status.retry_at.unwrap() > status.since + Duration::from_secs(40));
+
+    // shutdown interrupts the backoff
+    thread.shutdown().unwrap();
+}
+
+// with feature tls, upstream connections are TLS; see test_tls.rs
+#[cfg(not(feature = "tls"))]
+#[test]
+fn test_backoff_closed_at_once() {
+    use std::io::Write;
+    use std::net::{TcpListener, UdpSocket};
+    use std::thread::spawn;
+
+    const POSITION: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+    let target = UdpSocket::bind("127.0.0.1:9947").unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    // the upstream accepts connections and closes them, sending data on
+    // the third only
+    let listener = TcpListener::bind("127.0.0.1:9946").unwrap();
+    let server = spawn(move || {
+        for send in [false, false, true, false, false] {
+            let (mut client, _) = listener.accept().unwrap();
+            if send {
+                client.write_all(POSITION.as_bytes()).unwrap();
+            }
+        }
+    });
+    let thread = proxy_tcp_udp_with(
+        "127.0.0.1:9946".to_string(),
+        "127.0.0.1:9947".to_string(),
+        &fast_backoff(Some(2)),
+    )
+    .unwrap();
+
+    // connecting does not reset the failures, but receiving data does
+    let status = wait_for(&thread, |s| s.state == UpstreamState::Stopped);
+    assert_eq!(status.failures, 3);
+    assert!(status.connected_at.is_some());
+    assert_eq!(status.last_error.unwrap().kind, FailureKind::Eof);
+    assert!(matches!(thread.join(), Err(MproxyError::Recv { .. })));
+    server.join().unwrap();
+
+    let mut buf = [0u8; 1024];
+    let (c, _) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..c], POSITION.as_bytes());
+}
@@ -0,0 +1,211 @@
+// with feature tls, upstream connections are TLS; see test_tls.rs
+#![cfg(not(feature = "tls"))]
+
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, UdpSocket};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use mproxy_forward::{proxy_tcp_udp_with, HttpRequest, Preamble, UpstreamOptions};
+
+const POSITION: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+
+/// Accept one connection on `addr`, read lines until a blank line or
+/// `lines` lines, then send `response`. Returns the lines received
+fn serve_once(addr: &str, lines: usize, response: String) -> JoinHandle<Vec<String>> {
+    let listener = TcpListener::bind(addr).unwrap();
+    spawn(move || {
+        let (sock, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(sock.try_clone().unwrap());
+        let mut received = vec![];
+        while received.len() < lines {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_string();
+            if line.is_empty() {
+                break;
+            }
+            received.push(line);
+        }
+        let mut sock = sock;
+        sock.write_all(response.as_bytes()).unwrap();
+        received
+    })
+}
+
+fn udp_target(addr: &str) -> UdpSocket {
+    let target = UdpSocket::bind(addr).unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_millis(1000)))
+        .unwrap();
+    target
+}
+
+#[test]
+fn test_preamble_text() {
+    // plain TCP upstreams are sent nothing unless configured
+    assert_eq!(Preamble::default(), Preamble::None);
+    env::set_var("MPROXY_TEST_PASSWORD", "hunter2");
+    let target = udp_target("127.0.0.1:9972");
+    let server = serve_once("127.0.0.1:9971", 1, POSITION.to_string());
+    let options = UpstreamOptions {
+        preamble: Preamble::Text("LOGIN ais ${MPROXY_TEST_PASSWORD}\r\n".to_string()),
+        ..Default::default()
+    };
+    let thread = proxy_tcp_udp_with(
+        "127.0.0.1:9971".to_string(),
+        "127.0.0.1:9972".to_string(),
+        &options,
+    )
+    .unwrap();
+
+    let mut buf = [0u8; 1024];
+    let (c, _) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..c], POSITION.as_bytes());
+    assert_eq!(server.join().unwrap(), ["LOGIN ais hunter2"]);
+    thread.shutdown().unwrap();
+}
+
+#[test]
+fn test_preamble_http() {
+    env::set_var("MPROXY_TEST_TOKEN", "secret");
+    let target = udp_target("127.0.0.1:9974");
+    let response = format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\n{}",
+        POSITION
+    );
+    let server = serve_once("127.0.0.1:9973", 100, response);
+    let options = UpstreamOptions {
+        preamble: Preamble::Http(HttpRequest {
+            path: "/stream?format=nmea".to_string(),
+            headers: vec![(
+                "Authorization".to_string(),
+                "Bearer ${MPROXY_TEST_TOKEN}".to_string(),
+            )],
+        }),
+        ..Default::default()
+    };
+    let thread = proxy_tcp_udp_with(
+        "127.0.0.1:9973".to_string(),
+        "127.0.0.1:9974".to_string(),
+        &options,
+    )
+    .unwrap();
+
+    // only the response body is forwarded
+    let mut buf = [0u8; 1024];
+    let (c, _) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..c], POSITION.as_bytes());
+    let request = server.join().unwrap();
+    assert_eq!(request[0], "GET /stream?format=nmea HTTP/1.0");
+    assert!(request.contains(&"Host: 127.0.0.1:9973".to_string()));
+    assert!(request.contains(&"Authorization: Bearer secret".to_string()));
+    thread.shutdown().unwrap();
+}
+
+#[test]
+fn test_preamble_http_error_status() {
+    let target = udp_target("127.0.0.1:9976");
+    let response = format!("HTTP/1.0 401 Unauthorized\r\n\r\n{}", POSITION);
+    let server = serve_once("127.0.0.1:9975", 100, response);
+    let options = UpstreamOptions {
+        preamble: Preamble::Http(HttpRequest {
+            path: "/".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let thread = proxy_tcp_udp_with(
+        "127.0.0.1:9975".to_string(),
+        "127.0.0.1:9976".to_string(),
+        &options,
+    )
+    .unwrap();
+
+    server.join().unwrap();
+    let mut buf = [0u8; 1024];
+    assert!(target.recv_from(&mut buf).is_err());
+    thread.shutdown().unwrap();
+}
+
+#[test]
+fn test_preamble_errors() {
+    let options = UpstreamOptions {
+        preamble: Preamble::Text("LOGIN ${MPROXY_TEST_UNSET}\r\n".to_string()),
+        ..Default::default()
+    };
+    let e = proxy_tcp_udp_with(
+        "127.0.0.1:9977".to_string(),
+        "127.0.0.1:9978".to_string(),
+        &options,
+    )
+    .unwrap_err();
+    assert!(e.to_string().contains("MPROXY_TEST_UNSET"), "{}", e);
+
+    let options = UpstreamOptions {
+        preamble: Preamble::Text("LOGIN ${USER\r\n".to_string()),
+        ..Default::default()
+    };
+    let e = proxy_tcp_udp_with(
+        "127.0.0.1:9977".to_string(),
+        "127.0.0.1:9978".to_string(),
+        &options,
+    )
+    .unwrap_err();
+    assert!(e.to_string().contains("unterminated"), "{}", e);
+
+    let options = UpstreamOptions {
+        preamble: Preamble::Http(HttpRequest {
+            path: "stream".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let e = proxy_tcp_udp_with(
+        "127.0.0.1:9977".to_string(),
+        "127.0.0.1:9978".to_string(),
+        &options,
+    )
+    .unwrap_err();
+    assert!(e.to_string().contains("must start with '/'"), "{}", e);
+}
+
+#[test]
+fn test_preamble_http_endless_header() {
+    // the upstream sends a header line without end, and keeps the
+    // connection open
+    let listener = TcpListener::bind("127.0.0.1:9979").unwrap();
+    let server = spawn(move || {
+        let (mut sock, _) = listener.accept().unwrap();
+        sock.write_all(b"HTTP/1.0 200 OK\r\nX-Padding: ").unwrap();
+        let _ = sock.write_all(&[b'a'; 64 * 1024]);
+        sleep(Duration::from_secs(3));
+    });
+    let options = UpstreamOptions {
+        preamble: Preamble::Http(HttpRequest {
+            path: "/".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let thread = proxy_tcp_udp_with(
+        "127.0.0.1:9979".to_string(),
+        "127.0.0.1:9967".to_string(),
+        &options,
+    )
+    .unwrap();
+
+    // fails at the length limit, without waiting for the rest of the line
+    let start = Instant::now();
+    let error = loop {
+        if let Some(error) = thread.status().last_error {
+            break error;
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+        sleep(Duration::from_millis(10));
+    };
+    assert!(error.message.contains("too long"), "{}", error.message);
+    thread.shutdown().unwrap();
+    server.join().unwrap();
+}
@@ -2,9 +2,13 @@ use std::path::PathBuf;
 use std::thread::sleep;
 use std::time::Duration;
 
-use mproxy_client::client_socket_stream;
-use mproxy_forward::forward_udp;
-use mproxy_server::listener;
+use mproxy_client::{client_socket_stream, target_socket_interface};
+use mproxy_common::{DatagramMeta, MproxyError, Pipeline, Sentence, Stage};
+use mproxy_forward::{
+    forward_udp, forward_udp_with, proxy_gateway_with, DedupOptions, Deduplicator, Downsampler,
+    ForwardOptions, MessageFilter, ReassemblyOptions, TagOptions, Validation,
+};
+use mproxy_server::{listener, upstream_socket_interface};
 
 use testconfig::{truncate, TESTDATA, TESTINGDIR};
 
@@ -17,18 +21,18 @@ fn test_forward_udp_ipv4_unicast() {
     let server_listen = "0.0.0.0:8891".to_string();
 
     let data = PathBuf::from(TESTDATA);
-    let pathstr = &[TESTINGDIR, "streamoutput_forward_udp_ipv4_output.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_forward_udp_ipv4_output.log"].join("");
     let output = PathBuf::from(pathstr);
     assert!(data.is_file());
 
-    let _l = listener(server_listen, output, true);
+    let _l = listener(server_listen, output, true).unwrap();
     sleep(Duration::from_millis(15));
 
     let targets = vec![proxy_target];
-    let _p = forward_udp(proxy_listen, &targets, false);
+    let _p = forward_udp(proxy_listen, &targets, false).unwrap();
     sleep(Duration::from_millis(15));
 
-    let _c = client_socket_stream(&data, vec![client_target], false);
+    let _c = client_socket_stream(&data, vec![client_target], false, None);
 
     let output = PathBuf::from(pathstr);
     let bytesize = truncate(output);
@@ -43,18 +47,18 @@ fn test_forward_udp_ipv6_unicast() {
     let server_listen = "[::]:8893".to_string();
 
     let data = PathBuf::from(TESTDATA);
-    let pathstr = &[TESTINGDIR, "streamoutput_forward_udp_ipv6_output.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_forward_udp_ipv6_output.log"].join("");
     let output = PathBuf::from(pathstr);
     assert!(data.is_file());
 
-    let _l = listener(server_listen, output, false);
+    let _l = listener(server_listen, output, false).unwrap();
     sleep(Duration::from_millis(15));
 
     let targets = vec![proxy_target];
-    let _p = forward_udp(proxy_listen, &targets, false);
+    let _p = forward_udp(proxy_listen, &targets, false).unwrap();
     sleep(Duration::from_millis(15));
 
-    let _c = client_socket_stream(&data, vec![client_target], false);
+    let _c = client_socket_stream(&data, vec![client_target], false, None);
 
     let output = PathBuf::from(pathstr);
     let bytesize = truncate(output);
@@ -73,18 +77,18 @@ fn test_forward_udp_ipv4_multicast() {
         TESTINGDIR,
         "streamoutput_forward_udp_ipv4_multicast_output.log",
     ]
-    .join(&"");
+    .join("");
     let output = PathBuf::from(pathstr);
     assert!(data.is_file());
 
-    let _l = listener(server_listen, output, false);
+    let _l = listener(server_listen, output, false).unwrap();
     sleep(Duration::from_millis(15));
 
     let targets = vec![proxy_target];
-    let _p = forward_udp(proxy_listen, &targets, false);
+    let _p = forward_udp(proxy_listen, &targets, false).unwrap();
     sleep(Duration::from_millis(15));
 
-    let _c = client_socket_stream(&data, vec![client_target], false);
+    let _c = client_socket_stream(&data, vec![client_target], false, None);
 
     let output = PathBuf::from(pathstr);
     let bytesize = truncate(output);
@@ -103,21 +107,390 @@ fn test_forward_udp_ipv6_multicast() {
         TESTINGDIR,
         "streamoutput_forward_udp_ipv6_multicast_output.log",
     ]
-    .join(&"");
+    .join("");
     let output = PathBuf::from(pathstr);
     assert!(data.is_file());
 
-    let _l = listener(server_listen, output, false);
+    let _l = listener(server_listen, output, false).unwrap();
     sleep(Duration::from_millis(15));
 
     let targets = vec![proxy_target];
-    let _p = forward_udp(proxy_listen, &targets, false);
+    let _p = forward_udp(proxy_listen, &targets, false).unwrap();
     sleep(Duration::from_millis(15));
 
-    let _c = client_socket_stream(&data, vec![client_target], false);
+    let _c = client_socket_stream(&data, vec![client_target], false, None);
     sleep(Duration::from_millis(15));
 
     let output = PathBuf::from(pathstr);
     let bytesize = truncate(output);
     assert!(bytesize > 0);
 }
+
+#[test]
+fn test_forward_udp_shutdown() {
+    let proxy_listen = "127.0.0.1:8898".to_string();
+    let proxy_targets = vec!["127.0.0.1:8899".to_string()];
+
+    let p = forward_udp(proxy_listen.clone(), &proxy_targets, false).unwrap();
+    sleep(Duration::from_millis(15));
+    p.shutdown().unwrap();
+
+    // listening port is released after shutdown
+    let p = forward_udp(proxy_listen, &proxy_targets, false).unwrap();
+    p.shutdown().unwrap();
+}
+
+#[test]
+fn test_forward_udp_drop_corrupt() {
+    let proxy_listen = "127.0.0.1:8880".to_string();
+    let proxy_target = "127.0.0.1:8881".to_string();
+    let valid = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+    let corrupt = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepU@3n00Sa,0*5C\n";
+
+    let (_addr, server_socket) = upstream_socket_interface(proxy_target.clone()).unwrap();
+    server_socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let options = ForwardOptions {
+        validation: Validation::Drop,
+        ..Default::default()
+    };
+    let p = forward_udp_with(proxy_listen.clone(), &[proxy_target], &options).unwrap();
+    sleep(Duration::from_millis(15));
+
+    let (target_addr, target_socket) = target_socket_interface(&proxy_listen).unwrap();
+    for msg in [corrupt, valid, &format!("{}{}", corrupt, valid)] {
+        target_socket.send_to(msg.as_bytes(), target_addr).unwrap();
+    }
+
+    // datagrams containing only corrupt sentences are not forwarded
+    let mut received = vec![];
+    let mut buf = [0u8; 1024];
+    while let Ok((c, _remote)) = server_socket.recv_from(&mut buf) {
+        received.push(String::from_utf8(buf[0..c].to_vec()).unwrap());
+    }
+    assert_eq!(received, vec![valid, valid]);
+    p.shutdown().unwrap();
+}
+
+#[test]
+fn test_forward_udp_reassembly() {
+    let proxy_listen = "127.0.0.1:8882".to_string();
+    let proxy_target = "127.0.0.1:8883".to_string();
+    let first = "!AIVDM,2,1,3,B,55P5TL01VIaAL@7WKO@mBplU@<PDhh000000001S;AJ::4A80?4i@E53,0*3E\n";
+    let second = "!AIVDM,2,2,3,B,1@0000000000000,2*55\n";
+    let single = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+
+    let (_addr, server_socket) = upstream_socket_interface(proxy_target.clone()).unwrap();
+    server_socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let options = ForwardOptions {
+        reassembly: Some(ReassemblyOptions::default()),
+        ..Default::default()
+    };
+    let p = forward_udp_with(proxy_listen.clone(), &[proxy_target], &options).unwrap();
+    sleep(Duration::from_millis(15));
+
+    let (target_addr, target_socket) = target_socket_interface(&proxy_listen).unwrap();
+    for msg in [first, single, second] {
+        target_socket.send_to(msg.as_bytes(), target_addr).unwrap();
+    }
+
+    // fragments are forwarded together once complete
+    let mut received = vec![];
+    let mut buf = [0u8; 1024];
+    while let Ok((c, _remote)) = server_socket.recv_from(&mut buf) {
+        received.push(String::from_utf8(buf[0..c].to_vec()).unwrap());
+    }
+    assert_eq!(
+        received,
+        vec![single.to_string(), format!("{}{}", first, second)]
+    );
+    p.shutdown().unwrap();
+}
+
+#[test]
+fn test_proxy_gateway_dedup() {
+    let proxy_listen = vec!["127.0.0.1:8884".to_string(), "127.0.0.1:8885".to_string()];
+    let proxy_target = "127.0.0.1:8886".to_string();
+    let message = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+    let other = "!AIVDM,1,1,,A,H42O55i18tMET00000000000000,2*6D\n";
+
+    let (_addr, server_socket) = upstream_socket_interface(proxy_target.clone()).unwrap();
+    server_socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let dedup = Deduplicator::new(&DedupOptions::default());
+    let options = ForwardOptions {
+        dedup: Some(dedup.clone()),
+        ..Default::default()
+    };
+    let threads = proxy_gateway_with(&[proxy_target], &proxy_listen, &options).unwrap();
+    sleep(Duration::from_millis(15));
+
+    // the same message heard by two receivers is forwarded once
+    for (listen, msg) in [
+        (&proxy_listen[0], message),
+        (&proxy_listen[1], message),
+        (&proxy_listen[1], other),
+    ] {
+        let (target_addr, target_socket) = target_socket_interface(listen).unwrap();
+        target_socket.send_to(msg.as_bytes(), target_addr).unwrap();
+        sleep(Duration::from_millis(5));
+    }
+
+    let mut received = vec![];
+    let mut buf = [0u8; 1024];
+    while let Ok((c, _remote)) = server_socket.recv_from(&mut buf) {
+        received.push(String::from_utf8(buf[0..c].to_vec()).unwrap());
+    }
+    assert_eq!(received, vec![message, other]);
+    assert_eq!((dedup.hits(), dedup.misses()), (1, 2));
+    for thread in threads {
+        thread.shutdown().unwrap();
+    }
+}
+
+#[test]
+fn test_forward_udp_tag_blocks() {
+    let first_hop = "127.0.0.1:8887".to_string();
+    let second_hop = "127.0.0.1:8888".to_string();
+    let proxy_target = "127.0.0.1:8889".to_string();
+    let message = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+
+    let (_addr, server_socket) = upstream_socket_interface(proxy_target.clone()).unwrap();
+    server_socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let tag = |station: &str| ForwardOptions {
+        tags: TagOptions {
+            station: Some(station.to_string()),
+            timestamp: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let strip = ForwardOptions {
+        tags: TagOptions {
+            strip: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let p1 = forward_udp_with(
+        first_hop.clone(),
+        std::slice::from_ref(&second_hop),
+        &tag("rx1"),
+    )
+    .unwrap();
+    let p2 =
+        forward_udp_with(second_hop, std::slice::from_ref(&proxy_target), &tag("rx2")).unwrap();
+    sleep(Duration::from_millis(15));
+
+    // the station of the first hop is kept by the second
+    let (target_addr, target_socket) = target_socket_interface(&first_hop).unwrap();
+    target_socket
+        .send_to(message.as_bytes(), target_addr)
+        .unwrap();
+    let mut buf = [0u8; 1024];
+    let (c, _remote) = server_socket.recv_from(&mut buf).unwrap();
+    let sentence = Sentence::parse(&buf[0..c]).unwrap();
+    let tags = sentence.tags().unwrap().unwrap();
+    assert_eq!(tags.source.as_deref(), Some("rx1"));
+    assert!(tags.time.is_some());
+    p1.shutdown().unwrap();
+    p2.shutdown().unwrap();
+
+    // tag blocks are removed for legacy consumers
+    let p = forward_udp_with(first_hop.clone(), &[proxy_target], &strip).unwrap();
+    sleep(Duration::from_millis(15));
+    let tagged = format!("\\s:rx1*72\\{}", message);
+    target_socket
+        .send_to(tagged.as_bytes(), target_addr)
+        .unwrap();
+    let (c, _remote) = server_socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[0..c], message.as_bytes());
+    p.shutdown().unwrap();
+}
+
+#[test]
+fn test_forward_udp_filters() {
+    let proxy_listen = "127.0.0.1:8870".to_string();
+    let proxy_targets = vec!["127.0.0.1:8871".to_string(), "127.0.0.1:8872".to_string()];
+    let position = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+    let class_b = "!AIVDM,1,1,,A,B52MJh00Nmks:05J4L1howV44000,0*1D\n";
+
+    let servers: Vec<_> = proxy_targets
+        .iter()
+        .map(|target| {
+            let (_addr, socket) = upstream_socket_interface(target.clone()).unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+            socket
+        })
+        .collect();
+    let options = ForwardOptions {
+        filters: [(
+            proxy_targets[1].clone(),
+            MessageFilter::parse("types:18").unwrap(),
+        )]
+        .into(),
+        ..Default::default()
+    };
+    let p = forward_udp_with(proxy_listen.clone(), &proxy_targets, &options).unwrap();
+    sleep(Duration::from_millis(15));
+
+    let (target_addr, target_socket) = target_socket_interface(&proxy_listen).unwrap();
+    for msg in [position, class_b] {
+        target_socket.send_to(msg.as_bytes(), target_addr).unwrap();
+    }
+
+    // only the filtered target is restricted
+    let mut received = vec![vec![], vec![]];
+    let mut buf = [0u8; 1024];
+    for (server_socket, received) in servers.iter().zip(received.iter_mut()) {
+        while let Ok((c, _remote)) = server_socket.recv_from(&mut buf) {
+            received.push(String::from_utf8(buf[0..c].to_vec()).unwrap());
+        }
+    }
+    assert_eq!(received, vec![vec![position, class_b], vec![class_b]]);
+    p.shutdown().unwrap();
+}
+
+#[test]
+fn test_forward_udp_downsample() {
+    let proxy_listen = "127.0.0.1:8873".to_string();
+    let proxy_target = "127.0.0.1:8874".to_string();
+    let position = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+    let first = "!AIVDM,2,1,3,B,55P5TL01VIaAL@7WKO@mBplU@<PDhh000000001S;AJ::4A80?4i@E53,0*3E\n";
+    let second = "!AIVDM,2,2,3,B,1@0000000000000,2*55\n";
+    let message = format!("{}{}", first, second);
+
+    let (_addr, server_socket) = upstream_socket_interface(proxy_target.clone()).unwrap();
+    server_socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let options = ForwardOptions {
+        downsample: [(
+            proxy_target.clone(),
+            Downsampler::new(Duration::from_secs(60)),
+        )]
+        .into(),
+        ..Default::default()
+    };
+    let p = forward_udp_with(proxy_listen.clone(), &[proxy_target], &options).unwrap();
+    sleep(Duration::from_millis(15));
+
+    let (target_addr, target_socket) = target_socket_interface(&proxy_listen).unwrap();
+    for msg in [position, &message, position, &message] {
+        target_socket.send_to(msg.as_bytes(), target_addr).unwrap();
+    }
+
+    // repeated position reports are dropped, static data is always forwarded
+    let mut received = vec![];
+    let mut buf = [0u8; 1024];
+    while let Ok((c, _remote)) = server_socket.recv_from(&mut buf) {
+        received.push(String::from_utf8(buf[0..c].to_vec()).unwrap());
+    }
+    assert_eq!(received, vec![position, &message, &message]);
+    p.shutdown().unwrap();
+}
+
+#[test]
+fn test_proxy_gateway_downsample() {
+    let proxy_listen = vec!["127.0.0.1:8866".to_string(), "127.0.0.1:8867".to_string()];
+    let proxy_target = "127.0.0.1:8868".to_string();
+    let position = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+
+    let (_addr, server_socket) = upstream_socket_interface(proxy_target.clone()).unwrap();
+    server_socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let downsampler = Downsampler::new(Duration::from_secs(60));
+    let options = ForwardOptions {
+        downsample: [(proxy_target.clone(), downsampler.clone())].into(),
+        ..Default::default()
+    };
+    let threads = proxy_gateway_with(&[proxy_target], &proxy_listen, &options).unwrap();
+    sleep(Duration::from_millis(15));
+
+    // one report per vessel is forwarded, whichever receiver heard it
+    for listen in [&proxy_listen[0], &proxy_listen[1], &proxy_listen[1]] {
+        let (target_addr, target_socket) = target_socket_interface(listen).unwrap();
+        target_socket
+            .send_to(position.as_bytes(), target_addr)
+            .unwrap();
+        sleep(Duration::from_millis(5));
+    }
+
+    let mut received = vec![];
+    let mut buf = [0u8; 1024];
+    while let Ok((c, _remote)) = server_socket.recv_from(&mut buf) {
+        received.push(String::from_utf8(buf[0..c].to_vec()).unwrap());
+    }
+    assert_eq!(received, vec![position]);
+    assert_eq!(downsampler.dropped(), 2);
+    for thread in threads {
+        thread.shutdown().unwrap();
+    }
+}
+
+/// Split a datagram into one datagram per line
+#[derive(Clone)]
+struct SplitLines;
+
+impl Stage for SplitLines {
+    fn process(&mut self, data: &[u8], _meta: &DatagramMeta) -> Result<Vec<Vec<u8>>, MproxyError> {
+        Ok(data
+            .split_inclusive(|b| *b == b'\n')
+            .map(|line| line.to_vec())
+            .collect())
+    }
+}
+
+#[test]
+fn test_forward_udp_stages() {
+    let proxy_listen = "127.0.0.1:8875".to_string();
+    let proxy_targets = vec!["127.0.0.1:8876".to_string(), "127.0.0.1:8877".to_string()];
+    let position = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+    let class_b = "!AIVDM,1,1,,A,B52MJh00Nmks:05J4L1howV44000,0*1D\n";
+
+    let servers: Vec<_> = proxy_targets
+        .iter()
+        .map(|target| {
+            let (_addr, socket) = upstream_socket_interface(target.clone()).unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_millis(100)))
+                .unwrap();
+            socket
+        })
+        .collect();
+    let options = ForwardOptions {
+        stages: Pipeline::new().stage(SplitLines),
+        target_stages: [(
+            proxy_targets[1].clone(),
+            Pipeline::new().stage(MessageFilter::parse("types:1").unwrap()),
+        )]
+        .into(),
+        ..Default::default()
+    };
+    let p = forward_udp_with(proxy_listen.clone(), &proxy_targets, &options).unwrap();
+    sleep(Duration::from_millis(15));
+
+    let (target_addr, target_socket) = target_socket_interface(&proxy_listen).unwrap();
+    let data = format!("{}{}", position, class_b);
+    target_socket.send_to(data.as_bytes(), target_addr).unwrap();
+
+    // listener stages apply to all targets, target stages to one
+    let mut received = vec![vec![], vec![]];
+    let mut buf = [0u8; 1024];
+    for (server_socket, received) in servers.iter().zip(received.iter_mut()) {
+        while let Ok((c, _remote)) = server_socket.recv_from(&mut buf) {
+            received.push(String::from_utf8(buf[0..c].to_vec()).unwrap());
+        }
+    }
+    assert_eq!(received, vec![vec![position, class_b], vec![position]]);
+    p.shutdown().unwrap();
+}
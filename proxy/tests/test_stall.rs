@@ -0,0 +1,116 @@
+// with feature tls, upstream connections are TLS; see test_tls.rs
+#![cfg(not(feature = "tls"))]
+
+use std::io::Write;
+use std::net::{TcpListener, UdpSocket};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use mproxy_forward::{
+    proxy_tcp_udp_with, BackoffOptions, FailureKind, KeepaliveOptions, MproxyError, UpstreamHandle,
+    UpstreamOptions, UpstreamState, UpstreamStatus,
+};
+
+/// Poll the status of `thread` until `f` holds, or panic after 5 seconds
+fn wait_for(thread: &UpstreamHandle, f: impl Fn(&UpstreamStatus) -> bool) -> UpstreamStatus {
+    let start = Instant::now();
+    loop {
+        let status = thread.status();
+        if f(&status) {
+            return status;
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "{:?}", status);
+        sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_idle_timeout_options_errors() {
+    let options = UpstreamOptions {
+        idle_timeout: Some(Duration::ZERO),
+        ..Default::default()
+    };
+    let e = proxy_tcp_udp_with(
+        "localhost:9990".to_string(),
+        "127.0.0.1:9991".to_string(),
+        &options,
+    )
+    .unwrap_err();
+    assert!(matches!(e, MproxyError::Config(_)), "{}", e);
+
+    for keepalive in [
+        KeepaliveOptions {
+            idle: Duration::from_millis(500),
+            ..Default::default()
+        },
+        KeepaliveOptions {
+            retries: 0,
+            ..Default::default()
+        },
+    ] {
+        let options = UpstreamOptions {
+            keepalive: Some(keepalive),
+            ..Default::default()
+        };
+        let e = proxy_tcp_udp_with(
+            "localhost:9990".to_string(),
+            "127.0.0.1:9991".to_string(),
+            &options,
+        )
+        .unwrap_err();
+        assert!(matches!(e, MproxyError::Config(_)), "{}", e);
+    }
+}
+
+#[test]
+fn test_idle_timeout_reconnects() {
+    let listener = TcpListener::bind("127.0.0.1:9992").unwrap();
+    let downstream = UdpSocket::bind("127.0.0.1:9993").unwrap();
+    downstream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let thread = proxy_tcp_udp_with(
+        "127.0.0.1:9992".to_string(),
+        "127.0.0.1:9993".to_string(),
+        &UpstreamOptions {
+            backoff: BackoffOptions {
+                initial: Duration::from_millis(10),
+                max: Duration::from_millis(10),
+                jitter: 0.0,
+                ..Default::default()
+            },
+            idle_timeout: Some(Duration::from_millis(300)),
+            keepalive: Some(KeepaliveOptions::default()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    // the upstream sends data, then stays connected but goes silent
+    let (mut client, _) = listener.accept().unwrap();
+    client
+        .write_all(b"!AIVDM,1,1,,A,13u?etPv2;0n:dDPwUM1U1Cb069D,0*24\n")
+        .unwrap();
+    let mut buf = [0u8; 1024];
+    let len = downstream.recv(&mut buf).unwrap();
+    assert!(buf[..len].starts_with(b"!AIVDM"));
+    let status = wait_for(&thread, |s| s.state == UpstreamState::Connected);
+    assert_eq!(status.stalls, 0);
+
+    // data resets the timeout
+    sleep(Duration::from_millis(200));
+    client.write_all(b"!AIVDM\n").unwrap();
+    sleep(Duration::from_millis(200));
+    assert_eq!(thread.status().stalls, 0);
+
+    // the stalled connection is dropped and reconnected
+    let (_client, _) = listener.accept().unwrap();
+    let status = wait_for(&thread, |s| s.state == UpstreamState::Connected);
+    assert_eq!(status.stalls, 1);
+    // until the new connection delivers data
+    assert_eq!(status.failures, 1);
+    assert_eq!(status.last_error.unwrap().kind, FailureKind::Stall);
+
+    thread.shutdown().unwrap();
+    drop(client);
+}
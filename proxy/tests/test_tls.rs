@@ -0,0 +1,276 @@
+#![cfg(feature = "tls")]
+
+use std::fs::read_to_string;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, UdpSocket};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rustls::server::{AllowAnyAuthenticatedClient, ServerConfig, ServerConnection};
+use rustls::{Certificate, PrivateKey, RootCertStore, StreamOwned};
+
+use mproxy_forward::{
+    parse_fingerprint, proxy_tcp_udp_with, tls_connection_with, HttpRequest, Preamble, TlsOptions,
+    UpstreamOptions,
+};
+
+use testconfig::TESTINGDIR;
+
+const POSITION: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from([TESTINGDIR, "tls/", name].join(""))
+}
+
+/// DER contents of the first PEM block in a fixture
+fn pem(name: &str) -> Vec<u8> {
+    let text = read_to_string(fixture(name)).unwrap();
+    let base64: String = text
+        .lines()
+        .skip_while(|l| !l.starts_with("-----BEGIN"))
+        .skip(1)
+        .take_while(|l| !l.starts_with("-----END"))
+        .collect();
+    STANDARD.decode(base64).unwrap()
+}
+
+/// SHA-256 fingerprint of the server certificate
+fn server_fingerprint() -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, &pem("server.pem"));
+    digest.as_ref().try_into().unwrap()
+}
+
+/// Accept one TLS connection on `addr` and send a sentence, optionally
+/// requiring a client certificate signed by the test CA
+fn serve_once(addr: &str, client_auth: bool) -> JoinHandle<()> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = if client_auth {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(pem("ca.pem"))).unwrap();
+        builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+    } else {
+        builder.with_no_client_auth()
+    };
+    let config = builder
+        .with_single_cert(
+            vec![Certificate(pem("server.pem"))],
+            PrivateKey(pem("server.key")),
+        )
+        .unwrap();
+    let listener = TcpListener::bind(addr).unwrap();
+    spawn(move || {
+        let (sock, _) = listener.accept().unwrap();
+        let conn = ServerConnection::new(Arc::new(config)).unwrap();
+        let mut stream = StreamOwned::new(conn, sock);
+        // handshake failures are reported by the client
+        let _ = stream
+            .write_all(POSITION.as_bytes())
+            .and_then(|_| stream.flush());
+        stream.conn.send_close_notify();
+        let _ = stream.flush();
+    })
+}
+
+/// Connect to `addr` and read the sentence sent by [serve_once]
+fn receive(addr: &str, options: &TlsOptions) -> Result<String, String> {
+    let (conn, sock) = tls_connection_with(addr.to_string(), options).map_err(|e| e.to_string())?;
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut stream = StreamOwned::new(conn, sock);
+    let mut buf = String::new();
+    stream.read_to_string(&mut buf).map_err(|e| e.to_string())?;
+    Ok(buf)
+}
+
+fn trust_test_ca() -> TlsOptions {
+    TlsOptions {
+        ca_file: Some(fixture("ca.pem")),
+        server_name: Some("localhost".to_string()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_tls_custom_ca() {
+    let server = serve_once("127.0.0.1:9960", false);
+    assert_eq!(
+        receive("127.0.0.1:9960", &trust_test_ca()).unwrap(),
+        POSITION
+    );
+    server.join().unwrap();
+
+    // the built-in roots do not trust the test CA
+    let server = serve_once("127.0.0.1:9961", false);
+    let options = TlsOptions {
+        server_name: Some("localhost".to_string()),
+        ..Default::default()
+    };
+    let e = receive("127.0.0.1:9961", &options).unwrap_err();
+    assert!(e.contains("UnknownIssuer"), "{}", e);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_tls_client_certificate() {
+    let server = serve_once("127.0.0.1:9962", true);
+    let options = TlsOptions {
+        client_cert: Some(fixture("client.pem")),
+        client_key: Some(fixture("client.key")),
+        ..trust_test_ca()
+    };
+    assert_eq!(receive("127.0.0.1:9962", &options).unwrap(), POSITION);
+    server.join().unwrap();
+
+    let server = serve_once("127.0.0.1:9963", true);
+    assert!(receive("127.0.0.1:9963", &trust_test_ca()).is_err());
+    server.join().unwrap();
+}
+
+#[test]
+fn test_tls_pinning() {
+    let server = serve_once("127.0.0.1:9964", false);
+    let options = TlsOptions {
+        pins: vec![server_fingerprint()],
+        ..trust_test_ca()
+    };
+    assert_eq!(receive("127.0.0.1:9964", &options).unwrap(), POSITION);
+    server.join().unwrap();
+
+    let server = serve_once("127.0.0.1:9965", false);
+    let options = TlsOptions {
+        pins: vec![[0u8; 32]],
+        ..trust_test_ca()
+    };
+    let e = receive("127.0.0.1:9965", &options).unwrap_err();
+    assert!(e.contains("pinned fingerprint"), "{}", e);
+    server.join().unwrap();
+
+    // insecure mode skips verification by the CA, but not by pins
+    let server = serve_once("127.0.0.1:9966", false);
+    let options = TlsOptions {
+        pins: vec![server_fingerprint()],
+        insecure: true,
+        ..Default::default()
+    };
+    assert_eq!(receive("127.0.0.1:9966", &options).unwrap(), POSITION);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_tls_options_errors() {
+    let e = tls_connection_with("[::1]:9967".to_string(), &TlsOptions::default()).unwrap_err();
+    assert!(e.to_string().contains("IP address ::1"), "{}", e);
+
+    let options = TlsOptions {
+        client_cert: Some(fixture("client.pem")),
+        ..trust_test_ca()
+    };
+    let e = tls_connection_with("localhost:9967".to_string(), &options).unwrap_err();
+    assert!(e.to_string().contains("must be given together"), "{}", e);
+
+    let options = UpstreamOptions {
+        tls: TlsOptions {
+            ca_file: Some(fixture("missing.pem")),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    assert!(proxy_tcp_udp_with(
+        "localhost:9967".to_string(),
+        "127.0.0.1:9968".to_string(),
+        &options
+    )
+    .is_err());
+
+    let fingerprint = server_fingerprint();
+    let hex: Vec<String> = fingerprint.iter().map(|b| format!("{:02X}", b)).collect();
+    assert_eq!(parse_fingerprint(&hex.join(":")).unwrap(), fingerprint);
+    assert_eq!(
+        parse_fingerprint(&hex.join("").to_lowercase()).unwrap(),
+        fingerprint
+    );
+    assert!(parse_fingerprint("D0:C8").is_err());
+}
+
+#[test]
+fn test_proxy_tcp_udp_tls() {
+    let target = UdpSocket::bind("127.0.0.1:9970").unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let server = serve_once("127.0.0.1:9969", false);
+    let thread = proxy_tcp_udp_with(
+        "127.0.0.1:9969".to_string(),
+        "127.0.0.1:9970".to_string(),
+        &UpstreamOptions {
+            tls: trust_test_ca(),
+            preamble: Preamble::None,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let mut buf = [0u8; 1024];
+    let (c, _) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..c], POSITION.as_bytes());
+    server.join().unwrap();
+    thread.shutdown().unwrap();
+}
+
+#[test]
+fn test_proxy_tcp_udp_tls_default_preamble() {
+    let target = UdpSocket::bind("127.0.0.1:9945").unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![Certificate(pem("server.pem"))],
+            PrivateKey(pem("server.key")),
+        )
+        .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:9944").unwrap();
+    let server = spawn(move || {
+        let (sock, _) = listener.accept().unwrap();
+        let conn = ServerConnection::new(Arc::new(config)).unwrap();
+        let mut stream = BufReader::new(StreamOwned::new(conn, sock));
+        let mut request = String::new();
+        while !request.ends_with("\r\n\r\n") {
+            assert!(stream.read_line(&mut request).unwrap() > 0);
+        }
+        let stream = stream.get_mut();
+        write!(stream, "HTTP/1.0 200 OK\r\n\r\n{}", POSITION).unwrap();
+        stream.conn.send_close_notify();
+        stream.flush().unwrap();
+        request
+    });
+
+    // TLS upstreams are sent GET / by default, as in earlier versions
+    assert_eq!(
+        UpstreamOptions::default().preamble,
+        Preamble::Http(HttpRequest {
+            path: "/".to_string(),
+            headers: vec![],
+        })
+    );
+    let thread = proxy_tcp_udp_with(
+        "127.0.0.1:9944".to_string(),
+        "127.0.0.1:9945".to_string(),
+        &UpstreamOptions {
+            tls: trust_test_ca(),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let mut buf = [0u8; 1024];
+    let (c, _) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..c], POSITION.as_bytes());
+    let request = server.join().unwrap();
+    assert!(request.starts_with("GET / HTTP/1.0\r\n"), "{}", request);
+    thread.shutdown().unwrap();
+}
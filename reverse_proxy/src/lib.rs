@@ -10,7 +10,7 @@
 //! In `Cargo.toml`
 //! ```toml
 //! [dependencies]
-//! mproxy-reverse = "0.1"
+//! mproxy-reverse = { version = "0.1", features = ["tls"] }
 //! ```
 //!
 //! Example `src/main.rs`
@@ -27,27 +27,47 @@
 //!
 //! // TCP connection listener -> UDP multicast channel
 //! if let Some(tcpin) = tcp_listen_addr {
-//!     let tcp_rproxy = reverse_proxy_tcp_udp(tcpin, multicast_addr.clone());
+//!     let tcp_rproxy = reverse_proxy_tcp_udp(tcpin, multicast_addr.clone()).unwrap();
 //!     threads.push(tcp_rproxy);
 //! }
 //!
 //! // UDP multicast listener -> TCP sender
 //! if let Some(tcpout) = &tcp_output_addr {
-//!     let tcp_proxy = reverse_proxy_udp_tcp(multicast_addr.clone(), tcpout.to_string());
+//!     let tcp_proxy = reverse_proxy_udp_tcp(multicast_addr.clone(), tcpout.to_string()).unwrap();
 //!     threads.push(tcp_proxy);
 //! }
 //!
 //! // UDP multicast listener -> UDP sender
 //! if let Some(udpout) = udp_output_addr {
-//!     let udp_proxy = reverse_proxy_udp(multicast_addr, udpout);
+//!     let udp_proxy = reverse_proxy_udp(multicast_addr, udpout).unwrap();
 //!     threads.push(udp_proxy);
 //! }
 //!
+//! // run until the threads exit. Call thread.shutdown() to stop a thread
 //! for thread in threads {
 //!     thread.join().unwrap();
 //! }
 //! ```
 //!
+//! ## TLS
+//! With feature `tls`, the TCP listeners may terminate TLS, and require
+//! clients to present a certificate signed by a given CA
+//! ```rust,no_run
+//! use mproxy_reverse::{reverse_proxy_udp_tcp_with, ReverseOptions, TlsServerOptions};
+//!
+//! let options = ReverseOptions {
+//!     tls: TlsServerOptions {
+//!         cert: Some("server.pem".into()),
+//!         key: Some("server.key".into()),
+//!         client_ca: Some("partners_ca.pem".into()),
+//!     },
+//!     ..Default::default()
+//! };
+//! let thread =
+//!     reverse_proxy_udp_tcp_with("[ff02::1]:9918".into(), "0.0.0.0:9921".into(), &options).unwrap();
+//! thread.join().unwrap();
+//! ```
+//!
 //! ## Command Line Interface
 //! Install with Cargo
 //! ```bash
@@ -75,6 +95,31 @@
 //!   -h, --help    Prints help information
 //!   -t, --tee     Print UDP input to stdout
 //!
+//! FILTER OPTIONS:
+//!   --filter [IP=RULES]  Forward only AIS messages matching RULES to TCP clients connecting from IP.
+//!                        May be repeated. Lines which are not AIS messages are not forwarded to
+//!                        filtered clients. RULES are separated by ';'
+//!     types:LIST                            Message types or ranges, e.g. types:1-3,18
+//!     deny-types:LIST                       Drop message types or ranges
+//!     mmsi-allow:FILE                       MMSIs separated by whitespace or commas
+//!     mmsi-deny:FILE                        Drop MMSIs listed in FILE
+//!     bbox:MIN_LON,MIN_LAT,MAX_LON,MAX_LAT  Geofence by bounding box
+//!     polygon:LON LAT,LON LAT,...           Geofence by polygon
+//!   Static messages pass the geofence if the vessel's last position was inside it,
+//!   within the past hour
+//!
+//! DOWNSAMPLING OPTIONS:
+//!   --downsample [IP=MILLIS]  Forward at most one position report per vessel per MILLIS to TCP
+//!                             clients connecting from IP, after any --filter. May be repeated.
+//!                             Static and voyage data and other messages are always forwarded
+//!
+//! TLS OPTIONS:
+//!   Apply to --tcp-listen-addr and --tcp-output-addr. Require crate feature tls
+//!   --tls-cert      [FILE]  PEM certificate chain presented to TCP clients. Enables TLS
+//!   --tls-key       [FILE]  PEM private key of --tls-cert, PKCS#8, PKCS#1 or SEC1
+//!   --tls-client-ca [FILE]  PEM bundle of CA certificates. TCP clients must present a
+//!                           certificate signed by one of them
+//!
 //! EXAMPLE:
 //!   mproxy-reverse --udp-listen-addr '0.0.0.0:9920' --tcp-output-addr '[::1]:9921' --multicast-addr '224.0.0.1:9922'
 //! ```
@@ -86,139 +131,134 @@
 //! - [mproxy-reverse](https://docs.rs/mproxy-reverse/)
 //!
 
-use std::io::{BufWriter, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::thread::{spawn, JoinHandle};
-
-use mproxy_client::target_socket_interface;
-use mproxy_server::upstream_socket_interface;
+use std::collections::HashMap;
+use std::net::IpAddr;
 
-const BUFSIZE: usize = 8096;
+mod tls;
+pub use tls::TlsServerOptions;
 
-fn handle_client_tcp(downstream: TcpStream, multicast_addr: String) {
-    #[cfg(debug_assertions)]
-    println!(
-        "handling downstream client: {} UDP -> {:?} TCP",
-        multicast_addr, downstream
-    );
-    let (_multicast_addr, multicast_socket) =
-        if let Ok((addr, socket)) = upstream_socket_interface(multicast_addr) {
-            if !addr.ip().is_multicast() {
-                panic!("not a multicast address {}", addr);
-            }
-            (addr, socket)
-        } else {
-            panic!()
-        };
+use mproxy_common::{pump, Pipeline, Sink, TcpFanoutSink, TcpListenSource, UdpSink, UdpSource};
+pub use mproxy_common::{Downsampler, Geofence, MessageFilter, MproxyError, ShutdownHandle};
 
-    let mut buf = [0u8; BUFSIZE];
-    let mut tcp_writer = BufWriter::new(downstream);
+/// Options for [reverse_proxy_udp_tcp_with]
+#[derive(Clone, Debug, Default)]
+pub struct ReverseOptions {
+    /// Filters for individual TCP clients, keyed by the client IP address.
+    /// Connections from the same address share one filter and rate limit.
+    /// Other clients receive all input
+    pub filters: HashMap<IpAddr, MessageFilter>,
+    /// Rate limits on position reports for individual TCP clients, keyed by
+    /// the client IP address and applied after filters
+    pub downsample: HashMap<IpAddr, Downsampler>,
+    /// TLS for the TCP listener. Requires crate feature `tls`
+    pub tls: TlsServerOptions,
+}
 
-    loop {
-        match multicast_socket.recv_from(&mut buf[0..]) {
-            Ok((count_input, _remote_addr)) => {
-                //println!("{}", String::from_utf8_lossy(&buf[0..count_input]));
-                let _count_output = tcp_writer.write(&buf[0..count_input]);
-            }
-            Err(err) => {
-                eprintln!("reverse_proxy: got an error: {}", err);
-                break;
-            }
+impl ReverseOptions {
+    /// Pipelines for TCP clients connecting from each IP address with a
+    /// filter or rate limit configured
+    fn client_pipelines(&self) -> HashMap<IpAddr, Pipeline> {
+        let mut pipelines: HashMap<IpAddr, Pipeline> = HashMap::new();
+        for (ip, filter) in &self.filters {
+            pipelines
+                .entry(*ip)
+                .or_default()
+                .push(Box::new(filter.clone()));
         }
-        if let Err(_e) = tcp_writer.flush() {
-            #[cfg(debug_assertions)]
-            eprintln!("reverse_proxy: closing {:?} {}", multicast_socket, _e);
-            break;
+        for (ip, downsampler) in &self.downsample {
+            pipelines
+                .entry(*ip)
+                .or_default()
+                .push(Box::new(downsampler.clone()));
         }
+        pipelines
     }
 }
 
 /// Forward a UDP socket stream (e.g. from a multicast channel) to connected TCP clients.
-/// Spawns a listener thread, plus one thread for each incoming TCP connection.
-pub fn reverse_proxy_udp_tcp(multicast_addr: String, tcp_listen_addr: String) -> JoinHandle<()> {
+/// Spawns a single thread, which accepts TCP connections and writes to each client.
+pub fn reverse_proxy_udp_tcp(
+    multicast_addr: String,
+    tcp_listen_addr: String,
+) -> Result<ShutdownHandle, MproxyError> {
+    reverse_proxy_udp_tcp_with(multicast_addr, tcp_listen_addr, &ReverseOptions::default())
+}
+
+/// Forward a UDP socket stream to connected TCP clients, passing each client
+/// only the messages selected by its filter in `options`
+pub fn reverse_proxy_udp_tcp_with(
+    multicast_addr: String,
+    tcp_listen_addr: String,
+    options: &ReverseOptions,
+) -> Result<ShutdownHandle, MproxyError> {
     #[cfg(debug_assertions)]
     println!(
         "forwarding: {} UDP -> {} TCP",
         multicast_addr, tcp_listen_addr
     );
-    spawn(move || {
-        let listener = TcpListener::bind(tcp_listen_addr).expect("binding downstream TCP Listener");
-        for stream in listener.incoming() {
-            #[cfg(debug_assertions)]
-            println!("new client {:?}", stream);
-            let multicast_addr = multicast_addr.clone();
-            let _tcp_client = spawn(move || {
-                handle_client_tcp(stream.unwrap(), multicast_addr);
-            });
-        }
+    let mut source = UdpSource::bind(multicast_addr)?;
+    if !source.addr().ip().is_multicast() {
+        return Err(MproxyError::MulticastJoin {
+            addr: source.addr().to_string(),
+            source: std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "not a multicast address",
+            ),
+        });
+    }
+    let acceptor = tls::acceptor(&tcp_listen_addr, &options.tls)?;
+    let mut fanout =
+        TcpFanoutSink::bind(&tcp_listen_addr)?.with_pipelines(options.client_pipelines());
+    if let Some(acceptor) = acceptor {
+        fanout = fanout.with_acceptor(acceptor);
+    }
+    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(fanout)];
+    let name = format!("{}:reverse_proxy_udp_tcp", tcp_listen_addr);
+    ShutdownHandle::spawn(name, move |shutdown| {
+        pump(&mut source, &mut sinks, &shutdown)
     })
 }
 
 /// Forward bytes from UDP upstream socket address to UDP downstream socket address
-pub fn reverse_proxy_udp(udp_input_addr: String, udp_output_addr: String) -> JoinHandle<()> {
+pub fn reverse_proxy_udp(
+    udp_input_addr: String,
+    udp_output_addr: String,
+) -> Result<ShutdownHandle, MproxyError> {
     #[cfg(debug_assertions)]
     println!(
         "forwarding: {} UDP -> {} UDP",
         udp_input_addr, udp_output_addr
     );
-    spawn(move || {
-        let (addr, listen_socket) = upstream_socket_interface(udp_input_addr).unwrap();
-        let (outaddr, output_socket) = target_socket_interface(&udp_output_addr).unwrap();
-
-        let mut buf = [0u8; BUFSIZE];
-        loop {
-            match listen_socket.recv_from(&mut buf[0..]) {
-                Ok((c, remote_addr)) => {
-                    if c == 0 {
-                        eprintln!("got message with size 0 from upstream: {}", remote_addr);
-                    } else {
-                        let c_out = output_socket
-                            .send_to(&buf[0..c], outaddr)
-                            .expect("forwarding UDP downstream");
-                        assert!(c == c_out);
-                        //println!("{}", String::from_utf8_lossy(&buf[0..c]));
-                    }
-                }
-                Err(err) => {
-                    eprintln!("{}:reverse_proxy: error {}", addr, err);
-                    break;
-                }
-            }
-        }
+    let mut source = UdpSource::bind(udp_input_addr)?;
+    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(UdpSink::connect(&udp_output_addr)?)];
+    let name = format!("{}:reverse_proxy_udp", source.addr());
+    ShutdownHandle::spawn(name, move |shutdown| {
+        pump(&mut source, &mut sinks, &shutdown)
     })
 }
 
 /// Listen for incoming TCP connections and forward received bytes to a UDP socket address
-pub fn reverse_proxy_tcp_udp(upstream_tcp: String, downstream_udp: String) -> JoinHandle<()> {
-    //pub fn reverse_proxy_tcp_udp(upstream_tcp: String, downstream_udp: String) {
-    spawn(move || {
-        let listener = TcpListener::bind(upstream_tcp).expect("binding TCP socket");
-
-        for upstream in listener.incoming() {
-            let (target_addr, target_socket) = target_socket_interface(&downstream_udp).unwrap();
-            let mut buf = [0u8; BUFSIZE];
-            //let mut stream = stream.as_ref().expect("connecting to stream");
+pub fn reverse_proxy_tcp_udp(
+    upstream_tcp: String,
+    downstream_udp: String,
+) -> Result<ShutdownHandle, MproxyError> {
+    reverse_proxy_tcp_udp_with(upstream_tcp, downstream_udp, &TlsServerOptions::default())
+}
 
-            match upstream {
-                Ok(mut input) => {
-                    spawn(move || loop {
-                        match input.read(&mut buf[0..]) {
-                            Ok(c) => {
-                                target_socket
-                                    .send_to(&buf[0..c], target_addr)
-                                    .expect("sending to UDP socket");
-                            }
-                            Err(e) => {
-                                eprintln!("err: {}", e);
-                                break;
-                            }
-                        }
-                    });
-                }
-                Err(e) => {
-                    eprintln!("dropping client: {}", e);
-                }
-            }
-        }
+/// Listen for incoming TCP connections, as [reverse_proxy_tcp_udp],
+/// terminating TLS according to `tls`
+pub fn reverse_proxy_tcp_udp_with(
+    upstream_tcp: String,
+    downstream_udp: String,
+    tls: &TlsServerOptions,
+) -> Result<ShutdownHandle, MproxyError> {
+    let mut source = match tls::acceptor(&upstream_tcp, tls)? {
+        Some(acceptor) => TcpListenSource::bind_with(&upstream_tcp, acceptor)?,
+        None => TcpListenSource::bind(&upstream_tcp)?,
+    };
+    let mut sinks: Vec<Box<dyn Sink>> = vec![Box::new(UdpSink::connect(&downstream_udp)?)];
+    let name = format!("{}:reverse_proxy_tcp_udp", upstream_tcp);
+    ShutdownHandle::spawn(name, move |shutdown| {
+        pump(&mut source, &mut sinks, &shutdown)
     })
 }
@@ -1,7 +1,13 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::process::exit;
+use std::time::Duration;
 
 use mproxy_forward::forward_udp;
-use mproxy_reverse::{reverse_proxy_tcp_udp, reverse_proxy_udp, reverse_proxy_udp_tcp};
+use mproxy_reverse::{
+    reverse_proxy_tcp_udp_with, reverse_proxy_udp, reverse_proxy_udp_tcp_with, Downsampler,
+    MessageFilter, ReverseOptions, TlsServerOptions,
+};
 
 use pico_args::Arguments;
 
@@ -26,6 +32,31 @@ FLAGS:
   -h, --help    Prints help information
   -t, --tee     Print UDP input to stdout
 
+FILTER OPTIONS:
+  --filter [IP=RULES]  Forward only AIS messages matching RULES to TCP clients connecting from IP.
+                       May be repeated. Lines which are not AIS messages are not forwarded to
+                       filtered clients. RULES are separated by ';'
+    types:LIST                            Message types or ranges, e.g. types:1-3,18
+    deny-types:LIST                       Drop message types or ranges
+    mmsi-allow:FILE                       MMSIs separated by whitespace or commas
+    mmsi-deny:FILE                        Drop MMSIs listed in FILE
+    bbox:MIN_LON,MIN_LAT,MAX_LON,MAX_LAT  Geofence by bounding box
+    polygon:LON LAT,LON LAT,...           Geofence by polygon
+  Static messages pass the geofence if the vessel's last position was inside it,
+  within the past hour
+
+DOWNSAMPLING OPTIONS:
+  --downsample [IP=MILLIS]  Forward at most one position report per vessel per MILLIS to TCP
+                            clients connecting from IP, after any --filter. May be repeated.
+                            Static and voyage data and other messages are always forwarded
+
+TLS OPTIONS:
+  Apply to --tcp-listen-addr and --tcp-output-addr. Require crate feature tls
+  --tls-cert      [FILE]  PEM certificate chain presented to TCP clients. Enables TLS
+  --tls-key       [FILE]  PEM private key of --tls-cert, PKCS#8, PKCS#1 or SEC1
+  --tls-client-ca [FILE]  PEM bundle of CA certificates. TCP clients must present a
+                          certificate signed by one of them
+
 EXAMPLE:
   mproxy-reverse --udp-listen-addr '0.0.0.0:9920' --tcp-output-addr '[::1]:9921' --multicast-addr '224.0.0.1:9922'
 
@@ -37,9 +68,38 @@ pub struct ReverseProxyArgs {
     pub multicast_addr: Option<String>,
     pub tcp_output_addr: Option<String>,
     pub udp_output_addr: Option<String>,
+    pub filters: HashMap<IpAddr, MessageFilter>,
+    pub downsample: HashMap<IpAddr, Downsampler>,
+    pub tls: TlsServerOptions,
     pub tee: bool,
 }
 
+/// Parse a TCP client IP address and its filter rules, e.g. `10.0.0.5=types:1-3`
+fn parse_filter(arg: &str) -> Result<(IpAddr, MessageFilter), String> {
+    let (ip, rules) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected IP=RULES, got '{}'", arg))?;
+    let ip = ip
+        .parse::<IpAddr>()
+        .map_err(|e| format!("invalid client IP '{}': {}", ip, e))?;
+    let filter = MessageFilter::parse(rules).map_err(|e| e.to_string())?;
+    Ok((ip, filter))
+}
+
+/// Parse a TCP client IP address and its downsampling interval, e.g. `10.0.0.5=30000`
+fn parse_downsample(arg: &str) -> Result<(IpAddr, Downsampler), String> {
+    let (ip, millis) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected IP=MILLIS, got '{}'", arg))?;
+    let ip = ip
+        .parse::<IpAddr>()
+        .map_err(|e| format!("invalid client IP '{}': {}", ip, e))?;
+    let millis = millis
+        .parse::<u64>()
+        .map_err(|e| format!("invalid interval '{}': {}", millis, e))?;
+    Ok((ip, Downsampler::new(Duration::from_millis(millis))))
+}
+
 fn parse_args() -> Result<ReverseProxyArgs, pico_args::Error> {
     let mut pargs = Arguments::from_env();
     if pargs.contains(["-h", "--help"]) || pargs.clone().finish().is_empty() {
@@ -53,6 +113,19 @@ fn parse_args() -> Result<ReverseProxyArgs, pico_args::Error> {
         multicast_addr: pargs.opt_value_from_str("--multicast-addr")?,
         tcp_output_addr: pargs.opt_value_from_str("--tcp-output-addr")?,
         udp_output_addr: pargs.opt_value_from_str("--udp-output-addr")?,
+        filters: pargs
+            .values_from_fn("--filter", parse_filter)?
+            .into_iter()
+            .collect(),
+        downsample: pargs
+            .values_from_fn("--downsample", parse_downsample)?
+            .into_iter()
+            .collect(),
+        tls: TlsServerOptions {
+            cert: pargs.opt_value_from_str("--tls-cert")?,
+            key: pargs.opt_value_from_str("--tls-key")?,
+            client_ca: pargs.opt_value_from_str("--tls-client-ca")?,
+        },
         tee,
     };
     let remaining = pargs.finish();
@@ -82,19 +155,25 @@ pub fn main() {
     // UDP listener thread -> UPD multicast sender
     // rebroadcast upstream UDP via multicast to client threads
     if let Some(udp_listen) = args.udp_listen_addr {
-        let multicast = forward_udp(udp_listen, &[multicast.to_string()], args.tee);
+        let multicast = forward_udp(udp_listen, std::slice::from_ref(&multicast), args.tee);
         threads.push(multicast);
     }
 
     // UDP multicast listener -> TCP sender
     if let Some(tcpout) = &args.tcp_output_addr {
-        let tcp_proxy = reverse_proxy_udp_tcp(multicast.to_string(), tcpout.to_string());
+        let options = ReverseOptions {
+            filters: args.filters,
+            downsample: args.downsample,
+            tls: args.tls.clone(),
+        };
+        let tcp_proxy =
+            reverse_proxy_udp_tcp_with(multicast.to_string(), tcpout.to_string(), &options);
         threads.push(tcp_proxy);
     }
 
     // TCP connection listener -> UDP multicast
     if let Some(tcpin) = args.tcp_listen_addr {
-        let tcp_rproxy = reverse_proxy_tcp_udp(tcpin, multicast.to_string());
+        let tcp_rproxy = reverse_proxy_tcp_udp_with(tcpin, multicast.to_string(), &args.tls);
         threads.push(tcp_rproxy);
     }
 
@@ -104,7 +183,17 @@ pub fn main() {
         threads.push(udp_proxy);
     }
 
+    let threads = match threads.into_iter().collect::<Result<Vec<_>, _>>() {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Error: {}.", e);
+            exit(1);
+        }
+    };
+
     for thread in threads {
-        thread.join().unwrap();
+        if let Err(e) = thread.join() {
+            eprintln!("Error: {}.", e);
+        }
     }
 }
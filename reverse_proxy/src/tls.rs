@@ -0,0 +1,124 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use mproxy_common::{Acceptor, MproxyError};
+
+#[cfg(feature = "tls")]
+use std::net::TcpStream;
+
+#[cfg(feature = "tls")]
+use mproxy_common::{load_certs, load_key, Connection};
+#[cfg(feature = "tls")]
+use rustls::server::{AllowAnyAuthenticatedClient, ServerConfig, ServerConnection};
+#[cfg(feature = "tls")]
+use rustls::{RootCertStore, StreamOwned};
+
+/// TLS settings for TCP listeners of the reverse proxy. Requires crate
+/// feature `tls`. The default accepts plaintext connections
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsServerOptions {
+    /// PEM certificate chain presented to clients. Enables TLS
+    pub cert: Option<PathBuf>,
+    /// PEM private key of `cert`, in PKCS#8, PKCS#1 or SEC1 format.
+    /// Encrypted keys are not supported
+    pub key: Option<PathBuf>,
+    /// PEM bundle of CA certificates. If set, clients must present a
+    /// certificate signed by one of them
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsServerOptions {
+    /// Whether connections are encrypted
+    pub fn enabled(&self) -> bool {
+        self.cert.is_some()
+    }
+
+    fn validate(&self) -> Result<(), MproxyError> {
+        if self.cert.is_some() != self.key.is_some() {
+            return Err(MproxyError::Config(
+                "a TLS certificate and key must be given together".to_string(),
+            ));
+        }
+        if self.client_ca.is_some() && !self.enabled() {
+            return Err(MproxyError::Config(
+                "a TLS client CA requires a certificate and key".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// [Acceptor] for `options`, or `None` for plaintext connections
+#[cfg(not(feature = "tls"))]
+pub(crate) fn acceptor(
+    _addr: &str,
+    options: &TlsServerOptions,
+) -> Result<Option<Arc<dyn Acceptor>>, MproxyError> {
+    options.validate()?;
+    if options.enabled() {
+        return Err(MproxyError::Config(
+            "TLS options require crate feature tls".to_string(),
+        ));
+    }
+    Ok(None)
+}
+
+/// [Acceptor] for `options`, or `None` for plaintext connections
+#[cfg(feature = "tls")]
+pub(crate) fn acceptor(
+    addr: &str,
+    options: &TlsServerOptions,
+) -> Result<Option<Arc<dyn Acceptor>>, MproxyError> {
+    options.validate()?;
+    let (Some(cert), Some(key)) = (&options.cert, &options.key) else {
+        return Ok(None);
+    };
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &options.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(&cert).map_err(|e| MproxyError::Tls {
+                    addr: addr.to_string(),
+                    reason: format!("invalid CA certificate in {}: {}", path.display(), e),
+                })?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| MproxyError::Tls {
+            addr: addr.to_string(),
+            reason: format!("invalid certificate or key: {}", e),
+        })?;
+    Ok(Some(Arc::new(TlsAcceptor {
+        config: Arc::new(config),
+    })))
+}
+
+/// Terminates TLS on accepted connections
+#[cfg(feature = "tls")]
+struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+#[cfg(feature = "tls")]
+impl Acceptor for TlsAcceptor {
+    fn accept(&self, mut stream: TcpStream) -> Result<Box<dyn Connection>, MproxyError> {
+        let peer_addr = stream.peer_addr()?.to_string();
+        let tls_err = |reason: String| MproxyError::Tls {
+            addr: peer_addr.clone(),
+            reason,
+        };
+        let mut conn = ServerConnection::new(self.config.clone())
+            .map_err(|e| tls_err(format!("performing handshake: {}", e)))?;
+        // complete the handshake before the client is sent or read from
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)
+                .map_err(|e| tls_err(format!("performing handshake: {}", e)))?;
+        }
+        Ok(Box::new(StreamOwned::new(conn, stream)))
+    }
+}
@@ -1,9 +1,13 @@
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
 use std::path::PathBuf;
 use std::thread::sleep;
 use std::time::Duration;
 
-use mproxy_client::client_socket_stream;
-use mproxy_reverse::reverse_proxy_udp_tcp;
+use mproxy_client::{client_socket_stream, target_socket_interface};
+use mproxy_reverse::{
+    reverse_proxy_udp_tcp, reverse_proxy_udp_tcp_with, Downsampler, MessageFilter, ReverseOptions,
+};
 
 use testconfig::TESTDATA;
 
@@ -16,11 +20,11 @@ fn test_reverse_proxy_tcp() {
     let data = PathBuf::from(TESTDATA);
 
     // start reverse proxy and wait a moment for thread to spawn
-    let _r = reverse_proxy_udp_tcp(multicast_addr, proxy_tcp_output_addr);
+    let _r = reverse_proxy_udp_tcp(multicast_addr, proxy_tcp_output_addr).unwrap();
     sleep(Duration::from_millis(30));
 
     // send some data via the proxy
-    let _c = client_socket_stream(&data, vec![client_target_addr], false);
+    let _c = client_socket_stream(&data, vec![client_target_addr], false, None);
     sleep(Duration::from_millis(15));
 }
 
@@ -33,10 +37,111 @@ fn test_reverse_proxy_tcp_ipv6() {
     let data = PathBuf::from(TESTDATA);
 
     // start reverse proxy and wait a moment for thread to spawn
-    let _r = reverse_proxy_udp_tcp(multicast_addr, proxy_tcp_output_addr);
+    let _r = reverse_proxy_udp_tcp(multicast_addr, proxy_tcp_output_addr).unwrap();
     sleep(Duration::from_millis(30));
 
     // send some data via the proxy
-    let _c = client_socket_stream(&data, vec![client_target_addr], false);
+    let _c = client_socket_stream(&data, vec![client_target_addr], false, None);
     sleep(Duration::from_millis(15));
 }
+
+#[test]
+fn test_reverse_proxy_tcp_shutdown() {
+    let multicast_addr = "224.0.0.1:8996".to_string();
+    let proxy_tcp_output_addr = "127.0.0.1:8997".to_string();
+
+    let r = reverse_proxy_udp_tcp(multicast_addr.clone(), proxy_tcp_output_addr.clone()).unwrap();
+    let _client = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+    sleep(Duration::from_millis(150));
+
+    // connected clients are closed on shutdown
+    r.shutdown().unwrap();
+
+    let r = reverse_proxy_udp_tcp(multicast_addr, proxy_tcp_output_addr).unwrap();
+    r.shutdown().unwrap();
+}
+
+#[test]
+fn test_reverse_proxy_tcp_filters() {
+    let multicast_addr = "224.0.0.1:8980".to_string();
+    let proxy_tcp_output_addr = "127.0.0.1:8981".to_string();
+    let position = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+    let class_b = "!AIVDM,1,1,,A,B52MJh00Nmks:05J4L1howV44000,0*1D\n";
+
+    let options = ReverseOptions {
+        filters: [(
+            "127.0.0.1".parse().unwrap(),
+            MessageFilter::parse("types:18").unwrap(),
+        )]
+        .into(),
+        ..Default::default()
+    };
+    let r = reverse_proxy_udp_tcp_with(
+        multicast_addr.clone(),
+        proxy_tcp_output_addr.clone(),
+        &options,
+    )
+    .unwrap();
+    let client = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    sleep(Duration::from_millis(150));
+
+    let (target_addr, target_socket) = target_socket_interface(&multicast_addr).unwrap();
+    for msg in [position, class_b] {
+        target_socket.send_to(msg.as_bytes(), target_addr).unwrap();
+    }
+
+    // the client connecting from a filtered address receives matching messages only
+    let mut line = String::new();
+    BufReader::new(client).read_line(&mut line).unwrap();
+    assert_eq!(line, class_b);
+    r.shutdown().unwrap();
+}
+
+#[test]
+fn test_reverse_proxy_tcp_downsample_shared_address() {
+    let multicast_addr = "224.0.0.1:8982".to_string();
+    let proxy_tcp_output_addr = "127.0.0.1:8983".to_string();
+    let position = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+    let class_b = "!AIVDM,1,1,,A,B52MJh00Nmks:05J4L1howV44000,0*1D\n";
+
+    let options = ReverseOptions {
+        downsample: [(
+            "127.0.0.1".parse().unwrap(),
+            Downsampler::new(Duration::from_secs(60)),
+        )]
+        .into(),
+        ..Default::default()
+    };
+    let r = reverse_proxy_udp_tcp_with(
+        multicast_addr.clone(),
+        proxy_tcp_output_addr.clone(),
+        &options,
+    )
+    .unwrap();
+    let clients = [0, 1].map(|_| {
+        let client = TcpStream::connect(&proxy_tcp_output_addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        BufReader::new(client)
+    });
+    sleep(Duration::from_millis(150));
+
+    let (target_addr, target_socket) = target_socket_interface(&multicast_addr).unwrap();
+    for msg in [position, position, class_b] {
+        target_socket.send_to(msg.as_bytes(), target_addr).unwrap();
+    }
+
+    // clients connecting from the same address each receive the report once
+    for mut client in clients {
+        for expected in [position, class_b] {
+            let mut line = String::new();
+            client.read_line(&mut line).unwrap();
+            assert_eq!(line, expected);
+        }
+    }
+    r.shutdown().unwrap();
+}
@@ -0,0 +1,145 @@
+#![cfg(feature = "tls")]
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use mproxy_client::target_socket_interface;
+use mproxy_common::{load_certs, load_key};
+use mproxy_reverse::{
+    reverse_proxy_tcp_udp_with, reverse_proxy_udp_tcp_with, ReverseOptions, TlsServerOptions,
+};
+use rustls::client::{ClientConfig, ClientConnection, ServerName};
+use rustls::{RootCertStore, StreamOwned};
+
+use testconfig::TESTINGDIR;
+
+const POSITION: &str = "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n";
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from([TESTINGDIR, "tls/", name].join(""))
+}
+
+fn server_options(client_auth: bool) -> TlsServerOptions {
+    TlsServerOptions {
+        cert: Some(fixture("server.pem")),
+        key: Some(fixture("server.key")),
+        client_ca: client_auth.then(|| fixture("ca.pem")),
+    }
+}
+
+/// Connect to `addr` trusting the test CA, optionally with the client
+/// certificate, and complete the handshake
+fn connect(
+    addr: &str,
+    client_cert: bool,
+) -> std::io::Result<StreamOwned<ClientConnection, TcpStream>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&fixture("ca.pem")).unwrap() {
+        roots.add(&cert).unwrap();
+    }
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = if client_cert {
+        builder
+            .with_single_cert(
+                load_certs(&fixture("client.pem")).unwrap(),
+                load_key(&fixture("client.key")).unwrap(),
+            )
+            .unwrap()
+    } else {
+        builder.with_no_client_auth()
+    };
+    let name = ServerName::try_from("localhost").unwrap();
+    let mut conn = ClientConnection::new(Arc::new(config), name).unwrap();
+    let mut sock = TcpStream::connect(addr)?;
+    sock.set_read_timeout(Some(Duration::from_millis(1000)))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock)?;
+    }
+    Ok(StreamOwned::new(conn, sock))
+}
+
+#[test]
+fn test_reverse_proxy_udp_tcp_tls() {
+    let multicast_addr = "224.0.0.1:9980".to_string();
+    let tcp_output_addr = "127.0.0.1:9981".to_string();
+    let options = ReverseOptions {
+        tls: server_options(false),
+        ..Default::default()
+    };
+    let r = reverse_proxy_udp_tcp_with(multicast_addr.clone(), tcp_output_addr.clone(), &options)
+        .unwrap();
+    let client = connect(&tcp_output_addr, false).unwrap();
+    sleep(Duration::from_millis(150));
+
+    let (target_addr, target_socket) = target_socket_interface(&multicast_addr).unwrap();
+    target_socket
+        .send_to(POSITION.as_bytes(), target_addr)
+        .unwrap();
+
+    let mut line = String::new();
+    BufReader::new(client).read_line(&mut line).unwrap();
+    assert_eq!(line, POSITION);
+    r.shutdown().unwrap();
+}
+
+#[test]
+fn test_reverse_proxy_tcp_udp_tls_client_auth() {
+    let tcp_listen_addr = "127.0.0.1:9982".to_string();
+    let target = UdpSocket::bind("127.0.0.1:9983").unwrap();
+    target
+        .set_read_timeout(Some(Duration::from_millis(1000)))
+        .unwrap();
+    let r = reverse_proxy_tcp_udp_with(
+        tcp_listen_addr.clone(),
+        "127.0.0.1:9983".to_string(),
+        &server_options(true),
+    )
+    .unwrap();
+    sleep(Duration::from_millis(150));
+
+    // clients without a certificate signed by the CA are rejected
+    if let Ok(mut client) = connect(&tcp_listen_addr, false) {
+        let _ = client.write_all(b"rejected\n").and_then(|_| client.flush());
+    }
+    let mut client = connect(&tcp_listen_addr, true).unwrap();
+    client.write_all(POSITION.as_bytes()).unwrap();
+    client.flush().unwrap();
+
+    let mut buf = [0u8; 1024];
+    let (c, _) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..c], POSITION.as_bytes());
+    r.shutdown().unwrap();
+}
+
+#[test]
+fn test_tls_server_options_errors() {
+    let options = TlsServerOptions {
+        cert: Some(fixture("server.pem")),
+        ..Default::default()
+    };
+    let e = reverse_proxy_tcp_udp_with(
+        "127.0.0.1:9984".to_string(),
+        "127.0.0.1:9985".to_string(),
+        &options,
+    )
+    .unwrap_err();
+    assert!(e.to_string().contains("must be given together"), "{}", e);
+
+    let options = TlsServerOptions {
+        key: Some(fixture("missing.key")),
+        ..server_options(false)
+    };
+    let e = reverse_proxy_tcp_udp_with(
+        "127.0.0.1:9984".to_string(),
+        "127.0.0.1:9985".to_string(),
+        &options,
+    )
+    .unwrap_err();
+    assert!(e.to_string().contains("missing.key"), "{}", e);
+}
@@ -27,9 +27,14 @@ fn test_server_bitrate(_b: &mut Bencher) {
     let target_addr = "127.0.0.1:9907".to_string();
     let listen_addr = "0.0.0.0:9907".to_string();
 
-    let _l = listener(listen_addr, PathBuf::from_str(pathstr).unwrap(), false);
+    let _l = listener(listen_addr, PathBuf::from_str(pathstr).unwrap(), false).unwrap();
     let _c = Builder::new().spawn(move || {
-        client_socket_stream(&PathBuf::from("/dev/random"), vec![target_addr], false)
+        client_socket_stream(
+            &PathBuf::from("/dev/random"),
+            vec![target_addr],
+            false,
+            None,
+        )
     });
     let bytesize = truncate(PathBuf::from_str(pathstr).unwrap());
 
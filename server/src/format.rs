@@ -0,0 +1,173 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use mproxy_common::ais_messages;
+use serde_json::{json, Value};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Layout of received datagrams in the server output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Datagrams are written unchanged
+    #[default]
+    Raw,
+    /// Each line of a datagram is prefixed with its receive time and source
+    /// address, e.g. `2024-01-01T00:00:00.123456Z 127.0.0.1:50000 !AIVDM,...`
+    Prefix,
+    /// One JSON object per datagram, with the receive time, listening
+    /// address, source address, length, and payload. The payload is written
+    /// as a UTF-8 string, or base64 encoded if it is not valid UTF-8, e.g.
+    /// `{"received":"2024-01-01T00:00:00.123456Z","listen":"0.0.0.0:9920",
+    /// "source":"127.0.0.1:50000","length":6,"encoding":"utf8","payload":"hello\n"}`
+    Jsonl,
+    /// One JSON object per decoded AIS message, with the receive time,
+    /// source address, and decoded fields such as MMSI, position, speed,
+    /// and vessel name, e.g. `{"received":"2024-01-01T00:00:00.123456Z",
+    /// "source":"127.0.0.1:50000","message_type":1,"mmsi":366053209,...}`.
+    /// Multi-sentence messages are decoded when their fragments are
+    /// consecutive lines of one datagram, e.g. with reassembly enabled.
+    /// Other lines are skipped
+    AisJson,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(OutputFormat::Raw),
+            "prefix" => Ok(OutputFormat::Prefix),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            "ais-json" => Ok(OutputFormat::AisJson),
+            other => Err(format!(
+                "unknown output format '{}', expected one of raw, prefix, jsonl, ais-json",
+                other
+            )),
+        }
+    }
+}
+
+/// Representation of receive times in the server output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// RFC3339 in UTC, e.g. `2024-01-01T00:00:00.123456Z`
+    #[default]
+    Rfc3339,
+    /// Seconds since the unix epoch, e.g. `1704067200.123456`
+    Epoch,
+}
+
+impl TimestampFormat {
+    /// Format `t` as a string
+    pub fn format(&self, t: SystemTime) -> String {
+        match self {
+            TimestampFormat::Rfc3339 => OffsetDateTime::from(t)
+                .format(&Rfc3339)
+                .expect("formatting timestamp"),
+            TimestampFormat::Epoch => {
+                let since = t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+                format!("{}.{:06}", since.as_secs(), since.subsec_micros())
+            }
+        }
+    }
+
+    /// Format `t` as a JSON string, or as a number of epoch seconds
+    fn to_json(self, t: SystemTime) -> Value {
+        match self {
+            TimestampFormat::Rfc3339 => Value::String(self.format(t)),
+            TimestampFormat::Epoch => t
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64()
+                .into(),
+        }
+    }
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            "epoch" => Ok(TimestampFormat::Epoch),
+            other => Err(format!(
+                "unknown timestamp format '{}', expected one of rfc3339, epoch",
+                other
+            )),
+        }
+    }
+}
+
+/// A datagram received by the server
+pub(crate) struct Record<'a> {
+    pub received: SystemTime,
+    pub listen: SocketAddr,
+    pub source: SocketAddr,
+    pub payload: &'a [u8],
+}
+
+impl Record<'_> {
+    /// Append the formatted record to `out`
+    pub(crate) fn write(
+        &self,
+        out: &mut Vec<u8>,
+        format: OutputFormat,
+        timestamp: TimestampFormat,
+    ) {
+        match format {
+            OutputFormat::Raw => out.extend_from_slice(self.payload),
+            OutputFormat::Prefix => {
+                let prefix = format!("{} {} ", timestamp.format(self.received), self.source);
+                for line in self.payload.split(|b| *b == b'\n') {
+                    let line = line.strip_suffix(b"\r").unwrap_or(line);
+                    if line.is_empty() {
+                        continue;
+                    }
+                    out.extend_from_slice(prefix.as_bytes());
+                    out.extend_from_slice(line);
+                    out.push(b'\n');
+                }
+            }
+            OutputFormat::Jsonl => {
+                let (encoding, payload) = match std::str::from_utf8(self.payload) {
+                    Ok(text) => ("utf8", text.to_string()),
+                    Err(_) => ("base64", BASE64_STANDARD.encode(self.payload)),
+                };
+                let record = json!({
+                    "received": timestamp.to_json(self.received),
+                    "listen": self.listen.to_string(),
+                    "source": self.source.to_string(),
+                    "length": self.payload.len(),
+                    "encoding": encoding,
+                    "payload": payload,
+                });
+                serde_json::to_writer(&mut *out, &record).expect("serializing record");
+                out.push(b'\n');
+            }
+            OutputFormat::AisJson => {
+                // fragments of a multi-sentence message are decoded together
+                // when they arrive as consecutive lines, e.g. after reassembly
+                for message in ais_messages(self.payload) {
+                    let message = match message.decode() {
+                        Ok(message) => message,
+                        Err(_) => continue,
+                    };
+                    let mut record = serde_json::Map::new();
+                    record.insert("received".into(), timestamp.to_json(self.received));
+                    record.insert("source".into(), self.source.to_string().into());
+                    if let Value::Object(fields) =
+                        serde_json::to_value(&message).expect("serializing message")
+                    {
+                        record.extend(fields);
+                    }
+                    serde_json::to_writer(&mut *out, &record).expect("serializing record");
+                    out.push(b'\n');
+                }
+            }
+        }
+    }
+}
@@ -13,7 +13,6 @@
 //! Example `src/main.rs`
 //! ```rust,no_run
 //! use std::path::PathBuf;
-//! use std::thread::JoinHandle;
 //!
 //! use mproxy_server::listener;
 //!
@@ -27,7 +26,10 @@
 //! let tee = true;
 //!
 //! // bind socket listener thread
-//! let server_thread: JoinHandle<_> = listener(listen_addr, logpath, tee);
+//! let server_thread = listener(listen_addr, logpath, tee).unwrap();
+//!
+//! // stop the listener from another thread with server_thread.shutdown(),
+//! // or wait for it indefinitely
 //! server_thread.join().unwrap();
 //! ```
 //!
@@ -48,13 +50,57 @@
 //! OPTIONS:
 //!   --path        [FILE_DESCRIPTOR]   Filepath, descriptor, or handle.
 //!   --listen-addr [SOCKET_ADDR]       Upstream UDP listening address. May be repeated
+//!   --format      [FORMAT]            Output format: raw, prefix, jsonl, or ais-json. Default raw
+//!                                     prefix: each line as "<RECEIVE_TIME> <SOURCE_ADDR> <LINE>"
+//!                                     jsonl: one JSON object per datagram, with fields received,
+//!                                     listen, source, length, encoding (utf8 or base64), payload
+//!                                     ais-json: one JSON object per decoded AIS message, with fields
+//!                                     received, source, message_type, mmsi, and the decoded fields
+//!                                     of message types 1-5, 18, 19, 21, 24, and 27
+//!   --timestamp   [rfc3339|epoch]     Receive time format. Default rfc3339
+//!   --pcap        [FILE]              Also record received datagrams in a pcapng capture,
+//!                                     e.g. for inspection with Wireshark
 //!
 //! FLAGS:
 //!   -h, --help    Prints help information
 //!   -t, --tee     Copy input to stdout
 //!
+//! ROTATION OPTIONS:
+//!   --rotate    [never|hourly|daily]  Start a new output file each hour or day (UTC). The --path
+//!                                     filename may contain strftime-style %Y %m %d %H %M placeholders
+//!   --max-size  [BYTES]               Start a new output file after this many bytes
+//!   --max-files [COUNT]               Keep at most this many rotated output files
+//!   --max-age   [DAYS]                Delete rotated output files older than this many days
+//!   --compress  [none|gzip|zstd]      Compress rotated output files. Requires crate feature gzip or zstd
+//!   On unix, the output file is closed and reopened on SIGHUP, e.g. for use with logrotate
+//!
+//! BACKUP OPTIONS:
+//!   --backup-interval  [DAYS]                Delete backups older than this many days. Enables backups
+//!   --backup-dir       [DIR]                 Backup directory. Enables backups. Default ./ais_backup
+//!   --backup-pattern   [PATTERN]             Backup filename with strftime-style %Y %m %d %H %M
+//!                                            placeholders. Default %Y-%m-%d.log
+//!   --backup-rotation  [never|hourly|daily]  Start a new backup file each hour or day. Default daily
+//!   --backup-max-size  [BYTES]               Start a new backup file after this many bytes
+//!   --backup-max-files [COUNT]               Keep at most this many rotated backup files
+//!   --backup-compress  [none|gzip|zstd]      Compress rotated backup files. Requires crate
+//!                                            feature gzip or zstd
+//!
+//! NMEA OPTIONS:
+//!   --drop-corrupt                     Drop lines which are not valid NMEA sentences, checked by
+//!                                      *hh checksum
+//!   --quarantine             [FILE]    Append lines which are not valid NMEA sentences to FILE,
+//!                                      instead of writing them to --path
+//!   --reassemble                       Hold the fragments of multi-sentence AIS messages (e.g. type
+//!                                      5) from each source until complete, then pass them on together
+//!   --reassembly-timeout     [MILLIS]  Discard incomplete messages after this long. Default 2000
+//!   --reassembly-max-pending [COUNT]   Hold at most this many incomplete messages. Default 1024
+//!
 //! EXAMPLE:
 //!   mproxy-server --path logfile.log --listen-addr '127.0.0.1:9920' --listen-addr '[::1]:9921'
+//!   mproxy-server --path logfile.log --listen-addr '0.0.0.0:9920' --backup-dir /data/ais --backup-max-files 30
+//!   mproxy-server --path 'ais_%Y-%m-%d.log' --listen-addr '0.0.0.0:9920' --rotate daily --max-age 90
+//!   mproxy-server --path logfile.log --listen-addr '0.0.0.0:9920' --format prefix --timestamp epoch
+//!   mproxy-server --path decoded.jsonl --listen-addr '0.0.0.0:9920' --format ais-json --drop-corrupt
 //! ```
 //!
 //! ### See Also
@@ -64,121 +110,94 @@
 //! - [mproxy-reverse](https://docs.rs/mproxy-reverse/)
 //!
 
-use std::fs::OpenOptions;
-use std::io::{stdout, BufWriter, Result as ioResult, Write};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
 use std::path::PathBuf;
-use std::thread::{Builder, JoinHandle};
-
-const BUFSIZE: usize = 8096;
-
-pub fn upstream_socket_interface(listen_addr: String) -> ioResult<(SocketAddr, UdpSocket)> {
-    let addr = listen_addr
-        .to_socket_addrs()
-        .unwrap()
-        .next()
-        .expect("parsing socket address");
-    let listen_socket;
-    match (addr.ip().is_multicast(), addr.ip()) {
-        (false, std::net::IpAddr::V4(_)) => {
-            listen_socket = UdpSocket::bind(addr).expect("binding server socket");
-        }
-        (false, std::net::IpAddr::V6(_)) => {
-            listen_socket = UdpSocket::bind(addr).expect("binding server socket");
-        }
-        (true, std::net::IpAddr::V4(ip)) => {
-            #[cfg(not(target_os = "windows"))]
-            {
-                listen_socket = UdpSocket::bind(addr).expect("binding server socket");
-                listen_socket
-                    .join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)
-                    .unwrap_or_else(|e| panic!("{}", e));
-            }
-            #[cfg(target_os = "windows")]
-            {
-                listen_socket = UdpSocket::bind(SocketAddr::new(
-                    IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-                    addr.port(),
-                ))
-                .expect("binding server socket");
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
-                listen_socket
-                    .join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)
-                    .unwrap_or_else(|e| panic!("{}", e));
-            }
-        }
-        (true, std::net::IpAddr::V6(ip)) => {
-            listen_socket = UdpSocket::bind(SocketAddr::new(
-                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
-                addr.port(),
-            ))
-            .expect("binding server socket");
+use mproxy_common::{
+    pump, NmeaFilter, Pipeline, PipelineSink, Reassembler, Sink, UdpSource, WriterSink,
+};
+pub use mproxy_common::{
+    upstream_socket_interface, Compression, MproxyError, PcapSink, ReassemblyOptions, RotatingFile,
+    RotatingFileOptions, Rotation, ShutdownHandle, Validation,
+};
 
-            // specify "any available interface" with index 0
-            #[cfg(not(target_os = "macos"))]
-            let itf = 0; // unspecified
-            #[cfg(target_os = "macos")]
-            let itf = 12; // en0
+mod format;
+mod logfile;
+pub use format::{OutputFormat, TimestampFormat};
+use logfile::LogfileSink;
 
-            listen_socket
-                .join_multicast_v6(&ip, itf)
-                .unwrap_or_else(|e| panic!("{}", e));
-
-            #[cfg(target_os = "windows")]
-            listen_socket
-                .connect(&addr)
-                .unwrap_or_else(|e| panic!("{}", e));
-        }
-    };
-    Ok((addr, listen_socket))
+/// Options for [listener_with]
+#[derive(Clone, Debug, Default)]
+pub struct ServerOptions {
+    /// Copy input to stdout
+    pub tee: bool,
+    /// Keep an additional copy of the input in rotating backup files
+    pub backup: Option<RotatingFileOptions>,
+    /// Rotate `logfile`. The parent directory and filename of `logfile`
+    /// replace `dir` and `pattern`, so the filename may contain
+    /// strftime-style placeholders such as `%Y-%m-%d`
+    pub rotate: Option<RotatingFileOptions>,
+    /// When set, `logfile` is closed and reopened and the flag is cleared,
+    /// e.g. from a SIGHUP handler after logrotate has moved the file
+    pub reopen: Option<Arc<AtomicBool>>,
+    /// Layout of datagrams written to `logfile` and stdout.
+    /// Backups always contain the unmodified input
+    pub format: OutputFormat,
+    /// Representation of receive times, for formats that include them
+    pub timestamp: TimestampFormat,
+    /// Also record received datagrams in a pcapng capture
+    pub pcap: Option<PcapSink>,
+    /// Drop or quarantine corrupt NMEA sentences before writing `logfile`.
+    /// Backups and captures always contain the unmodified input
+    pub validation: Validation,
+    /// Hold the fragments of multi-sentence AIS messages from each source
+    /// address until complete, then write them together
+    pub reassembly: Option<ReassemblyOptions>,
 }
 
 /// Server UDP socket listener.
 /// Binds to UDP socket address `addr`, and logs input to `logfile`.
 /// Can optionally copy input to stdout if `tee` is true.
 /// `logfile` may be a filepath, file descriptor/handle, etc.
-pub fn listener(addr: String, logfile: PathBuf, tee: bool) -> JoinHandle<()> {
-    let file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open(&logfile);
-    let mut writer = BufWriter::new(file.unwrap());
-    let mut output_buffer = BufWriter::new(stdout());
+///
+/// Errors opening `logfile` or binding the socket are returned immediately.
+/// Errors encountered by the listener thread are returned when it is joined.
+/// The listener flushes `logfile` and closes the socket on shutdown.
+pub fn listener(addr: String, logfile: PathBuf, tee: bool) -> Result<ShutdownHandle, MproxyError> {
+    let options = ServerOptions {
+        tee,
+        ..Default::default()
+    };
+    listener_with(addr, logfile, &options)
+}
 
-    let (addr, listen_socket) = upstream_socket_interface(addr).unwrap();
+/// Server UDP socket listener, as [listener] with additional options
+pub fn listener_with(
+    addr: String,
+    logfile: PathBuf,
+    options: &ServerOptions,
+) -> Result<ShutdownHandle, MproxyError> {
+    let log = LogfileSink::open(logfile, options)?;
 
-    Builder::new()
-        .name(format!("{}:server", addr))
-        .spawn(move || {
-            let mut buf = [0u8; BUFSIZE]; // receive buffer
-            loop {
-                match listen_socket.recv_from(&mut buf[0..]) {
-                    Ok((c, _remote_addr)) => {
-                        if tee {
-                            let _o = output_buffer
-                                .write(&buf[0..c])
-                                .expect("writing to output buffer");
-                            #[cfg(debug_assertions)]
-                            assert!(c == _o);
-                        }
-                        let _ = writer
-                            .write(&buf[0..c])
-                            .unwrap_or_else(|_| panic!("writing to {:?}", &logfile));
-                    }
-                    Err(err) => {
-                        writer.flush().unwrap();
-                        eprintln!("{}:server: got an error: {}", addr, err);
-                        #[cfg(debug_assertions)]
-                        panic!("{}:server: got an error: {}", addr, err);
-                    }
-                }
+    // backups and captures contain the unmodified input
+    let mut sinks: Vec<Box<dyn Sink>> = vec![];
+    if let Some(pcap) = &options.pcap {
+        sinks.push(Box::new(pcap.clone()));
+    }
+    if let Some(backup) = &options.backup {
+        sinks.push(Box::new(WriterSink::new(RotatingFile::new(
+            backup.clone(),
+        )?)));
+    }
+    let mut pipeline = Pipeline::new().stage(NmeaFilter::new(&options.validation)?);
+    if let Some(reassembly) = &options.reassembly {
+        pipeline.push(Box::new(Reassembler::new(reassembly)));
+    }
+    sinks.push(Box::new(PipelineSink::new(pipeline, vec![Box::new(log)])));
 
-                writer.flush().unwrap();
-                if tee {
-                    output_buffer.flush().unwrap();
-                }
-            }
-        })
-        .unwrap()
+    let mut source = UdpSource::bind(addr)?;
+    ShutdownHandle::spawn(format!("{}:server", source.addr()), move |shutdown| {
+        pump(&mut source, &mut sinks, &shutdown)
+    })
 }
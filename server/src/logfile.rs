@@ -0,0 +1,163 @@
+use std::fs::{File, OpenOptions};
+use std::io::{stdout, BufWriter, Result as ioResult, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use mproxy_common::{DatagramMeta, MproxyError, RotatingFile, RotatingFileOptions, Sink};
+
+use crate::format::{OutputFormat, Record, TimestampFormat};
+use crate::ServerOptions;
+
+/// Server output file, optionally rotated
+pub(crate) enum Logfile {
+    Plain {
+        path: PathBuf,
+        writer: BufWriter<File>,
+    },
+    Rotating(RotatingFile),
+}
+
+impl Logfile {
+    /// Open `path` for appending.
+    /// If `rotate` is set, the parent directory and filename of `path` are
+    /// used as the rotation `dir` and `pattern`
+    pub(crate) fn open(
+        path: &Path,
+        rotate: Option<&RotatingFileOptions>,
+    ) -> Result<Self, MproxyError> {
+        match rotate {
+            Some(rotate) => {
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                    _ => PathBuf::from("."),
+                };
+                let pattern = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let options = RotatingFileOptions {
+                    dir,
+                    pattern,
+                    ..rotate.clone()
+                };
+                Ok(Logfile::Rotating(RotatingFile::new(options)?))
+            }
+            None => Ok(Logfile::Plain {
+                path: path.to_path_buf(),
+                writer: open_append(path)?,
+            }),
+        }
+    }
+
+    /// Close and reopen the current file
+    pub(crate) fn reopen(&mut self) -> Result<(), MproxyError> {
+        match self {
+            Logfile::Plain { path, writer } => {
+                writer.flush().map_err(|source| MproxyError::File {
+                    path: path.clone(),
+                    source,
+                })?;
+                *writer = open_append(path)?;
+                Ok(())
+            }
+            Logfile::Rotating(file) => file.reopen(),
+        }
+    }
+}
+
+impl Write for Logfile {
+    fn write(&mut self, buf: &[u8]) -> ioResult<usize> {
+        match self {
+            Logfile::Plain { writer, .. } => writer.write(buf),
+            Logfile::Rotating(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> ioResult<()> {
+        match self {
+            Logfile::Plain { writer, .. } => writer.flush(),
+            Logfile::Rotating(file) => file.flush(),
+        }
+    }
+}
+
+/// Writes formatted records of each datagram to the server output file,
+/// and optionally to stdout
+pub(crate) struct LogfileSink {
+    path: PathBuf,
+    writer: Logfile,
+    tee: Option<BufWriter<Stdout>>,
+    reopen: Option<Arc<AtomicBool>>,
+    format: OutputFormat,
+    timestamp: TimestampFormat,
+    /// Formatted output buffer, reused between datagrams
+    record: Vec<u8>,
+}
+
+impl LogfileSink {
+    /// Open `path` for appending, as [Logfile::open]
+    pub(crate) fn open(path: PathBuf, options: &ServerOptions) -> Result<Self, MproxyError> {
+        Ok(LogfileSink {
+            writer: Logfile::open(&path, options.rotate.as_ref())?,
+            path,
+            tee: options.tee.then(|| BufWriter::new(stdout())),
+            reopen: options.reopen.clone(),
+            format: options.format,
+            timestamp: options.timestamp,
+            record: vec![],
+        })
+    }
+
+    fn write_err(&self, source: std::io::Error) -> MproxyError {
+        MproxyError::File {
+            path: self.path.clone(),
+            source,
+        }
+    }
+}
+
+impl Sink for LogfileSink {
+    fn send(&mut self, data: &[u8], meta: &DatagramMeta) -> Result<(), MproxyError> {
+        self.record.clear();
+        Record {
+            received: meta.received,
+            listen: meta.listen_addr,
+            source: meta.source,
+            payload: data,
+        }
+        .write(&mut self.record, self.format, self.timestamp);
+        if let Some(tee) = self.tee.as_mut() {
+            tee.write_all(&self.record)?;
+        }
+        self.writer
+            .write_all(&self.record)
+            .map_err(|e| self.write_err(e))
+    }
+
+    /// Flush output, and reopen the file if requested
+    fn flush(&mut self) -> Result<(), MproxyError> {
+        self.writer.flush().map_err(|e| self.write_err(e))?;
+        if let Some(tee) = self.tee.as_mut() {
+            tee.flush()?;
+        }
+        if let Some(reopen) = &self.reopen {
+            if reopen.swap(false, Ordering::SeqCst) {
+                self.writer.reopen()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<BufWriter<File>, MproxyError> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map(BufWriter::new)
+        .map_err(|source| MproxyError::File {
+            path: path.to_path_buf(),
+            source,
+        })
+}
@@ -1,8 +1,14 @@
 use std::path::PathBuf;
 use std::process::exit;
 use std::str::FromStr;
+#[cfg(unix)]
+use std::sync::{atomic::AtomicBool, Arc};
+use std::time::Duration;
 
-use mproxy_server::listener;
+use mproxy_server::{
+    listener_with, Compression, PcapSink, ReassemblyOptions, RotatingFileOptions, Rotation,
+    ServerOptions, Validation,
+};
 
 use pico_args::Arguments;
 
@@ -17,20 +23,65 @@ USAGE:
 OPTIONS: 
   --path        [FILE_DESCRIPTOR]   Filepath, descriptor, or handle.
   --listen-addr [SOCKET_ADDR]       Upstream UDP listening address. May be repeated 
+  --format      [FORMAT]            Output format: raw, prefix, jsonl, or ais-json. Default raw
+                                    prefix: each line as "<RECEIVE_TIME> <SOURCE_ADDR> <LINE>"
+                                    jsonl: one JSON object per datagram, with fields received,
+                                    listen, source, length, encoding (utf8 or base64), payload
+                                    ais-json: one JSON object per decoded AIS message, with fields
+                                    received, source, message_type, mmsi, and the decoded fields
+                                    of message types 1-5, 18, 19, 21, 24, and 27
+  --timestamp   [rfc3339|epoch]     Receive time format. Default rfc3339
+  --pcap        [FILE]              Also record received datagrams in a pcapng capture,
+                                    e.g. for inspection with Wireshark
 
 FLAGS:
   -h, --help    Prints help information
   -t, --tee     Copy input to stdout
 
+ROTATION OPTIONS:
+  --rotate    [never|hourly|daily]  Start a new output file each hour or day (UTC). The --path
+                                    filename may contain strftime-style %Y %m %d %H %M placeholders
+  --max-size  [BYTES]               Start a new output file after this many bytes
+  --max-files [COUNT]               Keep at most this many rotated output files
+  --max-age   [DAYS]                Delete rotated output files older than this many days
+  --compress  [none|gzip|zstd]      Compress rotated output files. Requires crate feature gzip or zstd
+  On unix, the output file is closed and reopened on SIGHUP, e.g. for use with logrotate
+
+BACKUP OPTIONS:
+  --backup-interval  [DAYS]                Delete backups older than this many days. Enables backups
+  --backup-dir       [DIR]                 Backup directory. Enables backups. Default ./ais_backup
+  --backup-pattern   [PATTERN]             Backup filename with strftime-style %Y %m %d %H %M
+                                           placeholders. Default %Y-%m-%d.log
+  --backup-rotation  [never|hourly|daily]  Start a new backup file each hour or day. Default daily
+  --backup-max-size  [BYTES]               Start a new backup file after this many bytes
+  --backup-max-files [COUNT]               Keep at most this many rotated backup files
+  --backup-compress  [none|gzip|zstd]      Compress rotated backup files. Requires crate
+                                           feature gzip or zstd
+
+NMEA OPTIONS:
+  --drop-corrupt                     Drop lines which are not valid NMEA sentences, checked by
+                                     *hh checksum
+  --quarantine             [FILE]    Append lines which are not valid NMEA sentences to FILE,
+                                     instead of writing them to --path
+  --reassemble                       Hold the fragments of multi-sentence AIS messages (e.g. type
+                                     5) from each source until complete, then pass them on together
+  --reassembly-timeout     [MILLIS]  Discard incomplete messages after this long. Default 2000
+  --reassembly-max-pending [COUNT]   Hold at most this many incomplete messages. Default 1024
+
 EXAMPLE:
   mproxy-server --path logfile.log --listen-addr '127.0.0.1:9920' --listen-addr '[::1]:9921'
+  mproxy-server --path logfile.log --listen-addr '0.0.0.0:9920' --backup-dir /data/ais --backup-max-files 30
+  mproxy-server --path 'ais_%Y-%m-%d.log' --listen-addr '0.0.0.0:9920' --rotate daily --max-age 90
+  mproxy-server --path logfile.log --listen-addr '0.0.0.0:9920' --format prefix --timestamp epoch
+  mproxy-server --path decoded.jsonl --listen-addr '0.0.0.0:9920' --format ais-json --drop-corrupt
 
 "#;
 
 struct ServerArgs {
     listen_addr: Vec<String>,
     path: String,
-    tee: bool,
+    pcap: Option<PathBuf>,
+    options: ServerOptions,
 }
 
 fn parse_args() -> Result<ServerArgs, pico_args::Error> {
@@ -40,10 +91,88 @@ fn parse_args() -> Result<ServerArgs, pico_args::Error> {
         exit(0);
     }
     let tee = pargs.contains(["-t", "--tee"]);
+    let backup_interval: Option<u64> = pargs.opt_value_from_str("--backup-interval")?;
+    let backup_dir: Option<PathBuf> = pargs.opt_value_from_str("--backup-dir")?;
+    let backup = if backup_interval.is_some() || backup_dir.is_some() {
+        let default = RotatingFileOptions::default();
+        Some(RotatingFileOptions {
+            dir: backup_dir.unwrap_or(default.dir),
+            pattern: pargs
+                .opt_value_from_str("--backup-pattern")?
+                .unwrap_or(default.pattern),
+            rotation: pargs
+                .opt_value_from_str("--backup-rotation")?
+                .unwrap_or(default.rotation),
+            max_size: pargs.opt_value_from_str("--backup-max-size")?,
+            max_files: pargs.opt_value_from_str("--backup-max-files")?,
+            max_age: backup_interval.map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            compression: pargs
+                .opt_value_from_str("--backup-compress")?
+                .unwrap_or(default.compression),
+        })
+    } else {
+        None
+    };
+    let rotation: Option<Rotation> = pargs.opt_value_from_str("--rotate")?;
+    let max_size: Option<u64> = pargs.opt_value_from_str("--max-size")?;
+    let max_files: Option<usize> = pargs.opt_value_from_str("--max-files")?;
+    let max_age: Option<u64> = pargs.opt_value_from_str("--max-age")?;
+    let compression: Option<Compression> = pargs.opt_value_from_str("--compress")?;
+    let rotate = if rotation.is_some()
+        || max_size.is_some()
+        || max_files.is_some()
+        || max_age.is_some()
+        || compression.is_some()
+    {
+        Some(RotatingFileOptions {
+            rotation: rotation.unwrap_or(Rotation::Never),
+            max_size,
+            max_files,
+            max_age: max_age.map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            compression: compression.unwrap_or_default(),
+            ..Default::default()
+        })
+    } else {
+        None
+    };
+    let drop_corrupt = pargs.contains("--drop-corrupt");
+    let validation = match pargs.opt_value_from_str("--quarantine")? {
+        Some(path) => Validation::Quarantine(path),
+        None if drop_corrupt => Validation::Drop,
+        None => Validation::Off,
+    };
+    let reassemble = pargs.contains("--reassemble");
+    let reassembly_timeout: Option<u64> = pargs.opt_value_from_str("--reassembly-timeout")?;
+    let reassembly_max_pending: Option<usize> =
+        pargs.opt_value_from_str("--reassembly-max-pending")?;
+    let reassembly =
+        if reassemble || reassembly_timeout.is_some() || reassembly_max_pending.is_some() {
+            let default = ReassemblyOptions::default();
+            Some(ReassemblyOptions {
+                timeout: reassembly_timeout
+                    .map(Duration::from_millis)
+                    .unwrap_or(default.timeout),
+                max_pending: reassembly_max_pending.unwrap_or(default.max_pending),
+                ..default
+            })
+        } else {
+            None
+        };
     let args = ServerArgs {
         path: pargs.value_from_str("--path")?,
         listen_addr: pargs.values_from_str("--listen-addr")?,
-        tee,
+        pcap: pargs.opt_value_from_str("--pcap")?,
+        options: ServerOptions {
+            tee,
+            backup,
+            rotate,
+            reopen: None,
+            format: pargs.opt_value_from_str("--format")?.unwrap_or_default(),
+            timestamp: pargs.opt_value_from_str("--timestamp")?.unwrap_or_default(),
+            pcap: None,
+            validation,
+            reassembly,
+        },
     };
     let remaining = pargs.finish();
     if !remaining.is_empty() {
@@ -57,7 +186,7 @@ fn parse_args() -> Result<ServerArgs, pico_args::Error> {
 }
 
 pub fn main() {
-    let args = match parse_args() {
+    let mut args = match parse_args() {
         Ok(a) => a,
         Err(e) => {
             eprintln!("Error: {}.", e);
@@ -67,6 +196,17 @@ pub fn main() {
 
     let mut threads = vec![];
 
+    // datagrams from all listeners are recorded in the same capture
+    if let Some(path) = &args.pcap {
+        match PcapSink::create(path) {
+            Ok(pcap) => args.options.pcap = Some(pcap),
+            Err(e) => {
+                eprintln!("Error: {}.", e);
+                exit(1);
+            }
+        }
+    }
+
     let append_listen_addr = args.listen_addr.len() > 1;
 
     for hostname in args.listen_addr {
@@ -80,14 +220,46 @@ pub fn main() {
             }
         }
 
+        // likewise, keep separate backups for each client address
+        let mut options = args.options.clone();
+        if let (Some(backup), true) = (options.backup.as_mut(), append_listen_addr) {
+            let subdir: String = hostname
+                .chars()
+                .map(|c| {
+                    if c.is_alphanumeric() || c == '.' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            backup.dir.push(subdir);
+        }
+
+        // reopen the output file on SIGHUP
+        #[cfg(unix)]
+        {
+            let reopen = Arc::new(AtomicBool::new(false));
+            if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGHUP, reopen.clone())
+            {
+                eprintln!("Error: registering SIGHUP handler: {}.", e);
+                exit(1);
+            }
+            options.reopen = Some(reopen);
+        }
+
         println!("logging transmissions from {} to {}", hostname, logpath);
-        threads.push(listener(
-            hostname,
-            PathBuf::from_str(&logpath).unwrap(),
-            args.tee,
-        ));
+        match listener_with(hostname, PathBuf::from_str(&logpath).unwrap(), &options) {
+            Ok(thread) => threads.push(thread),
+            Err(e) => {
+                eprintln!("Error: {}.", e);
+                exit(1);
+            }
+        }
     }
     for thread in threads {
-        thread.join().unwrap();
+        if let Err(e) = thread.join() {
+            eprintln!("Error: {}.", e);
+        }
     }
 }
@@ -1,16 +1,22 @@
-use std::fs::File;
+use std::fs::{create_dir_all, read_to_string, remove_dir_all, remove_file, rename, File};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use mproxy_client::{client_socket_stream, target_socket_interface};
-use mproxy_server::listener;
+use mproxy_client::{client_socket_stream, line_timestamp, target_socket_interface};
+use mproxy_common::{PcapPacket, PcapReader};
+use mproxy_server::{
+    listener, listener_with, MproxyError, OutputFormat, PcapSink, ReassemblyOptions,
+    RotatingFileOptions, Rotation, ServerOptions, TimestampFormat,
+};
 
 use testconfig::{truncate, TESTINGDIR};
 
 fn demo_client(addr: String, logfile: PathBuf) {
-    listener(addr.clone(), logfile.clone(), false);
+    let _l = listener(addr.clone(), logfile.clone(), false).unwrap();
 
     sleep(Duration::from_millis(15));
 
@@ -34,7 +40,7 @@ fn demo_client(addr: String, logfile: PathBuf) {
 #[test]
 fn test_server_ipv4_unicast() {
     let ipv4 = "127.0.0.1:9900".to_string();
-    let pathstr = &[TESTINGDIR, "streamoutput_ipv4_unicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_ipv4_unicast.log"].join("");
     let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
     demo_client(ipv4, logfile);
 }
@@ -42,7 +48,7 @@ fn test_server_ipv4_unicast() {
 #[test]
 fn test_server_ipv4_multicast() {
     let ipv4 = "224.0.0.2:9901".to_string();
-    let pathstr = &[TESTINGDIR, "streamoutput_ipv4_multicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_ipv4_multicast.log"].join("");
     let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
     demo_client(ipv4, logfile);
 }
@@ -50,7 +56,7 @@ fn test_server_ipv4_multicast() {
 #[test]
 fn test_server_ipv6_unicast() {
     let listen = "[::1]:9902".to_string();
-    let pathstr = &[TESTINGDIR, "streamoutput_ipv6_unicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_ipv6_unicast.log"].join("");
     let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
     demo_client(listen, logfile);
 }
@@ -58,7 +64,7 @@ fn test_server_ipv6_unicast() {
 #[test]
 fn test_server_ipv6_multicast() {
     let listen = "[ff02::1]:9903".to_string();
-    let pathstr = &[TESTINGDIR, "streamoutput_ipv6_multicast.log"].join(&"");
+    let pathstr = &[TESTINGDIR, "streamoutput_ipv6_multicast.log"].join("");
     let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
     demo_client(listen, logfile);
 }
@@ -70,15 +76,25 @@ fn test_server_multiple_clients_single_channel() {
         TESTINGDIR,
         "streamoutput_client_ipv6_multiclient_samefile.log",
     ]
-    .join(&"");
-    File::create(&pathstr_1).expect("truncating file");
+    .join("");
+    File::create(pathstr_1).expect("truncating file");
     sleep(Duration::from_millis(15));
     let listen_addr_1 = "[::]:9904".to_string();
     let target_addr_1 = "[::1]:9904".to_string();
     let target_addr_2 = "[::1]:9904".to_string();
-    let _l = listener(listen_addr_1, PathBuf::from_str(pathstr_1).unwrap(), false);
-    let _c1 = client_socket_stream(&PathBuf::from("./Cargo.toml"), vec![target_addr_1], false, None);
-    let _c2 = client_socket_stream(&PathBuf::from("../Cargo.toml"), vec![target_addr_2], false, None);
+    let _l = listener(listen_addr_1, PathBuf::from_str(pathstr_1).unwrap(), false).unwrap();
+    let _c1 = client_socket_stream(
+        &PathBuf::from("./Cargo.toml"),
+        vec![target_addr_1],
+        false,
+        None,
+    );
+    let _c2 = client_socket_stream(
+        &PathBuf::from("../Cargo.toml"),
+        vec![target_addr_2],
+        false,
+        None,
+    );
 }
 
 #[test]
@@ -88,15 +104,369 @@ fn test_server_multiple_clients_dual_channel() {
         TESTINGDIR,
         "streamoutput_client_ipv6_multiclient_different_channels.log",
     ]
-    .join(&"");
-    File::create(&pathstr_1).expect("truncating file");
+    .join("");
+    File::create(pathstr_1).expect("truncating file");
     sleep(Duration::from_millis(15));
     let listen_addr_1 = "[::]:9905".to_string();
     let listen_addr_2 = "[::]:9906".to_string();
     let target_addr_1 = "[::1]:9905".to_string();
     let target_addr_2 = "[::1]:9906".to_string();
-    let _l1 = listener(listen_addr_1, PathBuf::from_str(pathstr_1).unwrap(), false);
-    let _l2 = listener(listen_addr_2, PathBuf::from_str(pathstr_1).unwrap(), false);
-    let _c1 = client_socket_stream(&PathBuf::from("./Cargo.toml"), vec![target_addr_1], false, None);
-    let _c2 = client_socket_stream(&PathBuf::from("../Cargo.toml"), vec![target_addr_2], false, None);
+    let _l1 = listener(listen_addr_1, PathBuf::from_str(pathstr_1).unwrap(), false).unwrap();
+    let _l2 = listener(listen_addr_2, PathBuf::from_str(pathstr_1).unwrap(), false).unwrap();
+    let _c1 = client_socket_stream(
+        &PathBuf::from("./Cargo.toml"),
+        vec![target_addr_1],
+        false,
+        None,
+    );
+    let _c2 = client_socket_stream(
+        &PathBuf::from("../Cargo.toml"),
+        vec![target_addr_2],
+        false,
+        None,
+    );
+}
+
+#[test]
+fn test_server_bind_address_in_use() {
+    let listen_addr = "127.0.0.1:9908".to_string();
+    let pathstr = &[TESTINGDIR, "streamoutput_bind_in_use.log"].join("");
+    let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
+    let _l = listener(listen_addr.clone(), logfile.clone(), false).unwrap();
+    match listener(listen_addr.clone(), logfile, false) {
+        Err(MproxyError::Bind { addr, .. }) => assert_eq!(addr, listen_addr),
+        other => panic!("expected bind error, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_server_shutdown_releases_socket() {
+    let listen_addr = "127.0.0.1:9909".to_string();
+    let pathstr = &[TESTINGDIR, "streamoutput_shutdown.log"].join("");
+    let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
+
+    let l = listener(listen_addr.clone(), logfile.clone(), false).unwrap();
+    sleep(Duration::from_millis(15));
+    let (target_addr, target_socket) = target_socket_interface(&listen_addr).unwrap();
+    target_socket
+        .send_to(b"Hello from client!", target_addr)
+        .unwrap();
+    sleep(Duration::from_millis(15));
+
+    l.shutdown().unwrap();
+
+    // output is flushed, and the listening port can be bound again
+    assert!(truncate(logfile.clone()) > 0);
+    let l = listener(listen_addr, logfile, false).unwrap();
+    assert!(!l.is_finished());
+    l.shutdown().unwrap();
+}
+
+#[test]
+fn test_server_backup() {
+    let listen_addr = "127.0.0.1:9907".to_string();
+    let pathstr = &[TESTINGDIR, "streamoutput_backup.log"].join("");
+    let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
+    let backup_dir = PathBuf::from(TESTINGDIR).join("server_backup");
+    let _ = remove_dir_all(&backup_dir);
+    let options = ServerOptions {
+        backup: Some(RotatingFileOptions {
+            dir: backup_dir.clone(),
+            pattern: "backup.log".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let l = listener_with(listen_addr.clone(), logfile.clone(), &options).unwrap();
+    sleep(Duration::from_millis(15));
+    let (target_addr, target_socket) = target_socket_interface(&listen_addr).unwrap();
+    target_socket
+        .send_to(b"Hello from client!\n", target_addr)
+        .unwrap();
+    sleep(Duration::from_millis(15));
+    l.shutdown().unwrap();
+
+    assert!(truncate(logfile) > 0);
+    let backup = read_to_string(backup_dir.join("backup.log")).unwrap();
+    assert_eq!(backup, "Hello from client!\n");
+    remove_dir_all(&backup_dir).unwrap();
+}
+
+#[test]
+fn test_server_rotate_size() {
+    let listen_addr = "127.0.0.1:9924".to_string();
+    let dir = PathBuf::from(TESTINGDIR).join("server_rotate");
+    let _ = remove_dir_all(&dir);
+    let options = ServerOptions {
+        rotate: Some(RotatingFileOptions {
+            rotation: Rotation::Never,
+            max_size: Some(10),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let l = listener_with(listen_addr.clone(), dir.join("rotated.log"), &options).unwrap();
+    sleep(Duration::from_millis(15));
+    let (target_addr, target_socket) = target_socket_interface(&listen_addr).unwrap();
+    for msg in ["0123456789\n", "abcdefghij\n", "tail\n"] {
+        target_socket.send_to(msg.as_bytes(), target_addr).unwrap();
+        sleep(Duration::from_millis(15));
+    }
+    l.shutdown().unwrap();
+
+    assert_eq!(
+        read_to_string(dir.join("rotated.log.1")).unwrap(),
+        "0123456789\n"
+    );
+    assert_eq!(
+        read_to_string(dir.join("rotated.log.2")).unwrap(),
+        "abcdefghij\n"
+    );
+    assert_eq!(read_to_string(dir.join("rotated.log")).unwrap(), "tail\n");
+    remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_server_reopen() {
+    let listen_addr = "127.0.0.1:9925".to_string();
+    let dir = PathBuf::from(TESTINGDIR).join("server_reopen");
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    let logfile = dir.join("reopen.log");
+    let reopen = Arc::new(AtomicBool::new(false));
+    let options = ServerOptions {
+        reopen: Some(reopen.clone()),
+        ..Default::default()
+    };
+
+    let l = listener_with(listen_addr.clone(), logfile.clone(), &options).unwrap();
+    sleep(Duration::from_millis(15));
+    let (target_addr, target_socket) = target_socket_interface(&listen_addr).unwrap();
+    target_socket.send_to(b"before\n", target_addr).unwrap();
+    sleep(Duration::from_millis(15));
+
+    // move the file away, as logrotate would, then signal the listener
+    rename(&logfile, dir.join("reopen.log.1")).unwrap();
+    reopen.store(true, Ordering::SeqCst);
+    sleep(Duration::from_millis(250));
+    assert!(!reopen.load(Ordering::SeqCst));
+
+    target_socket.send_to(b"after\n", target_addr).unwrap();
+    sleep(Duration::from_millis(15));
+    l.shutdown().unwrap();
+
+    assert_eq!(
+        read_to_string(dir.join("reopen.log.1")).unwrap(),
+        "before\n"
+    );
+    assert_eq!(read_to_string(&logfile).unwrap(), "after\n");
+    remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_server_prefix_format() {
+    let listen_addr = "127.0.0.1:9926".to_string();
+    let pathstr = &[TESTINGDIR, "streamoutput_prefix.log"].join("");
+    let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
+    let _ = remove_file(&logfile);
+    let options = ServerOptions {
+        format: OutputFormat::Prefix,
+        timestamp: TimestampFormat::Epoch,
+        ..Default::default()
+    };
+
+    let l = listener_with(listen_addr.clone(), logfile.clone(), &options).unwrap();
+    sleep(Duration::from_millis(15));
+    let (target_addr, target_socket) = target_socket_interface(&listen_addr).unwrap();
+    let before = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64();
+    target_socket
+        .send_to(b"!AIVDM,first\r\n!AIVDM,second\n", target_addr)
+        .unwrap();
+    sleep(Duration::from_millis(15));
+    l.shutdown().unwrap();
+
+    let output = read_to_string(&logfile).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 2);
+    let source = format!("127.0.0.1:{}", target_socket.local_addr().unwrap().port());
+    for (line, payload) in lines.iter().zip(["!AIVDM,first", "!AIVDM,second"]) {
+        let fields: Vec<&str> = line.split(' ').collect();
+        assert_eq!(fields[1..], [source.as_str(), payload]);
+        // receive times are readable by the client replay mode
+        let received = line_timestamp(line.as_bytes()).unwrap();
+        assert!(received >= before.floor() && received < before + 1.0);
+    }
+    truncate(logfile);
+}
+
+#[test]
+fn test_server_jsonl_format() {
+    let listen_addr = "127.0.0.1:9927".to_string();
+    let pathstr = &[TESTINGDIR, "streamoutput_jsonl.log"].join("");
+    let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
+    let _ = remove_file(&logfile);
+    let options = ServerOptions {
+        format: OutputFormat::Jsonl,
+        ..Default::default()
+    };
+
+    let l = listener_with(listen_addr.clone(), logfile.clone(), &options).unwrap();
+    sleep(Duration::from_millis(15));
+    let (target_addr, target_socket) = target_socket_interface(&listen_addr).unwrap();
+    target_socket
+        .send_to(b"!AIVDM,text\n", target_addr)
+        .unwrap();
+    sleep(Duration::from_millis(15));
+    target_socket
+        .send_to(&[0xff, 0x00, 0x41], target_addr)
+        .unwrap();
+    sleep(Duration::from_millis(15));
+    l.shutdown().unwrap();
+
+    let output = read_to_string(&logfile).unwrap();
+    let records: Vec<serde_json::Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 2);
+    let source = format!("127.0.0.1:{}", target_socket.local_addr().unwrap().port());
+    for record in &records {
+        assert_eq!(record["listen"], listen_addr);
+        assert_eq!(record["source"], source);
+        assert!(line_timestamp(record["received"].as_str().unwrap().as_bytes()).is_some());
+    }
+    assert_eq!(records[0]["length"], 12);
+    assert_eq!(records[0]["encoding"], "utf8");
+    assert_eq!(records[0]["payload"], "!AIVDM,text\n");
+    assert_eq!(records[1]["length"], 3);
+    assert_eq!(records[1]["encoding"], "base64");
+    assert_eq!(records[1]["payload"], "/wBB");
+    truncate(logfile);
+}
+
+#[test]
+fn test_server_ais_json_format() {
+    let listen_addr = "127.0.0.1:9930".to_string();
+    let pathstr = &[TESTINGDIR, "streamoutput_ais_json.log"].join("");
+    let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
+    let _ = remove_file(&logfile);
+    let options = ServerOptions {
+        format: OutputFormat::AisJson,
+        timestamp: TimestampFormat::Epoch,
+        ..Default::default()
+    };
+
+    let l = listener_with(listen_addr.clone(), logfile.clone(), &options).unwrap();
+    sleep(Duration::from_millis(15));
+    let (target_addr, target_socket) = target_socket_interface(&listen_addr).unwrap();
+    let datagram = concat!(
+        "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n",
+        "not a sentence\n",
+        "!AIVDM,1,1,,A,H42O55i18tMET00000000000000,2*6D\n",
+    );
+    target_socket
+        .send_to(datagram.as_bytes(), target_addr)
+        .unwrap();
+    sleep(Duration::from_millis(15));
+    l.shutdown().unwrap();
+
+    let output = read_to_string(&logfile).unwrap();
+    let records: Vec<serde_json::Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 2);
+    let source = format!("127.0.0.1:{}", target_socket.local_addr().unwrap().port());
+    for record in &records {
+        assert_eq!(record["source"], source);
+        assert!(record["received"].is_f64());
+    }
+    assert_eq!(records[0]["message_type"], 1);
+    assert_eq!(records[0]["mmsi"], 366053209);
+    assert_eq!(records[0]["cog"], 219.3);
+    assert_eq!(records[0]["heading"], 1);
+    assert_eq!(records[1]["message_type"], 24);
+    assert_eq!(records[1]["name"], "PROGUY");
+    assert!(records[1]["callsign"].is_null());
+    truncate(logfile);
+}
+
+#[test]
+fn test_server_ais_json_reassembly() {
+    let listen_addr = "127.0.0.1:9931".to_string();
+    let pathstr = &[TESTINGDIR, "streamoutput_ais_json_reassembly.log"].join("");
+    let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
+    let _ = remove_file(&logfile);
+    let options = ServerOptions {
+        format: OutputFormat::AisJson,
+        reassembly: Some(ReassemblyOptions::default()),
+        ..Default::default()
+    };
+
+    let l = listener_with(listen_addr.clone(), logfile.clone(), &options).unwrap();
+    sleep(Duration::from_millis(15));
+    let (target_addr, target_socket) = target_socket_interface(&listen_addr).unwrap();
+    for msg in [
+        "!AIVDM,2,1,3,B,55P5TL01VIaAL@7WKO@mBplU@<PDhh000000001S;AJ::4A80?4i@E53,0*3E\n",
+        "!AIVDM,1,1,,B,15M67FC000G?ufbE`FepT@3n00Sa,0*5C\n",
+        "!AIVDM,2,2,3,B,1@0000000000000,2*55\n",
+    ] {
+        target_socket.send_to(msg.as_bytes(), target_addr).unwrap();
+        sleep(Duration::from_millis(5));
+    }
+    sleep(Duration::from_millis(15));
+    l.shutdown().unwrap();
+
+    let output = read_to_string(&logfile).unwrap();
+    let records: Vec<serde_json::Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["message_type"], 1);
+    assert_eq!(records[1]["message_type"], 5);
+    assert_eq!(records[1]["mmsi"], 369190000);
+    assert_eq!(records[1]["name"], "MT.MITCHELL");
+    assert_eq!(records[1]["destination"], "SEATTLE");
+    truncate(logfile);
+}
+
+#[test]
+fn test_server_pcap() {
+    let listen_addr = "127.0.0.1:9929".to_string();
+    let pathstr = &[TESTINGDIR, "streamoutput_pcap.log"].join("");
+    let logfile: PathBuf = PathBuf::from_str(pathstr).unwrap();
+    let capture = PathBuf::from(&[TESTINGDIR, "streamoutput.pcapng"].join(""));
+    let options = ServerOptions {
+        pcap: Some(PcapSink::create(&capture).unwrap()),
+        ..Default::default()
+    };
+
+    let l = listener_with(listen_addr.clone(), logfile.clone(), &options).unwrap();
+    sleep(Duration::from_millis(15));
+    let (target_addr, target_socket) = target_socket_interface(&listen_addr).unwrap();
+    target_socket
+        .send_to(b"Hello from client!", target_addr)
+        .unwrap();
+    sleep(Duration::from_millis(15));
+    l.shutdown().unwrap();
+
+    let packets: Vec<PcapPacket> = PcapReader::new(File::open(&capture).unwrap())
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].payload, b"Hello from client!");
+    assert_eq!(packets[0].destination.to_string(), listen_addr);
+    assert_eq!(
+        packets[0].source.port(),
+        target_socket.local_addr().unwrap().port()
+    );
+    remove_file(&capture).unwrap();
+    truncate(logfile);
 }
@@ -0,0 +1,32 @@
+#!/bin/sh
+# Regenerate the certificates used by the TLS tests of mproxy-forward:
+# a private CA, a server certificate for localhost, 127.0.0.1 and ::1,
+# and a client certificate, each signed by the CA. Also an encrypted copy of
+# the client key and a P-521 key, which rustls cannot use
+set -e
+cd "$(dirname "$0")"
+DAYS=36500
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+    -keyout ca.key -out ca.pem -days $DAYS -subj "/CN=mproxy test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" \
+    -addext "keyUsage=critical,keyCertSign,cRLSign"
+
+for name in server client; do
+    openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+        -keyout $name.key -out $name.csr -subj "/CN=mproxy test $name"
+done
+
+printf 'basicConstraints=critical,CA:FALSE\nkeyUsage=critical,digitalSignature\nextendedKeyUsage=serverAuth\nsubjectAltName=DNS:localhost,IP:127.0.0.1,IP:::1\n' > server.ext
+printf 'basicConstraints=critical,CA:FALSE\nkeyUsage=critical,digitalSignature\nextendedKeyUsage=clientAuth\n' > client.ext
+
+for name in server client; do
+    openssl x509 -req -in $name.csr -CA ca.pem -CAkey ca.key -CAcreateserial \
+        -out $name.pem -days $DAYS -extfile $name.ext
+done
+
+openssl pkcs8 -topk8 -in client.key -out encrypted.key -passout pass:mproxy
+openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-521 -out unsupported.key
+
+rm -f ./*.csr ./*.ext ./*.srl
+openssl x509 -in server.pem -noout -fingerprint -sha256